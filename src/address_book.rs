@@ -0,0 +1,58 @@
+use std::{ collections::HashMap, path::PathBuf, time::Duration };
+use libp2p::{ Multiaddr, PeerId };
+use serde::{ Deserialize, Serialize };
+
+use crate::persist::{ unix_now, JsonFile };
+
+// Peers not seen for this long are dropped from the address book
+const FORGET_AFTER: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// A peer we have connected to before
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownPeer {
+    pub addresses: Vec<Multiaddr>,
+    pub nickname: Option<String>,
+    pub last_seen: u64, // Seconds since the UNIX epoch
+}
+
+// Every peer we've connected to, kept in a file so the next run can reconnect without the rendezvous server
+#[derive(Default)]
+pub struct AddressBook {
+    pub peers: HashMap<PeerId, KnownPeer>,
+    file: JsonFile<HashMap<PeerId, KnownPeer>>,
+}
+
+impl AddressBook {
+    pub fn load(file: PathBuf) -> Self {
+        let (file, mut peers): (_, HashMap<PeerId, KnownPeer>) = JsonFile::open(file, "the address book");
+        let cutoff = unix_now().saturating_sub(FORGET_AFTER.as_secs());
+        peers.retain(|_, peer| peer.last_seen >= cutoff);
+        AddressBook { peers, file }
+    }
+
+    // Remember an address we reached a peer on
+    pub fn record_connection(&mut self, peer_id: PeerId, address: Multiaddr) {
+        let peer = self.peers.entry(peer_id).or_insert_with(|| KnownPeer {
+            addresses: Vec::new(),
+            nickname: None,
+            last_seen: 0,
+        });
+        if !peer.addresses.contains(&address) {
+            peer.addresses.push(address);
+        }
+        peer.last_seen = unix_now();
+        self.save();
+    }
+
+    pub fn set_nickname(&mut self, peer_id: &PeerId, nickname: &str) {
+        let Some(peer) = self.peers.get_mut(peer_id) else { return };
+        if peer.nickname.as_deref() != Some(nickname) {
+            peer.nickname = Some(nickname.to_string());
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        self.file.save(&self.peers);
+    }
+}
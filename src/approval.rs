@@ -0,0 +1,318 @@
+use std::{ path::{ Path, PathBuf }, time::Instant };
+use libp2p::{ request_response::ResponseChannel, PeerId };
+use tokio::{ fs::File, io::AsyncReadExt };
+
+use crate::{
+    audit::{ self, AuditEvent },
+    bench,
+    behaviour::{ record_sent, save_received, RequestType, ResponseType, SwapBytesBehaviour, REQUEST_TIMEOUT },
+    chunking::{ self, Received },
+    filenames,
+    notify::{ self, Trigger },
+    output::{ self, Event, Kind },
+    util::ChatState,
+};
+
+// Which offered files we're willing to be asked about, from --accept-extensions and --max-offer-size
+#[derive(Debug, Default, Clone)]
+pub struct OfferPolicy {
+    pub extensions: Vec<String>, // Lowercase, without the dot. Empty allows any
+    pub max_size: Option<u64>,
+}
+
+impl OfferPolicy {
+    pub fn new(extensions: &[String], max_size: Option<u64>) -> Self {
+        let extensions = extensions.iter().map(|extension| extension.trim().trim_start_matches('.').to_lowercase()).filter(|extension| !extension.is_empty()).collect();
+        OfferPolicy { extensions, max_size }
+    }
+
+    // Why an offer falls outside the policy, if it does
+    pub fn check(&self, filename: &str, size: u64) -> Result<(), String> {
+        if let Some(max_size) = self.max_size && size > max_size {
+            return Err(format!("files over {max_size} bytes are not accepted"));
+        }
+        if self.extensions.is_empty() {
+            return Ok(());
+        }
+        let extension = Path::new(filename).extension().map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension {
+            Some(extension) if self.extensions.contains(&extension) => Ok(()),
+            _ => Err(format!("only {} files are accepted", self.extensions.join(", "))),
+        }
+    }
+}
+
+// The file in the share directory a peer's /request names. Only names directly in it are served, exactly as
+// they're written there, and nothing a link inside it leads out to. Without a share directory nothing is
+pub fn shared_path(share_dir: Option<&Path>, filename: &str) -> Result<PathBuf, String> {
+    let Some(share_dir) = share_dir else {
+        return Err("no files are being shared".to_string());
+    };
+    if filenames::sanitize(filename)? != filename {
+        return Err("only files directly in the share directory can be requested".to_string());
+    }
+    let root = share_dir.canonicalize().map_err(|e| format!("the share directory can't be read, {e}"))?;
+    match root.join(filename).canonicalize() {
+        Ok(path) if path.starts_with(&root) && path.is_file() => Ok(path),
+        Ok(_) => Err("it isn't in the share directory".to_string()),
+        Err(_) => Err("there is no such file".to_string()),
+    }
+}
+
+// Why an offer has to be turned down without asking, if it does: a suspicious name, or being outside the policy
+pub fn offer_rejection(state: &ChatState, filename: &str, size: u64) -> Option<String> {
+    filenames::sanitize(filename)
+        .map_err(|reason| format!("its name was refused, {reason}"))
+        .and_then(|name| state.offer_policy.check(&name, size))
+        .err()
+}
+
+// Whether a peer is in one of our rooms: a member of one of our private rooms, or subscribed to a topic we're on
+fn shares_room(swarm: &libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState, peer: &PeerId) -> bool {
+    state.rooms.values().any(|room| room.is_member(peer))
+        || swarm.behaviour().chat.gossipsub.topics().any(|topic| state.subscribers.is_subscribed(peer, topic))
+}
+
+// A file request or offer waiting for the user to answer with /yes or /no
+pub enum PendingApproval {
+    // A peer asked us to send them one of our files
+    FileRequest {
+        peer: PeerId,
+        filename: String,
+        channel: ResponseChannel<ResponseType>,
+    },
+    // A peer wants to send us a file
+    FileOffer {
+        peer: PeerId,
+        filename: String,
+        file: Received,
+        reply: OfferReply,
+    },
+    // A peer wants to measure their connection to us, see bench
+    Bench {
+        peer: PeerId,
+        bench_id: String,
+        size: u64,
+        channel: ResponseChannel<ResponseType>,
+    },
+}
+
+// Where the answer to an offer goes. Offers sent whole wait on their response channel, but a chunked offer's channel
+// is answered as soon as it's announced, since the chunks and the user together can take longer than a request is
+// kept open, and the answer follows as an OfferAnswer request
+pub enum OfferReply {
+    Channel(ResponseChannel<ResponseType>),
+    Request { transfer_id: String },
+}
+
+impl OfferReply {
+    // Send the answer: whether the offer was accepted, and if it was turned down without asking the user, why
+    fn send(self, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, peer: PeerId, accepted: bool, reason: Option<String>) -> bool {
+        match self {
+            OfferReply::Channel(channel) => {
+                let response = match reason {
+                    Some(reason) => ResponseType::FileOfferDeclined(reason),
+                    None => ResponseType::FileOfferResponse(accepted),
+                };
+                swarm.behaviour_mut().request_response.request_response.send_response(channel, response).is_ok()
+            }
+            OfferReply::Request { transfer_id } => {
+                let answer = RequestType::OfferAnswer { transfer_id, accepted, reason: reason.unwrap_or_default() };
+                swarm.behaviour_mut().request_response.request_response.send_request(&peer, answer);
+                true
+            }
+        }
+    }
+}
+
+impl PendingApproval {
+    pub fn peer(&self) -> &PeerId {
+        match self {
+            PendingApproval::FileRequest { peer, .. } | PendingApproval::FileOffer { peer, .. } | PendingApproval::Bench { peer, .. } => peer,
+        }
+    }
+
+    // The file asked about, if it's about a file
+    fn filename(&self) -> Option<&str> {
+        match self {
+            PendingApproval::FileRequest { filename, .. } | PendingApproval::FileOffer { filename, .. } => Some(filename),
+            PendingApproval::Bench { .. } => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            PendingApproval::FileRequest { filename, .. } => format!("wants you to send {filename}"),
+            PendingApproval::FileOffer { filename, file, .. } => format!("is offering {filename} ({} bytes)", file.size()),
+            PendingApproval::Bench { size, .. } => format!("wants to measure the connection by sending you {}", bench::format_bytes(*size as f64)),
+        }
+    }
+
+    pub fn event(&self, id: u32, nickname: &str) -> Event {
+        let (peer, nickname) = (*self.peer(), nickname.to_string());
+        match self {
+            PendingApproval::FileRequest { filename, .. } => Event::FileRequest { id, peer, nickname, filename: filename.clone() },
+            PendingApproval::FileOffer { filename, file, .. } => {
+                Event::FileOffer { id, peer, nickname, filename: filename.clone(), size: file.size() as usize }
+            }
+            PendingApproval::Bench { size, .. } => Event::BenchRequest { id, peer, nickname, size: *size },
+        }
+    }
+}
+
+// Queue a request or offer, unless the peer's rating means it can be answered straight away. It expires counting
+// from when it `arrived`, as that's when the peer started waiting
+pub async fn queue_approval(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, approval: PendingApproval, arrived: Instant) {
+    let peer = *approval.peer();
+    let name = state.cached_peer(&peer).map(|peer_data| peer_data.nickname.clone()).unwrap_or(peer.to_string());
+    match &approval {
+        PendingApproval::FileRequest { filename, .. } => state.audit(AuditEvent::RequestReceived, peer, filename, None),
+        PendingApproval::FileOffer { filename, file, .. } => state.audit(AuditEvent::OfferReceived, peer, filename, Some(file.digest())),
+        PendingApproval::Bench { .. } => {}
+    }
+    // Offers with a suspicious name or outside the policy are turned down without asking, telling the offerer why.
+    // Requests are refused the same way from peers outside our rooms, and for anything not in the share directory
+    let rejection = match &approval {
+        PendingApproval::FileOffer { filename, file, .. } => offer_rejection(state, filename, file.size()),
+        PendingApproval::FileRequest { .. } if !shares_room(swarm, state, &peer) => Some("they aren't in any of your rooms".to_string()),
+        PendingApproval::FileRequest { filename, .. } => shared_path(state.share_dir.as_deref(), filename).err(),
+        PendingApproval::Bench { .. } => None,
+    };
+    if let Some(reason) = rejection {
+        match approval {
+            PendingApproval::FileOffer { filename, reply, .. } => {
+                system!("Turned down {} from {name}: {reason}.", filename.escape_debug());
+                state.audit(AuditEvent::Declined, peer, &filename, None);
+                if !reply.send(swarm, peer, false, Some(reason)) {
+                    tracing::debug!("Could not tell {peer} why their offer was turned down");
+                }
+            }
+            PendingApproval::FileRequest { filename, channel, .. } => {
+                system!("Refused {name}'s request for {}: {reason}.", filename.escape_debug());
+                state.audit(AuditEvent::Declined, peer, &filename, None);
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::FileResponse(vec![], String::new())).is_err() {
+                    tracing::debug!("Could not refuse {peer}'s request");
+                }
+            }
+            PendingApproval::Bench { .. } => {}
+        }
+        return;
+    }
+    // Files chosen from a multi-file offer were already agreed to
+    if let PendingApproval::FileOffer { filename, file, .. } = &approval
+        && state.manifests.take(&peer, filename, file) {
+        system!("{filename} arrived from {}, one of the files you took.", state.peer_name(&peer));
+        respond_to_approval(swarm, state, approval, true).await;
+        return;
+    }
+    // Only offers are accepted automatically: requests send our files away, and benchmarks a lot of data
+    if matches!(approval, PendingApproval::FileOffer { .. }) && state.auto_accepts(&peer) {
+        system!("{name} {}. Accepting automatically, they meet your auto-accept rating.", approval.describe());
+        respond_to_approval(swarm, state, approval, true).await;
+        return;
+    }
+    let approval_id = state.next_approval_id;
+    state.next_approval_id += 1;
+    let trigger = match approval {
+        PendingApproval::FileRequest { .. } => Trigger::FileRequest,
+        PendingApproval::FileOffer { .. } => Trigger::FileOffer,
+        PendingApproval::Bench { .. } => Trigger::Benchmark,
+    };
+    notify::notify(trigger, &name, &format!("{name} {}", approval.describe()));
+    output::publish(approval.event(approval_id, &name));
+    output::print_text(Kind::System, &format!("[{approval_id}] {name} {}. Type /yes {approval_id} or /no {approval_id} to answer.", approval.describe()));
+    state.pending_approvals.insert(approval_id, (approval, arrived));
+}
+
+// Drop requests and offers that went unanswered until the peer gave up waiting, as any answer now would be lost.
+// Chunked offers are still waited on, so their senders are told
+pub fn expire(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState) {
+    let mut expired: Vec<u32> = state.pending_approvals.iter()
+        .filter(|(_, (_, arrived))| arrived.elapsed() >= REQUEST_TIMEOUT)
+        .map(|(approval_id, _)| *approval_id)
+        .collect();
+    expired.sort();
+    for approval_id in expired {
+        let Some((approval, _)) = state.pending_approvals.remove(&approval_id) else { continue };
+        let peer = *approval.peer();
+        if let Some(filename) = approval.filename() {
+            state.audit(AuditEvent::Declined, peer, filename, None);
+        }
+        system!("[{approval_id}] Expired: {} {}, but it went unanswered for {} minutes.", state.peer_name(&peer), approval.describe(), REQUEST_TIMEOUT.as_secs() / 60);
+        if let PendingApproval::FileOffer { reply: reply @ OfferReply::Request { .. }, .. } = approval {
+            reply.send(swarm, peer, false, Some("it went unanswered".to_string()));
+        }
+    }
+}
+
+// Answer a queued file request or offer
+pub async fn respond_to_approval(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, approval: PendingApproval, accept: bool) {
+    if let Some(filename) = approval.filename() {
+        state.audit(if accept { AuditEvent::Accepted } else { AuditEvent::Declined }, *approval.peer(), filename, None);
+    }
+    match approval {
+        PendingApproval::FileRequest { channel, .. } if !accept => {
+            // Send a rejection response
+            if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::FileResponse(vec![], String::new())).is_err() {
+                error!("Failed to send file response");
+            }
+        }
+        PendingApproval::FileRequest { peer, filename, channel } => {
+            // Digest the file a block at a time, then send it. The share directory is checked again, as the file
+            // could have been moved or replaced while the request waited
+            let opened = match shared_path(state.share_dir.as_deref(), &filename) {
+                Ok(path) => File::open(&path).await.ok().zip(audit::digest_file(&path).await.ok()),
+                Err(_) => None,
+            };
+            match opened {
+                Some((mut file, digest)) => {
+                    let size = file.metadata().await.map(|metadata| metadata.len()).unwrap_or(0);
+                    let chunk_size = chunking::chunk_size(state, &peer);
+                    // Files too big for the requester to take in one message follow the response in pieces, read
+                    // from disk as the requester takes them
+                    let (response, stream) = if size > chunk_size as u64 {
+                        let transfer_id = chunking::new_transfer_id();
+                        let response = ResponseType::ChunkedFileResponse { transfer_id: transfer_id.clone(), filename: filename.clone(), size };
+                        (response, Some((transfer_id, file)))
+                    } else {
+                        let mut buffer = Vec::new();
+                        if let Err(e) = file.read_to_end(&mut buffer).await {
+                            error!("Failed to read file: {:?}", e);
+                        }
+                        (ResponseType::FileResponse(buffer, filename.clone()), None)
+                    };
+                    // Send the response to the file requester
+                    match swarm.behaviour_mut().request_response.request_response.send_response(channel, response) {
+                        Ok(()) => {
+                            if let Some((transfer_id, file)) = stream {
+                                chunking::stream(swarm, state, peer, transfer_id, file, size).await;
+                            }
+                            record_sent(swarm, state, peer, &filename, Some(digest));
+                        }
+                        Err(_) => error!("Failed to send file response, the request may have timed out")
+                    }
+                }
+                // If the file doesn't exist send an empty vector
+                None => {
+                    error!("File not found. Sending empty response.");
+                    if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::FileResponse(vec![], String::new())).is_err() {
+                        error!("Failed to send file response");
+                    }
+                }
+            }
+        }
+        PendingApproval::FileOffer { peer, reply, .. } if !accept => {
+            // Send a rejection response
+            if !reply.send(swarm, peer, false, None) {
+                error!("Error sending rejection, the offer may have timed out");
+            }
+        }
+        PendingApproval::FileOffer { peer, filename, file, reply } => {
+            match reply.send(swarm, peer, true, None) {
+                true => save_received(swarm, state, peer, &filename, file).await,
+                false => error!("Error accepting the offer, it may have timed out")
+            }
+        }
+        PendingApproval::Bench { peer, bench_id, size, channel } => bench::answer(swarm, state, peer, bench_id, size, channel, accept),
+    }
+}
@@ -0,0 +1,409 @@
+use std::{ fmt, fs, path::{ Path, PathBuf } };
+use libp2p::{ identity::{ self, Keypair }, PeerId };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use tokio::io::AsyncReadExt;
+
+use crate::persist::{ self, unix_now };
+
+// The hash the first entry chains from
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// What happened in an exchange, from our side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditEvent {
+    RequestSent,
+    RequestReceived,
+    OfferSent,
+    OfferReceived,
+    Accepted,      // We said yes to their request or offer
+    Declined,      // We said no, or our offer policy did
+    PeerAccepted,  // They said yes to ours
+    PeerDeclined,
+    Sent,
+    Received,
+    Delivered,     // They signed a receipt for a file we sent
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            AuditEvent::RequestSent => "requested from",
+            AuditEvent::RequestReceived => "requested by",
+            AuditEvent::OfferSent => "offered to",
+            AuditEvent::OfferReceived => "offered by",
+            AuditEvent::Accepted => "accepted from",
+            AuditEvent::Declined => "declined from",
+            AuditEvent::PeerAccepted => "accepted by",
+            AuditEvent::PeerDeclined => "declined by",
+            AuditEvent::Sent => "sent to",
+            AuditEvent::Received => "received from",
+            AuditEvent::Delivered => "receipt signed by",
+        };
+        write!(f, "{text}")
+    }
+}
+
+// Signed by whoever received a file, over the file's hash, and sent back to the sender so they can
+// show it arrived
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedReceipt {
+    pub sender: PeerId,
+    pub receiver: PeerId,
+    pub receiver_key: Vec<u8>, // Protobuf-encoded identity key of the receiver
+    pub filename: String,
+    pub digest: String, // SHA-256 of the file, hex
+    pub timestamp: u64,
+    pub signature: Vec<u8>,
+}
+
+impl SignedReceipt {
+    pub fn new(keypair: &Keypair, sender: PeerId, filename: &str, digest: String) -> Self {
+        let timestamp = unix_now();
+        let signature = keypair.sign(&receipt_payload(&sender, filename, &digest, timestamp)).expect("Signing failed");
+        SignedReceipt {
+            sender,
+            receiver: keypair.public().to_peer_id(),
+            receiver_key: keypair.public().encode_protobuf(),
+            filename: filename.to_string(),
+            digest,
+            timestamp,
+            signature,
+        }
+    }
+
+    // Check the receipt is for a file we sent this peer, and was signed by them
+    pub fn verify(&self, sender: &PeerId, receiver: &PeerId) -> bool {
+        if self.sender != *sender || self.receiver != *receiver {
+            return false;
+        }
+        match identity::PublicKey::try_decode_protobuf(&self.receiver_key) {
+            Ok(public_key) => public_key.to_peer_id() == self.receiver
+                && public_key.verify(&receipt_payload(&self.sender, &self.filename, &self.digest, self.timestamp), &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+fn receipt_payload(sender: &PeerId, filename: &str, digest: &str, timestamp: u64) -> Vec<u8> {
+    let mut payload = b"swapbytes receipt".to_vec();
+    payload.extend_from_slice(&sender.to_bytes());
+    payload.extend_from_slice(filename.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(digest.as_bytes());
+    payload.extend_from_slice(&timestamp.to_be_bytes());
+    payload
+}
+
+// Signed by each side of a completed trade and sent to the other, naming the trade and both files' hashes.
+// The one a peer signed is proof we traded with them, so it can back up a rating we give them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedTradeReceipt {
+    pub trade_id: String,
+    pub signer: PeerId,
+    pub signer_key: Vec<u8>, // Protobuf-encoded identity key of the signer
+    pub counterparty: PeerId,
+    pub gave: String, // SHA-256 of the file the signer sent, hex
+    pub got: String,  // SHA-256 of the file the signer received, hex
+    pub timestamp: u64,
+    pub signature: Vec<u8>,
+}
+
+impl SignedTradeReceipt {
+    pub fn new(keypair: &Keypair, counterparty: PeerId, trade_id: &str, gave: &str, got: &str) -> Self {
+        let timestamp = unix_now();
+        let signature = keypair.sign(&trade_receipt_payload(trade_id, &counterparty, gave, got, timestamp)).expect("Signing failed");
+        SignedTradeReceipt {
+            trade_id: trade_id.to_string(),
+            signer: keypair.public().to_peer_id(),
+            signer_key: keypair.public().encode_protobuf(),
+            counterparty,
+            gave: gave.to_string(),
+            got: got.to_string(),
+            timestamp,
+            signature,
+        }
+    }
+
+    // Check the receipt is for a trade between these two peers, and was signed by the signer
+    pub fn verify(&self, signer: &PeerId, counterparty: &PeerId) -> bool {
+        if self.signer != *signer || self.counterparty != *counterparty {
+            return false;
+        }
+        match identity::PublicKey::try_decode_protobuf(&self.signer_key) {
+            Ok(public_key) => public_key.to_peer_id() == self.signer
+                && public_key.verify(&trade_receipt_payload(&self.trade_id, &self.counterparty, &self.gave, &self.got, self.timestamp), &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+fn trade_receipt_payload(trade_id: &str, counterparty: &PeerId, gave: &str, got: &str, timestamp: u64) -> Vec<u8> {
+    let mut payload = b"swapbytes trade receipt".to_vec();
+    payload.extend_from_slice(trade_id.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(&counterparty.to_bytes());
+    payload.extend_from_slice(gave.as_bytes());
+    payload.extend_from_slice(got.as_bytes());
+    payload.extend_from_slice(&timestamp.to_be_bytes());
+    payload
+}
+
+// The part of an entry its hash covers
+#[derive(Serialize)]
+struct Body<'a> {
+    index: u64,
+    timestamp: u64,
+    event: AuditEvent,
+    peer: &'a PeerId,
+    filename: &'a str,
+    digest: &'a Option<String>,
+    receipt: &'a Option<SignedReceipt>,
+    prev_hash: &'a str,
+}
+
+// One line of the log. Each entry's hash covers the one before it, and is signed with our identity key,
+// so entries can't be altered, removed or reordered without it showing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub index: u64,
+    pub timestamp: u64,
+    pub event: AuditEvent,
+    pub peer: PeerId,
+    pub filename: String,
+    pub digest: Option<String>, // SHA-256 of the file, hex, where we had it
+    pub receipt: Option<SignedReceipt>,
+    pub prev_hash: String,
+    pub hash: String,
+    pub signature: Vec<u8>,
+}
+
+impl AuditEntry {
+    fn body_hash(&self) -> String {
+        let body = Body {
+            index: self.index,
+            timestamp: self.timestamp,
+            event: self.event,
+            peer: &self.peer,
+            filename: &self.filename,
+            digest: &self.digest,
+            receipt: &self.receipt,
+            prev_hash: &self.prev_hash,
+        };
+        digest(&serde_json::to_vec(&body).expect("Audit entries serialize"))
+    }
+}
+
+// What /audit export writes: the entries plus the key they were signed with, so anyone can check them
+#[derive(Serialize, Deserialize)]
+pub struct AuditExport {
+    pub peer_id: PeerId,
+    pub public_key: Vec<u8>, // Protobuf-encoded
+    pub entries: Vec<AuditEntry>,
+}
+
+// Every request, offer, answer and transfer, appended to a file one JSON line at a time
+#[derive(Default)]
+pub struct AuditLog {
+    pub entries: Vec<AuditEntry>,
+    file: Option<PathBuf>,
+}
+
+impl AuditLog {
+    // Load the log kept in a file, appending every new entry to it
+    pub fn load(file: PathBuf) -> Self {
+        let contents = fs::read_to_string(&file).unwrap_or_default();
+        let entries = contents.lines().filter_map(|line| serde_json::from_str(line).ok()).collect();
+        // End a line cut short by a crash, so the next entry isn't written onto the end of it
+        if !contents.is_empty() && !contents.ends_with('\n') && let Err(e) = persist::append_private(&file, "") {
+            tracing::error!("Failed to repair your audit log in {}: {e}", file.display());
+        }
+        AuditLog { entries, file: Some(file) }
+    }
+
+    pub fn append(&mut self, keypair: &Keypair, event: AuditEvent, peer: PeerId, filename: &str, digest: Option<String>, receipt: Option<SignedReceipt>) {
+        let (index, prev_hash) = match self.entries.last() {
+            Some(last) => (last.index + 1, last.hash.clone()),
+            None => (0, GENESIS.to_string()),
+        };
+        let mut entry = AuditEntry {
+            index,
+            timestamp: unix_now(),
+            event,
+            peer,
+            filename: filename.to_string(),
+            digest,
+            receipt,
+            prev_hash,
+            hash: String::new(),
+            signature: Vec::new(),
+        };
+        entry.hash = entry.body_hash();
+        entry.signature = keypair.sign(entry.hash.as_bytes()).expect("Signing failed");
+        if let Some(file) = &self.file {
+            let saved = serde_json::to_string(&entry).map_err(|e| e.to_string())
+                .and_then(|line| persist::append_private(file, &line).map_err(|e| e.to_string()));
+            if let Err(e) = saved {
+                tracing::error!("Failed to add to your audit log in {}: {e}", file.display());
+            }
+        }
+        self.entries.push(entry);
+    }
+
+    pub fn export(&self, keypair: &Keypair, path: &Path) -> Result<(), String> {
+        let export = AuditExport {
+            peer_id: keypair.public().to_peer_id(),
+            public_key: keypair.public().encode_protobuf(),
+            entries: self.entries.clone(),
+        };
+        let contents = serde_json::to_vec_pretty(&export).map_err(|e| e.to_string())?;
+        fs::write(path, contents).map_err(|e| e.to_string())
+    }
+}
+
+// Check a log exported with /audit export, returning whose it is, how many entries it has and how many
+// carry a signed receipt
+pub fn check_export(path: &Path) -> Result<(PeerId, usize, usize), String> {
+    let contents = fs::read(path).map_err(|e| e.to_string())?;
+    let export: AuditExport = serde_json::from_slice(&contents).map_err(|e| format!("not an exported audit log: {e}"))?;
+    let public_key = identity::PublicKey::try_decode_protobuf(&export.public_key).map_err(|e| e.to_string())?;
+    if public_key.to_peer_id() != export.peer_id {
+        return Err("its key doesn't belong to the peer it names".to_string());
+    }
+    verify(&export.entries, &public_key).map_err(|index| format!("entry {index} has been altered, removed or reordered"))?;
+    let mut receipts = 0;
+    for entry in &export.entries {
+        if let Some(receipt) = &entry.receipt {
+            if !receipt.verify(&export.peer_id, &entry.peer) || Some(&receipt.digest) != entry.digest.as_ref() {
+                return Err(format!("the receipt in entry {} is forged", entry.index));
+            }
+            receipts += 1;
+        }
+    }
+    Ok((export.peer_id, export.entries.len(), receipts))
+}
+
+// Check the chain is unbroken and every entry was signed with this key, returning the first bad entry
+pub fn verify(entries: &[AuditEntry], public_key: &identity::PublicKey) -> Result<(), u64> {
+    let mut prev_hash = GENESIS;
+    for (index, entry) in entries.iter().enumerate() {
+        let intact = entry.index == index as u64
+            && entry.prev_hash == prev_hash
+            && entry.hash == entry.body_hash()
+            && public_key.verify(entry.hash.as_bytes(), &entry.signature);
+        if !intact {
+            return Err(index as u64);
+        }
+        prev_hash = &entry.hash;
+    }
+    Ok(())
+}
+
+pub fn digest(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// The same digest for a file on disk, read a block at a time so big files aren't loaded whole
+pub async fn digest_file(path: &Path) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut block = vec![0u8; 64 * 1024];
+    loop {
+        match file.read(&mut block).await? {
+            0 => break,
+            read => hasher.update(&block[..read]),
+        }
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A log of three exchanges with one peer, kept in memory only
+    fn log(keypair: &Keypair) -> AuditLog {
+        let peer = Keypair::generate_ed25519().public().to_peer_id();
+        let mut log = AuditLog::default();
+        log.append(keypair, AuditEvent::OfferSent, peer, "notes.txt", Some(digest(b"notes")), None);
+        log.append(keypair, AuditEvent::PeerAccepted, peer, "notes.txt", None, None);
+        log.append(keypair, AuditEvent::Sent, peer, "notes.txt", Some(digest(b"notes")), None);
+        log
+    }
+
+    #[test]
+    fn appended_entries_chain_and_verify() {
+        let keypair = Keypair::generate_ed25519();
+        let log = log(&keypair);
+        let indexes: Vec<u64> = log.entries.iter().map(|entry| entry.index).collect();
+        assert_eq!(indexes, vec![0, 1, 2]);
+        assert_eq!(log.entries[0].prev_hash, GENESIS);
+        assert_eq!(log.entries[2].prev_hash, log.entries[1].hash);
+        assert_eq!(verify(&log.entries, &keypair.public()), Ok(()));
+        assert_eq!(verify(&[], &keypair.public()), Ok(()));
+    }
+
+    #[test]
+    fn a_log_signed_with_another_key_fails() {
+        let log = log(&Keypair::generate_ed25519());
+        assert_eq!(verify(&log.entries, &Keypair::generate_ed25519().public()), Err(0));
+    }
+
+    #[test]
+    fn tampering_shows_at_the_altered_entry() {
+        let keypair = Keypair::generate_ed25519();
+        let log = log(&keypair);
+
+        let mut renamed = log.entries.clone();
+        renamed[1].filename = "other.txt".to_string();
+        assert_eq!(verify(&renamed, &keypair.public()), Err(1));
+
+        // Rehashing the altered entry doesn't help without the key to sign it
+        renamed[1].hash = renamed[1].body_hash();
+        assert_eq!(verify(&renamed, &keypair.public()), Err(1));
+
+        let mut reordered = log.entries.clone();
+        reordered.swap(1, 2);
+        assert_eq!(verify(&reordered, &keypair.public()), Err(1));
+    }
+
+    #[test]
+    fn removing_entries_breaks_the_chain_except_at_the_end() {
+        let keypair = Keypair::generate_ed25519();
+        let log = log(&keypair);
+
+        let mut middle = log.entries.clone();
+        middle.remove(1);
+        assert_eq!(verify(&middle, &keypair.public()), Err(1));
+
+        let mut first = log.entries.clone();
+        first.remove(0);
+        assert_eq!(verify(&first, &keypair.public()), Err(0));
+
+        // The latest entries aren't covered by anything after them, so cutting them off still verifies
+        assert_eq!(verify(&log.entries[..2], &keypair.public()), Ok(()));
+    }
+
+    // A line cut short, by a crash partway through writing it, is skipped and the log carries on from the last
+    // whole entry
+    #[test]
+    fn a_log_file_cut_off_mid_entry_loads_and_carries_on() {
+        let keypair = Keypair::generate_ed25519();
+        let file = std::env::temp_dir().join(format!("swapbytes-audit-{}.jsonl", keypair.public().to_peer_id()));
+        let mut log = AuditLog::load(file.clone());
+        let peer = Keypair::generate_ed25519().public().to_peer_id();
+        log.append(&keypair, AuditEvent::RequestReceived, peer, "notes.txt", None, None);
+        log.append(&keypair, AuditEvent::Accepted, peer, "notes.txt", None, None);
+        let contents = fs::read_to_string(&file).unwrap();
+        fs::write(&file, &contents[..contents.len() - 20]).unwrap();
+
+        let mut reloaded = AuditLog::load(file.clone());
+        assert_eq!(reloaded.entries.len(), 1);
+        reloaded.append(&keypair, AuditEvent::Sent, peer, "notes.txt", None, None);
+        assert_eq!(verify(&reloaded.entries, &keypair.public()), Ok(()));
+        let reloaded = AuditLog::load(file.clone());
+        assert_eq!(reloaded.entries.len(), 2);
+        assert_eq!(verify(&reloaded.entries, &keypair.public()), Ok(()));
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -1,18 +1,46 @@
-use std::time::Duration;
+use std::{ path::{ Path, PathBuf }, time::{ Duration, Instant } };
+
+use bytes::Bytes;
 
 use serde::{Deserialize, Serialize};
 use libp2p::{
-    gossipsub::{self, IdentTopic}, kad::{self, store::MemoryStore, QueryId, QueryResult}, mdns, ping, rendezvous, request_response::{self, ProtocolSupport}, swarm::NetworkBehaviour, PeerId, StreamProtocol
+    gossipsub::{self, IdentTopic}, kad::{self, QueryId, QueryResult}, mdns, ping, rendezvous, request_response::{self, ProtocolSupport}, swarm::NetworkBehaviour, Multiaddr, PeerId, StreamProtocol
 };
-use tokio::{fs::File, io::{self, AsyncReadExt, AsyncWriteExt}};
-use uuid::Uuid;
-use crate::util::{ChatState, ConnectionRequest, Invite, PeerData, PrivateRoomProtocol};
+use crate::{ approval::{ queue_approval, OfferReply, PendingApproval }, audit::{ self, AuditEvent, SignedReceipt, SignedTradeReceipt }, bench, board, chunking::{ self, Completion, Received }, codec::FileExchangeCodec, crypto, filenames, history::Direction, manifest::{ self, Manifest }, negotiation, notify::{ self, Trigger }, output, store::DiskStore, outbound, persist::unix_now, pipeline, policy::ConnectionGate, ratelimit, scan, trade::{ self, TradeAnswer, TradeProposal }, vault, rating::{ self, SignedVouch }, render, wishlist };
+use crate::room::{ self, invite_answered, join_room, publish_room_record, publish_roster, return_to_default, IncomingInvite, InviteOutcome, PendingInvite, Room, RoomMember, RoomRecord, SignedRoster };
+use crate::util::{change_nickname, check_own_nickname, claim_own_nickname, GossipOptions, KademliaOptions, NicknameCheck, NicknameClaim, nickname_held, own_peer_data, RECORD_TTL, persist_identity, suggest_nicknames, update_own_profile, publish_gossip, update_peer_rating, ChatState, GossipMessage, ConnectionDetails, ConnectionRequest, Invite, PeerData, PrivateRoomProtocol};
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ResponseType {
     FileResponse(Vec<u8>, String),
     FileOfferResponse(bool),
     PrivateRoomResponse(PrivateRoomProtocol),
+    RoomKeyResponse(bool),
+    // The requested file is too big for one message, so it follows as Chunk requests
+    ChunkedFileResponse { transfer_id: String, filename: String, size: u64 },
+    ChunkReceived(bool),
+    // A file offer turned down without asking the user, and why
+    FileOfferDeclined(String),
+    // Whether a chunked offer is being taken. The user's answer follows as an OfferAnswer request
+    ChunkedOfferAck(bool),
+    // Whether an OfferAnswer was for an offer we made
+    OfferAnswerAck(bool),
+    // Whether a receipt was added to the sender's audit log
+    ReceiptLogged(bool),
+    // Whether a trade proposal was accepted, see trade
+    TradeResponse { trade_id: String, answer: TradeAnswer },
+    // Whether a trade's file or cancellation was taken
+    TradeAck(bool),
+    // Whether a multi-file offer or the choice from one was taken, see manifest
+    ManifestAck(bool),
+    // Whether a peer takes part in a benchmark, and where they can be reached over each transport, see bench
+    BenchResponse { bench_id: String, accepted: bool, addresses: Vec<Multiaddr> },
+    BenchAck(bool),
+    // Whether the peer leaving was in the room
+    LeavingAck(bool),
+    // Whether we were still waiting to join the room we were refused
+    RoomRefusedAck(bool),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -20,6 +48,60 @@ pub enum RequestType {
     FileRequest(String, PeerId),
     FileOffer(Vec<u8>, String),
     PrivateRoomRequest(Invite),
+    // Room key wrapped with the key agreed during the invite, plus the current members, the creator's signed
+    // roster, missing from peers without room moderation, and the room's record, missing from peers before records
+    RoomKeyRequest {
+        room_id: String,
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+        members: Vec<RoomMember>,
+        #[serde(default)]
+        roster: Option<SignedRoster>,
+        #[serde(default)]
+        record: Option<Box<RoomRecord>>,
+    },
+    // Part of a file too big for one message, see chunking. The data goes last, see codec
+    Chunk {
+        transfer_id: String,
+        index: u32,
+        #[serde(deserialize_with = "crate::codec::payload")]
+        data: Bytes,
+    },
+    // A file offer whose data was sent as Chunk requests
+    ChunkedFileOffer { transfer_id: String, filename: String, size: u64 },
+    // The answer to a chunked offer, which has no response channel to go back on, see approval::OfferReply. The
+    // reason is why it was turned down without asking, empty if the user answered
+    OfferAnswer { transfer_id: String, accepted: bool, reason: String },
+    // Signed by the receiver once a file is saved, see audit
+    Receipt(SignedReceipt),
+    // Swapping one file for another, see trade
+    TradeProposal(TradeProposal),
+    TradeFile { trade_id: String, data: Vec<u8> },
+    // A trade's file that was sent as Chunk requests
+    ChunkedTradeFile { trade_id: String, transfer_id: String, size: u64 },
+    TradeCancel { trade_id: String, reason: String },
+    // Fair trades: we hold all of their sealed file, then the key to open ours
+    TradeHeld { trade_id: String },
+    TradeKey { trade_id: String, key: [u8; 32] },
+    // An answer to a counter-offer, which has no response channel to go back on, see negotiation
+    TradeAnswer { trade_id: String, answer: TradeAnswer },
+    // Signed by each side once a trade is complete, see audit
+    TradeReceipt(SignedTradeReceipt),
+    // Several files offered at once, and which of them the other peer is taking, see manifest
+    FileManifest(Manifest),
+    ManifestSelection { manifest_id: String, indices: Vec<u32> },
+    // Measuring throughput to a peer with a payload of `size` bytes, sent as BenchData, see bench
+    BenchRequest { bench_id: String, size: u64 },
+    BenchData {
+        bench_id: String,
+        #[serde(deserialize_with = "crate::codec::payload")]
+        data: Bytes,
+    },
+    // Sent to the other members of a private room on the way out, so they hear even if the goodbye on gossip is lost
+    Leaving { room_id: String },
+    // The inviter turned an acceptance away, such as one with the wrong passphrase, so the invitee stops waiting
+    // for the room key
+    RoomRefused { room_id: String, reason: String },
 }
 
 #[derive(NetworkBehaviour)]
@@ -30,14 +112,15 @@ pub struct ChatBehaviour {
 
 #[derive(NetworkBehaviour)]
 pub struct RequestResponseBehaviour {
-    pub request_response: request_response::cbor::Behaviour<RequestType, ResponseType>,
+    pub request_response: request_response::Behaviour<FileExchangeCodec>,
 }
 
 #[derive(NetworkBehaviour)]
 pub struct SwapBytesBehaviour {
+    pub gate: ConnectionGate,
     pub chat: ChatBehaviour,
     pub request_response: RequestResponseBehaviour,
-    pub kademlia: kad::Behaviour<MemoryStore>,
+    pub kademlia: kad::Behaviour<DiskStore>,
     pub rendezvous: RendezvousBehaviour
 }
 
@@ -47,31 +130,80 @@ pub struct RendezvousBehaviour {
     pub ping: ping::Behaviour,
 }
 
+// Peers registered under the rendezvous namespace `swapbytes.<room>` chat in `<room>` by default, so the peers
+// discovery finds and the people in the room are the same. Any other namespace keeps the usual "default" room
+pub const NAMESPACE_ROOM_PREFIX: &str = "swapbytes.";
+
+pub fn namespace_room(namespace: &str) -> &str {
+    match namespace.strip_prefix(NAMESPACE_ROOM_PREFIX) {
+        Some(room) if !room.is_empty() => room,
+        _ => "default",
+    }
+}
+
 /* Create the behaviour with all configuration. Used in main when creating the swarm */
-pub fn create_swapbytes_behaviour(key: &libp2p::identity::Keypair) -> Result<SwapBytesBehaviour, Box<dyn std::error::Error>> {
+// Gossipsub's own 64 KiB limit is easy to hit with a long message once it's signed and encrypted
+pub const DEFAULT_MAX_TRANSMIT_SIZE: usize = 256 * 1024;
+
+// Pings only check that peers, the rendezvous server above all, are still answering; they don't keep connections
+// open, so there's no need for them to be frequent
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+// How long a peer waits on any one of our requests, including a file request or offer waiting on /yes or /no
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+pub fn create_swapbytes_behaviour(key: &libp2p::identity::Keypair, record_file: Option<PathBuf>, gossip: &GossipOptions, kademlia: &KademliaOptions, max_message_size: u64, ping_interval: Duration, gate: ConnectionGate) -> Result<SwapBytesBehaviour, Box<dyn std::error::Error + Send + Sync>> {
+    let gossip = gossip.clone().with_preset();
+    let mut gossipsub_config = gossipsub::ConfigBuilder::default();
+    gossipsub_config.max_transmit_size(gossip.gossip_max_transmit_size.unwrap_or(DEFAULT_MAX_TRANSMIT_SIZE));
+    if let Some(heartbeat_ms) = gossip.gossip_heartbeat_ms {
+        gossipsub_config.heartbeat_interval(Duration::from_millis(heartbeat_ms));
+    }
+    if let Some(history_length) = gossip.gossip_history_length {
+        gossipsub_config.history_length(history_length);
+    }
+    if let Some(flood_publish) = gossip.gossip_flood_publish {
+        gossipsub_config.flood_publish(flood_publish);
+    }
+    if let Some(history_gossip) = gossip.gossip_history_gossip {
+        gossipsub_config.history_gossip(history_gossip);
+    }
+    if let Some(duplicate_cache_secs) = gossip.gossip_duplicate_cache_secs {
+        gossipsub_config.duplicate_cache_time(Duration::from_secs(duplicate_cache_secs));
+    }
+    // Every message must carry its author's signature; unsigned or forged ones are rejected before we see them
+    gossipsub_config.validation_mode(gossipsub::ValidationMode::Strict);
+    let gossipsub_config = gossipsub_config.build().map_err(|e| format!("Invalid gossipsub settings: {e}"))?;
     let chat_behaviour = ChatBehaviour {
         mdns: mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?,
-        gossipsub: gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(key.clone()), gossipsub::Config::default())?,
+        gossipsub: gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(key.clone()), gossipsub_config)?,
     };
 
     let request_response_behaviour = RequestResponseBehaviour {
-        request_response: request_response::cbor::Behaviour::new([(
+        request_response: request_response::Behaviour::with_codec(FileExchangeCodec { max_message_size }, [(
             StreamProtocol::new("/file-exchange/1"),
             ProtocolSupport::Full,
-        )], request_response::Config::default()),
+        )], request_response::Config::default().with_request_timeout(REQUEST_TIMEOUT)),
     };
 
-    let kademlia_behaviour = kad::Behaviour::new(
+    let mut kademlia_config = kad::Config::new(kad::PROTOCOL_NAME);
+    if let Some(parallelism) = kademlia.kad_parallelism {
+        kademlia_config.set_parallelism(parallelism);
+    }
+    kademlia_config.set_record_ttl(Some(RECORD_TTL));
+    let kademlia_behaviour = kad::Behaviour::with_config(
                             key.public().to_peer_id(),
-                            MemoryStore::new(key.public().to_peer_id()));
+                            DiskStore::new(key.public().to_peer_id(), record_file),
+                            kademlia_config);
 
     let rendezvous_behaviour = RendezvousBehaviour {
         rendezvous: rendezvous::client::Behaviour::new(key.clone()),
-        ping: ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(1))),
+        ping: ping::Behaviour::new(ping::Config::new().with_interval(ping_interval)),
     };
                                             
 
     Ok(SwapBytesBehaviour {
+        gate,
         chat: chat_behaviour,
         request_response: request_response_behaviour,
         kademlia: kademlia_behaviour,
@@ -81,12 +213,13 @@ pub fn create_swapbytes_behaviour(key: &libp2p::identity::Keypair) -> Result<Swa
 
 
 /* Handle all chat events */
-pub async fn handle_chat_event(chat_event: ChatBehaviourEvent, state: &mut ChatState, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>) {
+pub async fn handle_chat_event(chat_event: ChatBehaviourEvent, state: &mut ChatState, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, topic: &mut gossipsub::IdentTopic) {
     match chat_event {
         // Discovering a peer with mDNS
         ChatBehaviourEvent::Mdns(mdns::Event::Discovered(list)) => {
             for (peer_id, multiaddr) in list {
-                println!("mDns discovered new peer: {peer_id}, listening on {multiaddr}");
+                tracing::info!("mDNS discovered new peer: {peer_id}, listening on {multiaddr}");
+                output::publish(output::Event::PeerDiscovered { peer: peer_id, address: multiaddr.clone() });
                 swarm.behaviour_mut().chat.gossipsub.add_explicit_peer(&peer_id);
                 swarm.behaviour_mut().kademlia.add_address(&peer_id, multiaddr);
             }
@@ -94,23 +227,221 @@ pub async fn handle_chat_event(chat_event: ChatBehaviourEvent, state: &mut ChatS
         // mDNS connection expired
         ChatBehaviourEvent::Mdns(mdns::Event::Expired(list)) => {
             for (peer_id, multiaddr) in list {
-                println!("mDNS peer has expired: {peer_id}, listening on {multiaddr}");
+                tracing::debug!("mDNS peer has expired: {peer_id}, listening on {multiaddr}");
                 swarm.behaviour_mut().chat.gossipsub.remove_explicit_peer(&peer_id);
             }
         }
         // Sending a chat message
         ChatBehaviourEvent::Gossipsub(gossipsub::Event::Message {
-            propagation_source: peer_id,
+            propagation_source,
             message_id: _id,
             message,
         }) => {
-            let key = kad::RecordKey::new(&peer_id.to_bytes());
-            let query_id = swarm.behaviour_mut().kademlia.get_record(key);
-
-            // Store message data and query ID for later processing
-            let message_data = message.data.clone();
-            state.pending_messages.insert(query_id, (peer_id.clone(), message_data));
+            // The propagation source is only whoever relayed the message to us. The author is the signed source,
+            // which gossipsub has already checked against the signature
+            let Some(author) = message.source else {
+                error!("Dropped an unsigned message relayed by {propagation_source}");
+                return;
+            };
+            // Whatever this prints is labelled with the room it came from, which needn't be the one we're typing in
+            let _arrived_in = (message.topic != board::topic()).then(|| output::in_room(message.topic.as_str()));
+            // Other peers still relay what denied and muted peers say
+            if !swarm.behaviour().gate.permits(&author) || state.rate_limiter.is_muted(&author) {
+                return;
+            }
+            let mut gossip_message = serde_json::from_slice::<GossipMessage>(&message.data);
+            // Private room traffic must be sealed with the room key; anything else on that topic is dropped
+            if let Some(room) = state.rooms.get(message.topic.as_str()) {
+                let plaintext = match gossip_message {
+                    Ok(GossipMessage::Encrypted { nonce, ciphertext }) => room.open(nonce, &ciphertext),
+                    _ => None,
+                };
+                match plaintext.and_then(|plaintext| serde_json::from_slice::<GossipMessage>(&plaintext).ok()) {
+                    // Kicked and banned peers still hold the room key, so only take messages from current members
+                    Some(_) if !room.is_member(&author) => {
+                        tracing::debug!("Dropped a private room message from {author}, who is not a member");
+                        return;
+                    }
+                    Some(inner) => gossip_message = Ok(inner),
+                    None => {
+                        error!("Dropped a private room message from {author} that was not encrypted with the room key");
+                        return;
+                    }
+                }
+            }
+            match gossip_message {
+                // Board posts only count on the board topic, where every peer is listening, and nothing else belongs there
+                Ok(GossipMessage::Board { nickname, posts }) if message.topic == board::topic() => {
+                    let new = state.board.received(author, nickname.clone(), posts.clone());
+                    if new > 0 {
+                        let name = state.cached_peer(&author).map_or(nickname.clone(), |peer| peer.nickname.clone());
+                        system!("{name} put {new} new post{} on the board. Type /board to see them.", if new == 1 { "" } else { "s" });
+                    }
+                    wishlist::check(state, author, &nickname, &posts);
+                }
+                _ if message.topic == board::topic() => {}
+                Ok(GossipMessage::Chat(chat_message)) => {
+                    // Prefer the verified rating from the cache over the sender's own hint
+                    let verified_rating = state.ratings.summary(&author);
+                    // Show the nickname the author registered in the DHT, flagging a message that claims another
+                    let nickname = match state.cached_peer(&author) {
+                        Some(peer) if peer.nickname != chat_message.nickname => {
+                            format!("{} (claiming to be {})", peer.nickname, chat_message.nickname)
+                        }
+                        _ => chat_message.nickname.clone(),
+                    };
+                    // Filtered out messages are still acknowledged, just never shown
+                    let shown = pipeline::incoming(state, &chat_message.body);
+                    if let Some(body) = &shown {
+                        render::print_chat(
+                            &state.render,
+                            Some(&chat_message.id),
+                            &author,
+                            &nickname,
+                            verified_rating.unwrap_or(chat_message.rating_hint),
+                            body
+                        );
+                        if own_peer_data(swarm).is_some_and(|own| notify::mentions(body, &own.nickname)) {
+                            notify::notify(Trigger::Mention, &chat_message.nickname, body);
+                        }
+                    }
+                    // The message is shown under the claimed nickname straight away, and checked against the DHT
+                    // in the background unless the claim is already cached. Messages shown while a check is under
+                    // way are remembered with it, so they can be flagged if the claim turns out to be false
+                    let cached = state.cached_peer(&author).is_some_and(|peer| peer.nickname == chat_message.nickname);
+                    if !cached && let Some(body) = shown {
+                        let shown = (chat_message.id.clone(), body);
+                        match state.pending_verifications.values_mut().find(|check| check.peer == author && check.claimed == chat_message.nickname) {
+                            Some(check) => check.messages.push(shown),
+                            None => {
+                                let key = kad::RecordKey::new(&author.to_bytes());
+                                let query_id = swarm.behaviour_mut().kademlia.get_record(key);
+                                state.pending_verifications.insert(query_id, NicknameCheck {
+                                    peer: author,
+                                    claimed: chat_message.nickname.clone(),
+                                    messages: vec![shown],
+                                });
+                            }
+                        }
+                    }
+                    // Acknowledge delivery of private room messages straight away, and remember them until read
+                    if message.topic.as_str() != state.public_room {
+                        if let Err(e) = publish_gossip(swarm, state, message.topic.clone(), &GossipMessage::Delivered(chat_message.id.clone())) {
+                            tracing::warn!("Failed to send delivery receipt: {:?}", e);
+                        }
+                        state.unread_messages.push((message.topic.clone(), chat_message.id));
+                    }
+                }
+                Ok(GossipMessage::Delivered(message_id)) => {
+                    if let Some(body) = state.awaiting_receipts.get(&message_id) {
+                        system!("✓ delivered: {body}");
+                    }
+                }
+                Ok(GossipMessage::Read(message_id)) => {
+                    if let Some(body) = state.awaiting_receipts.remove(&message_id) {
+                        system!("✓✓ read: {body}");
+                    }
+                }
+                // Only the room's creator decides who is in it. They take a join announced by any member and send out
+                // a new roster; everyone else takes joins announced by the creator and waits for the roster otherwise
+                Ok(GossipMessage::MemberJoined(member)) => {
+                    let local_peer_id = *swarm.local_peer_id();
+                    if let Some(room) = state.rooms.get_mut(message.topic.as_str())
+                        && room.is_member(&author)
+                        && !room.is_banned(&member.peer_id) {
+                        // Members back after a restart announce themselves, which changes nothing
+                        let returning = room.is_member(&member.peer_id) && member.peer_id == author;
+                        // Rooms made by peers without moderation have no roster, so any member can let others in
+                        let decides = author == room.creator || room.creator == local_peer_id || room.roster.is_none();
+                        if !returning && !decides {
+                            tracing::debug!("Waiting on the creator's roster for {}, announced by {author}", member.nickname);
+                            return;
+                        }
+                        match returning {
+                            true => system!("{} is back in the room.", member.nickname),
+                            false => system!("{} joined the room.", member.nickname),
+                        }
+                        room.add_member(member);
+                        publish_roster(swarm, state, message.topic.as_str(), None, false);
+                    }
+                }
+                // Members can only announce their own departure
+                Ok(GossipMessage::MemberLeft(peer_id)) if peer_id != author => {
+                    error!("Ignored a message from {author} claiming that {peer_id} left the room");
+                }
+                Ok(GossipMessage::MemberLeft(peer_id)) => {
+                    if let Some(member) = state.rooms.get_mut(message.topic.as_str()).and_then(|room| room.remove_member(&peer_id)) {
+                        system!("{} left the room.", member.nickname);
+                        publish_roster(swarm, state, message.topic.as_str(), None, false);
+                    }
+                }
+                // Only a newer roster signed by the room's creator can change who's in the room
+                Ok(GossipMessage::Roster(signed)) => {
+                    let room_id = message.topic.to_string();
+                    let local_peer_id = *swarm.local_peer_id();
+                    let Some(room) = state.rooms.get_mut(&room_id) else { return };
+                    if !room.accepts_roster(&room_id, &signed) {
+                        tracing::debug!("Ignored a room roster from {author} that isn't a newer one from the room's creator");
+                        return;
+                    }
+                    let creator = signed.roster.creator;
+                    let banned = signed.roster.banned.clone();
+                    let kicked_us = signed.roster.kicked.contains(&local_peer_id);
+                    let topic_changed = room.topic != signed.roster.topic;
+                    let (added, removed) = room.apply_roster(signed);
+                    let new_topic = room.topic.clone();
+                    let creator_name = state.peer_name(&creator);
+                    if kicked_us || banned.contains(&local_peer_id) {
+                        error!("{creator_name} {} you from the private room.", if kicked_us { "kicked" } else { "banned" });
+                        if topic.hash() == message.topic {
+                            return_to_default(swarm, state, topic);
+                            system!("You are back in the default chatroom.");
+                        } else {
+                            state.rooms.remove(&room_id);
+                            swarm.behaviour_mut().chat.gossipsub.unsubscribe(&IdentTopic::new(room_id));
+                        }
+                        return;
+                    }
+                    for member in added {
+                        system!("{} joined the room.", member.nickname);
+                    }
+                    for member in removed {
+                        let action = if banned.contains(&member.peer_id) { "banned" } else { "kicked" };
+                        system!("{} was {action} from the room by {creator_name}.", member.nickname);
+                    }
+                    match new_topic {
+                        Some(topic) if topic_changed => system!("{creator_name} set the room's topic to: {topic}"),
+                        None if topic_changed => system!("{creator_name} cleared the room's topic."),
+                        _ => {}
+                    }
+                }
+                Ok(GossipMessage::Leaving(nickname)) => {
+                    system!("{nickname} has left SwapBytes.");
+                    state.invalidate_peer(&author);
+                    state.board.remove_peer(&author);
+                }
+                Ok(GossipMessage::Board { .. }) => {}
+                // A sealed message for a room we don't hold the key for
+                Ok(GossipMessage::Encrypted { .. }) => {}
+                Ok(GossipMessage::NicknameChanged { old, new }) => {
+                    system!("{old} is now known as {new}");
+                    state.invalidate_peer(&author);
+                }
+                // Fall back to a DHT lookup for peers sending raw bytes
+                Err(_) => {
+                    let Some(body) = pipeline::incoming(state, &String::from_utf8_lossy(&message.data)) else { return };
+                    if let Some(peer) = state.cached_peer(&author).cloned() {
+                        render::print_chat(&state.render, None, &author, &peer.nickname, state.ratings.summary(&author).unwrap_or_default(), &body);
+                        return;
+                    }
+                    let key = kad::RecordKey::new(&author.to_bytes());
+                    let query_id = swarm.behaviour_mut().kademlia.get_record(key);
 
+                    // Store message data and query ID for later processing
+                    let message_data = message.data.clone();
+                    state.pending_messages.insert(query_id, (author, message_data));
+                }
+            }
         },
 
         _ => {}
@@ -118,333 +449,879 @@ pub async fn handle_chat_event(chat_event: ChatBehaviourEvent, state: &mut ChatS
 }
 
 
+// Send a private connection request
+fn send_invite(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer_id: PeerId, nickname: String, details: ConnectionDetails) {
+    let ConnectionDetails { initiator_nickname, room_id, passphrase } = details;
+    if state.rooms.get(&room_id).is_some_and(|room| room.is_banned(&peer_id)) {
+        error!("{nickname} is banned from this room.");
+        invite_answered(state, &room_id, &nickname, InviteOutcome::Failed);
+        return;
+    }
+    let (secret, public_key) = crypto::generate_keypair();
+    let passphrase_required = passphrase.is_some();
+    let expires = unix_now() + crypto::INVITE_TTL.as_secs();
+    let request = swarm.behaviour_mut().request_response.request_response.send_request(
+        &peer_id,
+        RequestType::PrivateRoomRequest(Invite {
+            room_id: room_id.clone(),
+            initiator_nickname,
+            public_key,
+            initiator_key: state.keypair.public().encode_protobuf(),
+            token: crypto::sign_invite(&state.keypair, &room_id, &peer_id, expires),
+            passphrase_required,
+            expires: Some(expires),
+        })
+    );
+    system!("Private room request sent to {nickname}. You will automatically connect if they accept");
+    state.pending_invites.insert((room_id, peer_id), PendingInvite { secret, passphrase, nickname, request });
+}
+
+// A /connect lookup found nothing in the DHT. A peer we're connected to can still be asked directly, going by the
+// nickname we know them by or the peer ID given; otherwise the invite can't be sent
+fn connection_lookup_failed(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, request: ConnectionRequest, reason: &str) {
+    let (peer_id, name, details) = match request {
+        ConnectionRequest::NicknameLookup(nickname, details) => (state.find_peer(&nickname), nickname, details),
+        ConnectionRequest::PeerData(peer_id, details) => (Some(peer_id), state.peer_name(&peer_id), details),
+    };
+    match peer_id.filter(|peer_id| swarm.is_connected(peer_id)) {
+        Some(peer_id) => send_invite(swarm, state, peer_id, name, details),
+        None => {
+            error!("Could not invite {name}: {reason}.");
+            invite_answered(state, &details.room_id, &name, InviteOutcome::Failed);
+        }
+    }
+}
+
+// Print a cached profile for /whois once the peer's ratings are known, with our ledger of trades with them
+fn show_profile(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer_id: PeerId) {
+    if state.ratings.lookup(swarm, peer_id) {
+        state.pending_whois_ratings.insert(peer_id);
+        return;
+    }
+    let Some(peer) = state.cached_peer(&peer_id).cloned() else { return };
+    peer.print_profile(&peer_id, state.ratings.summary(&peer_id).unwrap_or_default());
+    state.trade_ledger.print(&peer_id);
+}
+
+// Someone's ratings have all been fetched: show whatever was waiting on them
+fn ratings_found(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer_id: PeerId) {
+    if state.pending_whois_ratings.remove(&peer_id) {
+        show_profile(swarm, state, peer_id);
+    }
+    state.ratings_found(swarm, &peer_id);
+}
+
+// A nickname lookup finished without finding anyone else holding the nickname: /nick takes it, and the nickname
+// we started with is claimed if some peer actually answered. If none did, that check is left for the next DHT peer
+// or republish. Returns whether the query was one of these
+fn nickname_free(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, topic: &gossipsub::IdentTopic, own_nickname: &mut String, id: &QueryId, answered: bool) -> bool {
+    if let Some(new_nickname) = state.pending_nickname_claims.remove(id) {
+        change_nickname(swarm, state, topic, own_nickname, &new_nickname);
+        return true;
+    }
+    if let NicknameClaim::Checking { query, retry } = state.nickname_claim && query == *id {
+        if answered {
+            claim_own_nickname(swarm, state);
+        } else if retry {
+            check_own_nickname(swarm, state, own_nickname);
+        } else {
+            state.nickname_claim = NicknameClaim::Unchecked;
+        }
+        return true;
+    }
+    false
+}
+
 /* Handle all kademlia events */
-pub async fn handle_kademlia_event(id: QueryId, result: QueryResult, state: &mut ChatState, swarm: &mut libp2p::Swarm<SwapBytesBehaviour> ) {
+pub async fn handle_kademlia_event(id: QueryId, result: QueryResult, state: &mut ChatState, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, topic: &gossipsub::IdentTopic, own_nickname: &mut String) {
     match result {
+        // One of the ratings of a peer, under its rater's key
+        kad::QueryResult::GetRecord(result) if state.ratings.fetches(&id) => {
+            let record = match &result {
+                Ok(kad::GetRecordOk::FoundRecord(peer_record)) => Some(&peer_record.record),
+                _ => None,
+            };
+            if let Some(peer_id) = state.ratings.fetched(&id, record) {
+                ratings_found(swarm, state, peer_id);
+            }
+        },
+
+        // Who has rated a peer, from the provider records raters publish
+        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })) => {
+            state.ratings.raters_found(swarm, &id, providers);
+        },
+
+        kad::QueryResult::GetProviders(_) => {
+            if let Some(peer_id) = state.ratings.raters_finished(&id) {
+                ratings_found(swarm, state, peer_id);
+            }
+        },
+
         kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(peer_record))) => {
             // Print a message that has been sent
             if let Some((peer_id, msg)) = state.pending_messages.remove(&id) {
                 match serde_json::from_slice::<PeerData>(&peer_record.record.value) {
                     Ok(peer) => {
-                        println!("{} ( {}★ ): {}",
-                            peer.nickname,
-                            peer.rating,
-                            String::from_utf8_lossy(&msg)
-                        );
+                        if let Some(body) = pipeline::incoming(state, &String::from_utf8_lossy(&msg)) {
+                            render::print_chat(&state.render, None, &peer_id, &peer.nickname, state.ratings.summary(&peer_id).unwrap_or_default(), &body);
+                        }
+                        state.cache_peer(swarm, peer_id, peer);
+                    }
+                    Err(_) => {
+                        if let Some(body) = pipeline::incoming(state, &String::from_utf8_lossy(&msg)) {
+                            chat!("Peer {peer_id}: {body}");
+                        }
+                    }
+                }
+            // Someone may already have the nickname given to /nick. It's only theirs while they keep republishing
+            // it; records that don't stop the claim leave it to the end of the query
+            } else if let Some(new_nickname) = state.pending_nickname_claims.get(&id).cloned() {
+                if let Ok(owner) = PeerId::from_bytes(&peer_record.record.value)
+                    && owner != *swarm.local_peer_id() && nickname_held(&peer_record.record) {
+                    state.pending_nickname_claims.remove(&id);
+                    let suggestions = suggest_nicknames(&new_nickname, swarm.local_peer_id()).join(", ");
+                    system!("The nickname {new_nickname} is already taken by {owner}. Try one of: {suggestions}");
+                }
+            // The same for the nickname we started with, which we already go by, so all we can do is warn
+            } else if state.nickname_claim.checks(&id) {
+                if let Ok(owner) = PeerId::from_bytes(&peer_record.record.value)
+                    && owner != *swarm.local_peer_id() && nickname_held(&peer_record.record) {
+                    state.nickname_claim = NicknameClaim::Taken;
+                    let suggestions = suggest_nicknames(own_nickname, swarm.local_peer_id()).join(", ");
+                    error!("The nickname {own_nickname} is already taken by {owner}, so peers looking you up by it will find them. Change it with /nick, for example to one of: {suggestions}");
+                }
+            // Check a nickname claimed in a chat message against the DHT
+            } else if let Some(check) = state.pending_verifications.remove(&id) {
+                match serde_json::from_slice::<PeerData>(&peer_record.record.value) {
+                    Ok(peer) => {
+                        if peer.nickname != check.claimed {
+                            check.flag(&peer.nickname);
+                        }
+                        state.cache_peer(swarm, check.peer, peer);
                     }
                     Err(_) => {
-                        println!("Peer {peer_id}: {}", String::from_utf8_lossy(&msg));
+                        error!("Could not verify the nickname {} used by peer {}", check.claimed, check.peer);
                     }
                 }
             // Handle a private connection request
             } else if let Some(request_type) = state.pending_connections.remove(&id) {
                 match request_type {
                     // Check that the other peer exists before connecting
-                    ConnectionRequest::NicknameLookup(initiator_nickname, initiator_peer_id) => {
+                    ConnectionRequest::NicknameLookup(nickname, details) => {
                         match PeerId::from_bytes(&peer_record.record.value) {
                             Ok(peer_id) => {
                                 // Check if the peer ID is not the same as the local peer ID
                                 if peer_id == *swarm.local_peer_id() {
-                                    println!("You cannot connect to yourself.");
+                                    error!("You cannot connect to yourself.");
+                                    invite_answered(state, &details.room_id, &nickname, InviteOutcome::Failed);
                                     return;
                                 }
                                 let peer_data_key = kad::RecordKey::new(&peer_id.to_bytes());
                                 let data_query_id = swarm.behaviour_mut().kademlia.get_record(peer_data_key);
-                                state.pending_connections.insert(data_query_id, ConnectionRequest::PeerData(peer_id, initiator_nickname, initiator_peer_id));
+                                state.pending_connections.insert(data_query_id, ConnectionRequest::PeerData(peer_id, details));
                             }
                             Err(e) => {
-                                println!("Invalid Peer ID in record: {:?}\nRaw bytes: {:?}",
+                                error!("Invalid Peer ID in record: {:?}\nRaw bytes: {:?}",
                                     e,
                                     peer_record.record.value
                                 );
+                                invite_answered(state, &details.room_id, &nickname, InviteOutcome::Failed);
                             }
                         }
                     },
                     // Send a private connection request
-                    ConnectionRequest::PeerData(other_peer_id, initiator_nickname, initiator_peer_id) => {
+                    ConnectionRequest::PeerData(other_peer_id, details) => {
                         match serde_json::from_slice::<PeerData>(&peer_record.record.value) {
-                            Ok(peer) => {
-                                let room_id = format!("{}-{}-{}-{}-{}",initiator_nickname.clone(), peer.nickname.clone(), initiator_peer_id, other_peer_id, Uuid::new_v4().to_string());
-                                swarm.behaviour_mut().request_response.request_response.send_request(
-                                    &other_peer_id,
-                                    RequestType::PrivateRoomRequest(Invite {
-                                        room_id: room_id.clone(),
-                                        initiator_nickname: initiator_nickname.clone(),
-                                    })
-                                );
-                                println!("Private room request sent to {}. You will automatically connect if they accept", peer.nickname);
+                            Ok(peer) => send_invite(swarm, state, other_peer_id, peer.nickname, details),
+                            Err(e) => {
+                                error!("Invalid peer data for {}: {}", other_peer_id, e);
+                                let name = state.peer_name(&other_peer_id);
+                                invite_answered(state, &details.room_id, &name, InviteOutcome::Failed);
                             }
-                            Err(e) => println!("Invalid peer data for {}: {}", other_peer_id, e),
                         }
                     },
                 }
-            // Handle a rating update (when leaving a private room)
-            } else if let Some(rating) = state.pending_rating_update.remove(&id) {
+            // Resolve the nickname given to /rate, then rate that peer
+            } else if let Some(rating) = state.pending_rating_lookups.remove(&id) {
+                match PeerId::from_bytes(&peer_record.record.value) {
+                    Ok(peer_id) => {
+                        let proof = state.trade_ledger.latest_receipt(&peer_id);
+                        update_peer_rating(swarm, peer_id, rating, proof, state).await;
+                    }
+                    Err(e) => error!("Invalid Peer ID in record: {:?}", e),
+                }
+            // Resolve the nickname given to /whois, then show that peer's profile
+            } else if state.pending_whois_lookups.remove(&id) {
+                match PeerId::from_bytes(&peer_record.record.value) {
+                    Ok(peer_id) => match state.cached_peer(&peer_id) {
+                        Some(_) => show_profile(swarm, state, peer_id),
+                        None => {
+                            let query_id = swarm.behaviour_mut().kademlia.get_record(kad::RecordKey::new(&peer_id.to_bytes()));
+                            state.pending_whois.insert(query_id);
+                        }
+                    },
+                    Err(e) => error!("Invalid Peer ID in record: {:?}", e),
+                }
+            } else if state.pending_whois.remove(&id) {
+                let Ok(peer_id) = PeerId::from_bytes(peer_record.record.key.as_ref()) else {
+                    error!("Error retrieving profile: invalid record key");
+                    return;
+                };
                 match serde_json::from_slice::<PeerData>(&peer_record.record.value) {
                     Ok(peer) => {
-                        // Update the peer's rating in the local store
-                        let updated_peer = PeerData {
-                            nickname: peer.nickname.clone(),
-                            rating: peer.rating + rating,
-                        };
-                        let serialized = serde_json::to_vec(&updated_peer).expect("Serialization failed");
+                        state.cache_peer(swarm, peer_id, peer);
+                        show_profile(swarm, state, peer_id);
+                    }
+                    Err(e) => error!("Invalid profile for {peer_id}: {e}"),
+                }
+            // Resolve the nickname given to /bench, then ask that peer to take part
+            } else if let Some(size) = state.pending_bench_lookups.remove(&id) {
+                match PeerId::from_bytes(&peer_record.record.value) {
+                    Ok(peer_id) if peer_id == *swarm.local_peer_id() => error!("You cannot benchmark yourself."),
+                    Ok(peer_id) => bench::start(swarm, state, peer_id, size),
+                    Err(e) => error!("Invalid Peer ID in record: {:?}", e),
+                }
+            // Resolve the nickname given to /ping, then show the latest round trips to that peer
+            } else if let Some(nickname) = state.pending_ping_lookups.remove(&id) {
+                match PeerId::from_bytes(&peer_record.record.value) {
+                    Ok(peer_id) if peer_id == *swarm.local_peer_id() => error!("You cannot ping yourself."),
+                    Ok(peer_id) => state.connections.print_latency(&peer_id, &nickname),
+                    Err(e) => error!("Invalid Peer ID in record: {:?}", e),
+                }
+            // Resolve the nickname given to /vouch, then fetch that peer's record
+            } else if state.pending_vouch_lookups.remove(&id) {
+                match PeerId::from_bytes(&peer_record.record.value) {
+                    Ok(peer_id) if peer_id == *swarm.local_peer_id() => error!("You cannot vouch for yourself."),
+                    Ok(peer_id) => {
+                        let query_id = swarm.behaviour_mut().kademlia.get_record(kad::RecordKey::new(&peer_id.to_bytes()));
+                        state.pending_vouches.insert(query_id);
+                    }
+                    Err(e) => error!("Invalid Peer ID in record: {:?}", e),
+                }
+            // Add our signed vouch to the peer's record
+            } else if state.pending_vouches.remove(&id) {
+                let Ok(vouchee) = PeerId::from_bytes(peer_record.record.key.as_ref()) else {
+                    error!("Error retrieving peer data for vouch: invalid record key");
+                    return;
+                };
+                match serde_json::from_slice::<PeerData>(&peer_record.record.value) {
+                    Ok(mut peer) => {
+                        rating::upsert_vouch(&mut peer.vouches, SignedVouch::new(&state.keypair, vouchee));
                         let updated_record = kad::Record {
                             key: peer_record.record.key,
-                            value: serialized,
+                            value: serde_json::to_vec(&peer).expect("Serialization failed"),
                             publisher: None,
                             expires: None,
                         };
-                        // Store the updated record in the DHT
-                        swarm.behaviour_mut().kademlia.put_record(updated_record, kad::Quorum::All).expect("Failed to store updated record locally.");
-                        println!("Updated rating for {}: {}★", peer.nickname, updated_peer.rating);
+                        if let Err(e) = swarm.behaviour_mut().kademlia.put_record(updated_record, state.kademlia.profile_quorum()) {
+                            output::report_failure(&format!("Could not store your vouch for {}", peer.nickname), e);
+                            return;
+                        }
+                        state.trusted.insert(vouchee);
+                        persist_identity(swarm, state);
+                        let others = peer.trusted_vouches(&vouchee, &state.trusted);
+                        system!("You now vouch for {}. {others} of your trusted peers vouch for them.", peer.nickname);
+                        state.cache_peer(swarm, vouchee, peer);
                     }
                     Err(_) => {
-                        println!("Error retrieving peer data for rating update: {}", String::from_utf8_lossy(&peer_record.record.value));
+                        error!("Error retrieving peer data for vouch: {}", String::from_utf8_lossy(&peer_record.record.value));
                     }
                 }
+            // A peer looked up for /list or /top. Anything else is a later record for a query already answered
             } else {
-                match serde_json::from_slice::<PeerData>(&peer_record.record.value) {
-                    Ok(peer_data) => {
-                        println!("Connected peer: {}", peer_data.nickname);
-                    }
-                    Err(e) => {
-                        println!("Failed to deserialize peer data. Error: {:?}", e);
-                    }
+                let peer_data = serde_json::from_slice::<PeerData>(&peer_record.record.value);
+                if state.resolve_listed_peer(swarm, &id, peer_data.as_ref().ok().cloned())
+                    && let Err(e) = peer_data {
+                    tracing::warn!("Failed to deserialize peer data. Error: {:?}", e);
                 }
             }
         },
 
-        kad::QueryResult::GetRecord(Err(kad::GetRecordError::NotFound { .. })) => {
-            println!("No peer found with that nickname.");
-            if let Some((peer_id, msg)) = state.pending_messages.remove(&id) {
-                println!("Peer {peer_id}: {}", String::from_utf8_lossy(&msg));
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord { .. })) => {
+            nickname_free(swarm, state, topic, own_nickname, &id, true);
+        },
+
+        kad::QueryResult::GetRecord(Err(kad::GetRecordError::NotFound { closest_peers, .. })) => {
+            if nickname_free(swarm, state, topic, own_nickname, &id, !closest_peers.is_empty()) {
+                return;
+            }
+            if state.pending_verifications.remove(&id).is_some() {
+                return;
+            }
+            if state.resolve_listed_peer(swarm, &id, None) {
+                return;
+            }
+            if let Some(request) = state.pending_connections.remove(&id) {
+                connection_lookup_failed(swarm, state, request, "no peer was found by that name");
+                return;
+            }
+            state.pending_rating_lookups.remove(&id);
+            state.pending_vouch_lookups.remove(&id);
+            state.pending_vouches.remove(&id);
+            state.pending_whois_lookups.remove(&id);
+            state.pending_whois.remove(&id);
+            state.pending_bench_lookups.remove(&id);
+            state.pending_ping_lookups.remove(&id);
+            system!("No peer found with that nickname.");
+            if let Some((peer_id, msg)) = state.pending_messages.remove(&id)
+                && let Some(body) = pipeline::incoming(state, &String::from_utf8_lossy(&msg)) {
+                chat!("Peer {peer_id}: {body}");
             }
         },
 
         kad::QueryResult::GetRecord(Err(err)) => {
-            println!("Error retrieving record: {err}");
-            if let Some((peer_id, msg)) = state.pending_messages.remove(&id) {
-                println!("Peer {peer_id}: {}", String::from_utf8_lossy(&msg));
+            // Without peers to ask we can't tell whether the nickname is taken, so claim it
+            if nickname_free(swarm, state, topic, own_nickname, &id, false) {
+                return;
+            }
+            if state.pending_verifications.remove(&id).is_some() {
+                return;
+            }
+            if state.resolve_listed_peer(swarm, &id, None) {
+                return;
+            }
+            if let Some(request) = state.pending_connections.remove(&id) {
+                connection_lookup_failed(swarm, state, request, &format!("the lookup failed: {err}"));
+                return;
+            }
+            state.pending_rating_lookups.remove(&id);
+            state.pending_vouch_lookups.remove(&id);
+            state.pending_vouches.remove(&id);
+            state.pending_whois_lookups.remove(&id);
+            state.pending_whois.remove(&id);
+            state.pending_bench_lookups.remove(&id);
+            state.pending_ping_lookups.remove(&id);
+            error!("Error retrieving record: {err}");
+            if let Some((peer_id, msg)) = state.pending_messages.remove(&id)
+                && let Some(body) = pipeline::incoming(state, &String::from_utf8_lossy(&msg)) {
+                chat!("Peer {peer_id}: {body}");
             }
         },
 
+        kad::QueryResult::PutRecord(Ok(_)) if state.pending_republish.remove(&id) => {
+            state.records_published = true;
+        },
+
+        kad::QueryResult::PutRecord(Err(err)) if state.pending_republish.remove(&id) => {
+            // Try again when the next peer connects
+            state.records_published = false;
+            error!("Failed to republish your records: {err}");
+        },
+
         _ => {}
     }
 }
 
+// A peer answered one of our offers, on its response channel or with an OfferAnswer. A reason means it was turned
+// down without asking the user
+fn offer_answered(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, filename: &str, accepted: bool, reason: String) {
+    if !reason.is_empty() {
+        system!("{} turned down {filename}: {reason}.", state.peer_name(&peer));
+    } else if accepted {
+        system!("File offer accepted.");
+    } else {
+        system!("File offer rejected.");
+    }
+    if filename.is_empty() {
+        return;
+    }
+    match accepted {
+        true => {
+            state.audit(AuditEvent::PeerAccepted, peer, filename, None);
+            record_sent(swarm, state, peer, filename, None);
+        }
+        false => state.audit(AuditEvent::PeerDeclined, peer, filename, None),
+    }
+}
+
+// A file reached a peer: remember it, so they can be rated
+pub fn record_sent(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, filename: &str, digest: Option<String>) {
+    state.transfer_history.record(peer, filename, Direction::Sent);
+    state.audit(AuditEvent::Sent, peer, filename, digest);
+    update_own_profile(swarm, state, |profile| profile.shared_files += 1);
+}
+
+// A file from a peer was saved: remember it, and send them a signed receipt for their audit log
+pub fn record_received(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, filename: &str, digest: String) {
+    state.transfer_history.record(peer, filename, Direction::Received);
+    let receipt = SignedReceipt::new(&state.keypair, peer, filename, digest);
+    state.audit(AuditEvent::Received, peer, filename, Some(receipt.digest.clone()));
+    swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::Receipt(receipt));
+}
+
+// Write a file a peer sent us into the download directory, or into quarantine to be scanned first
+pub async fn save_received_file(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, filename: &str, file_data: &[u8]) {
+    let name = match filenames::sanitize(filename) {
+        Ok(name) => name,
+        Err(reason) => {
+            error!("Refused to save a file from {}: {reason}.", state.peer_name(&peer));
+            return;
+        }
+    };
+    system!("Received {name} ({} bytes)", file_data.len());
+    let saved = match state.scanner {
+        Some(_) => scan::quarantine(state, peer, filename, &name, file_data).await
+            .inspect(|_| system!("Scanning {name} before it goes into your downloads...")),
+        None => vault::write(&state.download_dir, &name, file_data, state.download_key.as_ref()).await
+            .inspect(|path| vault::report_saved(path, state.download_key.is_some())),
+    };
+    match saved {
+        Ok(_) => record_received(swarm, state, peer, filename, audit::digest(file_data)),
+        Err(e) => error!("Failed to write file: {e}"),
+    }
+}
+
+// Save an offered or traded file, whether it came in one message or is waiting in its part file
+pub async fn save_received(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, filename: &str, file: Received) {
+    match file {
+        Received::Data(file_data) => save_received_file(swarm, state, peer, filename, &file_data).await,
+        Received::Part { part, .. } => save_received_part(swarm, state, peer, filename, part.path()).await,
+    }
+}
+
+// Move a file that was streamed to disk in pieces into the download directory. Files that have to be scanned or
+// encrypted first are read back and saved like any other
+pub async fn save_received_part(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, filename: &str, part: &Path) {
+    if state.scanner.is_some() || state.download_key.is_some() {
+        match tokio::fs::read(part).await {
+            Ok(file_data) => save_received_file(swarm, state, peer, filename, &file_data).await,
+            Err(e) => error!("Failed to read back a file from {}: {e}", state.peer_name(&peer)),
+        }
+        let _ = tokio::fs::remove_file(part).await;
+        return;
+    }
+    let name = match filenames::sanitize(filename) {
+        Ok(name) => name,
+        Err(reason) => {
+            error!("Refused to save a file from {}: {reason}.", state.peer_name(&peer));
+            let _ = tokio::fs::remove_file(part).await;
+            return;
+        }
+    };
+    let size = tokio::fs::metadata(part).await.map(|metadata| metadata.len()).unwrap_or(0);
+    system!("Received {name} ({size} bytes)");
+    let digest = match audit::digest_file(part).await {
+        Ok(digest) => digest,
+        Err(e) => {
+            error!("Failed to read back a file from {}: {e}", state.peer_name(&peer));
+            return;
+        }
+    };
+    // Claim a name the usual way, then put the part file in its place
+    let saved = match filenames::create_unique(&state.download_dir, &name).await {
+        Ok((path, _)) => tokio::fs::rename(part, &path).await.map(|()| path),
+        Err(e) => Err(e),
+    };
+    match saved {
+        Ok(path) => {
+            vault::report_saved(&path, false);
+            record_received(swarm, state, peer, filename, digest);
+        }
+        Err(e) => {
+            error!("Failed to write file: {e}");
+            let _ = tokio::fs::remove_file(part).await;
+        }
+    }
+}
+
 
 /* Handle all request response events */
-pub async fn handle_req_res_event(request_response_event: request_response::Event<RequestType, ResponseType>, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, stdin: &mut io::Lines<io::BufReader<io::Stdin>>, topic: &mut gossipsub::IdentTopic) {
+pub async fn handle_req_res_event(request_response_event: request_response::Event<RequestType, ResponseType>, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, topic: &mut gossipsub::IdentTopic, state: &mut ChatState) {
+    // Before anything is awaited, so requests waiting on the user expire with the peer's side of them
+    let arrived = Instant::now();
     match request_response_event {
-        request_response::Event::Message {message, ..} => match message {
+        // Over the peer's rate limit: refuse straight away so their queue moves on, without asking the user
+        request_response::Event::Message { peer, message: request_response::Message::Request { request, channel, .. }, .. }
+            if ratelimit::refusal(&request).is_some() && !ratelimit::taken_from_manifest(state, &peer, &request) && !ratelimit::allow(state, peer) => {
+            if let Some(response) = ratelimit::refusal(&request)
+                && swarm.behaviour_mut().request_response.request_response.send_response(channel, response).is_err() {
+                tracing::debug!("Could not refuse a request from {peer}");
+            }
+        },
+
+        request_response::Event::Message { peer, connection_id, message } => match message {
             request_response::Message::Request { request: RequestType::FileRequest(filename, _requested_peer_id), channel, .. } => {
-                // A file request has been received
-                println!("Received file request for: {}", filename);
-                println!("Do you want to send the file? (y/n)");
-                let response;
-                loop {
-                    match stdin.next_line().await {
-                        Ok(Some(line)) => {
-                            let trimmed = line.trim();
-                            if trimmed == "y" || trimmed == "n" {
-                                response = trimmed.to_string();
-                                break;
-                            } else {
-                                println!("Invalid input. Please enter 'y' or 'n'.");
-                            }
-                        }
-                        Ok(None) => {
-                            println!("No input received. Please try again.");
-                        }
-                        Err(e) => {
-                            println!("Error reading input: {}. Please try again.", e);
-                        }
-                    }
-                }
-                if response == "n" {
-                    // Send a rejection response
-                    swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::FileResponse(vec![], String::new())).unwrap();
-                } else {
-                    // If the user accepts, read the file and send it
-                    match File::open(filename.clone()).await {
-                        Ok(mut file) => {
-                            let mut buffer = Vec::new();
-                            // Read the file into a buffer
-                            if let Err(e) = file.read_to_end(&mut buffer).await {
-                                println!("Failed to read file: {:?}", e);
-                            }
-                            // Send the response to the file requester
-                            match swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::FileResponse(buffer, filename)) {
-                                Ok(()) => {},
-                                Err(_) => println!("Failed to send file response")
-                            }
-                        }
-                        // If the file doesn't exist send an empty vector
-                        Err(_) => {
-                            println!("File not found. Sending empty response.");
-                            match swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::FileResponse(vec![], String::new())) {
-                                Ok(()) => {},
-                                Err(_) => println!("Failed to send file response")
-                            }
-                        }
-                    };
-                }
+                // A file request has been received; the user answers it with /yes or /no
+                queue_approval(swarm, state, PendingApproval::FileRequest { peer, filename, channel }, arrived).await;
             },
 
             request_response::Message::Request { request: RequestType::FileOffer(file_data, filename), channel, .. } => {
-                // A file offer has been received
-                println!("Received file offer for: {}", filename);
-                println!("Do you want the file? (y/n)");
-                let response;
-                loop {
-                    match stdin.next_line().await {
-                        Ok(Some(line)) => {
-                            let trimmed = line.trim();
-                            if trimmed == "y" || trimmed == "n" {
-                                response = trimmed.to_string();
-                                break;
-                            } else {
-                                println!("Invalid input. Please enter 'y' or 'n'.");
-                            }
-                        }
-                        Ok(None) => {
-                            println!("No input received. Please try again.");
-                        }
-                        Err(e) => {
-                            println!("Error reading input: {}. Please try again.", e);
-                        }
-                    }
-                }
-                if response == "n" {
-                    // Send a rejection response
-                    match swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::FileOfferResponse(false)) {
-                        Ok(()) => {},
-                        Err(e) => println!("Error sending rejection: {e:?}")
-                    }
-                } else {
-                    match swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::FileOfferResponse(true)) {
-                        Ok(()) => {
-                            let filename = format!("received_file_{}", filename);
-                            let mut file = File::create(filename).await.unwrap();
-                            if let Err(e) = file.write_all(&file_data).await {
-                                println!("Failed to write file: {:?}", e);
-                            } else {
-                                println!("File received and saved successfully.");
-                            }
-                        },
-                        Err(e) => println!("Error sending rejection: {e:?}")
-                    }
-                    
-                }
+                // A file offer has been received; the user answers it with /yes or /no
+                let reply = OfferReply::Channel(channel);
+                queue_approval(swarm, state, PendingApproval::FileOffer { peer, filename, file: Received::Data(file_data), reply }, arrived).await;
             },
 
-            request_response::Message::Request { request: RequestType::PrivateRoomRequest(Invite { room_id, initiator_nickname }), channel, .. } => {
-                // Handle private room request
-                println!("Received private room request from {initiator_nickname}");
-                // Ask user to accept or reject the request
-                println!("Do you accept the private room request? (y/n)");
-                let response ;
-                loop {
-                    match stdin.next_line().await {
-                        Ok(Some(line)) => {
-                            let trimmed = line.trim();
-                            if trimmed == "y" || trimmed == "n" {
-                                response = trimmed.to_string();
-                                break;
-                            } else {
-                                println!("Invalid input. Please enter 'y' or 'n'.");
-                            }
-                        }
-                        Ok(None) => {
-                            println!("No input received. Please try again.");
-                        }
-                        Err(e) => {
-                            println!("Error reading input: {}. Please try again.", e);
-                        }
+            request_response::Message::Request { request: RequestType::PrivateRoomRequest(Invite { room_id, initiator_nickname, public_key, initiator_key, token, passphrase_required, expires }), channel, .. } => {
+                // Refuse invites whose token wasn't signed by the sender for us, or has expired
+                let local_peer_id = *swarm.local_peer_id();
+                if !crypto::verify_invite(&peer, &initiator_key, &token, &room_id, &local_peer_id, expires, unix_now()) {
+                    error!("Ignored a private room request from {peer} with an invalid or expired invite token");
+                    if let Err(e) = swarm.behaviour_mut().request_response.request_response.send_response(
+                        channel,
+                        ResponseType::PrivateRoomResponse(PrivateRoomProtocol::Reject(room_id)),
+                    ) {
+                        error!("Error sending response: {:?}", e);
                     }
+                    return;
                 }
-                let private_room_response;
-                if response == "y" {
-                    private_room_response = PrivateRoomProtocol::Accept(room_id.clone());
-                    // Connect to the private room topic
-                    // Unsubscribe from the default topic
-                    let default_topic = gossipsub::IdentTopic::new("default"); // or your current topic name
-                    swarm.behaviour_mut().chat.gossipsub.unsubscribe(&default_topic);
-                    // Subscribe to the private room topic
-                    let private_topic = IdentTopic::new(format!("{room_id}"));
-                    swarm.behaviour_mut().chat.gossipsub.subscribe(&private_topic).unwrap();
-                    *topic = private_topic.clone();
-                    println!("You have joined the private room: {room_id}");
-                } else {
-                    private_room_response = PrivateRoomProtocol::Reject(room_id.clone());
-                };
-                // Send the response back to the requester
-                match swarm.behaviour_mut().request_response.request_response.send_response(
-                    channel,
-                    ResponseType::PrivateRoomResponse(private_room_response),
-                ) {
-                    Ok(()) => {}
-                    Err(e) => {
-                        println!("Error sending response: {:?}", e);
-                    }
+                // Queue the invite so the user can answer with /accept or /decline without blocking the swarm
+                let invite_id = state.next_invite_id;
+                state.next_invite_id += 1;
+                system!("Received private room request from {initiator_nickname}. Type /accept {invite_id} or /decline {invite_id} to respond.");
+                if passphrase_required {
+                    system!("This room is protected by a passphrase: /accept {invite_id} <passphrase>");
                 }
-                
+                notify::notify(Trigger::Invite, &initiator_nickname, &format!("{initiator_nickname} invited you to a private room"));
+                state.incoming_invites.insert(invite_id, IncomingInvite {
+                    peer,
+                    room_id,
+                    initiator_nickname,
+                    public_key,
+                    passphrase_required,
+                    channel,
+                });
             },
 
             // Handle receiving a file
             request_response::Message::Response {response: ResponseType::FileResponse(file_data, filename), request_id } => {
+                let request = outbound::completed(swarm, state, &request_id);
                 if file_data.is_empty() {
-                    println!("File request was rejected or file not found.");
+                    error!("File request was rejected or file not found.");
+                    let requested = request.as_ref().map(outbound::filename).unwrap_or_default().to_string();
+                    state.audit(AuditEvent::PeerDeclined, peer, &requested, None);
                     return;
                 }
-                println!("Received file {:?}", file_data);
-                // Save the response to a file
-                let filename = format!("received_file_{}_{}", filename, request_id);
-                if let Ok(mut file) = File::create(filename).await {
-                    if let Err(e) = file.write_all(&file_data).await {
-                        println!("Failed to write file: {:?}", e);
-                    } else {
-                        println!("File received and saved successfully.");
-                    }
+                save_received_file(swarm, state, peer, &filename, &file_data).await;
+            },
+
+            // The file is on its way in pieces; it's saved once the last one arrives
+            request_response::Message::Response {response: ResponseType::ChunkedFileResponse { transfer_id, filename, size }, request_id } => {
+                outbound::completed(swarm, state, &request_id);
+                system!("Receiving {filename} ({size} bytes) in parts...");
+                chunking::expect(swarm, state, peer, transfer_id, size, Completion::Response { filename }).await;
+            },
+
+            request_response::Message::Request { request: RequestType::Chunk { transfer_id, index, data }, channel, .. } => {
+                let accepted = chunking::receive_chunk(swarm, state, peer, transfer_id, index, data).await;
+                if let Err(e) = swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::ChunkReceived(accepted)) {
+                    tracing::debug!("Could not acknowledge a chunk from {peer}: {e:?}");
+                }
+            },
+
+            // Answered straight away, so the sender doesn't wait on the chunks and the user within one request
+            request_response::Message::Request { request: RequestType::ChunkedFileOffer { transfer_id, filename, size }, channel, .. } => {
+                let offer = Completion::Offer { filename, transfer_id: transfer_id.clone() };
+                let response = match chunking::expect(swarm, state, peer, transfer_id, size, offer).await {
+                    Some(reason) => ResponseType::FileOfferDeclined(reason),
+                    None => ResponseType::ChunkedOfferAck(true),
+                };
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, response).is_err() {
+                    tracing::debug!("Could not acknowledge a chunked offer from {peer}");
+                }
+            },
+
+            request_response::Message::Response {response: ResponseType::ChunkedOfferAck(taken), request_id } => {
+                let filename = state.pending_file_offers.remove(&request_id).unwrap_or_default();
+                if taken {
+                    outbound::offer_taken(state, &request_id);
                 } else {
-                    println!("Error saving file");
+                    error!("{} did not take your offer of {filename}.", state.peer_name(&peer));
                 }
+                outbound::completed(swarm, state, &request_id);
+            },
+
+            request_response::Message::Request { request: RequestType::OfferAnswer { transfer_id, accepted, reason }, channel, .. } => {
+                let offered = outbound::answered(state, peer, &transfer_id);
+                if let Some(filename) = &offered {
+                    offer_answered(swarm, state, peer, filename, accepted, reason);
+                }
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::OfferAnswerAck(offered.is_some())).is_err() {
+                    tracing::debug!("Could not acknowledge an answer to an offer from {peer}");
+                }
+            },
+            request_response::Message::Response {response: ResponseType::OfferAnswerAck(_), .. } => {}
+
+            request_response::Message::Response {response: ResponseType::ChunkReceived(accepted), request_id } => {
+                chunking::acknowledged(swarm, state, &request_id, accepted).await;
             },
 
             // Update initiator on offer result
-            request_response::Message::Response {response: ResponseType::FileOfferResponse(offer_accepted), .. } => {
-                if offer_accepted {
-                    println!("File offer accepted.");
+            request_response::Message::Response {response: ResponseType::FileOfferResponse(offer_accepted), request_id } => {
+                outbound::completed(swarm, state, &request_id);
+                let filename = state.pending_file_offers.remove(&request_id).unwrap_or_default();
+                offer_answered(swarm, state, peer, &filename, offer_accepted, String::new());
+            }
+            request_response::Message::Response {response: ResponseType::FileOfferDeclined(reason), request_id } => {
+                outbound::completed(swarm, state, &request_id);
+                let filename = state.pending_file_offers.remove(&request_id).unwrap_or_default();
+                offer_answered(swarm, state, peer, &filename, false, reason);
+            }
+            request_response::Message::Request { request: RequestType::FileManifest(manifest), channel, .. } => {
+                let shown = manifest::received(state, peer, manifest);
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::ManifestAck(shown)).is_err() {
+                    tracing::debug!("Could not acknowledge a multi-file offer from {peer}");
+                }
+            }
+            request_response::Message::Request { request: RequestType::ManifestSelection { manifest_id, indices }, channel, .. } => {
+                let queued = manifest::selected(swarm, state, peer, &manifest_id, indices).await;
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::ManifestAck(queued)).is_err() {
+                    tracing::debug!("Could not acknowledge the files {peer} chose");
+                }
+            }
+            request_response::Message::Request { request: RequestType::BenchRequest { bench_id, size }, channel, .. } => {
+                if size > bench::MAX_SIZE {
+                    let response = ResponseType::BenchResponse { bench_id, accepted: false, addresses: Vec::new() };
+                    if swarm.behaviour_mut().request_response.request_response.send_response(channel, response).is_err() {
+                        tracing::debug!("Could not turn down a benchmark from {peer}");
+                    }
+                    return;
+                }
+                queue_approval(swarm, state, PendingApproval::Bench { peer, bench_id, size, channel }, arrived).await;
+            }
+            request_response::Message::Request { request: RequestType::BenchData { bench_id, data }, channel, .. } => {
+                let accepted = bench::data_received(state, peer, &bench_id, data.len() as u64);
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::BenchAck(accepted)).is_err() {
+                    tracing::debug!("Could not acknowledge benchmark data from {peer}");
+                }
+            }
+            request_response::Message::Response {response: ResponseType::BenchResponse { bench_id, accepted, addresses }, .. } => {
+                bench::answered(swarm, state, peer, bench_id, accepted, addresses);
+            }
+            request_response::Message::Response {response: ResponseType::BenchAck(accepted), request_id } => {
+                bench::acknowledged(swarm, state, connection_id, &request_id, accepted);
+            }
+            request_response::Message::Request { request: RequestType::Leaving { room_id }, channel, .. } => {
+                let left = state.rooms.get_mut(&room_id).and_then(|room| room.remove_member(&peer));
+                if let Some(member) = &left {
+                    system!("{} has quit SwapBytes and left the room.", member.nickname);
+                    publish_roster(swarm, state, &room_id, None, false);
+                }
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::LeavingAck(left.is_some())).is_err() {
+                    tracing::debug!("Could not acknowledge {peer} leaving");
+                }
+            }
+            // Only sent while shutting down, which waits for these itself
+            request_response::Message::Response {response: ResponseType::LeavingAck(_), .. } => {}
+            request_response::Message::Request { request: RequestType::RoomRefused { room_id, reason }, channel, .. } => {
+                let waiting = state.pending_room_joins.remove(&room_id).is_some();
+                if waiting {
+                    error!("Could not join the private room {room_id}: {reason}.");
+                }
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::RoomRefusedAck(waiting)).is_err() {
+                    tracing::debug!("Could not acknowledge being refused a room by {peer}");
+                }
+            }
+            request_response::Message::Response {response: ResponseType::RoomRefusedAck(_), .. } => {}
+            request_response::Message::Response {response: ResponseType::ManifestAck(taken), .. } => {
+                if !taken {
+                    error!("{} did not take your multi-file offer, it may have been too many requests or files.", state.peer_name(&peer));
+                }
+            }
+            // A signed receipt for a file we sent, only kept for files we actually sent them
+            request_response::Message::Request { request: RequestType::Receipt(receipt), channel, .. } => {
+                let sent = state.transfer_history.transfers.iter()
+                    .any(|transfer| transfer.peer == peer && transfer.filename == receipt.filename && transfer.direction == Direction::Sent);
+                let logged = sent && receipt.verify(swarm.local_peer_id(), &peer);
+                if logged {
+                    let (filename, digest) = (receipt.filename.clone(), Some(receipt.digest.clone()));
+                    state.audit_log.append(&state.keypair, AuditEvent::Delivered, peer, &filename, digest, Some(receipt));
                 } else {
-                    println!("File offer rejected.");
+                    tracing::debug!("Ignored a receipt from {peer} for a file we didn't send them");
+                }
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::ReceiptLogged(logged)).is_err() {
+                    tracing::debug!("Could not acknowledge a receipt from {peer}");
+                }
+            }
+            request_response::Message::Request { request: RequestType::TradeProposal(proposal), channel, .. } => {
+                negotiation::proposal_received(swarm, state, peer, proposal, channel);
+            }
+            request_response::Message::Response {response: ResponseType::TradeResponse { trade_id, answer }, .. } => {
+                negotiation::answered(swarm, state, peer, &trade_id, answer);
+            }
+            request_response::Message::Request { request: RequestType::TradeAnswer { trade_id, answer }, channel, .. } => {
+                let answered = negotiation::answered(swarm, state, peer, &trade_id, answer);
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::TradeAck(answered)).is_err() {
+                    tracing::debug!("Could not acknowledge a trade answer from {peer}");
+                }
+            }
+            request_response::Message::Request { request: RequestType::TradeFile { trade_id, data }, channel, .. } => {
+                let accepted = trade::file_arrived(swarm, state, peer, &trade_id, Received::Data(data)).await;
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::TradeAck(accepted)).is_err() {
+                    tracing::debug!("Could not acknowledge a trade file from {peer}");
+                }
+            }
+            request_response::Message::Request { request: RequestType::ChunkedTradeFile { trade_id, transfer_id, size }, channel, .. } => {
+                let expected = state.trades.expects_file(&peer, &trade_id);
+                if expected {
+                    state.trades.started(&peer, &trade_id);
+                    chunking::expect(swarm, state, peer, transfer_id, size, Completion::Trade { trade_id }).await;
+                }
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::TradeAck(expected)).is_err() {
+                    tracing::debug!("Could not acknowledge a trade file from {peer}");
+                }
+            }
+            request_response::Message::Request { request: RequestType::TradeCancel { trade_id, reason }, channel, .. } => {
+                let cancelled = trade::cancelled(state, peer, &trade_id, &reason);
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::TradeAck(cancelled)).is_err() {
+                    tracing::debug!("Could not acknowledge a cancelled trade from {peer}");
+                }
+            }
+            request_response::Message::Request { request: RequestType::TradeHeld { trade_id }, channel, .. } => {
+                let held = trade::held(swarm, state, peer, &trade_id);
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::TradeAck(held)).is_err() {
+                    tracing::debug!("Could not acknowledge a held trade file from {peer}");
+                }
+            }
+            request_response::Message::Request { request: RequestType::TradeKey { trade_id, key }, channel, .. } => {
+                let opened = trade::key_arrived(swarm, state, peer, &trade_id, key).await;
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::TradeAck(opened)).is_err() {
+                    tracing::debug!("Could not acknowledge a trade key from {peer}");
+                }
+            }
+            request_response::Message::Request { request: RequestType::TradeReceipt(receipt), channel, .. } => {
+                let kept = trade::receipt_arrived(state, peer, receipt);
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::TradeAck(kept)).is_err() {
+                    tracing::debug!("Could not acknowledge a trade receipt from {peer}");
+                }
+            }
+            request_response::Message::Response {response: ResponseType::TradeAck(accepted), .. } => {
+                if !accepted {
+                    tracing::debug!("{peer} did not take part of a trade");
+                }
+            }
+            request_response::Message::Response {response: ResponseType::ReceiptLogged(logged), .. } => {
+                if !logged {
+                    tracing::debug!("{peer} did not keep our receipt");
                 }
             }
+            // A new key for a room we're in, after its creator removed someone
+            request_response::Message::Request { request: RequestType::RoomKeyRequest { room_id, nonce, ciphertext, .. }, channel, .. }
+                if !state.pending_room_joins.contains_key(&room_id) && state.rooms.contains_key(&room_id) => {
+                let taken = room::rekeyed(state, &peer, &room_id, nonce, &ciphertext);
+                if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::RoomKeyResponse(taken)).is_err() {
+                    tracing::debug!("Could not answer a new room key from {peer}");
+                }
+            },
+
+            // Join a private room once the inviter has sent the room key
+            request_response::Message::Request { request: RequestType::RoomKeyRequest { room_id, nonce, ciphertext, members, roster, record }, channel, .. } => {
+                let room_key = state.pending_room_joins
+                    .remove(&room_id)
+                    .and_then(|pair_key| crypto::open(&pair_key, nonce, &ciphertext));
+                let joined = match room_key {
+                    Some(room_key) if room_key.len() == 32 => {
+                        // Without a record, the best guess is that whoever invited us started the room just now
+                        let record = record.map(|record| *record).filter(|record| record.room_id == room_id)
+                            .unwrap_or_else(|| RoomRecord { room_id: room_id.clone(), creator: peer, created: unix_now(), members, topic: None });
+                        let room = Room {
+                            members: record.members,
+                            creator: record.creator,
+                            created: record.created,
+                            topic: record.topic,
+                            key: *chacha20poly1305::Key::from_slice(&room_key),
+                            previous_key: None,
+                            passphrase: None,
+                            roster: roster.filter(|roster| roster.roster.creator == record.creator && roster.verify(&room_id)),
+                        };
+                        join_room(swarm, state, topic, &room_id, room);
+                        true
+                    }
+                    _ => {
+                        error!("Could not join the private room {room_id}: the room key was invalid.");
+                        false
+                    }
+                };
+                if let Err(e) = swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::RoomKeyResponse(joined)) {
+                    error!("Error sending response: {:?}", e);
+                }
+            },
+
             // Accept or Reject a private room invitation
             request_response::Message::Response {response: ResponseType::PrivateRoomResponse(protocol), .. } => {
-                if let PrivateRoomProtocol::Reject(_room_id) = protocol {
-                    println!("Private room request rejected.");
-                } else if let PrivateRoomProtocol::Accept(room_id) = protocol {
-                    // Connect to the private room topic
-                    // Unsubscribe from the default topic
-                    let default_topic = gossipsub::IdentTopic::new("default"); // or your current topic name
-                    swarm.behaviour_mut().chat.gossipsub.unsubscribe(&default_topic);
-                    // Subscribe to the private room topic
-                    let private_topic = IdentTopic::new(format!("{room_id}"));
-                    swarm.behaviour_mut().chat.gossipsub.subscribe(&private_topic).unwrap();
-                    *topic = private_topic.clone();
-                    println!("You have joined the private room: {room_id}");
+                if let PrivateRoomProtocol::Reject(room_id) = protocol {
+                    let Some(invite) = state.pending_invites.remove(&(room_id.clone(), peer)) else { return };
+                    system!("{} declined your private room request.", invite.nickname);
+                    invite_answered(state, &room_id, &invite.nickname, InviteOutcome::Declined);
+                } else if let PrivateRoomProtocol::Accept { room_id, public_key, passphrase_proof } = protocol {
+                    let Some(PendingInvite { secret, passphrase, nickname, .. }) = state.pending_invites.remove(&(room_id.clone(), peer)) else {
+                        system!("Received an acceptance for an unknown private room: {room_id}");
+                        return;
+                    };
+                    // Don't let them in if they couldn't give the room passphrase
+                    if let Some(passphrase) = &passphrase
+                        && passphrase_proof != Some(crypto::passphrase_proof(&room_id, passphrase)) {
+                        error!("{nickname} entered the wrong passphrase and was not let into the room.");
+                        invite_answered(state, &room_id, &nickname, InviteOutcome::Failed);
+                        let refusal = RequestType::RoomRefused { room_id, reason: "the passphrase was wrong".to_string() };
+                        swarm.behaviour_mut().request_response.request_response.send_request(&peer, refusal);
+                        return;
+                    }
+                    let pair_key = crypto::derive_room_key(&secret, public_key, &room_id, passphrase.as_deref());
+
+                    // Create the room on the first acceptance, otherwise add them to the room we're in
+                    if !state.rooms.contains_key(&room_id) {
+                        let own_member = match own_peer_data(swarm) {
+                            Some(own) => RoomMember { peer_id: *swarm.local_peer_id(), nickname: own.nickname },
+                            None => RoomMember { peer_id: *swarm.local_peer_id(), nickname: String::new() },
+                        };
+                        let mut room = Room {
+                            members: vec![own_member],
+                            creator: *swarm.local_peer_id(),
+                            created: unix_now(),
+                            topic: None,
+                            key: crypto::generate_room_key(),
+                            previous_key: None,
+                            passphrase,
+                            roster: None,
+                        };
+                        // Whoever starts the room moderates it
+                        room.sign_roster(&state.keypair, &room_id, None, false);
+                        join_room(swarm, state, topic, &room_id, room);
+                    }
+                    if state.rooms.get(&room_id).is_some_and(|room| room.is_banned(&peer)) {
+                        error!("{nickname} is banned from this room and was not let in.");
+                        invite_answered(state, &room_id, &nickname, InviteOutcome::Failed);
+                        return;
+                    }
+                    system!("{nickname} accepted your private room request.");
+                    invite_answered(state, &room_id, &nickname, InviteOutcome::Joined);
+                    let new_member = RoomMember { peer_id: peer, nickname };
+                    if let Err(e) = publish_gossip(swarm, state, IdentTopic::new(room_id.clone()).hash(), &GossipMessage::MemberJoined(new_member.clone())) {
+                        tracing::warn!("Failed to announce the new member: {:?}", e);
+                    }
+                    let Some(room) = state.rooms.get_mut(&room_id) else { return };
+                    room.add_member(new_member);
+                    publish_room_record(swarm, state.kademlia.room_quorum(), &room_id, room);
+                    publish_roster(swarm, state, &room_id, None, false);
+                    let Some(room) = state.rooms.get(&room_id) else { return };
+                    let (nonce, ciphertext) = crypto::seal(&pair_key, room.key.as_slice());
+                    let (members, roster, record) = (room.members.clone(), room.roster.clone(), Some(Box::new(room.record(&room_id))));
+                    swarm.behaviour_mut().request_response.request_response.send_request(
+                        &peer,
+                        RequestType::RoomKeyRequest { room_id, nonce, ciphertext, members, roster, record },
+                    );
+                }
+            }
+
+            request_response::Message::Response {response: ResponseType::RoomKeyResponse(joined), .. } => {
+                if !joined {
+                    error!("{} could not take the private room's key.", state.peer_name(&peer));
                 }
             }
         },
 
         // outgoing request fails to be sent
         request_response::Event::OutboundFailure {request_id, error, .. } => {
-            println!("Request {:?} failed to send: {:?}", request_id, error);
+            // Transfers cut off by a dropped connection are resent once the peer is back. Chunks of a stream are
+            // resent by the stream, see chunking
+            if !chunking::chunk_failed(swarm, state, &request_id, &error) && !bench::failed(state, &request_id, &error) && !outbound::failed(swarm, state, &request_id, &error) && !room::invite_failed(state, &request_id) {
+                tracing::warn!("Request {:?} failed to send: {:?}", request_id, error);
+            }
         },
 
         // incoming request fails to be processed
         request_response::Event::InboundFailure {peer, request_id, error, .. } => {
-            println!("Request {:?} from peer {:?} failed to be read: {:?}", request_id, peer, error);
+            tracing::warn!("Request {:?} from peer {:?} failed to be read: {:?}", request_id, peer, error);
         },
 
         // outgoing response is successfully sent
@@ -0,0 +1,251 @@
+use std::{ collections::{ BTreeMap, HashMap }, time::{ Duration, Instant } };
+use bytes::Bytes;
+use libp2p::{ request_response::{ OutboundRequestId, ResponseChannel }, swarm::{ dial_opts::{ DialOpts, PeerCondition }, ConnectionId }, Multiaddr, PeerId };
+use uuid::Uuid;
+
+use crate::{
+    behaviour::{ RequestType, ResponseType, SwapBytesBehaviour },
+    chunking,
+    connections::Transport,
+    util::ChatState,
+};
+
+// What /bench sends when no size is given
+pub const DEFAULT_SIZE: u64 = 8 * 1024 * 1024;
+// The most a peer can be asked to take
+pub const MAX_SIZE: u64 = 256 * 1024 * 1024;
+// Empty requests sent ahead of the payload to time round trips
+const PROBES: u32 = 8;
+
+// What went over one transport during a benchmark
+#[derive(Default)]
+struct Sample {
+    round_trips: Vec<Duration>,
+    bytes: u64,
+    busy: Duration, // Spent waiting on payload chunks
+}
+
+// A benchmark we're running against a peer. Requests are sent one at a time, so each is timed on its own, and are
+// spread over every connection to the peer, so one run measures TCP and QUIC side by side
+struct Outgoing {
+    peer: PeerId,
+    size: u64,
+    sent: u64,
+    probes: u32, // Still to send
+    dialling: Vec<ConnectionId>, // Connections over the transports we weren't using, opened for the benchmark
+    in_flight: Option<(OutboundRequestId, Instant, u64)>,
+    samples: BTreeMap<Transport, Sample>,
+}
+
+// Benchmarks we're running, by ID, and the ones peers are running against us with how much they may still send
+#[derive(Default)]
+pub struct Benchmarks {
+    outgoing: HashMap<String, Outgoing>,
+    incoming: HashMap<String, (PeerId, u64)>,
+}
+
+// A /bench size: bytes, or with a K, M or G suffix
+pub fn parse_size(size: &str) -> Option<u64> {
+    let upper = size.to_uppercase();
+    let (number, unit) = match upper.trim_end_matches(['B', 'I']).char_indices().last() {
+        Some((index, 'K')) => (&upper[..index], 1024),
+        Some((index, 'M')) => (&upper[..index], 1024 * 1024),
+        Some((index, 'G')) => (&upper[..index], 1024 * 1024 * 1024),
+        _ => (upper.as_str(), 1),
+    };
+    number.parse::<u64>().ok().and_then(|number| number.checked_mul(unit)).filter(|size| *size > 0)
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    match bytes {
+        b if b >= 1024.0 * 1024.0 * 1024.0 => format!("{:.1} GiB", b / (1024.0 * 1024.0 * 1024.0)),
+        b if b >= 1024.0 * 1024.0 => format!("{:.1} MiB", b / (1024.0 * 1024.0)),
+        b if b >= 1024.0 => format!("{:.1} KiB", b / 1024.0),
+        b => format!("{b:.0} bytes"),
+    }
+}
+
+// /bench <nickname> [size]: ask the peer to take part
+pub fn start(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, size: u64) {
+    if !swarm.behaviour().gate.permits(&peer) {
+        error!("Your connection policy doesn't allow {}. Use /allow or /unlist first.", state.peer_name(&peer));
+        return;
+    }
+    let bench_id = Uuid::new_v4().to_string();
+    swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::BenchRequest { bench_id: bench_id.clone(), size });
+    state.benchmarks.outgoing.insert(bench_id, Outgoing {
+        peer,
+        size,
+        sent: 0,
+        probes: PROBES,
+        dialling: Vec::new(),
+        in_flight: None,
+        samples: BTreeMap::new(),
+    });
+    system!("Asking {} to take part in a {} benchmark...", state.peer_name(&peer), format_bytes(size as f64));
+}
+
+// Answer a peer's benchmark request, with the addresses we listen on so they can reach us over every transport
+pub fn answer(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, bench_id: String, size: u64, channel: ResponseChannel<ResponseType>, accept: bool) {
+    let addresses = if accept { swarm.listeners().chain(swarm.external_addresses()).cloned().collect() } else { Vec::new() };
+    let response = ResponseType::BenchResponse { bench_id: bench_id.clone(), accepted: accept, addresses };
+    if swarm.behaviour_mut().request_response.request_response.send_response(channel, response).is_err() {
+        error!("Failed to answer the benchmark, the request may have timed out");
+        return;
+    }
+    if accept {
+        state.benchmarks.incoming.insert(bench_id, (peer, size));
+    }
+}
+
+// The peer answered our request. Transports we have no connection over are dialled first, so they're measured too
+pub fn answered(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, bench_id: String, accepted: bool, addresses: Vec<Multiaddr>) {
+    if state.benchmarks.outgoing.get(&bench_id).is_none_or(|bench| bench.peer != peer) {
+        return;
+    }
+    if !accepted {
+        state.benchmarks.outgoing.remove(&bench_id);
+        system!("{} turned down the benchmark.", state.peer_name(&peer));
+        return;
+    }
+    let connected: Vec<_> = state.connections.to(&peer).iter().map(|connection| connection.transport).collect();
+    let mut dialling = Vec::new();
+    for transport in [Transport::Tcp, Transport::Quic] {
+        let addresses: Vec<_> = addresses.iter().filter(|address| Transport::of(address) == transport).cloned().collect();
+        if connected.contains(&transport) || addresses.is_empty() {
+            continue;
+        }
+        let dial = DialOpts::peer_id(peer).addresses(addresses).condition(PeerCondition::Always).build();
+        let connection_id = dial.connection_id();
+        match swarm.dial(dial) {
+            Ok(()) => dialling.push(connection_id),
+            Err(e) => tracing::debug!("Could not dial {peer} over {transport} for a benchmark: {e}"),
+        }
+    }
+    system!("{} agreed to the benchmark.", state.peer_name(&peer));
+    let Some(bench) = state.benchmarks.outgoing.get_mut(&bench_id) else { return };
+    bench.dialling = dialling;
+    if bench.dialling.is_empty() {
+        send_next(swarm, state, &bench_id);
+    }
+}
+
+// A connection we may have been waiting on for a benchmark opened or failed to
+pub fn dial_finished(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, connection_id: ConnectionId) {
+    let Some((bench_id, bench)) = state.benchmarks.outgoing.iter_mut().find(|(_, bench)| bench.dialling.contains(&connection_id)) else { return };
+    bench.dialling.retain(|dialling| *dialling != connection_id);
+    if bench.dialling.is_empty() {
+        let bench_id = bench_id.clone();
+        send_next(swarm, state, &bench_id);
+    }
+}
+
+// Send the next probe or payload chunk, or report once everything has been acknowledged
+fn send_next(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, bench_id: &str) {
+    let Some(bench) = state.benchmarks.outgoing.get(bench_id) else { return };
+    let peer = bench.peer;
+    let chunk_size = chunking::chunk_size(state, &peer) as u64;
+    let Some(bench) = state.benchmarks.outgoing.get_mut(bench_id) else { return };
+    let length = if bench.probes > 0 {
+        bench.probes -= 1;
+        0
+    } else if bench.sent < bench.size {
+        let length = chunk_size.min(bench.size - bench.sent);
+        bench.sent += length;
+        length
+    } else {
+        if let Some(bench) = state.benchmarks.outgoing.remove(bench_id) {
+            report(state, &bench);
+        }
+        return;
+    };
+    let request = RequestType::BenchData { bench_id: bench_id.to_string(), data: Bytes::from(vec![0; length as usize]) };
+    let request_id = swarm.behaviour_mut().request_response.request_response.send_request(&peer, request);
+    bench.in_flight = Some((request_id, Instant::now(), length));
+}
+
+// The peer acknowledged a probe or chunk. Returns false if it wasn't one of ours
+pub fn acknowledged(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, connection_id: ConnectionId, request_id: &OutboundRequestId, accepted: bool) -> bool {
+    let transport = state.connections.get(&connection_id).map(|connection| connection.transport);
+    let Some((bench_id, bench)) = state.benchmarks.outgoing.iter_mut().find(|(_, bench)| bench.in_flight.is_some_and(|(id, ..)| id == *request_id)) else {
+        return false;
+    };
+    let bench_id = bench_id.clone();
+    let Some((_, sent_at, length)) = bench.in_flight.take() else { return true };
+    if !accepted {
+        let peer = bench.peer;
+        state.benchmarks.outgoing.remove(&bench_id);
+        error!("{} stopped taking part in the benchmark.", state.peer_name(&peer));
+        return true;
+    }
+    // The connection closed as the answer arrived, so there's no telling which transport carried it
+    if let Some(transport) = transport {
+        let sample = bench.samples.entry(transport).or_default();
+        if length == 0 {
+            sample.round_trips.push(sent_at.elapsed());
+        } else {
+            sample.bytes += length;
+            sample.busy += sent_at.elapsed();
+        }
+    }
+    send_next(swarm, state, &bench_id);
+    true
+}
+
+// A probe or chunk failed to send. Returns false if it wasn't one of ours
+pub fn failed(state: &mut ChatState, request_id: &OutboundRequestId, error: impl std::fmt::Display) -> bool {
+    let Some(bench_id) = state.benchmarks.outgoing.iter()
+        .find(|(_, bench)| bench.in_flight.is_some_and(|(id, ..)| id == *request_id))
+        .map(|(bench_id, _)| bench_id.clone()) else {
+        return false;
+    };
+    if let Some(bench) = state.benchmarks.outgoing.remove(&bench_id) {
+        error!("The benchmark with {} failed: {error}", state.peer_name(&bench.peer));
+    }
+    true
+}
+
+// A probe or chunk of a benchmark a peer is running against us. Returns whether we take it
+pub fn data_received(state: &mut ChatState, peer: PeerId, bench_id: &str, length: u64) -> bool {
+    let Some((from, remaining)) = state.benchmarks.incoming.get_mut(bench_id) else { return false };
+    if *from != peer || length > *remaining {
+        return false;
+    }
+    *remaining -= length;
+    if *remaining == 0 && length > 0 {
+        state.benchmarks.incoming.remove(bench_id);
+        system!("Finished a benchmark with {}.", state.peer_name(&peer));
+    }
+    true
+}
+
+fn report(state: &ChatState, bench: &Outgoing) {
+    let name = state.peer_name(&bench.peer);
+    system!("Benchmark with {name}, {} sent:", format_bytes(bench.size as f64));
+    for (transport, sample) in &bench.samples {
+        let round_trip = match sample.round_trips.iter().min() {
+            Some(round_trip) => format!("round trip {:.1} ms", round_trip.as_secs_f64() * 1000.0),
+            None => "round trip not measured".to_string(),
+        };
+        match sample.bytes {
+            0 => system!("  {transport}: {round_trip}, none of the payload went this way"),
+            bytes => system!(
+                "  {transport}: {round_trip}, {} in {:.2}s, {}/s",
+                format_bytes(bytes as f64),
+                sample.busy.as_secs_f64(),
+                format_bytes(bytes as f64 / sample.busy.as_secs_f64().max(0.001))
+            ),
+        }
+    }
+    let measured: Vec<_> = bench.samples.iter().filter(|(_, sample)| sample.bytes > 0).collect();
+    match measured.as_slice() {
+        [] => {}
+        [(transport, _)] => system!("Only {transport} was measured: {name} can't be reached over anything else right now."),
+        _ => {
+            let throughput = |sample: &Sample| sample.bytes as f64 / sample.busy.as_secs_f64().max(0.001);
+            if let Some((fastest, _)) = measured.iter().max_by(|a, b| throughput(a.1).total_cmp(&throughput(b.1))) {
+                system!("{fastest} was the fastest path to {name}.");
+            }
+        }
+    }
+}
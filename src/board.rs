@@ -0,0 +1,211 @@
+use std::{ collections::HashMap, fmt, time::{ Duration, Instant } };
+use libp2p::{ gossipsub::TopicHash, PeerId };
+use serde::{ Deserialize, Serialize };
+use tokio::fs;
+
+use crate::{
+    audit,
+    behaviour::SwapBytesBehaviour,
+    util::{ publish_gossip, ChatState, GossipMessage },
+};
+
+// Every node subscribes to this topic alongside whichever room it is in
+pub const TOPIC: &str = "board";
+// Posts are republished with our DHT records every hour, so a peer's posts are dropped once they miss a few
+const POST_TTL: Duration = Duration::from_secs(3 * 60 * 60);
+// Limits on what we keep from each peer, so one peer can't fill the board
+const MAX_POSTS: usize = 20;
+const MAX_TEXT: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PostKind {
+    Want,
+    Have,
+}
+
+impl fmt::Display for PostKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostKind::Want => write!(f, "want"),
+            PostKind::Have => write!(f, "have"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoardPost {
+    pub id: u32,
+    pub kind: PostKind,
+    pub text: String,      // What's wanted, or the name of the file on offer
+    pub size: Option<u64>, // Of the file, for haves
+    #[serde(default)]
+    pub digest: Option<String>, // SHA-256 of the file, for haves, so wishlists can match it by hash
+}
+
+impl fmt::Display for BoardPost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.size {
+            Some(size) => write!(f, "{}: {} ({size} bytes)", self.kind, self.text),
+            None => write!(f, "{}: {}", self.kind, self.text),
+        }
+    }
+}
+
+// Another peer's posts, as of the last snapshot they published
+struct PeerPosts {
+    nickname: String,
+    posts: Vec<BoardPost>,
+    received: Instant,
+}
+
+// Wants and haves posted with /want and /have. Each peer publishes all of its posts at once on the board
+// topic, so a new snapshot replaces whatever we had from them
+#[derive(Default)]
+pub struct Board {
+    own: Vec<BoardPost>,
+    next_id: u32,
+    peers: HashMap<PeerId, PeerPosts>,
+    // Who each number in the last /board listing was posted by, and which post
+    listing: Vec<(PeerId, u32)>,
+}
+
+impl Board {
+    // Take a snapshot a peer published, returning how many of its posts we hadn't seen
+    pub fn received(&mut self, author: PeerId, nickname: String, mut posts: Vec<BoardPost>) -> usize {
+        posts.truncate(MAX_POSTS);
+        for post in &mut posts {
+            if let Some((end, _)) = post.text.char_indices().nth(MAX_TEXT) {
+                post.text.truncate(end);
+            }
+        }
+        let new = posts.iter()
+            .filter(|post| self.peers.get(&author).is_none_or(|previous| previous.posts.iter().all(|seen| seen.id != post.id)))
+            .count();
+        if posts.is_empty() {
+            self.peers.remove(&author);
+        } else {
+            self.peers.insert(author, PeerPosts { nickname, posts, received: Instant::now() });
+        }
+        new
+    }
+
+    // Peers leaving SwapBytes take their posts with them
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    // Every other peer's haves we still hold, for checking them against a new wish
+    pub fn haves(&self) -> Vec<(PeerId, String, Vec<BoardPost>)> {
+        self.peers.iter()
+            .filter(|(_, posts)| posts.received.elapsed() < POST_TTL)
+            .map(|(peer, posts)| {
+                let haves = posts.posts.iter().filter(|post| post.kind == PostKind::Have).cloned().collect();
+                (*peer, posts.nickname.clone(), haves)
+            })
+            .collect()
+    }
+
+    // Who posted a number in the last /board listing
+    pub fn poster(&self, number: usize) -> Option<PeerId> {
+        self.listing.get(number.checked_sub(1)?).map(|(peer, _)| *peer)
+    }
+}
+
+pub fn topic() -> TopicHash {
+    TopicHash::from_raw(TOPIC)
+}
+
+// Publish all of our posts, replacing what other peers had from us. An empty list takes them all down
+fn publish(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState, nickname: &str) {
+    let message = GossipMessage::Board { nickname: nickname.to_string(), posts: state.board.own.clone() };
+    if let Err(e) = publish_gossip(swarm, state, topic(), &message) {
+        tracing::debug!("Failed to publish board posts: {:?}", e);
+    }
+}
+
+// Publish our posts again, for peers that have just joined the board or may have dropped them
+pub fn republish(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState, nickname: &str) {
+    if !state.board.own.is_empty() {
+        publish(swarm, state, nickname);
+    }
+}
+
+fn post(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, nickname: &str, kind: PostKind, text: &str, size: Option<u64>, digest: Option<String>) {
+    if state.board.own.len() >= MAX_POSTS {
+        error!("You already have {MAX_POSTS} posts on the board. Take one down with /board remove <number> first.");
+        return;
+    }
+    if text.chars().count() > MAX_TEXT {
+        error!("Posts can be at most {MAX_TEXT} characters.");
+        return;
+    }
+    state.board.next_id += 1;
+    let post = BoardPost { id: state.board.next_id, kind, text: text.to_string(), size, digest };
+    system!("Posted to the board, {post}");
+    state.board.own.push(post);
+    publish(swarm, state, nickname);
+}
+
+// /want <description>
+pub fn want(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, nickname: &str, description: &str) {
+    post(swarm, state, nickname, PostKind::Want, description, None, None);
+}
+
+// /have <file>
+pub async fn have(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, nickname: &str, path: &str) {
+    // Read in full for its hash, so wishlists can match it even if it's renamed
+    match fs::read(path).await {
+        Ok(data) => post(swarm, state, nickname, PostKind::Have, path, Some(data.len() as u64), Some(audit::digest(&data))),
+        Err(e) => error!("Could not read {path}: {e}"),
+    }
+}
+
+// /board remove <number>
+pub fn remove(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, nickname: &str, number: usize) {
+    let local_peer_id = *swarm.local_peer_id();
+    let post_id = match number.checked_sub(1).and_then(|index| state.board.listing.get(index)) {
+        Some((peer, post_id)) if *peer == local_peer_id => *post_id,
+        Some(_) => {
+            error!("Post {number} isn't yours.");
+            return;
+        }
+        None => {
+            system!("There is no post {number}. Type /board to see them.");
+            return;
+        }
+    };
+    let Some(index) = state.board.own.iter().position(|post| post.id == post_id) else {
+        system!("You have already taken post {number} down.");
+        return;
+    };
+    let post = state.board.own.remove(index);
+    system!("Took down your post, {post}");
+    publish(swarm, state, nickname);
+}
+
+// /board: our own posts first, then everyone else's, numbered for /board connect and /board remove
+pub fn list(swarm: &libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, nickname: &str) {
+    let local_peer_id = *swarm.local_peer_id();
+    state.board.peers.retain(|_, peer| peer.received.elapsed() < POST_TTL);
+    let mut peers: Vec<_> = state.board.peers.iter()
+        .map(|(peer, posts)| (*peer, posts.nickname.clone(), posts.posts.clone()))
+        .collect();
+    // The nickname the peer registered, when we have it, rather than the one they posted under
+    for (peer, name, _) in &mut peers {
+        if let Some(data) = state.cached_peer(peer) {
+            name.clone_from(&data.nickname);
+        }
+    }
+    peers.sort_by(|(_, a, _), (_, b, _)| a.cmp(b));
+    let mut entries: Vec<_> = state.board.own.iter().map(|post| (local_peer_id, format!("{nickname} (you)"), post.clone())).collect();
+    for (peer, name, posts) in peers {
+        entries.extend(posts.into_iter().map(|post| (peer, name.clone(), post)));
+    }
+    if entries.is_empty() {
+        system!("The board is empty. Post to it with /want <description> or /have <file>.");
+    }
+    for (number, (_, name, post)) in entries.iter().enumerate() {
+        system!("[{}] {name}, {post}", number + 1);
+    }
+    state.board.listing = entries.into_iter().map(|(peer, _, post)| (peer, post.id)).collect();
+}
@@ -0,0 +1,106 @@
+use std::{ error::Error, path::PathBuf, time::Duration };
+use futures::StreamExt;
+use libp2p::{ identify, kad::{ self, store::RecordStore }, noise, ping, relay, rendezvous, swarm::{ NetworkBehaviour, SwarmEvent }, tcp, yamux };
+use tokio::{ select, signal };
+
+use crate::{ relay::{ self as relay_server, RelayBehaviour }, server::{ self, Directory }, policy::ConnectionGate, status::{ RelayStats, StatusBoard }, store::DiskStore, util::{ RelayOptions, ServerOptions } };
+
+// Where the bootstrap node keeps DHT records unless given --records, so they outlive restarts
+pub const DEFAULT_RECORDS_FILE: &str = "bootstrap-records.json";
+// How often changed records are written to that file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+// Everything an always-on node does for its community: it is the rendezvous server clients look for, relays for
+// those behind NATs, and holds DHT records while their publishers are offline
+#[derive(NetworkBehaviour)]
+pub struct BootstrapBehaviour {
+    pub gate: ConnectionGate,
+    pub rendezvous: rendezvous::server::Behaviour,
+    pub relay: relay::Behaviour,
+    pub identify: identify::Behaviour,
+    pub kademlia: kad::Behaviour<DiskStore>,
+    pub ping: ping::Behaviour,
+}
+
+// `swapbytes bootstrap`: run the rendezvous server, a relay and a Kademlia server in one node until Ctrl-C
+pub async fn run(port: u16, identity: Option<PathBuf>, records: PathBuf, options: ServerOptions, limits: RelayOptions) -> Result<(), Box<dyn Error>> {
+    // With --allow-peers, only those peers can register, discover, relay or use the DHT
+    let gate = server::load_allowed(options.allow_peers.as_deref())?;
+    let mut swarm = libp2p::SwarmBuilder
+        ::with_existing_identity(server::keypair(identity.as_deref())?)
+        .with_tokio()
+        .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)?
+        .with_quic()
+        .with_behaviour(|key| {
+            let RelayBehaviour { relay, identify, ping } = RelayBehaviour::new(key, &limits);
+            let peer_id = key.public().to_peer_id();
+            BootstrapBehaviour {
+                gate,
+                rendezvous: server::rendezvous_behaviour(options.min_ttl),
+                relay,
+                identify,
+                kademlia: kad::Behaviour::new(peer_id, DiskStore::new(peer_id, Some(records.clone()))),
+                ping,
+            }
+        })?
+        // Clients stay connected for discovery anyway, and the DHT is more use with them in the routing table
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(300)))
+        .build();
+    // Answer DHT queries even before we know our own public address
+    swarm.behaviour_mut().kademlia.set_mode(Some(kad::Mode::Server));
+    swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{port}").parse()?)?;
+    swarm.listen_on(format!("/ip4/0.0.0.0/udp/{port}/quic-v1").parse()?)?;
+    let peer_id = *swarm.local_peer_id();
+    system!("Keeping DHT records in {}", records.display());
+    server::print_address(&peer_id, port);
+
+    let mut directory = options.registrations.map(Directory::load).unwrap_or_default();
+    directory.redial(&mut swarm);
+    let status = StatusBoard::new(options.status).await?;
+    let mut stats = RelayStats::default();
+    let mut flush_tick = tokio::time::interval(FLUSH_INTERVAL);
+
+    loop {
+        select! {
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => system!("Bootstrap node listening on {address}/p2p/{peer_id}"),
+                SwarmEvent::Behaviour(BootstrapBehaviourEvent::Rendezvous(event)) => {
+                    // Registered peers are DHT peers too, so records have somewhere to go besides us
+                    if let rendezvous::server::Event::PeerRegistered { peer, registration } = &event {
+                        for address in registration.record.addresses() {
+                            swarm.behaviour_mut().kademlia.add_address(peer, address.clone());
+                        }
+                    }
+                    directory.handle_event(event);
+                }
+                SwarmEvent::Behaviour(BootstrapBehaviourEvent::Relay(event)) => relay_server::handle_event(event, &mut stats),
+                SwarmEvent::Behaviour(BootstrapBehaviourEvent::Identify(event)) => relay_server::handle_identify(&mut swarm, event),
+                SwarmEvent::Behaviour(BootstrapBehaviourEvent::Kademlia(kad::Event::InboundRequest { request })) => {
+                    tracing::debug!("DHT request: {request:?}");
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, .. } => tracing::debug!("{peer_id} connected"),
+                SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => tracing::info!("Refused {send_back_addr}: {error}"),
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    tracing::debug!("{peer_id} disconnected");
+                    stats.unreserved(&peer_id);
+                }
+                _ => {}
+            },
+            _ = flush_tick.tick() => {
+                swarm.behaviour_mut().kademlia.store_mut().flush();
+            }
+            _ = signal::ctrl_c() => break,
+        }
+        let records = swarm.behaviour_mut().kademlia.store_mut().records().count();
+        status.update(|status| {
+            status.connected_peers = swarm.network_info().num_peers();
+            status.registrations = Some(directory.namespaces());
+            status.relay = Some(stats.clone());
+            status.dht_records = Some(records);
+        });
+    }
+    if let Some(flush) = swarm.behaviour_mut().kademlia.store_mut().flush() {
+        let _ = flush.await;
+    }
+    Ok(())
+}
@@ -0,0 +1,641 @@
+use std::{ collections::{ BTreeMap, HashMap }, path::{ Path, PathBuf }, time::{ Duration, Instant } };
+use bytes::Bytes;
+use futures::FutureExt;
+use libp2p::{ request_response::{ OutboundFailure, OutboundRequestId }, PeerId };
+use tokio::{ fs::{ self, File }, io::{ self, AsyncReadExt, AsyncWriteExt } };
+use uuid::Uuid;
+
+use crate::{
+    approval::{ self, queue_approval, OfferReply, PendingApproval },
+    audit,
+    behaviour::{ save_received_part, RequestType, SwapBytesBehaviour },
+    bench::format_bytes,
+    trade,
+    util::ChatState,
+};
+
+// Largest request or response our codec accepts unless --max-message-size says otherwise
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 8 * 1024 * 1024;
+// The smallest --max-message-size allowed, so every chunk still has room for data
+pub const MIN_MAX_MESSAGE_SIZE: u64 = 128 * 1024;
+// What we assume of peers that haven't advertised a limit: the cbor codec's default request size
+const ASSUMED_PEER_LIMIT: u64 = 1024 * 1024;
+// Room left in each message for the other fields and the cbor encoding
+const OVERHEAD: u64 = 16 * 1024;
+// Chunked transfers that go this long without a chunk arriving are dropped
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(600);
+// Largest chunked transfer we'll take
+const MAX_TRANSFER_SIZE: usize = 1024 * 1024 * 1024;
+// Chunks of a stream that can be waiting for the receiver at once, so only this many are in memory
+const WINDOW: u32 = 4;
+// Every stream starts with chunks this big, see Pacing
+const FIRST_CHUNK: usize = 64 * 1024;
+// How much chunks grow with each acknowledgement once a stream has had to slow down
+const STEP: usize = 64 * 1024;
+// Times a chunk is sent before its stream is given up on
+const MAX_ATTEMPTS: u32 = 3;
+
+// What to do with a chunked file once all of it is here
+pub enum Completion {
+    // A file offer, to be answered with /yes or /no like any other. The answer goes as an OfferAnswer request, see
+    // approval::OfferReply
+    Offer { filename: String, transfer_id: String },
+    // The answer to one of our /requests
+    Response { filename: String },
+    // Their side of a trade, see trade
+    Trade { trade_id: String },
+}
+
+// A file being received in pieces. Chunks are appended to a part file in the download directory as soon as the
+// ones before them are in, so only chunks that arrive early are held in memory
+struct Incoming {
+    part: PathBuf,
+    file: Option<File>, // Created with the first chunk written
+    written: u32,       // Chunks in the part file, all the ones before this index
+    written_size: u64,  // and the bytes in them
+    early: BTreeMap<u32, Bytes>,
+    size: usize,
+    // The size of the whole file, known once the offer or response announcing the transfer arrives, which can be
+    // before or after the chunks
+    expected: Option<(u64, Completion)>,
+    active: Instant, // When a chunk last arrived, so a slow sender held to --max-upload-rate isn't dropped
+}
+
+impl Incoming {
+    // Append every chunk that is next in line to the part file
+    async fn write_ready(&mut self) -> io::Result<()> {
+        while let Some(data) = self.early.remove(&self.written) {
+            let file = match &mut self.file {
+                Some(file) => file,
+                None => self.file.insert(File::create(&self.part).await?),
+            };
+            file.write_all(&data).await?;
+            self.written += 1;
+            self.written_size += data.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+// A part file that is deleted once nothing holds it, unless it was moved into the downloads first
+pub struct PartFile(PathBuf);
+
+impl PartFile {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// A file a peer sent us for an offer or trade. Small ones arrive whole in one message; chunked ones stay in their
+// part file while they wait for an answer or a key, so big files aren't held in memory
+pub enum Received {
+    Data(Vec<u8>),
+    Part { part: PartFile, size: u64, digest: String },
+}
+
+impl Received {
+    pub fn size(&self) -> u64 {
+        match self {
+            Received::Data(data) => data.len() as u64,
+            Received::Part { size, .. } => *size,
+        }
+    }
+
+    pub fn digest(&self) -> String {
+        match self {
+            Received::Data(data) => audit::digest(data),
+            Received::Part { digest, .. } => digest.clone(),
+        }
+    }
+
+    // All of the file in memory, for the sealed files of fair trades, which can only be opened whole
+    pub async fn read(self) -> io::Result<Vec<u8>> {
+        match self {
+            Received::Data(data) => Ok(data),
+            Received::Part { part, .. } => fs::read(part.path()).await,
+        }
+    }
+}
+
+// Where a stream's data comes from
+enum Source {
+    // Files on disk, read a chunk at a time
+    File(File),
+    // Offers sent with an RPC call and the sealed files of fair trades, which are only in memory. Every chunk is
+    // a slice of them
+    Memory(Bytes),
+}
+
+// How big the next chunk of a stream is. The best size depends on the link, so it's found as the stream goes, like
+// TCP's slow start: chunks start small and double with each one acknowledged, until one is lost or arrives at under
+// half the best rate seen so far. That halves the size, and from then on it only grows a step at a time. On a LAN
+// chunks soon reach the most the peer takes in one message, and on a flaky link they stay at sizes that get through.
+// With --max-upload-rate, chunks are also spaced out so the stream never sends faster than that on average
+struct Pacing {
+    size: usize,
+    ceiling: usize,   // The most the peer takes in one message
+    threshold: usize, // Chunks smaller than this double, larger ones grow by STEP
+    best_rate: f64,   // Bytes per second, since the stream last slowed down
+    rate_cap: Option<u64>, // Bytes per second the stream is held to
+    next_send: Instant,    // When the cap lets the next chunk go
+}
+
+impl Pacing {
+    fn new(ceiling: usize, rate_cap: Option<u64>) -> Self {
+        // Under a cap, chunks stay at a quarter of a second's worth, so even a short stream keeps to it
+        let ceiling = match rate_cap {
+            Some(cap) => ceiling.min((cap as usize / 4).max(FIRST_CHUNK)),
+            None => ceiling,
+        };
+        let size = FIRST_CHUNK.min(ceiling);
+        Pacing { size, ceiling, threshold: ceiling, best_rate: 0.0, rate_cap, next_send: Instant::now() }
+    }
+
+    // Whether the rate cap lets another chunk go now
+    fn ready(&self) -> bool {
+        self.next_send <= Instant::now()
+    }
+
+    // A chunk went out, so the next one waits as long as the cap takes to send this one
+    fn sent(&mut self, length: usize) {
+        if let Some(cap) = self.rate_cap {
+            let spacing = Duration::from_secs_f64(length as f64 / cap.max(1) as f64);
+            self.next_send = self.next_send.max(Instant::now()) + spacing;
+        }
+    }
+
+    fn acknowledged(&mut self, length: usize, round_trip: Duration) {
+        let rate = length as f64 / round_trip.as_secs_f64().max(0.001);
+        if rate < self.best_rate / 2.0 {
+            self.back_off();
+            return;
+        }
+        self.best_rate = self.best_rate.max(rate);
+        let grown = if self.size < self.threshold { self.size * 2 } else { self.size + STEP };
+        self.size = grown.min(self.ceiling);
+    }
+
+    fn back_off(&mut self) {
+        self.size = (self.size / 2).max(FIRST_CHUNK.min(self.ceiling));
+        self.threshold = self.size;
+        self.best_rate = 0.0;
+    }
+}
+
+// A file being sent in pieces, a chunk at a time as the receiver acknowledges the ones before
+struct Outgoing {
+    peer: PeerId,
+    source: Source,
+    size: u64,
+    sent: u64, // Bytes taken from the source so far
+    next: u32,
+    pacing: Pacing,
+    acknowledged: bool, // Whether the receiver has taken any chunk yet
+    held: u32,          // Chunks left for resume to send: kept back by the rate cap, or the first of a stream_later
+}
+
+// A chunk on its way, kept until it's acknowledged in case it has to be sent again
+struct InFlight {
+    transfer_id: String,
+    index: u32,
+    data: Bytes,
+    sent: Instant,
+    attempts: u32,
+}
+
+// Chunked transfers being received, by sender and transfer ID, and files being streamed to peers
+#[derive(Default)]
+pub struct ChunkedTransfers {
+    incoming: HashMap<(PeerId, String), Incoming>,
+    outgoing: HashMap<String, Outgoing>,
+    in_flight: HashMap<OutboundRequestId, InFlight>,
+    upload_rate: Option<u64>, // From --max-upload-rate, for every stream we send
+}
+
+impl ChunkedTransfers {
+    pub fn new(upload_rate: Option<u64>) -> Self {
+        ChunkedTransfers { upload_rate, ..Default::default() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.incoming.is_empty() && self.outgoing.is_empty()
+    }
+
+    pub fn is_sending(&self, transfer_id: &str) -> bool {
+        self.outgoing.contains_key(transfer_id)
+    }
+
+    // Whether any stream has chunks waiting to be sent, for the node to call resume
+    pub fn held(&self) -> bool {
+        self.outgoing.values().any(|outgoing| outgoing.held > 0)
+    }
+
+    fn entry(&mut self, peer: PeerId, transfer_id: String, download_dir: &Path) -> &mut Incoming {
+        self.incoming.retain(|_, incoming| {
+            let current = incoming.active.elapsed() < TRANSFER_TIMEOUT;
+            if !current {
+                let _ = std::fs::remove_file(&incoming.part);
+            }
+            current
+        });
+        let part = download_dir.join(format!(".{transfer_id}.part"));
+        self.incoming.entry((peer, transfer_id)).or_insert_with(|| Incoming {
+            part,
+            file: None,
+            written: 0,
+            written_size: 0,
+            early: BTreeMap::new(),
+            size: 0,
+            expected: None,
+            active: Instant::now(),
+        })
+    }
+
+    // Drop a transfer, and whatever of it was written
+    fn abandon(&mut self, key: &(PeerId, String)) {
+        if let Some(incoming) = self.incoming.remove(key) {
+            let _ = std::fs::remove_file(&incoming.part);
+        }
+    }
+
+    // Hand back the transfer if every chunk has been written
+    fn take_if_complete(&mut self, key: (PeerId, String)) -> Option<Incoming> {
+        let complete = self.incoming.get(&key)
+            .is_some_and(|incoming| matches!(incoming.expected, Some((size, _)) if incoming.written_size == size));
+        if !complete {
+            return None;
+        }
+        self.incoming.remove(&key)
+    }
+}
+
+// /stats: how far each chunked transfer has got
+pub fn print_progress(state: &ChatState) {
+    for line in progress(state) {
+        system!("  {line}");
+    }
+}
+
+// A line for each chunked transfer, for /stats and the --tui transfers pane
+pub fn progress(state: &ChatState) -> Vec<String> {
+    let mut lines = Vec::new();
+    for ((peer, _), incoming) in &state.chunked.incoming {
+        let received = format_bytes(incoming.size as f64);
+        lines.push(match &incoming.expected {
+            Some((size, _)) => format!(
+                "Receiving from {}: {received} of {} ({:.0}%)",
+                state.peer_name(peer),
+                format_bytes(*size as f64),
+                incoming.size as f64 * 100.0 / (*size).max(1) as f64
+            ),
+            None => format!("Receiving from {}: {received} so far", state.peer_name(peer)),
+        });
+    }
+    for outgoing in state.chunked.outgoing.values() {
+        lines.push(format!(
+            "Sending to {}: {} of {} ({:.0}%), in chunks of {}",
+            state.peer_name(&outgoing.peer),
+            format_bytes(outgoing.sent as f64),
+            format_bytes(outgoing.size as f64),
+            outgoing.sent as f64 * 100.0 / outgoing.size.max(1) as f64,
+            format_bytes(outgoing.pacing.size as f64)
+        ));
+    }
+    lines
+}
+
+// The largest message a peer has said it accepts
+fn peer_limit(state: &mut ChatState, peer: &PeerId) -> u64 {
+    state.cached_peer(peer)
+        .and_then(|peer_data| peer_data.profile.max_message_size)
+        .unwrap_or(ASSUMED_PEER_LIMIT)
+}
+
+// The most file data a message to the peer can carry
+pub fn chunk_size(state: &mut ChatState, peer: &PeerId) -> usize {
+    peer_limit(state, peer).saturating_sub(OVERHEAD).max(OVERHEAD) as usize
+}
+
+pub fn new_transfer_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+// Start streaming a file from disk to a peer, once the response announcing its `size` is sent. The rest follows as
+// the receiver acknowledges each chunk, see acknowledged
+pub async fn stream(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, transfer_id: String, file: File, size: u64) {
+    start(swarm, state, peer, transfer_id, Source::File(file), size).await;
+}
+
+// Stream a file that is already in memory, for offers and trades too big for one message
+pub fn stream_bytes(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, transfer_id: String, data: Bytes) {
+    let size = data.len() as u64;
+    // Nothing has to be read, so the first chunks are sent without waiting
+    let _ = start(swarm, state, peer, transfer_id, Source::Memory(data), size).now_or_never();
+}
+
+// Stream a file from disk where the disk can't be waited on, as when a queued offer's turn comes. Its first chunks
+// go with the next resume
+pub fn stream_later(state: &mut ChatState, peer: PeerId, transfer_id: String, file: File, size: u64) {
+    add_stream(state, peer, transfer_id, Source::File(file), size, WINDOW);
+}
+
+fn add_stream(state: &mut ChatState, peer: PeerId, transfer_id: String, source: Source, size: u64, held: u32) {
+    let pacing = Pacing::new(chunk_size(state, &peer), state.chunked.upload_rate);
+    state.chunked.outgoing.insert(transfer_id, Outgoing { peer, source, size, sent: 0, next: 0, pacing, acknowledged: false, held });
+}
+
+async fn start(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, transfer_id: String, source: Source, size: u64) {
+    add_stream(state, peer, transfer_id.clone(), source, size, 0);
+    for _ in 0..WINDOW {
+        send_next(swarm, state, &transfer_id).await;
+    }
+}
+
+// Take the next chunk of a stream from its source and send it, finishing the stream once every chunk is acknowledged
+async fn send_next(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, transfer_id: &str) {
+    let Some(outgoing) = state.chunked.outgoing.get_mut(transfer_id) else { return };
+    let length = (outgoing.size - outgoing.sent).min(outgoing.pacing.size as u64) as usize;
+    if length == 0 {
+        if !state.chunked.in_flight.values().any(|chunk| chunk.transfer_id == transfer_id) {
+            state.chunked.outgoing.remove(transfer_id);
+        }
+        return;
+    }
+    if !outgoing.pacing.ready() {
+        outgoing.held += 1;
+        return;
+    }
+    let data = match &mut outgoing.source {
+        Source::Memory(data) => Ok(data.slice(outgoing.sent as usize..outgoing.sent as usize + length)),
+        Source::File(file) => {
+            let mut data = Vec::with_capacity(length);
+            file.take(length as u64).read_to_end(&mut data).await.map(|_| Bytes::from(data))
+        }
+    };
+    let data = match data {
+        Ok(data) if !data.is_empty() => data,
+        Ok(_) => return abandon_stream(state, transfer_id, "it is shorter than it was"),
+        Err(e) => return abandon_stream(state, transfer_id, &e.to_string()),
+    };
+    outgoing.sent += data.len() as u64;
+    outgoing.pacing.sent(data.len());
+    let (peer, index) = (outgoing.peer, outgoing.next);
+    outgoing.next += 1;
+    send(swarm, state, peer, InFlight { transfer_id: transfer_id.to_string(), index, data, sent: Instant::now(), attempts: 1 });
+}
+
+// Send the chunks streams held back, as far as the rate cap now allows
+pub async fn resume(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState) {
+    let due: Vec<String> = state.chunked.outgoing.iter()
+        .filter(|(_, outgoing)| outgoing.held > 0)
+        .map(|(transfer_id, _)| transfer_id.clone())
+        .collect();
+    for transfer_id in due {
+        while let Some(outgoing) = state.chunked.outgoing.get_mut(&transfer_id) && outgoing.held > 0 && outgoing.pacing.ready() {
+            outgoing.held -= 1;
+            send_next(swarm, state, &transfer_id).await;
+        }
+    }
+}
+
+// Stop sending a file whose offer failed or was turned down, as nobody will take the rest
+pub fn stop_stream(state: &mut ChatState, transfer_id: &str) {
+    if state.chunked.outgoing.remove(transfer_id).is_some() {
+        state.chunked.in_flight.retain(|_, chunk| chunk.transfer_id != transfer_id);
+    }
+}
+
+fn abandon_stream(state: &mut ChatState, transfer_id: &str, reason: &str) {
+    if let Some(outgoing) = state.chunked.outgoing.remove(transfer_id) {
+        error!("Stopped sending a file to {}, it could not be read: {reason}", state.peer_name(&outgoing.peer));
+    }
+}
+
+fn send(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, mut chunk: InFlight) {
+    let request = RequestType::Chunk { transfer_id: chunk.transfer_id.clone(), index: chunk.index, data: chunk.data.clone() };
+    chunk.sent = Instant::now();
+    let request_id = swarm.behaviour_mut().request_response.request_response.send_request(&peer, request);
+    state.chunked.in_flight.insert(request_id, chunk);
+}
+
+// A peer answered one of our chunks. The stream grows its chunks and sends the next, or stops if the peer refused it
+pub async fn acknowledged(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, request_id: &OutboundRequestId, accepted: bool) {
+    let Some(mut chunk) = state.chunked.in_flight.remove(request_id) else { return };
+    if !accepted {
+        // The first chunks of an offer or trade can overtake its announcement and be turned away as unexpected, so
+        // until one is taken they're sent again a few times
+        match state.chunked.outgoing.get(&chunk.transfer_id) {
+            Some(outgoing) if !outgoing.acknowledged && chunk.attempts < MAX_ATTEMPTS => {
+                chunk.attempts += 1;
+                let peer = outgoing.peer;
+                send(swarm, state, peer, chunk);
+            }
+            Some(outgoing) => {
+                error!("{} refused part of a file you sent them.", state.peer_name(&outgoing.peer));
+                state.chunked.outgoing.remove(&chunk.transfer_id);
+            }
+            None => {}
+        }
+        return;
+    }
+    if let Some(outgoing) = state.chunked.outgoing.get_mut(&chunk.transfer_id) {
+        outgoing.acknowledged = true;
+        outgoing.pacing.acknowledged(chunk.data.len(), chunk.sent.elapsed());
+    }
+    send_next(swarm, state, &chunk.transfer_id).await;
+}
+
+// A chunk never reached the peer. If it timed out or its stream broke it's sent again and the stream slows down, a
+// few times at most; if the connection is gone the rest of the stream is pointless. Returns whether it was one of ours
+pub fn chunk_failed(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, request_id: &OutboundRequestId, error: &OutboundFailure) -> bool {
+    let Some(mut chunk) = state.chunked.in_flight.remove(request_id) else { return false };
+    let lost = matches!(error, OutboundFailure::Timeout | OutboundFailure::Io(_));
+    match state.chunked.outgoing.get_mut(&chunk.transfer_id) {
+        Some(outgoing) if lost && chunk.attempts < MAX_ATTEMPTS => {
+            tracing::debug!("Resending part {} of a file to {}: {error}", chunk.index, outgoing.peer);
+            outgoing.pacing.back_off();
+            chunk.attempts += 1;
+            let peer = outgoing.peer;
+            send(swarm, state, peer, chunk);
+        }
+        Some(_) => {
+            tracing::warn!("Stopped streaming a file, a chunk failed to send: {error:?}");
+            state.chunked.outgoing.remove(&chunk.transfer_id);
+        }
+        None => {}
+    }
+    true
+}
+
+// Store a chunk, returning whether it was accepted, and finish the transfer if it was the last piece
+pub async fn receive_chunk(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, transfer_id: String, index: u32, data: Bytes) -> bool {
+    // The ID names the part file, so it has to be one of ours and not a path
+    if Uuid::try_parse(&transfer_id).is_err() {
+        return false;
+    }
+    // Only transfers we know of are taken: ones announced to us, or the answer to a /request or trade of ours whose
+    // chunks overtook it
+    let key = (peer, transfer_id.clone());
+    if !state.chunked.incoming.contains_key(&key) && !state.outbound.awaits_file_from(&peer) && !state.trades.awaits_file_from(&peer) {
+        return false;
+    }
+    let incoming = state.chunked.entry(peer, transfer_id, &state.download_dir);
+    if incoming.size + data.len() > MAX_TRANSFER_SIZE {
+        state.chunked.abandon(&key);
+        error!("Dropped a file from {} that is larger than {} MiB.", state.peer_name(&peer), MAX_TRANSFER_SIZE / 1024 / 1024);
+        return false;
+    }
+    // Resent chunks are already here
+    if index < incoming.written || incoming.early.contains_key(&index) {
+        return true;
+    }
+    // A sender keeps no more than WINDOW chunks unacknowledged, so anything beyond that isn't held
+    if index != incoming.written && incoming.early.len() >= WINDOW as usize {
+        return false;
+    }
+    incoming.size += data.len();
+    incoming.active = Instant::now();
+    incoming.early.insert(index, data);
+    if let Err(e) = incoming.write_ready().await {
+        state.chunked.abandon(&key);
+        error!("Dropped a file from {}, it could not be written: {e}", state.peer_name(&peer));
+        return false;
+    }
+    if let Some(incoming) = state.chunked.take_if_complete(key) {
+        finish(swarm, state, peer, incoming).await;
+    }
+    true
+}
+
+// The offer or response announcing a chunked transfer arrived. Returns why it was refused, if it was
+pub async fn expect(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, transfer_id: String, size: u64, completion: Completion) -> Option<String> {
+    if Uuid::try_parse(&transfer_id).is_err() {
+        tracing::debug!("Ignored a chunked transfer from {peer} with an invalid ID");
+        return Some("its transfer ID is invalid".to_string());
+    }
+    // Transfers we'd refuse once they're here are refused before anything of them is stored
+    let rejection = match &completion {
+        _ if size > MAX_TRANSFER_SIZE as u64 => Some(format!("files over {} MiB are not accepted", MAX_TRANSFER_SIZE / 1024 / 1024)),
+        Completion::Offer { filename, .. } => approval::offer_rejection(state, filename, size),
+        Completion::Response { .. } | Completion::Trade { .. } => None,
+    };
+    if let Some(reason) = rejection {
+        state.chunked.abandon(&(peer, transfer_id));
+        match completion {
+            Completion::Offer { filename, .. } => system!("Turned down {} from {}: {reason}.", filename.escape_debug(), state.peer_name(&peer)),
+            _ => error!("Refused a file from {}: {reason}.", state.peer_name(&peer)),
+        }
+        return Some(reason);
+    }
+    state.chunked.entry(peer, transfer_id.clone(), &state.download_dir).expected = Some((size, completion));
+    if let Some(incoming) = state.chunked.take_if_complete((peer, transfer_id)) {
+        finish(swarm, state, peer, incoming).await;
+    }
+    None
+}
+
+async fn finish(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, incoming: Incoming) {
+    let Some((_, completion)) = incoming.expected else { return };
+    if let Some(mut file) = incoming.file
+        && let Err(e) = file.flush().await {
+        error!("Failed to write a file from {}: {e}", state.peer_name(&peer));
+        let _ = fs::remove_file(&incoming.part).await;
+        return;
+    }
+    // Requested files go straight from the part file into the downloads. Offers and trades stay in it, digested a
+    // block at a time, until they're answered
+    if let Completion::Response { filename } = &completion {
+        return save_received_part(swarm, state, peer, filename, &incoming.part).await;
+    }
+    let part = PartFile(incoming.part);
+    let digest = match audit::digest_file(part.path()).await {
+        Ok(digest) => digest,
+        Err(e) => {
+            error!("Failed to read back a file from {}: {e}", state.peer_name(&peer));
+            return;
+        }
+    };
+    let file = Received::Part { part, size: incoming.written_size, digest };
+    match completion {
+        Completion::Offer { filename, transfer_id } => {
+            // No request is held open for the answer, so the wait starts now the whole file is here
+            let reply = OfferReply::Request { transfer_id };
+            queue_approval(swarm, state, PendingApproval::FileOffer { peer, filename, file, reply }, Instant::now()).await;
+        }
+        Completion::Trade { trade_id } => {
+            trade::file_arrived(swarm, state, peer, &trade_id, file).await;
+        }
+        Completion::Response { .. } => unreachable!("Requested files are saved from the part file"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CEILING: usize = 1024 * 1024;
+
+    #[test]
+    fn pacing_starts_small_and_doubles_up_to_the_ceiling() {
+        let mut pacing = Pacing::new(CEILING, None);
+        assert_eq!(pacing.size, FIRST_CHUNK);
+        let mut sizes = Vec::new();
+        for _ in 0..6 {
+            pacing.acknowledged(pacing.size, Duration::from_millis(10));
+            sizes.push(pacing.size);
+        }
+        assert_eq!(sizes, [128 * 1024, 256 * 1024, 512 * 1024, CEILING, CEILING, CEILING]);
+        assert_eq!(Pacing::new(16 * 1024, None).size, 16 * 1024);
+    }
+
+    #[test]
+    fn pacing_halves_on_a_slow_chunk_then_grows_a_step_at_a_time() {
+        let mut pacing = Pacing::new(CEILING, None);
+        for _ in 0..3 {
+            pacing.acknowledged(pacing.size, Duration::from_millis(10));
+        }
+        assert_eq!(pacing.size, 512 * 1024);
+        // A tenth of the rate so far
+        pacing.acknowledged(pacing.size, Duration::from_millis(400));
+        assert_eq!(pacing.size, 256 * 1024);
+        pacing.acknowledged(pacing.size, Duration::from_millis(10));
+        assert_eq!(pacing.size, 256 * 1024 + STEP);
+        pacing.acknowledged(pacing.size, Duration::from_millis(10));
+        assert_eq!(pacing.size, 256 * 1024 + 2 * STEP);
+    }
+
+    #[test]
+    fn pacing_never_backs_off_below_the_first_chunk() {
+        let mut pacing = Pacing::new(CEILING, None);
+        for _ in 0..5 {
+            pacing.back_off();
+        }
+        assert_eq!(pacing.size, FIRST_CHUNK);
+        pacing.acknowledged(pacing.size, Duration::from_millis(10));
+        assert_eq!(pacing.size, FIRST_CHUNK + STEP);
+    }
+
+    #[test]
+    fn pacing_spaces_chunks_out_to_the_rate_cap() {
+        let mut pacing = Pacing::new(CEILING, Some(1024 * 1024));
+        assert!(pacing.ready());
+        let before = Instant::now();
+        pacing.sent(512 * 1024);
+        assert!(!pacing.ready());
+        pacing.sent(512 * 1024);
+        let wait = pacing.next_send - before;
+        assert!(wait >= Duration::from_secs(1) && wait < Duration::from_millis(1100), "{wait:?}");
+        // Without a cap every chunk can go straight away
+        let mut pacing = Pacing::new(CEILING, None);
+        pacing.sent(CEILING);
+        assert!(pacing.ready());
+        assert_eq!(Pacing::new(CEILING, Some(1024 * 1024)).ceiling, 256 * 1024);
+        assert_eq!(Pacing::new(CEILING, Some(1024)).ceiling, FIRST_CHUNK);
+    }
+}
@@ -0,0 +1,153 @@
+use std::{ cell::RefCell, fmt, io };
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt };
+use libp2p::{ request_response, StreamProtocol };
+use serde::{ de::{ DeserializeOwned, SeqAccess, Visitor }, Deserializer, Serialize };
+
+use crate::behaviour::{ RequestType, ResponseType };
+
+// The same cbor encoding as libp2p's request_response::cbor codec, so older peers still understand us, but with
+// one size limit we choose for requests and responses, and a clear error instead of a truncated read when it's exceeded
+#[derive(Clone)]
+pub struct FileExchangeCodec {
+    pub max_message_size: u64,
+}
+
+thread_local! {
+    // The message being decoded, so payloads can be handed out as slices of it, see payload
+    static DECODING: RefCell<Option<Bytes>> = const { RefCell::new(None) };
+}
+
+// Deserializes chunk and benchmark data without copying it out of the message it arrived in. Older peers sent it
+// as an array of numbers rather than a byte string, which is still read
+pub fn payload<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    deserializer.deserialize_any(PayloadVisitor)
+}
+
+struct PayloadVisitor;
+
+impl<'de> Visitor<'de> for PayloadVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bytes")
+    }
+
+    fn visit_borrowed_bytes<E>(self, data: &'de [u8]) -> Result<Bytes, E> {
+        let slice = DECODING.with_borrow(|message| {
+            let message = message.as_ref()?;
+            let within = message.as_ptr_range();
+            (within.start <= data.as_ptr() && data.as_ptr_range().end <= within.end).then(|| message.slice_ref(data))
+        });
+        Ok(slice.unwrap_or_else(|| Bytes::copy_from_slice(data)))
+    }
+
+    fn visit_bytes<E>(self, data: &[u8]) -> Result<Bytes, E> {
+        Ok(Bytes::copy_from_slice(data))
+    }
+
+    fn visit_byte_buf<E>(self, data: Vec<u8>) -> Result<Bytes, E> {
+        Ok(Bytes::from(data))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+        let mut data = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            data.push(byte);
+        }
+        Ok(Bytes::from(data))
+    }
+}
+
+async fn read_limited<T, M>(io: &mut T, max_message_size: u64) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: DeserializeOwned,
+{
+    let mut buffer = Vec::new();
+    io.take(max_message_size + 1).read_to_end(&mut buffer).await?;
+    if buffer.len() as u64 > max_message_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message larger than the {max_message_size} byte limit")));
+    }
+    let buffer = Bytes::from(buffer);
+    DECODING.set(Some(buffer.clone()));
+    let message = cbor4ii::serde::from_slice(&buffer);
+    DECODING.set(None);
+    message.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+async fn write<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let buffer = cbor4ii::serde::to_vec(Vec::new(), message).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    io.write_all(&buffer).await
+}
+
+// Write a message whose last field is `payload`, encoded with that field left empty. The empty byte string it ends
+// with is swapped for the payload's length, and the payload written straight from its buffer, instead of being
+// copied into the encoding with the rest
+async fn write_with_payload<T, M>(io: &mut T, message: &M, payload: &Bytes) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    const EMPTY_BYTES: u8 = 0x40;
+    let mut buffer = cbor4ii::serde::to_vec(Vec::new(), message).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    if buffer.pop() != Some(EMPTY_BYTES) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "payload is not the last field of the message"));
+    }
+    // A cbor byte string header: the length in the low bits, or in the 1, 2, 4 or 8 bytes after
+    let length = payload.len() as u64;
+    match length {
+        0..24 => buffer.push(EMPTY_BYTES | length as u8),
+        24..0x100 => buffer.extend([EMPTY_BYTES | 24, length as u8]),
+        0x100..0x10000 => buffer.extend([&[EMPTY_BYTES | 25][..], &(length as u16).to_be_bytes()].concat()),
+        0x10000..0x1_0000_0000 => buffer.extend([&[EMPTY_BYTES | 26][..], &(length as u32).to_be_bytes()].concat()),
+        _ => buffer.extend([&[EMPTY_BYTES | 27][..], &length.to_be_bytes()].concat()),
+    }
+    io.write_all(&buffer).await?;
+    io.write_all(payload).await
+}
+
+#[async_trait]
+impl request_response::Codec for FileExchangeCodec {
+    type Protocol = StreamProtocol;
+    type Request = RequestType;
+    type Response = ResponseType;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<RequestType>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_limited(io, self.max_message_size).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<ResponseType>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_limited(io, self.max_message_size).await
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, request: RequestType) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let (request, payload) = match request {
+            RequestType::Chunk { transfer_id, index, data } => (RequestType::Chunk { transfer_id, index, data: Bytes::new() }, data),
+            RequestType::BenchData { bench_id, data } => (RequestType::BenchData { bench_id, data: Bytes::new() }, data),
+            request => return write(io, &request).await,
+        };
+        write_with_payload(io, &request, &payload).await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, response: ResponseType) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write(io, &response).await
+    }
+}
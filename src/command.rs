@@ -0,0 +1,697 @@
+use std::fmt;
+
+// Where a command makes sense
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Anywhere,
+    Public,  // Only in the public room
+    Private, // Only in a private room
+}
+
+// A command the user can type, and everything /help says about it
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub scope: Scope,
+    pub usage: &'static [(&'static str, &'static str)], // Each way to use it, with what that does
+    pub arguments: &'static [(&'static str, &'static str)],
+    pub examples: &'static [&'static str],
+}
+
+// Every command, in the order /help lists them
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "/help",
+        aliases: &["/h", "/?"],
+        scope: Scope::Anywhere,
+        usage: &[("/help", "display a list of available commands"), ("/help <command>", "explain one command in detail")],
+        arguments: &[("<command>", "a command's name or short form, with or without the /")],
+        examples: &["/help offer", "/help /c"],
+    },
+    Command {
+        name: "/exit",
+        aliases: &["/quit", "/q"],
+        scope: Scope::Anywhere,
+        usage: &[("/exit", "leave SwapBytes, telling the rooms you're in. If files are still being sent or received, you're asked first")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/connect",
+        aliases: &["/c"],
+        scope: Scope::Anywhere,
+        usage: &[("/connect <peer nickname or ID>... [-p <passphrase>]", "invite peers to a private room to request and offer files, or into the private room you're in")],
+        arguments: &[
+            ("<peer nickname or ID>...", "the peers to invite, all to the same room. It starts with whoever accepts first. A peer ID skips looking the nickname up, and a peer you're connected to is invited even if the DHT doesn't know them"),
+            ("-p <passphrase>", "when starting a room, a passphrase the peers must enter to join. Only peers who know it can read or post in the room"),
+        ],
+        examples: &["/connect bob", "/connect alice bob carol", "/c \"kush desai\" -p hunter2"],
+    },
+    Command {
+        name: "/list",
+        aliases: &["/ls"],
+        scope: Scope::Anywhere,
+        usage: &[("/list", "list connected peers, best rated first")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/who",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/who", "list who's in the room you're typing in, with their ratings")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/peers",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/peers", "show the peers found through the rendezvous server and whether they're connected")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/stats",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/stats", "show how this node is connected to the network")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/top",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/top", "show the best rated peers you know of")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/nick",
+        aliases: &[],
+        scope: Scope::Public,
+        usage: &[("/nick <new nickname>", "change your nickname")],
+        arguments: &[("<new nickname>", "the name other peers will know you by, if nobody else has it")],
+        examples: &["/nick alice"],
+    },
+    Command {
+        name: "/request",
+        aliases: &["/r", "/req"],
+        scope: Scope::Private,
+        usage: &[("/request <file> [nickname]", "request a file another peer in the room shares")],
+        arguments: &[
+            ("<file>", "the name of a file directly in the other peer's share directory, not a path"),
+            ("[nickname]", "who to ask, if there's more than one other peer in the room"),
+        ],
+        examples: &["/request notes.txt", "/r \"holiday photos.zip\" bob"],
+    },
+    Command {
+        name: "/offer",
+        aliases: &["/o"],
+        scope: Scope::Private,
+        usage: &[("/offer <file or pattern>... [nickname]", "offer files to another peer in the room, who picks which ones to take")],
+        arguments: &[
+            ("<file or pattern>", "a file, or a pattern like *.pdf. Several files are offered as a list to pick from"),
+            ("[nickname]", "who to offer them to, if there's more than one other peer in the room"),
+        ],
+        examples: &["/offer notes.txt", "/o \"my notes.txt\" *.pdf bob"],
+    },
+    Command {
+        name: "/take",
+        aliases: &[],
+        scope: Scope::Private,
+        usage: &[("/take <id> <numbers|all|none>", "choose files from a multi-file offer")],
+        arguments: &[
+            ("<id>", "the offer's number"),
+            ("<numbers|all|none>", "the files to take, comma separated, or all or none of them"),
+        ],
+        examples: &["/take 2 1,3,4", "/take 2 all"],
+    },
+    Command {
+        name: "/trade",
+        aliases: &["/t"],
+        scope: Scope::Anywhere,
+        usage: &[
+            ("/trade [fair] <your file> <their file> [nickname]", "propose swapping one of your files for one of theirs"),
+            ("/trade <accept|decline|confirm|cancel> <id>", "answer or follow through on a trade"),
+            ("/trade counter <id> <your file> <their file>", "answer a trade with different terms"),
+            ("/trade rate <id> <-1|0|1>", "rate the peer you completed a trade with"),
+        ],
+        arguments: &[
+            ("[fair]", "swap sealed files first, so neither side gets the other's file without giving up their own"),
+            ("<your file>", "the file you give"),
+            ("<their file>", "the file you want in return"),
+            ("[nickname]", "who to trade with, if there's more than one other peer in the room"),
+            ("<id>", "the trade's number, from /trades"),
+        ],
+        examples: &["/trade song.mp3 album.zip", "/t fair song.mp3 album.zip bob", "/trade counter 3 song.mp3 single.mp3", "/trade rate 3 1"],
+    },
+    Command {
+        name: "/trades",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/trades", "list your trades")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/want",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/want <description>", "post what you're looking for to the board")],
+        arguments: &[("<description>", "the rest of the line, as typed")],
+        examples: &["/want lecture notes for COMP3000"],
+    },
+    Command {
+        name: "/have",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/have <file>", "post a file you can share to the board")],
+        arguments: &[("<file>", "a file on your machine")],
+        examples: &["/have notes.pdf"],
+    },
+    Command {
+        name: "/board",
+        aliases: &["/b"],
+        scope: Scope::Anywhere,
+        usage: &[
+            ("/board", "browse everyone's wants and haves"),
+            ("/board connect <number> [passphrase]", "invite a poster to a private room, or into the room you're in"),
+            ("/board remove <number>", "take down your post"),
+        ],
+        arguments: &[
+            ("<number>", "the post's number, from /board"),
+            ("[passphrase]", "when starting a room, a passphrase the poster must enter to join"),
+        ],
+        examples: &["/board connect 2", "/b remove 1"],
+    },
+    Command {
+        name: "/wish",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[
+            ("/wish <file name, hash or keyword>", "be told when someone has a file you're after"),
+            ("/wish remove <number>", "stop looking for a file"),
+        ],
+        arguments: &[
+            ("<file name, hash or keyword>", "the rest of the line, as typed"),
+            ("<number>", "the wish's number, from /wishlist"),
+        ],
+        examples: &["/wish notes.pdf", "/wish remove 1"],
+    },
+    Command {
+        name: "/wishlist",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/wishlist", "list your wishes and the files found for them")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/get",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/get <id>", "request a file found for your wishlist")],
+        arguments: &[("<id>", "the file's number, from /wishlist")],
+        examples: &["/get 4"],
+    },
+    Command {
+        name: "/history",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/history", "list the files you have exchanged")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/audit",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[
+            ("/audit [count]", "show the latest entries in your signed log of exchanges"),
+            ("/audit export <file>", "save your log for others"),
+            ("/audit verify <file>", "check a log someone saved"),
+        ],
+        arguments: &[("[count]", "how many entries to show"), ("<file>", "where the log is saved")],
+        examples: &["/audit 20", "/audit export log.json"],
+    },
+    Command {
+        name: "/rate",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/rate <nickname> <-1|0|1>", "rate a peer you have exchanged files with")],
+        arguments: &[("<nickname>", "the peer to rate"), ("<-1|0|1>", "bad, neutral or good")],
+        examples: &["/rate bob 1"],
+    },
+    Command {
+        name: "/whois",
+        aliases: &["/w"],
+        scope: Scope::Anywhere,
+        usage: &[("/whois <nickname>", "show a peer's profile")],
+        arguments: &[("<nickname>", "the peer to look up")],
+        examples: &["/whois bob"],
+    },
+    Command {
+        name: "/bench",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/bench <nickname> [size]", "measure throughput and round trips to a peer over each transport")],
+        arguments: &[("<nickname>", "the peer to measure"), ("[size]", "how much to send, in bytes or with a K, M or G suffix. 8M if left out")],
+        examples: &["/bench bob", "/bench bob 64M"],
+    },
+    Command {
+        name: "/ping",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/ping <nickname>", "show the latest round trip to a peer over each connection")],
+        arguments: &[("<nickname>", "a peer you're connected to")],
+        examples: &["/ping bob"],
+    },
+    Command {
+        name: "/profile",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/profile set <bio|tags|namespaces> [value]", "edit your profile")],
+        arguments: &[("[value]", "the new value, comma separated for tags and namespaces. Leaving it out clears the field")],
+        examples: &["/profile set bio \"Sharing lecture notes\"", "/profile set tags music, notes"],
+    },
+    Command {
+        name: "/vouch",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/vouch <nickname>", "vouch for a peer you trust")],
+        arguments: &[("<nickname>", "the peer to vouch for")],
+        examples: &["/vouch bob"],
+    },
+    Command {
+        name: "/id",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[
+            ("/id", "show your peer ID and the addresses others can reach you at"),
+            ("/id export <file>", "save your identity to an encrypted file"),
+        ],
+        arguments: &[("<file>", "where to save it")],
+        examples: &["/id export identity.key"],
+    },
+    Command {
+        name: "/decrypt",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/decrypt <file> [output]", "recover a file saved with --encrypt-downloads")],
+        arguments: &[("<file>", "the encrypted download"), ("[output]", "where to write it. The file's name without its extension if left out")],
+        examples: &["/decrypt notes.pdf.enc notes.pdf"],
+    },
+    Command {
+        name: "/autoaccept",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/autoaccept <min rating>|off", "accept offered files from well rated peers without asking")],
+        arguments: &[("<min rating>", "the lowest rating, from -1 to 1, a peer can have for their files to be accepted")],
+        examples: &["/autoaccept 0.5", "/autoaccept off"],
+    },
+    Command {
+        name: "/emoji",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/emoji <on|off>", "turn :shortcode: emoji on or off")],
+        arguments: &[],
+        examples: &["/emoji off"],
+    },
+    Command {
+        name: "/set",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/set <markdown|colors> <on|off>", "change how messages are displayed")],
+        arguments: &[],
+        examples: &["/set colors off"],
+    },
+    Command {
+        name: "/deny",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/deny <nickname|peer id>", "refuse all connections from a peer")],
+        arguments: &[],
+        examples: &["/deny mallory"],
+    },
+    Command {
+        name: "/allow",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/allow <nickname|peer id>", "add a peer to your allow list")],
+        arguments: &[],
+        examples: &["/allow bob"],
+    },
+    Command {
+        name: "/unlist",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/unlist <nickname|peer id>", "take a peer off your allow and deny lists")],
+        arguments: &[],
+        examples: &["/unlist bob"],
+    },
+    Command {
+        name: "/allowonly",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/allowonly <on|off>", "only let peers on your allow list connect")],
+        arguments: &[],
+        examples: &["/allowonly on"],
+    },
+    Command {
+        name: "/policy",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/policy", "show your allow and deny lists")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/unmute",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/unmute <nickname|peer id>", "let a peer muted for spamming back in")],
+        arguments: &[],
+        examples: &["/unmute bob"],
+    },
+    Command {
+        name: "/invites",
+        aliases: &[],
+        scope: Scope::Public,
+        usage: &[("/invites", "list private room requests waiting on an answer")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/accept",
+        aliases: &["/a"],
+        scope: Scope::Public,
+        usage: &[("/accept <id> [passphrase]", "accept a private room request")],
+        arguments: &[("<id>", "the request's number, from /invites"), ("[passphrase]", "the room's passphrase, if it has one")],
+        examples: &["/accept 1", "/a 2 hunter2"],
+    },
+    Command {
+        name: "/decline",
+        aliases: &["/d"],
+        scope: Scope::Public,
+        usage: &[("/decline <id>", "decline a private room request")],
+        arguments: &[("<id>", "the request's number, from /invites")],
+        examples: &["/decline 1"],
+    },
+    Command {
+        name: "/approvals",
+        aliases: &[],
+        scope: Scope::Anywhere,
+        usage: &[("/approvals", "list file requests and offers waiting on an answer")],
+        arguments: &[],
+        examples: &[],
+    },
+    Command {
+        name: "/yes",
+        aliases: &["/y"],
+        scope: Scope::Anywhere,
+        usage: &[("/yes <id>", "agree to a file request or offer")],
+        arguments: &[("<id>", "the request or offer's number, from /approvals")],
+        examples: &["/yes 1"],
+    },
+    Command {
+        name: "/no",
+        aliases: &["/n"],
+        scope: Scope::Anywhere,
+        usage: &[("/no <id>", "turn down a file request or offer")],
+        arguments: &[("<id>", "the request or offer's number, from /approvals")],
+        examples: &["/no 1"],
+    },
+    Command {
+        name: "/topic",
+        aliases: &[],
+        scope: Scope::Private,
+        usage: &[
+            ("/topic", "show what the room is for"),
+            ("/topic set <text>", "set the topic of a room you created"),
+            ("/topic clear", "remove the topic of a room you created"),
+        ],
+        arguments: &[("<text>", "the rest of the line, up to 200 characters")],
+        examples: &["/topic set Swapping lecture notes for COSC 301"],
+    },
+    Command {
+        name: "/kick",
+        aliases: &[],
+        scope: Scope::Private,
+        usage: &[("/kick <nickname>", "remove a member from a room you created")],
+        arguments: &[],
+        examples: &["/kick mallory"],
+    },
+    Command {
+        name: "/ban",
+        aliases: &[],
+        scope: Scope::Private,
+        usage: &[("/ban <nickname>", "remove a member from a room you created for good")],
+        arguments: &[],
+        examples: &["/ban mallory"],
+    },
+    Command {
+        name: "/switch",
+        aliases: &["/s"],
+        scope: Scope::Anywhere,
+        usage: &[
+            ("/switch", "list the rooms you're in"),
+            ("/switch <room>", "send what you type to another room you're in"),
+        ],
+        arguments: &[("<room>", "the start of a private room's ID as shown in front of its lines, a member's nickname, or public for the public room")],
+        examples: &["/switch public", "/s 683c047a", "/switch bob"],
+    },
+    Command {
+        name: "/leave",
+        aliases: &[],
+        scope: Scope::Private,
+        usage: &[("/leave", "leave the private room you're typing in")],
+        arguments: &[],
+        examples: &[],
+    },
+];
+
+// /help: the commands that make sense in the room you're in
+pub fn print_overview(public: bool) {
+    let mut text = String::from("Available commands:");
+    for command in COMMANDS.iter().filter(|command| command.scope != if public { Scope::Private } else { Scope::Public }) {
+        for (usage, summary) in command.usage {
+            text.push_str(&format!("\n  {usage} - {summary}"));
+        }
+    }
+    let aliases: Vec<_> = COMMANDS.iter().flat_map(|command| command.aliases.iter().map(move |alias| format!("{alias} for {}", command.name))).collect();
+    text.push_str(&format!("\nShort forms: {}", aliases.join(", ")));
+    text.push_str("\nPut arguments with spaces in quotes, like /offer \"my notes.txt\". Start a message with // to send it beginning with /");
+    text.push_str("\nType /help <command> for more about a command");
+    system!("{text}");
+}
+
+// /help <command>: how to use one command
+pub fn print_page(name: &str) {
+    let typed = if name.starts_with('/') { name.to_string() } else { format!("/{name}") };
+    let Some(command) = find(&typed) else {
+        match suggest(&typed) {
+            Some(suggestion) => error!("There's no {typed} command. Did you mean {suggestion}?"),
+            None => error!("There's no {typed} command. Type /help to see them all."),
+        }
+        return;
+    };
+    let mut text = command.name.to_string();
+    if !command.aliases.is_empty() {
+        text.push_str(&format!(" (also {})", command.aliases.join(", ")));
+    }
+    match command.scope {
+        Scope::Anywhere => {}
+        Scope::Public => text.push_str(", in the public room only"),
+        Scope::Private => text.push_str(", in a private room only"),
+    }
+    text.push_str("\nUsage:");
+    for (usage, summary) in command.usage {
+        text.push_str(&format!("\n  {usage} - {summary}"));
+    }
+    if !command.arguments.is_empty() {
+        text.push_str("\nArguments:");
+        for (argument, meaning) in command.arguments {
+            text.push_str(&format!("\n  {argument} - {meaning}"));
+        }
+    }
+    if !command.examples.is_empty() {
+        text.push_str("\nExamples:");
+        for example in command.examples {
+            text.push_str(&format!("\n  {example}"));
+        }
+    }
+    system!("{text}");
+}
+
+// What a line of input is
+pub enum Input<'a> {
+    // Text for the room. A line starting with // is sent with one slash
+    Chat(&'a str),
+    Command(Invocation),
+}
+
+// A command as typed: which one it is and its arguments, with quotes taken off
+pub struct Invocation {
+    pub name: &'static str,
+    pub args: Vec<String>,
+    // Everything after the command as it was typed, for commands that take free text
+    pub rest: String,
+    ends: Vec<usize>, // Where each argument ends in `rest`
+}
+
+impl Invocation {
+    // The text typed after the first `count` arguments
+    pub fn rest_after(&self, count: usize) -> &str {
+        match count.checked_sub(1).and_then(|last| self.ends.get(last)) {
+            Some(end) => self.rest[*end..].trim(),
+            None if count == 0 => &self.rest,
+            None => "",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Unknown { typed: String, suggestion: Option<&'static str> },
+    UnclosedQuote(char),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Unknown { typed, suggestion: Some(suggestion) } => write!(f, "Unknown command {typed}. Did you mean {suggestion}?"),
+            ParseError::Unknown { typed, suggestion: None } => write!(f, "Unknown command {typed}. Type /help to see them all, or start with // to send a message beginning with /."),
+            ParseError::UnclosedQuote(quote) => write!(f, "There's a {quote} without one to close it."),
+        }
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    let name = name.to_lowercase();
+    COMMANDS.iter().find(|command| command.name == name || command.aliases.contains(&name.as_str()))
+}
+
+pub fn parse(line: &str) -> Result<Input<'_>, ParseError> {
+    if line.starts_with("//") {
+        return Ok(Input::Chat(&line[1..]));
+    }
+    if !line.starts_with('/') {
+        return Ok(Input::Chat(line));
+    }
+    let (typed, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let Some(command) = find(typed) else {
+        return Err(ParseError::Unknown { typed: typed.to_string(), suggestion: suggest(typed) });
+    };
+    let rest = rest.trim();
+    let (args, ends) = split_arguments(rest)?;
+    Ok(Input::Command(Invocation { name: command.name, args, rest: rest.to_string(), ends }))
+}
+
+// Split on whitespace, keeping anything in double or single quotes together, so file names can have spaces. A
+// backslash keeps the quote, space or backslash after it as it is; any other backslash is left alone, for Windows paths
+fn split_arguments(text: &str) -> Result<(Vec<String>, Vec<usize>), ParseError> {
+    let (mut args, mut ends) = (Vec::new(), Vec::new());
+    let mut current: Option<String> = None;
+    let mut quote = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match (c, quote) {
+            ('\\', _) if chars.peek().is_some_and(|(_, next)| matches!(next, '"' | '\'' | '\\' | ' ')) => {
+                let (_, next) = chars.next().expect("Just peeked");
+                current.get_or_insert_default().push(next);
+            }
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                current.get_or_insert_default();
+            }
+            (c, Some(open)) if c == open => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if let Some(arg) = current.take() {
+                    args.push(arg);
+                    ends.push(index);
+                }
+            }
+            (c, _) => current.get_or_insert_default().push(c),
+        }
+    }
+    if let Some(open) = quote {
+        return Err(ParseError::UnclosedQuote(open));
+    }
+    if let Some(arg) = current {
+        args.push(arg);
+        ends.push(text.len());
+    }
+    Ok((args, ends))
+}
+
+// The command the user most likely meant: one they typed the start of, or the closest within a couple of typos
+fn suggest(typed: &str) -> Option<&'static str> {
+    let typed = typed.to_lowercase();
+    let names = || COMMANDS.iter().flat_map(|command| std::iter::once(command.name).chain(command.aliases.iter().copied()).map(move |name| (name, command.name)));
+    let mut started: Vec<_> = names().filter(|(name, _)| typed.len() > 2 && name.starts_with(&typed)).map(|(_, command)| command).collect();
+    started.dedup();
+    if let [command] = started[..] {
+        return Some(command);
+    }
+    let allowed = if typed.len() <= 4 { 1 } else { 2 };
+    names()
+        .map(|(name, command)| (edit_distance(&typed, name), command))
+        .filter(|(distance, _)| *distance <= allowed)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, command)| command)
+}
+
+// How many characters have to be added, removed, changed or swapped with the next to turn one word into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            row[j] = substitution.min(rows[i - 1][j] + 1).min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text: &str) -> Vec<String> {
+        split_arguments(text).expect("Quotes are closed").0
+    }
+
+    #[test]
+    fn splits_on_whitespace_outside_quotes() {
+        assert_eq!(args("/offer  a.txt\tb.txt "), ["/offer", "a.txt", "b.txt"]);
+        assert_eq!(args(r#"/offer "my file.txt" 'it''s' bob"#), ["/offer", "my file.txt", "its", "bob"]);
+        assert_eq!(args(r#"/offer "" bob"#), ["/offer", "", "bob"]);
+        assert_eq!(args(r#"/say "she said 'hi'""#), ["/say", "she said 'hi'"]);
+    }
+
+    #[test]
+    fn backslashes_escape_only_quotes_spaces_and_backslashes() {
+        assert_eq!(args(r#"/offer my\ file.txt \"quoted\" a\\b"#), ["/offer", "my file.txt", "\"quoted\"", r"a\b"]);
+        assert_eq!(args(r"/offer C:\Users\kush\notes.txt"), ["/offer", r"C:\Users\kush\notes.txt"]);
+    }
+
+    #[test]
+    fn records_where_each_argument_ends() {
+        let (_, ends) = split_arguments("/nick  kush ").unwrap();
+        assert_eq!(ends, [5, 11]);
+    }
+
+    #[test]
+    fn unclosed_quotes_are_an_error() {
+        assert!(matches!(split_arguments(r#"/offer "my file.txt"#), Err(ParseError::UnclosedQuote('"'))));
+        assert!(matches!(split_arguments("/offer 'a"), Err(ParseError::UnclosedQuote('\''))));
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{
+    command::{ self, COMMANDS },
+    line_editor::LineReader,
+    output::{ self, Kind },
+    util::ChatState,
+};
+
+// What the word at the end of the line is, so Tab knows what to complete it with
+enum Completing {
+    Command,
+    Nickname,
+    Path,
+    Nothing,
+}
+
+// Tab: finish the command, nickname or path being typed, as far as the choices agree, or list them if it can't
+// go any further
+pub async fn complete(stdin: &mut LineReader, state: &ChatState, own_nickname: &str) {
+    let (start, word, completing) = completing(stdin.typed());
+    let candidates: Vec<String> = match completing {
+        Completing::Command => {
+            let word = word.to_lowercase();
+            COMMANDS.iter().map(|command| command.name.to_string()).filter(|name| name.starts_with(&word)).collect()
+        }
+        Completing::Nickname => state.known_nicknames().into_iter().filter(|nickname| nickname != own_nickname && nickname.starts_with(&word)).collect(),
+        Completing::Path => paths(&word).await,
+        Completing::Nothing => return,
+    };
+    match candidates.as_slice() {
+        [] => {}
+        // Directories are left open, to carry on into
+        [only] if only.ends_with('/') => stdin.replace_from(start, &quote(only, true)),
+        [only] => stdin.replace_from(start, &format!("{} ", quote(only, true))),
+        _ => {
+            let common = common_prefix(&candidates);
+            if common.len() > word.len() {
+                stdin.replace_from(start, &quote(common, false));
+            } else {
+                // Paths are listed by name, without the directory typed so far
+                let shown = match completing {
+                    Completing::Path => word.rfind('/').map_or(0, |slash| slash + 1),
+                    _ => 0,
+                };
+                let names: Vec<_> = candidates.iter().map(|candidate| &candidate[shown..]).collect();
+                output::print_text(Kind::System, &names.join("  "));
+            }
+        }
+    }
+}
+
+// Where the last word starts, the word with any quotes taken off, and what it should be completed with
+fn completing(typed: &str) -> (usize, String, Completing) {
+    let (mut start, mut before, mut quote) = (0, 0, None);
+    for (index, c) in typed.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(open)) if c == open => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if start < index {
+                    before += 1;
+                }
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    let word: String = typed[start..].chars().filter(|c| !matches!(c, '"' | '\'')).collect();
+    if before == 0 {
+        let completing = if word.starts_with('/') { Completing::Command } else { Completing::Nothing };
+        return (start, word, completing);
+    }
+    let name = typed.split_whitespace().next().and_then(command::find).map(|command| command.name);
+    // Which argument of the command the word is, counting from 1
+    let completing = match (name, before) {
+        // /connect takes any number of nicknames, up to a passphrase after -p
+        (Some("/connect"), _) if !typed[..start].trim_end().ends_with(" -p") => Completing::Nickname,
+        (Some("/whois" | "/ping" | "/bench" | "/vouch" | "/rate" | "/kick" | "/ban" | "/deny" | "/allow" | "/unlist" | "/unmute"), 1) => Completing::Nickname,
+        (Some("/offer"), _) | (Some("/have" | "/decrypt"), 1) => Completing::Path,
+        _ => Completing::Nothing,
+    };
+    (start, word, completing)
+}
+
+// Files and directories starting with what's been typed, read from the directory typed so far. Hidden ones are
+// only offered once a dot has been typed
+async fn paths(word: &str) -> Vec<String> {
+    let (directory, prefix) = match word.rfind('/') {
+        Some(slash) => word.split_at(slash + 1),
+        None => ("", word),
+    };
+    let Ok(mut entries) = tokio::fs::read_dir(if directory.is_empty() { "." } else { directory }).await else {
+        return Vec::new();
+    };
+    let mut paths = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            continue;
+        }
+        // Following links, so a link to a directory is completed as one
+        let is_directory = tokio::fs::metadata(entry.path()).await.is_ok_and(|metadata| metadata.is_dir());
+        paths.push(format!("{directory}{name}{}", if is_directory { "/" } else { "" }));
+    }
+    paths.sort();
+    paths
+}
+
+fn common_prefix(candidates: &[String]) -> &str {
+    let first = &candidates[0];
+    let length = candidates[1..].iter().fold(first.len(), |length, candidate| {
+        first[..length].char_indices().zip(candidate.chars()).find(|((_, a), b)| a != b).map_or(length.min(candidate.len()), |((index, _), _)| index)
+    });
+    &first[..length]
+}
+
+// Put quotes around text with spaces or quotes in it, so it's read back as one argument. A prefix of several
+// choices is left open, for the rest to be typed inside the quotes
+fn quote(text: &str, close: bool) -> String {
+    if !text.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'')) {
+        return text.to_string();
+    }
+    let quote = if text.contains('"') { '\'' } else { '"' };
+    match close {
+        true => format!("{quote}{text}{quote}"),
+        false => format!("{quote}{text}"),
+    }
+}
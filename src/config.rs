@@ -0,0 +1,456 @@
+use std::{ env, error::Error, fs, iter::Peekable, net::SocketAddr, path::{ Path, PathBuf }, str::Chars };
+use clap::Args;
+use serde::{ Deserialize, Deserializer };
+use serde_json::{ Map, Number, Value };
+
+use crate::{ chunking, util::{ Cli, FilterOptions, GossipOptions, KademliaOptions } };
+
+// Settings read from the --config file, or ~/.config/swapbytes/config.toml. Anything also given on the command line
+// is overridden by it
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub nickname: Option<String>,
+    #[serde(deserialize_with = "string_or_number")]
+    pub port: Option<String>,
+    #[serde(deserialize_with = "string_or_list")]
+    pub server: Vec<String>,
+    pub server_peer: Option<String>,
+    pub namespace: Option<String>,
+    pub registration_ttl: Option<u64>,
+    pub ping_interval: Option<u64>,
+    pub download_dir: Option<PathBuf>,
+    pub share_dir: Option<PathBuf>,
+    pub encrypt_downloads: Option<bool>,
+    pub scan_command: Option<String>,
+    pub quarantine_dir: Option<PathBuf>,
+    pub auto_accept: Option<f64>,
+    pub accept_extensions: Vec<String>,
+    pub max_offer_size: Option<u64>,
+    pub max_message_size: Option<u64>,
+    pub max_upload_rate: Option<u64>,
+    pub rate_limit: Option<u32>,
+    pub auto_mute: Option<bool>,
+    pub trade_timeout: Option<u64>,
+    pub identity: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub records: Option<PathBuf>,
+    pub peers: Option<PathBuf>,
+    pub policy: Option<PathBuf>,
+    pub no_color: Option<bool>,
+    pub json: Option<bool>,
+    pub tui: Option<bool>,
+    pub rpc: Option<SocketAddr>,
+    pub rpc_token: Option<String>,
+    pub notify: Option<String>,
+    pub log_level: Option<String>,
+    pub log_file: Option<PathBuf>,
+    #[serde(flatten)]
+    pub gossip: GossipOptions,
+    #[serde(flatten)]
+    pub kademlia: KademliaOptions,
+    #[serde(flatten)]
+    pub filter: FilterOptions,
+}
+
+// Ports are strings on the command line but read more naturally as numbers in a config file, so take either
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::String(string)) => Some(string),
+        Some(Value::Number(number)) => Some(number.to_string()),
+        None | Some(Value::Null) => None,
+        Some(other) => return Err(serde::de::Error::custom(format!("expected a string or number, found {other}"))),
+    })
+}
+
+// A single rendezvous server is most naturally a string, fallbacks an array, so take either
+fn string_or_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::String(string)) => vec![string],
+        Some(Value::Array(values)) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::String(string) => Ok(string),
+                other => Err(serde::de::Error::custom(format!("expected a string, found {other}"))),
+            })
+            .collect::<Result<_, _>>()?,
+        None | Some(Value::Null) => Vec::new(),
+        Some(other) => return Err(serde::de::Error::custom(format!("expected a string or array, found {other}"))),
+    })
+}
+
+// $XDG_CONFIG_HOME/swapbytes/config.toml, falling back to ~/.config
+pub fn default_path() -> PathBuf {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(".config"));
+    config_home.join("swapbytes").join("config.toml")
+}
+
+// Where lines typed at the terminal are kept without a profile: ~/.swapbytes/input-history
+pub fn default_input_history() -> PathBuf {
+    let root = env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(".swapbytes");
+    if let Err(e) = fs::create_dir_all(&root) {
+        tracing::debug!("Could not create {}: {e}", root.display());
+    }
+    root.join("input-history")
+}
+
+// Where files are shared from without a profile or --share-dir: ~/.swapbytes/share, so nothing is shared until
+// it's put there
+pub fn default_share_dir() -> PathBuf {
+    let share_dir = env::var_os("HOME").map(PathBuf::from).unwrap_or_default().join(".swapbytes").join("share");
+    if let Err(e) = fs::create_dir_all(&share_dir) {
+        tracing::debug!("Could not create {}: {e}", share_dir.display());
+    }
+    share_dir
+}
+
+// Config's own keys. serde can't deny unknown fields alongside the flattened option groups, so they're checked by hand
+const KEYS: &[&str] = &[
+    "nickname", "port", "server", "server_peer", "namespace", "registration_ttl", "ping_interval", "download_dir",
+    "share_dir", "encrypt_downloads", "scan_command", "quarantine_dir", "auto_accept", "accept_extensions",
+    "max_offer_size", "max_message_size", "max_upload_rate", "rate_limit", "auto_mute", "trade_timeout", "identity",
+    "profile", "records", "peers", "policy", "no_color", "json", "tui", "rpc", "rpc_token", "notify", "log_level",
+    "log_file",
+];
+
+// A misspelt key would otherwise be silently ignored, leaving the setting at its default
+fn check_keys(value: &Value) -> Result<(), String> {
+    let Value::Object(map) = value else {
+        return Err("expected a table of settings".to_string());
+    };
+    let command = [GossipOptions::augment_args, KademliaOptions::augment_args, FilterOptions::augment_args]
+        .into_iter()
+        .fold(clap::Command::new("swapbytes"), |command, augment| augment(command));
+    let known = |key: &str| KEYS.contains(&key) || command.get_arguments().any(|arg| arg.get_id() == key);
+    match map.keys().find(|key| !known(key)) {
+        Some(key) => Err(format!("unknown key `{key}`")),
+        None => Ok(()),
+    }
+}
+
+// Files ending in .json are read as JSON, everything else as TOML
+fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Could not read config file {}: {e}", path.display()))?;
+    let value = if path.extension().is_some_and(|extension| extension == "json") {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        parse_toml(&contents)
+    };
+    let config = value.and_then(|value| {
+        check_keys(&value)?;
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    });
+    Ok(config.map_err(|e| format!("Invalid config file {}: {e}", path.display()))?)
+}
+
+// Fill in whatever the command line left out from the config file: the --config one, or the default one if it exists
+pub fn apply(cli: &mut Cli) -> Result<(), Box<dyn Error>> {
+    let path = match &cli.config {
+        Some(path) => path.clone(),
+        None => match default_path() {
+            path if path.is_file() => path,
+            _ => return Ok(()),
+        },
+    };
+    let config = load(&path)?;
+    cli.nickname = cli.nickname.take().or(config.nickname);
+    cli.port = cli.port.take().or(config.port);
+    if cli.server.is_empty() {
+        cli.server = config.server;
+    }
+    cli.server_peer = cli.server_peer.take().or(config.server_peer);
+    cli.namespace = cli.namespace.take().or(config.namespace);
+    cli.registration_ttl = cli.registration_ttl.take().or(config.registration_ttl);
+    cli.ping_interval = cli.ping_interval.take().or(config.ping_interval);
+    cli.download_dir = cli.download_dir.take().or(config.download_dir);
+    cli.share_dir = cli.share_dir.take().or(config.share_dir);
+    cli.auto_accept = cli.auto_accept.take().or(config.auto_accept);
+    if cli.accept_extensions.is_empty() {
+        cli.accept_extensions = config.accept_extensions;
+    }
+    cli.max_offer_size = cli.max_offer_size.take().or(config.max_offer_size);
+    cli.max_message_size = cli.max_message_size.take().or(config.max_message_size);
+    cli.max_upload_rate = cli.max_upload_rate.take().or(config.max_upload_rate);
+    cli.rate_limit = cli.rate_limit.take().or(config.rate_limit);
+    cli.auto_mute = cli.auto_mute.or(config.auto_mute);
+    cli.trade_timeout = cli.trade_timeout.take().or(config.trade_timeout);
+    cli.encrypt_downloads = cli.encrypt_downloads.or(config.encrypt_downloads);
+    cli.scan_command = cli.scan_command.take().or(config.scan_command);
+    cli.quarantine_dir = cli.quarantine_dir.take().or(config.quarantine_dir);
+    cli.identity = cli.identity.take().or(config.identity);
+    cli.profile = cli.profile.take().or(config.profile);
+    cli.records = cli.records.take().or(config.records);
+    cli.peers = cli.peers.take().or(config.peers);
+    cli.policy = cli.policy.take().or(config.policy);
+    cli.no_color = cli.no_color.or(config.no_color);
+    cli.json = cli.json.or(config.json);
+    cli.tui = cli.tui.or(config.tui);
+    cli.rpc = cli.rpc.take().or(config.rpc);
+    cli.rpc_token = cli.rpc_token.take().or(config.rpc_token);
+    cli.notify = cli.notify.take().or(config.notify);
+    if cli.log_level.is_none() && let Some(level) = config.log_level {
+        cli.log_level = Some(level.parse().map_err(|e| format!("Invalid log_level in {}: {e}", path.display()))?);
+    }
+    cli.log_file = cli.log_file.take().or(config.log_file);
+    cli.gossip = std::mem::take(&mut cli.gossip).or(config.gossip);
+    cli.kademlia = std::mem::take(&mut cli.kademlia).or(config.kademlia);
+    cli.filter = std::mem::take(&mut cli.filter).or(config.filter);
+    if let Some(threshold) = cli.auto_accept && !(-1.0..=1.0).contains(&threshold) {
+        return Err("The auto_accept rating must be a number between -1 and 1".into());
+    }
+    if let Some(size) = cli.max_message_size && size < chunking::MIN_MAX_MESSAGE_SIZE {
+        return Err(format!("The max_message_size must be at least {} bytes", chunking::MIN_MAX_MESSAGE_SIZE).into());
+    }
+    if cli.max_upload_rate == Some(0) {
+        return Err("The max_upload_rate must be more than 0 bytes per second".into());
+    }
+    Ok(())
+}
+
+// Parse the subset of TOML a flat config file needs: `key = value` lines with strings, numbers, booleans and
+// single-line arrays of them, plus comments. The result is a JSON object so it deserializes like a JSON config
+fn parse_toml(contents: &str) -> Result<Value, String> {
+    let mut table = Map::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let at_line = |e: String| format!("line {}: {e}", number + 1);
+        if line.starts_with('[') {
+            return Err(at_line("tables aren't supported, put every setting at the top level".to_string()));
+        }
+        let (key, rest) = line.split_once('=').ok_or_else(|| at_line("expected `key = value`".to_string()))?;
+        let key = key.trim().trim_matches('"');
+        if key.is_empty() {
+            return Err(at_line("missing key".to_string()));
+        }
+        let mut chars = rest.trim_start().chars().peekable();
+        let value = parse_toml_value(&mut chars).map_err(at_line)?;
+        let trailing: String = chars.collect();
+        let trailing = trailing.trim_start();
+        if !trailing.is_empty() && !trailing.starts_with('#') {
+            return Err(at_line(format!("unexpected `{trailing}` after the value")));
+        }
+        if table.insert(key.to_string(), value).is_some() {
+            return Err(at_line(format!("`{key}` is set twice")));
+        }
+    }
+    Ok(Value::Object(table))
+}
+
+fn parse_toml_value(chars: &mut Peekable<Chars>) -> Result<Value, String> {
+    match chars.peek() {
+        Some('"') => {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => return Ok(Value::String(string)),
+                    Some('\\') => string.push(match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        other => return Err(format!("unsupported escape `\\{}`", other.unwrap_or(' '))),
+                    }),
+                    Some(c) => string.push(c),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+        }
+        // Literal strings take everything up to the closing quote as it is, handy for Windows paths
+        Some('\'') => {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    Some('\'') => return Ok(Value::String(string)),
+                    Some(c) => string.push(c),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            let mut items = Vec::new();
+            loop {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                if chars.next_if_eq(&']').is_some() {
+                    return Ok(Value::Array(items));
+                }
+                items.push(parse_toml_value(chars)?);
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(']') => return Ok(Value::Array(items)),
+                    _ => return Err("expected `,` or `]` in array".to_string()),
+                }
+            }
+        }
+        _ => {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != ',' && *c != ']' && *c != '#') {
+                word.push(c);
+            }
+            match word.as_str() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                "" => Err("missing value".to_string()),
+                _ => {
+                    let number = word.replace('_', "");
+                    if let Ok(integer) = number.parse::<i64>() {
+                        Ok(Value::from(integer))
+                    } else if let Some(float) = number.parse::<f64>().ok().and_then(Number::from_f64) {
+                        Ok(Value::Number(float))
+                    } else {
+                        Err(format!("`{word}` is not a string, number or boolean; quote strings"))
+                    }
+                }
+            }
+        }
+    }
+}
+
+// A --profile's state directory: its identity (with nickname), transfer history, audit log, trade ledger, wishlist, private rooms, DHT records, known peers, connection policy, JSON-RPC token, input history, downloads and shared files,
+// so one machine can run several personas side by side
+pub struct ProfileDir {
+    pub root: PathBuf,
+}
+
+impl ProfileDir {
+    // ~/.swapbytes/profiles/<name>, created if it doesn't exist yet
+    pub fn open(name: &str) -> Result<Self, Box<dyn Error>> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err("Profile names can only contain letters, numbers, '-' and '_'".into());
+        }
+        let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+        let root = home.join(".swapbytes").join("profiles").join(name);
+        fs::create_dir_all(root.join("downloads"))?;
+        fs::create_dir_all(root.join("share"))?;
+        Ok(ProfileDir { root })
+    }
+
+    pub fn identity(&self) -> PathBuf {
+        self.root.join("identity")
+    }
+
+    pub fn history(&self) -> PathBuf {
+        self.root.join("history.json")
+    }
+
+    pub fn audit(&self) -> PathBuf {
+        self.root.join("audit.jsonl")
+    }
+
+    pub fn ledger(&self) -> PathBuf {
+        self.root.join("ledger.json")
+    }
+
+    pub fn wishlist(&self) -> PathBuf {
+        self.root.join("wishlist.json")
+    }
+
+    pub fn rooms(&self) -> PathBuf {
+        self.root.join("rooms.json")
+    }
+
+    pub fn input_history(&self) -> PathBuf {
+        self.root.join("input-history")
+    }
+
+    pub fn rpc_token(&self) -> PathBuf {
+        self.root.join("rpc-token")
+    }
+
+    pub fn records(&self) -> PathBuf {
+        self.root.join("records.json")
+    }
+
+    pub fn peers(&self) -> PathBuf {
+        self.root.join("peers.json")
+    }
+
+    pub fn policy(&self) -> PathBuf {
+        self.root.join("policy.json")
+    }
+
+    pub fn downloads(&self) -> PathBuf {
+        self.root.join("downloads")
+    }
+
+    pub fn share(&self) -> PathBuf {
+        self.root.join("share")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_flat_toml() {
+        let contents = r#"
+            # A comment
+            nickname = "kush" # and another
+            port = 9_999
+            auto_accept = 0.5
+            json = false
+            share_dir = 'C:\Users\kush\share'
+            accept_extensions = ["pdf", "txt", ]
+            "quoted" = "tab\there"
+        "#;
+        assert_eq!(parse_toml(contents).unwrap(), json!({
+            "nickname": "kush",
+            "port": 9999,
+            "auto_accept": 0.5,
+            "json": false,
+            "share_dir": r"C:\Users\kush\share",
+            "accept_extensions": ["pdf", "txt"],
+            "quoted": "tab\there",
+        }));
+    }
+
+    #[test]
+    fn rejects_what_it_doesnt_understand() {
+        let errors = [
+            ("[gossip]", "line 1: tables aren't supported"),
+            ("nickname", "line 1: expected `key = value`"),
+            ("port = 1\nport = 2", "line 2: `port` is set twice"),
+            ("nickname = kush", "`kush` is not a string"),
+            ("nickname = \"kush", "unterminated string"),
+            ("share_dir = 'C:\\share", "unterminated string"),
+            ("nickname = \"a\\qb\"", "unsupported escape"),
+            ("port = 1 2", "unexpected `2` after the value"),
+            ("tags = [\"a\" \"b\"]", "expected `,` or `]` in array"),
+            ("port =", "missing value"),
+        ];
+        for (contents, error) in errors {
+            let parsed = parse_toml(contents);
+            assert!(parsed.as_ref().is_err_and(|e| e.contains(error)), "{contents:?} gave {parsed:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(check_keys(&json!({ "nickname": "kush", "gossip_heartbeat_ms": 500, "mask_profanity": true })).is_ok());
+        assert_eq!(check_keys(&json!({ "nickname": "kush", "jsno": true })), Err("unknown key `jsno`".to_string()));
+    }
+
+    #[test]
+    fn the_command_line_wins_over_the_config_file_either_way() {
+        let path = env::temp_dir().join(format!("swapbytes-config-{}.toml", std::process::id()));
+        fs::write(&path, "json = true\nauto_mute = false\nmask_profanity = true\n").unwrap();
+        let config = path.to_str().unwrap();
+        let mut cli = <Cli as clap::Parser>::try_parse_from(["swapbytes", "--config", config, "--json=false", "--auto-mute"]).unwrap();
+        apply(&mut cli).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(cli.json, Some(false));
+        assert_eq!(cli.auto_mute, Some(true));
+        assert_eq!(cli.filter.mask_profanity, Some(true));
+        assert_eq!(cli.tui, None);
+    }
+}
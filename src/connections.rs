@@ -0,0 +1,119 @@
+use std::{ collections::HashMap, fmt, time::{ Duration, Instant } };
+use libp2p::{ multiaddr::Protocol, ping, swarm::{ ConnectionId, SwarmEvent }, Multiaddr, PeerId };
+
+use crate::behaviour::{ RendezvousBehaviourEvent, SwapBytesBehaviourEvent };
+
+// How a connection reaches the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Transport {
+    Tcp,
+    Quic,
+    // Through a circuit relay, over whichever transport reaches the relay
+    Relay,
+}
+
+impl Transport {
+    pub fn of(address: &Multiaddr) -> Self {
+        if address.iter().any(|protocol| matches!(protocol, Protocol::P2pCircuit)) {
+            Transport::Relay
+        } else if address.iter().any(|protocol| matches!(protocol, Protocol::QuicV1 | Protocol::Quic)) {
+            Transport::Quic
+        } else {
+            Transport::Tcp
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Tcp => "tcp",
+            Transport::Quic => "quic",
+            Transport::Relay => "relay",
+        })
+    }
+}
+
+// How long a connection has been open, to the largest two units
+pub fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        secs @ ..60 => format!("{secs}s"),
+        secs @ ..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        secs @ ..86400 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        secs => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+// One open connection to a peer
+pub struct Connection {
+    pub peer: PeerId,
+    pub transport: Transport,
+    pub since: Instant,
+    pub rtt: Option<Duration>, // From the last ping answered over it
+    pub pinged: Option<Instant>, // When that was
+}
+
+// Every open connection, kept up to date from swarm events. The swarm only counts connections per peer, and a peer
+// can be connected over TCP and QUIC at once
+#[derive(Default)]
+pub struct Connections {
+    connections: HashMap<ConnectionId, Connection>,
+}
+
+impl Connections {
+    pub fn observe(&mut self, event: &SwarmEvent<SwapBytesBehaviourEvent>) {
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                self.connections.insert(*connection_id, Connection {
+                    peer: *peer_id,
+                    transport: Transport::of(endpoint.get_remote_address()),
+                    since: Instant::now(),
+                    rtt: None,
+                    pinged: None,
+                });
+            }
+            SwarmEvent::ConnectionClosed { connection_id, .. } => {
+                self.connections.remove(connection_id);
+            }
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(RendezvousBehaviourEvent::Ping(ping::Event { connection, result: Ok(rtt), .. }))) => {
+                if let Some(open) = self.connections.get_mut(connection) {
+                    open.rtt = Some(*rtt);
+                    open.pinged = Some(Instant::now());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn get(&self, connection_id: &ConnectionId) -> Option<&Connection> {
+        self.connections.get(connection_id)
+    }
+
+    // /ping: the latest round trip over each connection to a peer. Pings go out on their own every --ping-interval,
+    // so these are as old as that at most
+    pub fn print_latency(&self, peer: &PeerId, name: &str) {
+        let connections = self.to(peer);
+        if connections.is_empty() {
+            system!("You aren't connected to {name}. Connect to them first, with /connect or by joining their room.");
+            return;
+        }
+        for connection in connections {
+            match (connection.rtt, connection.pinged) {
+                (Some(rtt), Some(pinged)) => system!(
+                    "{name}: {:.1} ms over {}, measured {} ago",
+                    rtt.as_secs_f64() * 1000.0,
+                    connection.transport,
+                    format_age(pinged.elapsed())
+                ),
+                _ => system!("{name}: no ping answered over {} yet, try again shortly", connection.transport),
+            }
+        }
+    }
+
+    // The connections to one peer, oldest first
+    pub fn to(&self, peer: &PeerId) -> Vec<&Connection> {
+        let mut connections: Vec<_> = self.connections.values().filter(|connection| connection.peer == *peer).collect();
+        connections.sort_by_key(|connection| connection.since);
+        connections
+    }
+}
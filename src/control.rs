@@ -0,0 +1,83 @@
+use std::{ error::Error, fs, path::Path, time::Duration };
+use tokio::{
+    io::{ AsyncBufReadExt, AsyncWriteExt, BufReader },
+    net::{ UnixListener, UnixStream },
+    sync::{ broadcast, mpsc },
+    time::timeout,
+};
+
+use crate::output::{ Output, RoomEvent };
+
+pub const DEFAULT_SOCKET: &str = "swapbytes.sock";
+
+// Open the daemon's control socket, clearing away one left behind by a daemon that didn't shut down cleanly
+pub fn listen(path: &Path) -> Result<UnixListener, Box<dyn Error>> {
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(format!("A daemon is already listening on {}", path.display()).into());
+        }
+        fs::remove_file(path)?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+// Accept `swapbytes ctl` connections. Each line a client sends is passed on to the event loop as if it had been
+// typed, and every event is sent back to every connected client as a line of text
+pub async fn serve(listener: UnixListener, commands: mpsc::UnboundedSender<String>, output: Output) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_client(stream, commands.clone(), output.subscribe()));
+            }
+            Err(e) => tracing::warn!("Failed to accept a control connection: {e}"),
+        }
+    }
+}
+
+async fn handle_client(stream: UnixStream, commands: mpsc::UnboundedSender<String>, mut events: broadcast::Receiver<RoomEvent>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader).lines();
+    loop {
+        tokio::select! {
+            command = reader.next_line() => match command {
+                Ok(Some(command)) => {
+                    if commands.send(command).is_err() {
+                        return;
+                    }
+                }
+                _ => return,
+            },
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if writer.write_all(format!("{event}\n").as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("A control client fell behind and missed {skipped} lines");
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+        }
+    }
+}
+
+// `swapbytes ctl <command>`: run a command on the daemon and print what it says back, until it has been quiet
+// for `wait`. Without a command, print everything the daemon says until interrupted
+pub async fn send(path: &Path, command: &str, wait: Duration) -> Result<(), Box<dyn Error>> {
+    let stream = UnixStream::connect(path).await
+        .map_err(|e| format!("Could not reach a daemon on {}: {e}", path.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader).lines();
+    if command.is_empty() {
+        while let Some(line) = reader.next_line().await? {
+            println!("{line}");
+        }
+        return Ok(());
+    }
+    writer.write_all(format!("{command}\n").as_bytes()).await?;
+    while let Ok(Ok(Some(line))) = timeout(wait, reader.next_line()).await {
+        println!("{line}");
+    }
+    Ok(())
+}
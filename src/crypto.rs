@@ -0,0 +1,179 @@
+use chacha20poly1305::{ aead::{ rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng }, ChaCha20Poly1305, Key, Nonce };
+use base64::prelude::*;
+use hkdf::Hkdf;
+use hmac::{ Hmac, Mac };
+use libp2p::{ identity::{ self, Keypair }, PeerId };
+use std::time::Duration;
+use sha2::{ Digest, Sha256 };
+use x25519_dalek::{ PublicKey, StaticSecret };
+
+const KEYSTORE_ITERATIONS: u32 = 200_000;
+
+// How long an invite token is good for once it's signed, allowing for clocks that are a few minutes apart
+pub const INVITE_TTL: Duration = Duration::from_secs(10 * 60);
+
+// Our half of the X25519 exchange for a private room invite
+pub fn generate_keypair() -> (StaticSecret, [u8; 32]) {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret).to_bytes();
+    (secret, public)
+}
+
+// Combine our secret with the other peer's public key into a symmetric key bound to the room.
+// A room passphrase is mixed in so that only peers who know it can read or write room messages
+pub fn derive_room_key(secret: &StaticSecret, their_public: [u8; 32], room_id: &str, passphrase: Option<&str>) -> Key {
+    let shared = secret.diffie_hellman(&PublicKey::from(their_public));
+    let salt = format!("{room_id}{}", passphrase.unwrap_or_default());
+    let hkdf = Hkdf::<Sha256>::new(Some(salt.as_bytes()), shared.as_bytes());
+    let mut key = Key::default();
+    hkdf.expand(b"swapbytes private room", &mut key).expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+// A fresh random key for a new private room, shared with members as they join
+pub fn generate_room_key() -> Key {
+    ChaCha20Poly1305::generate_key(&mut OsRng)
+}
+
+// A fresh random bearer token for the JSON-RPC server, see rpc
+pub fn generate_token() -> String {
+    let mut token = [0u8; 32];
+    OsRng.fill_bytes(&mut token);
+    BASE64_URL_SAFE_NO_PAD.encode(token)
+}
+
+// A fresh random key for sealing our side of a fair trade
+pub fn generate_file_key() -> Key {
+    ChaCha20Poly1305::generate_key(&mut OsRng)
+}
+
+// Encrypt a payload, returning the random nonce alongside the ciphertext
+pub fn seal(key: &Key, plaintext: &[u8]) -> ([u8; 12], Vec<u8>) {
+    let cipher = ChaCha20Poly1305::new(key);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, plaintext).expect("Encryption failed");
+    (nonce.into(), ciphertext)
+}
+
+// Decrypt a payload, returning None if it was tampered with or sealed under another key
+pub fn open(key: &Key, nonce: [u8; 12], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(key).decrypt(Nonce::from_slice(&nonce), ciphertext).ok()
+}
+
+// Proof sent back with an acceptance that the invitee knows the room passphrase
+pub fn passphrase_proof(room_id: &str, passphrase: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"swapbytes room passphrase");
+    hasher.update(room_id.as_bytes());
+    hasher.update(passphrase.as_bytes());
+    hasher.finalize().into()
+}
+
+// Sign an invite so it is bound to both the room and the invitee and can't be replayed to someone else
+pub fn sign_invite(keypair: &Keypair, room_id: &str, invitee: &PeerId, expires: u64) -> Vec<u8> {
+    keypair.sign(&invite_payload(room_id, invitee, Some(expires))).expect("Signing failed")
+}
+
+// Check an invite token was signed by the peer that sent it, for this room and for us, and hasn't expired by `now`.
+// Peers from before tokens expired send none, and sign without one
+pub fn verify_invite(sender: &PeerId, encoded_key: &[u8], token: &[u8], room_id: &str, invitee: &PeerId, expires: Option<u64>, now: u64) -> bool {
+    if expires.is_some_and(|expires| expires < now) {
+        return false;
+    }
+    match identity::PublicKey::try_decode_protobuf(encoded_key) {
+        Ok(public_key) => public_key.to_peer_id() == *sender && public_key.verify(&invite_payload(room_id, invitee, expires), token),
+        Err(_) => false,
+    }
+}
+
+fn invite_payload(room_id: &str, invitee: &PeerId, expires: Option<u64>) -> Vec<u8> {
+    let mut payload = room_id.as_bytes().to_vec();
+    payload.extend(invitee.to_bytes());
+    if let Some(expires) = expires {
+        payload.extend(expires.to_be_bytes());
+    }
+    payload
+}
+
+// Stretch a keystore passphrase into a key with PBKDF2-HMAC-SHA256, so guessing it offline is slow
+pub fn derive_keystore_key(passphrase: &str, salt: &[u8]) -> Key {
+    pbkdf2_sha256(passphrase.as_bytes(), salt, KEYSTORE_ITERATIONS)
+}
+
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> Key {
+    let mac = <Hmac<Sha256> as Mac>::new_from_slice(password).expect("HMAC accepts keys of any length");
+    // A single output block is exactly the 32 bytes we need
+    let mut block = mac.clone().chain_update(salt).chain_update(1u32.to_be_bytes()).finalize().into_bytes();
+    let mut key = block;
+    for _ in 1..iterations {
+        block = mac.clone().chain_update(block).finalize().into_bytes();
+        key.iter_mut().zip(block.iter()).for_each(|(k, b)| *k ^= b);
+    }
+    key
+}
+
+// A random salt for a new keystore
+pub fn generate_salt() -> [u8; 16] {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(key: &[u8]) -> String {
+        key.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    // The first 32 bytes of the PBKDF2-HMAC-SHA256 vectors in RFC 7914 section 11, and the ones commonly used
+    // alongside RFC 6070's SHA-1 vectors
+    #[test]
+    fn pbkdf2_matches_known_vectors() {
+        let vectors: &[(&[u8], &[u8], u32, &str)] = &[
+            (b"passwd", b"salt", 1, "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"),
+            (b"Password", b"NaCl", 80_000, "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56"),
+            (b"password", b"salt", 1, "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"),
+            (b"password", b"salt", 2, "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43"),
+            (b"password", b"salt", 4096, "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"),
+        ];
+        for (password, salt, iterations, expected) in vectors {
+            assert_eq!(hex(&pbkdf2_sha256(password, salt, *iterations)), *expected, "{iterations} iterations");
+        }
+    }
+
+    #[test]
+    fn invites_only_verify_for_their_sender_room_and_invitee_until_they_expire() {
+        let (sender, invitee, other) = (Keypair::generate_ed25519(), Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let (sender_id, invitee_id) = (sender.public().to_peer_id(), invitee.public().to_peer_id());
+        let sender_key = sender.public().encode_protobuf();
+        let now = 1_700_000_000;
+        let expires = now + INVITE_TTL.as_secs();
+        let token = sign_invite(&sender, "room", &invitee_id, expires);
+        assert!(verify_invite(&sender_id, &sender_key, &token, "room", &invitee_id, Some(expires), now));
+        assert!(verify_invite(&sender_id, &sender_key, &token, "room", &invitee_id, Some(expires), expires));
+
+        // Forged: signed by someone else, whether they give their own key or the sender's
+        let forged = sign_invite(&other, "room", &invitee_id, expires);
+        assert!(!verify_invite(&sender_id, &sender_key, &forged, "room", &invitee_id, Some(expires), now));
+        assert!(!verify_invite(&sender_id, &other.public().encode_protobuf(), &forged, "room", &invitee_id, Some(expires), now));
+        assert!(!verify_invite(&sender_id, b"not a key", &token, "room", &invitee_id, Some(expires), now));
+
+        // Expired, or with the expiry pushed back
+        assert!(!verify_invite(&sender_id, &sender_key, &token, "room", &invitee_id, Some(expires), expires + 1));
+        assert!(!verify_invite(&sender_id, &sender_key, &token, "room", &invitee_id, Some(expires + 60), expires + 1));
+        assert!(!verify_invite(&sender_id, &sender_key, &token, "room", &invitee_id, None, expires + 1));
+
+        // For another room, or someone else
+        assert!(!verify_invite(&sender_id, &sender_key, &token, "another room", &invitee_id, Some(expires), now));
+        assert!(!verify_invite(&sender_id, &sender_key, &token, "room", &other.public().to_peer_id(), Some(expires), now));
+    }
+
+    // Existing keystores were made with this many iterations, so changing it would lock them
+    #[test]
+    fn keystore_key_uses_the_keystore_iterations() {
+        let key = derive_keystore_key("correct horse battery staple", b"swapbytes salt!!");
+        assert_eq!(hex(&key), "12b84c56adff9788092eed898a36cc1a406d1cde53bb3d9714cce2e787e0a84f");
+    }
+}
@@ -0,0 +1,239 @@
+use std::{ collections::HashMap, time::{ Duration, Instant } };
+use libp2p::{ rendezvous, swarm::{ dial_opts::DialOpts, ConnectionId }, Multiaddr, PeerId };
+
+use crate::{ behaviour::SwapBytesBehaviour, util::ChatState };
+
+// A dial that hasn't connected or failed by now is tried again
+const DIAL_TIMEOUT: Duration = Duration::from_secs(30);
+// Wait before dialling a peer that failed again; it grows with every failure up to MAX_RETRY
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+const MAX_RETRY: Duration = Duration::from_secs(30 * 60);
+// Failed pings or dials in a row before the active rendezvous server counts as unhealthy
+const MAX_SERVER_FAILURES: u32 = 3;
+
+// One of the rendezvous servers given with --server
+struct RendezvousServer {
+    address: Multiaddr,
+    failures: u32, // In a row, since it last answered
+    unhealthy: bool,
+}
+
+// The rendezvous servers we know, in the order they were given. Discovery uses one at a time, pinging it all the
+// while, and moves on to the next when it stops answering. All of them share one PeerId, ChatState's rendezvous,
+// so fallbacks run with a copy of the same --identity
+#[derive(Default)]
+pub struct RendezvousServers {
+    servers: Vec<RendezvousServer>,
+    active: usize,
+    // Waiting for the connection to the server we gave up on to close before dialling the next one
+    pub switching: bool,
+    // A dial to the active server's address while we don't know its PeerId yet
+    unknown_dial: Option<ConnectionId>,
+}
+
+impl RendezvousServers {
+    pub fn new(addresses: Vec<Multiaddr>) -> Self {
+        let servers = addresses.into_iter().map(|address| RendezvousServer { address, failures: 0, unhealthy: false }).collect();
+        RendezvousServers { servers, active: 0, switching: false, unknown_dial: None }
+    }
+
+    pub fn dialling_unknown(&mut self, connection_id: ConnectionId) {
+        self.unknown_dial = Some(connection_id);
+    }
+
+    pub fn dialling(&self) -> bool {
+        self.unknown_dial.is_some()
+    }
+
+    // A dial connected or failed. Returns whether it was the one to a server we don't know the PeerId of
+    pub fn dial_finished(&mut self, connection_id: ConnectionId) -> bool {
+        self.unknown_dial.take_if(|dial| *dial == connection_id).is_some()
+    }
+
+    // Where discovery registers and looks for peers right now
+    pub fn active(&self) -> &Multiaddr {
+        &self.servers[self.active].address
+    }
+
+    // The active server answered a ping or a dial
+    pub fn answered(&mut self) {
+        let server = &mut self.servers[self.active];
+        if server.unhealthy {
+            system!("Rendezvous server {} is responding again.", server.address);
+        }
+        server.failures = 0;
+        server.unhealthy = false;
+    }
+
+    // The active server missed a ping or a dial failed. Once that has happened too often in a row it is marked
+    // unhealthy and, if there is another server, discovery moves on to it. Returns whether it did
+    pub fn failed(&mut self, error: impl std::fmt::Display) -> bool {
+        let count = self.servers.len();
+        let server = &mut self.servers[self.active];
+        server.failures += 1;
+        if server.failures < MAX_SERVER_FAILURES {
+            return false;
+        }
+        if count == 1 {
+            if !std::mem::replace(&mut server.unhealthy, true) {
+                tracing::warn!("Rendezvous server {} is unhealthy: {error}", server.address);
+            }
+            return false;
+        }
+        server.unhealthy = true;
+        // The next healthy server, or just the next one if none of them are
+        let next = (1..count)
+            .map(|offset| (self.active + offset) % count)
+            .find(|index| !self.servers[*index].unhealthy)
+            .unwrap_or((self.active + 1) % count);
+        system!(
+            "Rendezvous server {} stopped responding ({error}), switching discovery to {}.",
+            self.servers[self.active].address,
+            self.servers[next].address
+        );
+        self.active = next;
+        self.servers[next].failures = 0;
+        true
+    }
+
+    // For /stats
+    pub fn print(&self) {
+        for (index, server) in self.servers.iter().enumerate() {
+            let health = match (index == self.active, server.unhealthy) {
+                (true, false) => "active",
+                (true, true) => "active, unhealthy",
+                (false, false) => "standby",
+                (false, true) => "unhealthy",
+            };
+            system!("  Rendezvous server {}: {health}", server.address);
+        }
+    }
+}
+
+// Our registration with the rendezvous server. A shorter TTL drops peers that vanished from the directory sooner,
+// at the cost of registering more often
+#[derive(Default)]
+pub struct Registration {
+    pub ttl: Option<u64>, // Seconds asked for with --registration-ttl, the server's default if None
+    granted: Option<(Instant, u64)>, // When the server last accepted it, and for how many seconds
+}
+
+impl Registration {
+    pub fn registered(&mut self, ttl: u64) {
+        self.granted = Some((Instant::now(), ttl));
+    }
+
+    // Our registration went with the connection, or a renewal is on its way. Returns whether we were registered
+    pub fn lapsed(&mut self) -> bool {
+        self.granted.take().is_some()
+    }
+
+    // Registrations are renewed halfway through their TTL, so a slow renewal doesn't drop us from the directory
+    pub fn renewal_due(&self) -> bool {
+        self.granted.is_some_and(|(at, ttl)| at.elapsed() >= Duration::from_secs(ttl / 2))
+    }
+
+    // For /stats
+    pub fn print(&self) {
+        let requested = match self.ttl {
+            Some(ttl) => format!("{ttl}s requested"),
+            None => format!("server default of {}s", rendezvous::DEFAULT_TTL),
+        };
+        match self.granted {
+            Some((at, ttl)) => {
+                let renewal = Duration::from_secs(ttl / 2).saturating_sub(at.elapsed());
+                system!("  Rendezvous registration: TTL {ttl}s ({requested}), renewed in {}s", renewal.as_secs());
+            }
+            None => system!("  Rendezvous registration: not registered ({requested})"),
+        }
+    }
+}
+
+// Where we're up to with a peer discovered through the rendezvous server
+enum DialState {
+    Dialling { since: Instant },
+    Connected,
+    Failed { at: Instant, failures: u32, error: String },
+    Disconnected { at: Instant },
+}
+
+// Peers discovered through the rendezvous server, so each discovery only dials the ones we aren't connected to or
+// already dialling, and leaves peers that just failed alone for a while
+#[derive(Default)]
+pub struct DialStates {
+    peers: HashMap<PeerId, DialState>,
+}
+
+impl DialStates {
+    // Whether a discovered peer is worth dialling now
+    fn should_dial(&self, peer: &PeerId) -> bool {
+        match self.peers.get(peer) {
+            None | Some(DialState::Disconnected { .. }) => true,
+            Some(DialState::Dialling { since }) => since.elapsed() >= DIAL_TIMEOUT,
+            Some(DialState::Connected) => false,
+            Some(DialState::Failed { at, failures, .. }) => at.elapsed() >= (RETRY_AFTER * *failures).min(MAX_RETRY),
+        }
+    }
+
+    // A connection to the peer is up, however it was made
+    pub fn connected(&mut self, peer: PeerId) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            *state = DialState::Connected;
+        }
+    }
+
+    // The last connection to the peer closed
+    pub fn disconnected(&mut self, peer: PeerId) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            *state = DialState::Disconnected { at: Instant::now() };
+        }
+    }
+
+    // Dialling the peer failed. Only peers we're tracking count, so dials made for other reasons don't start tracking
+    pub fn failed(&mut self, peer: PeerId, error: impl ToString) {
+        let Some(state) = self.peers.get_mut(&peer) else { return };
+        let failures = match state {
+            DialState::Failed { failures, .. } => *failures + 1,
+            _ => 1,
+        };
+        *state = DialState::Failed { at: Instant::now(), failures, error: error.to_string() };
+    }
+}
+
+// Dial a peer the rendezvous server told us about, at all of its addresses at once, unless we're connected,
+// already dialling or it failed too recently
+pub fn dial(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, addresses: Vec<Multiaddr>) {
+    if swarm.is_connected(&peer) {
+        state.dials.peers.insert(peer, DialState::Connected);
+        return;
+    }
+    if !state.dials.should_dial(&peer) {
+        tracing::debug!("Not dialling discovered peer {peer} again yet");
+        return;
+    }
+    state.dials.peers.insert(peer, DialState::Dialling { since: Instant::now() });
+    if let Err(e) = swarm.dial(DialOpts::peer_id(peer).addresses(addresses).build()) {
+        tracing::info!("Could not dial discovered peer {peer}: {e}");
+        state.dials.failed(peer, e);
+    }
+}
+
+// /peers: every peer discovered through the rendezvous server and where we're up to with it
+pub fn list(state: &ChatState) {
+    if state.dials.peers.is_empty() {
+        system!("No peers have been discovered through the rendezvous server yet.");
+        return;
+    }
+    let mut peers: Vec<_> = state.dials.peers.iter().map(|(peer, dial)| (state.peer_name(peer), dial)).collect();
+    peers.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (name, dial) in peers {
+        let status = match dial {
+            DialState::Dialling { since } => format!("dialling for {}s", since.elapsed().as_secs()),
+            DialState::Connected => "connected".to_string(),
+            DialState::Failed { at, failures: 1, error } => format!("failed {}s ago: {error}", at.elapsed().as_secs()),
+            DialState::Failed { at, failures, error } => format!("failed {failures} times, last {}s ago: {error}", at.elapsed().as_secs()),
+            DialState::Disconnected { at } => format!("disconnected {}s ago", at.elapsed().as_secs()),
+        };
+        system!("{name}: {status}");
+    }
+}
@@ -0,0 +1,108 @@
+use std::{ io, path::{ Path, PathBuf } };
+use tokio::fs::{ File, OpenOptions };
+
+// Longest name we'll save, in bytes, leaving room under the usual 255 for numbering and .enc
+const MAX_LEN: usize = 200;
+// Give up numbering copies of a name after this many
+const MAX_COPIES: u32 = 1000;
+// Device names Windows won't create files under, with or without an extension
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Characters that reorder the text around them, so "txt.exe" can be shown as "exe.txt"
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+// Turn a filename a peer sent into one that's safe to create in the download directory: only its last path
+// component, with characters Windows can't store replaced and without leading dots that would hide it.
+// Names that could only be meant to disguise a file are refused
+pub fn sanitize(name: &str) -> Result<String, String> {
+    if name.chars().any(char::is_control) {
+        return Err("it contains control characters".to_string());
+    }
+    if name.chars().any(is_bidi_control) {
+        return Err("it contains characters that reorder text, which can disguise its extension".to_string());
+    }
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let replaced: String = base.chars()
+        .map(|c| if matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*') { '_' } else { c })
+        .collect();
+    let cleaned = replaced.trim_start_matches(['.', ' ']).trim_end_matches(['.', ' ']);
+    if cleaned.is_empty() {
+        return Err("there is no name left once its directories are removed".to_string());
+    }
+    let stem = cleaned.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        return Err(format!("{stem} is a reserved device name"));
+    }
+    if cleaned.len() > MAX_LEN {
+        return Err(format!("it is longer than {MAX_LEN} bytes"));
+    }
+    Ok(cleaned.to_string())
+}
+
+// Create a new file in a directory, numbering the name "notes (1).pdf", "notes (2).pdf"... rather than
+// replacing a file that's already there
+pub async fn create_unique(dir: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+    // Number before the first extension, so "notes.pdf.enc" becomes "notes (1).pdf.enc"
+    let (stem, extensions) = match name.find('.') {
+        Some(dot) => name.split_at(dot),
+        None => (name, ""),
+    };
+    for copy in 0..MAX_COPIES {
+        let path = match copy {
+            0 => dir.join(name),
+            _ => dir.join(format!("{stem} ({copy}){extensions}")),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path).await {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{MAX_COPIES} files called {name} are already there")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_a_safe_last_component() {
+        let cleaned = [
+            ("notes.pdf", "notes.pdf"),
+            ("../../etc/passwd", "passwd"),
+            (r"C:\Users\kush\report.docx", "report.docx"),
+            ("what?.txt", "what_.txt"),
+            ("a<b>c:d|e*.txt", "a_b_c_d_e_.txt"),
+            (".bashrc", "bashrc"),
+            ("  trailing. ", "trailing"),
+            ("CONSOLE.txt", "CONSOLE.txt"),
+        ];
+        for (name, expected) in cleaned {
+            assert_eq!(sanitize(name).as_deref(), Ok(expected), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn refuses_names_meant_to_disguise_a_file() {
+        let refused = [
+            "bad\nname.txt",
+            "invoice\u{202E}fdp.exe",
+            "dir/",
+            "...",
+            "con",
+            "nul.tar.gz",
+            "LPT1 .txt",
+        ];
+        for name in refused {
+            assert!(sanitize(name).is_err(), "{name:?}");
+        }
+        assert!(sanitize(&"a".repeat(MAX_LEN + 1)).is_err());
+        assert!(sanitize(&"a".repeat(MAX_LEN)).is_ok());
+    }
+}
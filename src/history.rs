@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+use libp2p::PeerId;
+use serde::{ Deserialize, Serialize };
+
+use crate::{ output, persist::{ unix_now, JsonFile }, scan::ScanVerdict };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// A file exchange that completed with another peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferRecord {
+    pub peer: PeerId,
+    pub filename: String,
+    pub direction: Direction,
+    pub timestamp: u64,
+    // What --scan-command made of a received file, if it was scanned
+    #[serde(default)]
+    pub verdict: Option<ScanVerdict>,
+}
+
+// Completed transfers, used to decide who can be rated
+#[derive(Default)]
+pub struct TransferHistory {
+    pub transfers: Vec<TransferRecord>,
+    file: JsonFile<Vec<TransferRecord>>,
+}
+
+impl TransferHistory {
+    // Load the history kept in a file, saving every new transfer back to it
+    pub fn load(file: PathBuf) -> Self {
+        let (file, transfers) = JsonFile::open(file, "your transfer history");
+        TransferHistory { transfers, file }
+    }
+
+    pub fn record(&mut self, peer: PeerId, filename: &str, direction: Direction) {
+        self.transfers.push(TransferRecord {
+            peer,
+            filename: filename.to_string(),
+            direction,
+            timestamp: unix_now(),
+            verdict: None,
+        });
+        output::publish(output::Event::TransferComplete { peer, filename: filename.to_string(), direction });
+        self.save();
+    }
+
+    // Note the scanner's verdict on the latest file received from a peer under this name
+    pub fn record_verdict(&mut self, peer: &PeerId, filename: &str, verdict: &ScanVerdict) {
+        let received = self.transfers.iter_mut()
+            .rev()
+            .find(|transfer| transfer.peer == *peer && transfer.filename == filename && transfer.direction == Direction::Received);
+        if let Some(transfer) = received {
+            transfer.verdict = Some(verdict.clone());
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        self.file.save(&self.transfers);
+    }
+
+    // Whether at least one file has gone either way between us and this peer
+    pub fn has_exchanged_with(&self, peer: &PeerId) -> bool {
+        self.transfers.iter().any(|transfer| transfer.peer == *peer)
+    }
+}
@@ -1,10 +1,30 @@
-use std::str::FromStr;
-use libp2p::{ gossipsub::{ self, TopicHash }, kad};
-use tokio::{ fs::File, io::{ self, AsyncReadExt } };
+use std::{ collections::{ HashMap, HashSet }, path::{ Path, PathBuf } };
+use libp2p::{ gossipsub::{ self, TopicHash }, kad::{ self, store::RecordStore }, multiaddr::Protocol, PeerId };
+
+use uuid::Uuid;
 
 use crate::{
+    approval::respond_to_approval,
+    audit,
+    bench,
+    board,
+    chunking,
+    command::{ self, Input },
+    discovery,
     behaviour::{ RequestType, SwapBytesBehaviour },
-    util::{ update_peer_rating, ChatState, ConnectionRequest },
+    history::Direction,
+    keystore::{ self, StoredIdentity },
+    line_editor::LineReader,
+    manifest,
+    negotiation,
+    output,
+    pipeline,
+    outbound,
+    room::{ self, publish_room_record, InviteOutcome, publish_roster, respond_to_invite, return_to_default, switch },
+    trade,
+    util::{ own_peer_data, own_rating_hint, publish_gossip, send_read_receipts, update_own_profile, ChatMessage, ChatState, ConnectionDetails, ConnectionRequest, GossipMessage, ListingKind, PeerData, PeerListing },
+    vault,
+    wishlist,
 };
 
 pub async fn handle_input(
@@ -12,243 +32,961 @@ pub async fn handle_input(
     swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
     topic: &mut gossipsub::IdentTopic,
     state: &mut ChatState,
-    own_nickname: String,
-    stdin: &mut io::Lines<io::BufReader<io::Stdin>>
+    own_nickname: &mut String,
+    stdin: &mut LineReader
 ) {
-    match line {
-        "/exit" => {
-            println!("Thank you for using SwapBytes! Goodbye!");
-            std::process::exit(0);
+    // Typing anything means the user has seen what arrived in the private room
+    send_read_receipts(swarm, state);
+
+    // Leaving with transfers going is confirmed with /exit again or yes; any other answer stays
+    if std::mem::take(&mut state.exit_prompt) {
+        if matches!(line.to_lowercase().as_str(), "y" | "yes") || command::find(line).is_some_and(|command| command.name == "/exit") {
+            state.exit_requested = true;
+        } else {
+            system!("Staying in SwapBytes.");
         }
-        "/help" => {
-            let topic_hash: TopicHash = topic.hash().clone();
-            if topic_hash.as_str() == "default" {
-                println!(
-                    "Available commands:\n
-                /help - display a list of available commands\n
-                /exit - leave SwapBytes\n
-                /connect <peer nickname> - invite a peer to a private room to request and offer files\n
-                /list - list connected peers\n
-                <message>"
-                );
-            } else {
-                println!(
-                    "Available commands:\n
-                /help - display a list of available commands\n
-                /exit - leave SwapBytes\n
-                /list - list connected peers\n
-                /request <file> - request a file from the other peer\n
-                /offer <file> - offer a file to the other peer\n
-                /leave - leave the current chatroom\n
-                <message>"
-                );
+        return;
+    }
+
+    // A rating prompt from a completed trade takes the next line, if it's a rating or empty; anything else skips it
+    if let Some(id) = state.rating_prompt.take() {
+        if line.is_empty() {
+            return;
+        }
+        if let Some(rating) = parse_rating(line) {
+            trade::rate(swarm, state, id, rating).await;
+            return;
+        }
+    }
+
+    let command = match command::parse(line) {
+        Ok(Input::Chat(text)) => return send_chat(text, swarm, topic, state, own_nickname),
+        Ok(Input::Command(command)) => command,
+        Err(e) => {
+            error!("{e}");
+            return;
+        }
+    };
+    // The command's canonical name, then its arguments
+    let parts: Vec<&str> = std::iter::once(command.name).chain(command.args.iter().map(String::as_str)).collect();
+    match command.name {
+        "/exit" => {
+            let (in_flight, queued) = state.outbound.counts();
+            if (!state.chunked.is_empty() || in_flight + queued > 0) && output::prompts_enabled() {
+                system!("Files are still being sent or received, and leaving will cut them off. Leave anyway? Type yes or /exit again to leave, or anything else to stay.");
+                state.exit_prompt = true;
+                return;
             }
+            state.exit_requested = true;
         }
+        "/help" => match parts.get(1) {
+            Some(name) => command::print_page(name),
+            None => command::print_overview(topic.hash().as_str() == state.public_room),
+        },
+
+        "/peers" => discovery::list(state),
+
+        "/stats" => print_stats(swarm, state),
 
         "/list" => {
+            // use the cache where we can and look the rest up in the DHT, then print them all sorted by rating
             let connected_peers: Vec<_> = swarm.connected_peers().cloned().collect();
+            let mut listing = PeerListing::default();
             for peer_id in connected_peers {
-                let key = kad::RecordKey::new(&peer_id.to_bytes());
-                swarm.behaviour_mut().kademlia.get_record(key);
+                match state.cached_peer(&peer_id) {
+                    Some(peer) => listing.resolved.push((peer_id, Some(peer.clone()))),
+                    None => { listing.waiting.insert(peer_id); }
+                }
             }
+            state.start_listing(swarm, listing);
         }
 
-        // /connect <peer>
-        val if val.starts_with("/connect") => {
-            // check that the user is not already in a private room
-            let topic_hash: TopicHash = topic.hash().clone();
-            if topic_hash.as_str() != "default" {
-                println!(
-                    "You are already in a private room. Please leave the room before connecting to another peer."
-                );
+        "/who" => {
+            // whoever we've seen subscribe to the room, plus a private room's members who haven't, named from the
+            // cache or the DHT like /list
+            let room = topic.hash();
+            let local_peer_id = *swarm.local_peer_id();
+            let mut peers: HashSet<PeerId> = state.subscribers.of(&room).copied().collect();
+            if let Some(private) = state.rooms.get(room.as_str()) {
+                peers.extend(private.members.iter().map(|member| member.peer_id));
+            }
+            peers.remove(&local_peer_id);
+            let mut listing = PeerListing { kind: ListingKind::Members(room), ..Default::default() };
+            listing.resolved.push((local_peer_id, own_peer_data(swarm)));
+            for peer_id in peers {
+                match state.cached_peer(&peer_id) {
+                    Some(peer) => listing.resolved.push((peer_id, Some(peer.clone()))),
+                    None => { listing.waiting.insert(peer_id); }
+                }
+            }
+            state.start_listing(swarm, listing);
+        }
+
+        "/top" => {
+            // every peer we know of: records in our local store, the cache, and anyone connected (looked up in the DHT)
+            let local_peer_id = *swarm.local_peer_id();
+            let mut known: HashMap<PeerId, PeerData> = swarm
+                .behaviour_mut()
+                .kademlia.store_mut().records()
+                .filter_map(|record| {
+                    let peer_id = PeerId::from_bytes(record.key.as_ref()).ok()?;
+                    let peer = serde_json::from_slice::<PeerData>(&record.value).ok()?;
+                    Some((peer_id, peer))
+                })
+                .collect();
+            let cached: Vec<_> = state.peer_cache.keys().copied().collect();
+            for peer_id in cached {
+                if let Some(peer) = state.cached_peer(&peer_id) {
+                    known.insert(peer_id, peer.clone());
+                }
+            }
+            known.remove(&local_peer_id);
+            let mut listing = PeerListing { kind: ListingKind::Leaderboard, ..Default::default() };
+            let connected_peers: Vec<_> = swarm.connected_peers().cloned().collect();
+            for peer_id in connected_peers {
+                if !known.contains_key(&peer_id) {
+                    listing.waiting.insert(peer_id);
+                }
+            }
+            listing.resolved.extend(known.into_iter().map(|(peer_id, peer)| (peer_id, Some(peer))));
+            state.start_listing(swarm, listing);
+        }
+
+        // /nick <new nickname>
+        "/nick" => {
+            // private rooms' records and rosters list members by nickname, so only allow this in the public room
+            if topic.hash().as_str() != state.public_room {
+                system!("Please leave the private room before changing your nickname.");
                 return;
             }
-            // get the other peer's nickname that is connected to the current topic
-            let parts: Vec<&str> = val.split_whitespace().collect();
             if parts.len() == 2 {
-                let peer_nickname = parts[1].to_string();
-                let reverse_key = kad::RecordKey::new(&format!("nickname:{}", peer_nickname));
+                if parts[1] == own_nickname.as_str() {
+                    system!("Your nickname is already {}.", own_nickname);
+                    return;
+                }
+                // make sure no other peer holds the nickname before claiming it
+                let reverse_key = kad::RecordKey::new(&format!("nickname:{}", parts[1]));
                 let query_id = swarm.behaviour_mut().kademlia.get_record(reverse_key);
-                state.pending_connections.insert(
-                    query_id,
-                    ConnectionRequest::NicknameLookup(
-                        own_nickname.clone(),
-                        swarm.local_peer_id().clone()
-                    )
-                );
+                state.pending_nickname_claims.insert(query_id, parts[1].to_string());
+                system!("Checking that {} is available...", parts[1]);
+            } else {
+                system!("Usage: /nick <new nickname>");
+            }
+        }
+
+        // /connect <nickname or peer id>... [-p <passphrase>]
+        "/connect" => {
+            let (nicknames, passphrase) = match &parts[1..] {
+                [nicknames @ .., "-p", passphrase] => (nicknames, Some(*passphrase)),
+                nicknames => (nicknames, None),
+            };
+            if nicknames.is_empty() || nicknames.contains(&"-p") {
+                system!("Usage: /connect <peer nickname or ID>... [-p <passphrase>]");
+                return;
+            }
+            let mut seen = HashSet::new();
+            let nicknames: Vec<&str> = nicknames.iter().copied().filter(|nickname| seen.insert(*nickname)).collect();
+            // everyone is invited to the same room, which starts with whoever accepts first
+            let details = connection_details(topic, state, own_nickname, passphrase);
+            if nicknames.len() > 1 {
+                state.invite_rounds.entry(details.room_id.clone()).or_default().waiting += nicknames.len();
+            }
+            for nickname in nicknames {
+                // a peer ID needs no nickname lookup
+                let (query_id, request) = match nickname.parse::<PeerId>() {
+                    Ok(peer_id) if peer_id == *swarm.local_peer_id() => {
+                        error!("You cannot connect to yourself.");
+                        room::invite_answered(state, &details.room_id, nickname, InviteOutcome::Failed);
+                        continue;
+                    }
+                    Ok(peer_id) => (
+                        swarm.behaviour_mut().kademlia.get_record(kad::RecordKey::new(&peer_id.to_bytes())),
+                        ConnectionRequest::PeerData(peer_id, details.clone()),
+                    ),
+                    Err(_) => (
+                        swarm.behaviour_mut().kademlia.get_record(kad::RecordKey::new(&format!("nickname:{}", nickname))),
+                        ConnectionRequest::NicknameLookup(nickname.to_string(), details.clone()),
+                    ),
+                };
+                state.pending_connections.insert(query_id, request);
+            }
+        }
+
+        // /emoji <on|off>
+        "/emoji" => {
+            match parts.get(1).copied() {
+                Some("on") => {
+                    state.emoji_enabled = true;
+                    system!("Emoji shortcodes are on.");
+                }
+                Some("off") => {
+                    state.emoji_enabled = false;
+                    system!("Emoji shortcodes are off.");
+                }
+                _ => system!("Usage: /emoji <on|off>"),
+            }
+        }
+
+        // /autoaccept <min rating>|off
+        "/autoaccept" => {
+            match parts.get(1).copied() {
+                Some("off") => {
+                    state.auto_accept_threshold = None;
+                    system!("Auto-accept is off. You will be asked about every file offer.");
+                }
+                Some(threshold) => match threshold.parse::<f64>() {
+                    Ok(threshold) if (-1.0..=1.0).contains(&threshold) => {
+                        state.auto_accept_threshold = Some(threshold);
+                        system!("Auto-accepting offered files from peers rated {threshold} or higher. Peers rated below 0 are always asked about.");
+                    }
+                    _ => system!("The rating must be a number between -1 and 1."),
+                },
+                None => match state.auto_accept_threshold {
+                    Some(threshold) => system!("Auto-accepting offered files from peers rated {threshold} or higher."),
+                    None => system!("Usage: /autoaccept <min rating>|off"),
+                },
+            }
+        }
+
+        // /deny, /allow or /unlist <nickname|peer id>
+        "/deny" | "/allow" | "/unlist" => {
+            let Some(name) = parts.get(1) else {
+                system!("Usage: {} <nickname|peer id>", parts[0]);
+                return;
+            };
+            let Some(peer_id) = state.find_peer(name) else {
+                error!("You haven't seen a peer called {name} yet. Use their peer ID instead.");
+                return;
+            };
+            if peer_id == *swarm.local_peer_id() || Some(peer_id) == state.rendezvous {
+                error!("Your connection policy can't apply to {name}.");
+                return;
+            }
+            let name = state.peer_name(&peer_id);
+            match parts[0] {
+                "/deny" => {
+                    swarm.behaviour_mut().gate.deny(peer_id);
+                    disconnect_refused(swarm, state);
+                    system!("{name} is denied and can no longer connect to you.");
+                }
+                "/allow" => {
+                    swarm.behaviour_mut().gate.allow(peer_id);
+                    if swarm.behaviour().gate.lists().allow_only {
+                        system!("{name} is allowed to connect to you.");
+                    } else {
+                        system!("{name} is on your allow list, which takes effect with /allowonly on.");
+                    }
+                }
+                _ => {
+                    if swarm.behaviour_mut().gate.unlist(&peer_id) {
+                        disconnect_refused(swarm, state);
+                        system!("{name} has been taken off your allow and deny lists.");
+                    } else {
+                        system!("{name} isn't on your allow or deny lists.");
+                    }
+                }
+            }
+        }
+
+        // /unmute <nickname|peer id>
+        "/unmute" => {
+            let Some(name) = parts.get(1) else {
+                system!("Usage: /unmute <nickname|peer id>");
+                return;
+            };
+            match state.find_peer(name) {
+                Some(peer_id) if state.rate_limiter.unmute(&peer_id) => system!("{} is no longer muted.", state.peer_name(&peer_id)),
+                Some(peer_id) => system!("{} isn't muted.", state.peer_name(&peer_id)),
+                None => error!("You haven't seen a peer called {name} yet. Use their peer ID instead."),
+            }
+        }
+
+        // /allowonly <on|off>
+        "/allowonly" => {
+            let allow_only = match parts.get(1).copied() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => {
+                    system!("Usage: /allowonly <on|off>");
+                    return;
+                }
+            };
+            swarm.behaviour_mut().gate.set_allow_only(allow_only);
+            if allow_only {
+                disconnect_refused(swarm, state);
+                system!("Only peers on your allow list can connect to you now.");
             } else {
-                println!("Usage: /connect <peer nickname>");
+                system!("Any peer that isn't denied can connect to you now.");
+            }
+        }
+
+        "/policy" => {
+            let lists = swarm.behaviour().gate.lists();
+            let names = |peers: &std::collections::BTreeSet<PeerId>| match peers.is_empty() {
+                true => "nobody".to_string(),
+                false => peers.iter().map(|peer_id| state.peer_name(peer_id)).collect::<Vec<_>>().join(", "),
+            };
+            system!("Allow-only mode is {}.", if lists.allow_only { "on" } else { "off" });
+            system!("Allowed: {}", names(&lists.allowed));
+            system!("Denied: {}", names(&lists.denied));
+        }
+
+        // /set <markdown|colors> <on|off>
+        "/set" => {
+            let enabled = match parts.get(2) {
+                Some(&"on") => true,
+                Some(&"off") => false,
+                _ => {
+                    system!("Usage: /set <markdown|colors> <on|off>");
+                    return;
+                }
+            };
+            match parts.get(1) {
+                Some(&"markdown") => state.render.markdown = enabled,
+                Some(&"colors") => {
+                    state.render.colors = enabled;
+                    output::set_colors(enabled);
+                }
+                _ => {
+                    system!("Usage: /set <markdown|colors> <on|off>");
+                    return;
+                }
+            }
+            system!("{} turned {}.", parts[1], parts[2]);
+        }
+
+        "/history" => {
+            if state.transfer_history.transfers.is_empty() {
+                system!("You haven't exchanged any files yet.");
+            }
+            for transfer in &state.transfer_history.transfers {
+                let direction = match transfer.direction {
+                    Direction::Sent => "sent to",
+                    Direction::Received => "received from",
+                };
+                let verdict = transfer.verdict.as_ref().map(|verdict| format!(" (scan: {verdict})")).unwrap_or_default();
+                system!("[{}] {} {direction} {}{verdict}", transfer.timestamp, transfer.filename, transfer.peer);
+            }
+        }
+
+        // /audit [count], /audit export <file>, /audit verify <file>
+        "/audit" => {
+            match (parts.get(1).copied(), parts.get(2)) {
+                (Some("export"), Some(file)) => match state.audit_log.export(&state.keypair, Path::new(file)) {
+                    Ok(()) => system!("Exported {} audit log entries to {file}. Anyone can check it with /audit verify {file}.", state.audit_log.entries.len()),
+                    Err(e) => error!("Failed to export your audit log: {e}"),
+                },
+                (Some("verify"), Some(file)) => match audit::check_export(Path::new(file)) {
+                    Ok((peer_id, entries, receipts)) => system!(
+                        "{file} is intact: {entries} entries signed by {}, {receipts} with a receipt signed by the other peer.",
+                        state.peer_name(&peer_id),
+                    ),
+                    Err(e) => error!("{file} can't be trusted: {e}"),
+                },
+                (count, None) if count.is_none_or(|count| count.parse::<usize>().is_ok()) => {
+                    let log = &state.audit_log;
+                    if log.entries.is_empty() {
+                        system!("Your audit log is empty.");
+                        return;
+                    }
+                    if let Err(index) = audit::verify(&log.entries, &state.keypair.public()) {
+                        error!("Your audit log has been tampered with from entry {index} on.");
+                    }
+                    let count = count.and_then(|count| count.parse().ok()).unwrap_or(20);
+                    for entry in &log.entries[log.entries.len().saturating_sub(count)..] {
+                        let digest = entry.digest.as_ref().map(|digest| format!(" (sha256 {})", &digest[..16])).unwrap_or_default();
+                        system!("[{}] {} {} {} {}{digest}", entry.index, entry.timestamp, entry.filename, entry.event, state.peer_name(&entry.peer));
+                    }
+                }
+                _ => system!("Usage: /audit [count], /audit export <file> or /audit verify <file>"),
+            }
+        }
+
+        // /rate <nickname> <-1|0|1>
+        "/rate" => {
+            let (Some(nickname), Some(rating)) = (parts.get(1), parts.get(2).and_then(|rating| parse_rating(rating))) else {
+                system!("Usage: /rate <nickname> <-1|0|1>");
+                return;
+            };
+            if *nickname == own_nickname.as_str() {
+                error!("You cannot rate yourself.");
+                return;
+            }
+            let reverse_key = kad::RecordKey::new(&format!("nickname:{}", nickname));
+            let query_id = swarm.behaviour_mut().kademlia.get_record(reverse_key);
+            state.pending_rating_lookups.insert(query_id, rating);
+        }
+
+        // /id export <file>
+        "/id" => {
+            if parts.len() == 1 {
+                print_own_addresses(swarm, own_nickname);
+                return;
+            }
+            let (Some(&"export"), Some(file)) = (parts.get(1), parts.get(2)) else {
+                system!("Usage: /id, or /id export <file>");
+                return;
+            };
+            let passphrase = match keystore::read_passphrase(stdin, "Choose a passphrase to protect the export:").await {
+                Ok(passphrase) => passphrase,
+                Err(e) => {
+                    error!("{e}");
+                    return;
+                }
+            };
+            let identity = StoredIdentity::new(&state.keypair, own_peer_data(swarm), state.trusted.iter().copied().collect());
+            match keystore::export(Path::new(file), &identity, &passphrase) {
+                Ok(()) => system!("Your identity has been exported to {file}. Run `swapbytes identity import {file}` on another machine to use it there."),
+                Err(e) => error!("Failed to export your identity: {e}"),
+            }
+        }
+
+        // /decrypt <file> [output]
+        "/decrypt" => {
+            let Some(file) = parts.get(1) else {
+                system!("Usage: /decrypt <file> [output]");
+                return;
+            };
+            // Names printed when files are received are relative to the download directory
+            let path = match Path::new(file) {
+                path if !path.exists() && state.download_dir.join(path).exists() => state.download_dir.join(path),
+                path => path.to_path_buf(),
+            };
+            let output = parts.get(2).map(PathBuf::from).unwrap_or_else(|| vault::decrypted_path(&path));
+            if output.exists() {
+                error!("{} already exists. Give another name to write to: /decrypt <file> <output>", output.display());
+                return;
+            }
+            let data = match tokio::fs::read(&path).await {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Could not read {}: {e}", path.display());
+                    return;
+                }
+            };
+            // Try this run's passphrase first, then ask, in case the file is from another run
+            let plaintext = match state.download_key.as_ref().map(|key| vault::open(&data, key.passphrase(), Some(key))) {
+                Some(Ok(plaintext)) => Ok(plaintext),
+                _ => match keystore::read_passphrase(stdin, "Enter the passphrase the file was encrypted with:").await {
+                    Ok(passphrase) => vault::open(&data, &passphrase, state.download_key.as_ref()),
+                    Err(e) => Err(e.to_string()),
+                },
+            };
+            match plaintext {
+                Ok(plaintext) => match tokio::fs::write(&output, plaintext).await {
+                    Ok(()) => system!("Decrypted {} to {}.", path.display(), output.display()),
+                    Err(e) => error!("Could not write {}: {e}", output.display()),
+                },
+                Err(e) => error!("Could not decrypt {}: {e}", path.display()),
+            }
+        }
+
+        // /profile [set <bio|tags|namespaces> [value]]
+        "/profile" => {
+            match (parts.get(1).copied(), parts.get(2).copied()) {
+                (None, _) => match own_peer_data(swarm) {
+                    Some(peer) => peer.print_profile(swarm.local_peer_id(), own_rating_hint(swarm, state)),
+                    None => system!("Your profile hasn't been published yet."),
+                },
+                (Some("set"), Some(field @ ("bio" | "tags" | "namespaces"))) => {
+                    // leaving the value out clears the field. A single quoted value has its quotes taken off
+                    let value = match &command.args[2..] {
+                        [value] => value.clone(),
+                        _ => command.rest_after(2).to_string(),
+                    };
+                    let list: Vec<String> = value.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect();
+                    update_own_profile(swarm, state, |profile| match field {
+                        "bio" => profile.bio = Some(value).filter(|bio| !bio.is_empty()),
+                        "tags" => profile.tags = list,
+                        _ => profile.namespaces = list,
+                    });
+                    system!("Your profile has been updated.");
+                }
+                _ => system!("Usage: /profile set <bio|tags|namespaces> [value]. Tags and namespaces are comma separated."),
+            }
+        }
+
+        // /whois <nickname>
+        "/whois" => {
+            let Some(nickname) = parts.get(1) else {
+                system!("Usage: /whois <nickname>");
+                return;
+            };
+            let reverse_key = kad::RecordKey::new(&format!("nickname:{}", nickname));
+            let query_id = swarm.behaviour_mut().kademlia.get_record(reverse_key);
+            state.pending_whois_lookups.insert(query_id);
+        }
+
+        // /bench <nickname> [size]
+        "/bench" => {
+            let size = match parts.get(2) {
+                Some(size) => bench::parse_size(size),
+                None => Some(bench::DEFAULT_SIZE),
+            };
+            let (Some(nickname), Some(size), 2..=3) = (parts.get(1), size, parts.len()) else {
+                system!("Usage: /bench <nickname> [size, like 16M]");
+                return;
+            };
+            if size > bench::MAX_SIZE {
+                error!("Benchmarks can send at most {}.", bench::format_bytes(bench::MAX_SIZE as f64));
+                return;
+            }
+            let reverse_key = kad::RecordKey::new(&format!("nickname:{}", nickname));
+            let query_id = swarm.behaviour_mut().kademlia.get_record(reverse_key);
+            state.pending_bench_lookups.insert(query_id, size);
+        }
+
+        // /ping <nickname>
+        "/ping" => {
+            let Some(nickname) = parts.get(1) else {
+                system!("Usage: /ping <nickname>");
+                return;
+            };
+            let reverse_key = kad::RecordKey::new(&format!("nickname:{}", nickname));
+            let query_id = swarm.behaviour_mut().kademlia.get_record(reverse_key);
+            state.pending_ping_lookups.insert(query_id, nickname.to_string());
+        }
+
+        // /vouch <nickname>
+        "/vouch" => {
+            let Some(nickname) = parts.get(1) else {
+                system!("Usage: /vouch <nickname>");
+                return;
+            };
+            if *nickname == own_nickname.as_str() {
+                error!("You cannot vouch for yourself.");
+                return;
+            }
+            let reverse_key = kad::RecordKey::new(&format!("nickname:{}", nickname));
+            let query_id = swarm.behaviour_mut().kademlia.get_record(reverse_key);
+            state.pending_vouch_lookups.insert(query_id);
+        }
+
+        "/invites" => {
+            if state.incoming_invites.is_empty() {
+                system!("You have no pending private room requests.");
+            }
+            let mut invite_ids: Vec<_> = state.incoming_invites.keys().copied().collect();
+            invite_ids.sort();
+            for invite_id in invite_ids {
+                let invite = &state.incoming_invites[&invite_id];
+                let protected = if invite.passphrase_required { " (passphrase required)" } else { "" };
+                system!("[{invite_id}] {} ({}){protected}", invite.initiator_nickname, invite.peer);
+            }
+        }
+
+        // /accept <id> [passphrase]
+        "/accept" => {
+            let Some(invite_id) = parts.get(1).and_then(|id| id.parse::<u32>().ok()) else {
+                system!("Usage: /accept <id> [passphrase]");
+                return;
+            };
+            if state.rooms.contains_key(topic.hash().as_str()) {
+                system!("You are already in a private room. Please leave the room before accepting another request.");
+                return;
+            }
+            let passphrase = parts.get(2).map(|passphrase| passphrase.to_string());
+            match state.incoming_invites.get(&invite_id) {
+                Some(invite) if invite.passphrase_required && passphrase.is_none() => {
+                    system!("This room is protected by a passphrase. Usage: /accept {invite_id} <passphrase>");
+                }
+                Some(_) => {
+                    let invite = state.incoming_invites.remove(&invite_id).expect("Invite was just found");
+                    respond_to_invite(swarm, state, invite, true, passphrase);
+                }
+                None => system!("No pending private room request with ID {invite_id}. Type /invites to see them."),
+            }
+        }
+
+        // /decline <id>
+        "/decline" => {
+            let Some(invite_id) = parts.get(1).and_then(|id| id.parse::<u32>().ok()) else {
+                system!("Usage: /decline <id>");
+                return;
+            };
+            match state.incoming_invites.remove(&invite_id) {
+                Some(invite) => respond_to_invite(swarm, state, invite, false, None),
+                None => system!("No pending private room request with ID {invite_id}. Type /invites to see them."),
+            }
+        }
+
+        "/approvals" => {
+            if state.pending_approvals.is_empty() {
+                system!("You have no file requests or offers waiting on an answer.");
+            }
+            let mut approval_ids: Vec<_> = state.pending_approvals.keys().copied().collect();
+            approval_ids.sort();
+            for approval_id in approval_ids {
+                let (approval, _) = &state.pending_approvals[&approval_id];
+                let (peer, description) = (*approval.peer(), approval.describe());
+                let name = state.cached_peer(&peer).map(|peer_data| peer_data.nickname.clone()).unwrap_or(peer.to_string());
+                system!("[{approval_id}] {name} {description}");
+            }
+        }
+
+        // /yes <id> and /no <id>
+        "/yes" | "/no" => {
+            let accept = parts[0] == "/yes";
+            let Some(approval_id) = parts.get(1).and_then(|id| id.parse::<u32>().ok()) else {
+                system!("Usage: {} <id>", parts[0]);
+                return;
+            };
+            match state.pending_approvals.remove(&approval_id) {
+                Some((approval, _)) => respond_to_approval(swarm, state, approval, accept).await,
+                None => system!("No file request or offer with ID {approval_id}. Type /approvals to see them."),
             }
         }
 
         "/leave" => {
-            // get the other peer's nickname that is connected to the current topic
             let topic_hash: TopicHash = topic.hash().clone();
-            if topic_hash.as_str() != "default" {
-                //split the topic hash to get the other peer's nickname
-                let parts: Vec<&str> = topic_hash.as_str().split('-').collect();
-                let nickname1 = parts[0].to_string();
-                let nickname2 = parts[1].to_string();
-                let other_peer_nickname;
-                let other_peer_id;
-                if nickname1 == own_nickname {
-                    other_peer_nickname = nickname2;
-                    other_peer_id = parts[3];
-                } else {
-                    other_peer_nickname = nickname1;
-                    other_peer_id = parts[2];
-                }
-                // send a leave message to the other peer
-                println!("Please rate {} before leaving the chatroom: -1, 0, 1", other_peer_nickname);
-                loop {
-                    match stdin.next_line().await {
-                        Ok(Some(line)) => {
-                            let trimmed = line.trim();
-                            if !trimmed.is_empty() {
-                                let rating = trimmed.to_string();
-                                if rating == "-1" || rating == "0" || rating == "1" {
-                                    // update the rating of the other peer in the Kademlia routing table
-                                    if let Ok(parsed_rating) = rating.parse::<i32>() {
-                                        if
-                                            let Ok(other_peer_id) =
-                                                libp2p::PeerId::from_str(other_peer_id)
-                                        {
-                                            update_peer_rating(
-                                                swarm,
-                                                other_peer_id,
-                                                parsed_rating,
-                                                state
-                                            ).await;
-                                        } else {
-                                            println!(
-                                                "Failed to parse PeerId from the given string."
-                                            );
-                                        }
-                                        println!(
-                                            "You have left the chatroom and rated {} with {}",
-                                            other_peer_id,
-                                            rating
-                                        );
-                                        break;
-                                    } else {
-                                        println!(
-                                            "Failed to parse rating. Please enter a valid number."
-                                        );
-                                    }
-                                } else {
-                                    println!("Please enter a valid rating: -1, 0, 1");
-                                }
-                            } else {
-                                println!("Rating cannot be empty. Please enter a valid rating.");
-                            }
-                        }
-
-                        Ok(None) => {
-                            println!("No input received. Please try again.");
-                        }
-                        Err(_) => {
-                            println!("Error reading input. Please try again.");
-                        }
-                    }
+            if let Some(mut room) = state.rooms.remove(topic_hash.as_str()) {
+                let local_peer_id = *swarm.local_peer_id();
+                // tell the room, then drop ourselves from the room record
+                room.remove_member(&local_peer_id);
+                state.rooms.insert(topic_hash.to_string(), room);
+                if let Err(e) = publish_gossip(swarm, state, topic_hash.clone(), &GossipMessage::MemberLeft(local_peer_id)) {
+                    tracing::warn!("Failed to announce leaving the room: {:?}", e);
+                }
+                if let Some(room) = state.rooms.get(topic_hash.as_str()) {
+                    publish_room_record(swarm, state.kademlia.room_quorum(), topic_hash.as_str(), room);
                 }
-                let default_topic = gossipsub::IdentTopic::new("default");
-                swarm.behaviour_mut().chat.gossipsub.unsubscribe(topic);
-                swarm.behaviour_mut().chat.gossipsub.subscribe(&default_topic).unwrap();
-                *topic = default_topic;
+                return_to_default(swarm, state, topic);
+                system!("You have left the chatroom.");
             } else {
-                println!("You are already in the default chatroom.");
+                system!("You are already in the default chatroom.");
             }
         }
 
-        // /request <file>
-        val if val.starts_with("/request") => {
-            // check that the user is already in a private room
+        "/switch" => switch(state, topic, parts.get(1).copied()),
+
+        // /kick or /ban <nickname>
+        "/kick" | "/ban" => {
+            let Some(nickname) = parts.get(1) else {
+                system!("Usage: {} <nickname>", parts[0]);
+                return;
+            };
+            let ban = parts[0] == "/ban";
             let topic_hash: TopicHash = topic.hash().clone();
-            if topic_hash.as_str() == "default" {
-                println!(
-                    "You are in a default room. Please connect with a peer before offering a file."
-                );
+            let local_peer_id = *swarm.local_peer_id();
+            let Some(room) = state.rooms.get(topic_hash.as_str()) else {
+                system!("You are not in a private room.");
+                return;
+            };
+            if room.creator != local_peer_id {
+                error!("Only the room's creator can kick or ban members.");
                 return;
             }
-            let parts: Vec<&str> = topic_hash.as_str().split('-').collect();
-            let nickname1 = parts[0].to_string();
-            let other_peer_id;
-            let own_peer_id = *swarm.local_peer_id();
-            if nickname1 == own_nickname {
-                other_peer_id = parts[3];
-            } else {
-                other_peer_id = parts[2];
-            }
-            let file_offer: Vec<&str> = val.split_whitespace().collect();
-            if file_offer.len() == 2 {
-                let file_path = file_offer[1].to_string();
-                if let Ok(other_peer_id) = libp2p::PeerId::from_str(other_peer_id) {
-                    swarm
-                        .behaviour_mut()
-                        .request_response.request_response.send_request(
-                            &other_peer_id,
-                            RequestType::FileRequest(file_path.clone(), own_peer_id)
-                        );
+            // Peers who already left can still be banned
+            let peer_id = match room.target(&local_peer_id, Some(nickname)) {
+                Ok(peer_id) => peer_id,
+                Err(e) => match state.find_peer(nickname).filter(|_| ban) {
+                    Some(peer_id) => peer_id,
+                    None => {
+                        error!("{e}");
+                        return;
+                    }
+                },
+            };
+            if peer_id == local_peer_id {
+                error!("Use /leave to leave the room yourself.");
+                return;
+            }
+            publish_roster(swarm, state, topic_hash.as_str(), Some(peer_id), ban);
+            // The roster goes out under the old key, so they still hear they were removed
+            room::rekey(swarm, state, topic_hash.as_str());
+            if let Some(room) = state.rooms.get(topic_hash.as_str()) {
+                publish_room_record(swarm, state.kademlia.room_quorum(), topic_hash.as_str(), room);
+            }
+            system!("{nickname} has been {} from the room.", if ban { "banned" } else { "kicked" });
+        }
+
+        // /topic, /topic set <text>, /topic clear
+        "/topic" => match parts.get(1).copied() {
+            None => room::show_topic(state, topic.hash().as_str()),
+            Some("set") if parts.len() > 2 => {
+                // a single quoted topic has its quotes taken off
+                let text = match &command.args[1..] {
+                    [text] => text.clone(),
+                    _ => command.rest_after(1).to_string(),
+                };
+                room::set_topic(swarm, state, topic.hash().as_str(), Some(text));
+            }
+            Some("clear") if parts.len() == 2 => room::set_topic(swarm, state, topic.hash().as_str(), None),
+            _ => system!("Usage: /topic, /topic set <text> or /topic clear"),
+        },
+
+        "/trades" => trade::list(state),
+
+        "/board" if parts.len() == 1 => board::list(swarm, state, own_nickname),
+
+        // /board connect <number> [passphrase], /board remove <number>
+        "/board" => {
+            match (parts.get(1).copied(), parts.get(2).and_then(|number| number.parse::<usize>().ok()), parts.len()) {
+                (Some("connect"), Some(number), 3 | 4) => {
+                    let Some(peer_id) = state.board.poster(number) else {
+                        system!("There is no post {number}. Type /board to see them.");
+                        return;
+                    };
+                    if peer_id == *swarm.local_peer_id() {
+                        error!("You cannot connect to yourself.");
+                        return;
+                    }
+                    // We already know who posted it, so skip the nickname lookup /connect starts with
+                    let details = connection_details(topic, state, own_nickname, parts.get(3).copied());
+                    let query_id = swarm.behaviour_mut().kademlia.get_record(kad::RecordKey::new(&peer_id.to_bytes()));
+                    state.pending_connections.insert(query_id, ConnectionRequest::PeerData(peer_id, details));
                 }
+                (Some("remove"), Some(number), 3) => board::remove(swarm, state, own_nickname, number),
+                _ => system!("Usage: /board, /board connect <number> [passphrase] or /board remove <number>"),
+            }
+        }
+
+        // /want <description>
+        "/want" => match command.rest.as_str() {
+            "" => system!("Usage: /want <description>"),
+            description => board::want(swarm, state, own_nickname, description),
+        },
+
+        // /have <file>
+        "/have" => match parts[..] {
+            [_, path] => board::have(swarm, state, own_nickname, path).await,
+            _ => system!("Usage: /have <file>"),
+        },
+
+        "/wishlist" => wishlist::list(state),
+
+        // /wish remove <number> or /wish <file name, hash or keyword>
+        "/wish" => match parts[..] {
+            [_, "remove", number] => match number.parse::<usize>() {
+                Ok(number) => wishlist::remove(state, number),
+                Err(_) => system!("Usage: /wish remove <number>"),
+            },
+            [_, _, ..] => wishlist::add(state, &command.rest),
+            _ => system!("Usage: /wish <file name, hash or keyword> or /wish remove <number>"),
+        },
+
+        // /get <id>
+        "/get" => match parts[..] {
+            [_, id] => match id.parse::<u32>() {
+                Ok(id) => wishlist::get(swarm, state, id),
+                Err(_) => system!("Usage: /get <id>"),
+            },
+            _ => system!("Usage: /get <id>"),
+        },
+
+        // /trade <accept|decline|confirm|cancel> <id>, /trade counter <id> <your file> <their file>, /trade rate <id> <rating>,
+        // or /trade [fair] <your file> <their file> [nickname]
+        "/trade" => {
+            match (parts.get(1).copied(), parts.get(2).and_then(|id| id.parse::<u32>().ok()), parts.len()) {
+                (Some("accept"), Some(id), 3) => negotiation::accept(swarm, state, id).await,
+                (Some("counter"), Some(id), 5) => negotiation::counter(swarm, state, id, parts[3], parts[4]).await,
+                (Some("decline" | "cancel"), Some(id), 3) => trade::cancel(swarm, state, id),
+                (Some("confirm"), Some(id), 3) => trade::confirm(swarm, state, id).await,
+                (Some("rate"), Some(id), 4) => match parse_rating(parts[3]) {
+                    Some(rating) => trade::rate(swarm, state, id, rating).await,
+                    None => system!("Usage: /trade rate <id> <-1|0|1>"),
+                },
+                (Some(_), _, 3..=5) => {
+                    // /trade fair <your file> <their file> [nickname] swaps sealed files first
+                    let (fair, files) = match parts[1] {
+                        "fair" => (true, &parts[2..]),
+                        _ => (false, &parts[1..]),
+                    };
+                    if !(2..=3).contains(&files.len()) {
+                        system!("Usage: /trade [fair] <your file> <their file> [nickname], /trade <accept|decline|confirm|cancel> <id>, /trade counter <id> <your file> <their file> or /trade rate <id> <-1|0|1>");
+                        return;
+                    }
+                    let Some(room) = state.rooms.get(topic.hash().as_str()) else {
+                        system!("You are in a default room. Please connect with a peer before proposing a trade.");
+                        return;
+                    };
+                    match room.target(swarm.local_peer_id(), files.get(2).copied()) {
+                        Ok(other_peer_id) => negotiation::propose(swarm, state, other_peer_id, files[0], files[1], fair).await,
+                        Err(e) => error!("{e}"),
+                    }
+                }
+                _ => system!("Usage: /trade [fair] <your file> <their file> [nickname], /trade <accept|decline|confirm|cancel> <id>, /trade counter <id> <your file> <their file> or /trade rate <id> <-1|0|1>"),
+            }
+        }
+
+        // /request <file> [nickname]
+        "/request" => {
+            if parts.len() == 2 || parts.len() == 3 {
+                request_file(swarm, topic, state, parts[1], parts.get(2).copied());
             } else {
-                println!("Usage: /offer <file>");
+                system!("Usage: /request <file> [nickname]");
+            }
+        }
+
+        // /take <id> <numbers|all|none>
+        "/take" => {
+            match (parts.get(1).and_then(|id| id.parse::<u32>().ok()), parts.len()) {
+                (Some(id), 3) => manifest::take(swarm, state, id, parts[2]),
+                _ => system!("Usage: /take <id> <numbers, like 1,3,4|all|none>"),
             }
         }
 
-        // /offer <file>
-        val if val.starts_with("/offer") => {
+        // /offer <file or pattern>... [nickname]
+        "/offer" => {
             // check that the user is already in a private room
             let topic_hash: TopicHash = topic.hash().clone();
-            if topic_hash.as_str() == "default" {
-                println!(
+            if !state.rooms.contains_key(topic_hash.as_str()) {
+                system!(
                     "You are in a default room. Please connect with a peer before offering a file."
                 );
                 return;
             }
-            let parts: Vec<&str> = topic_hash.as_str().split('-').collect();
-            let nickname1 = parts[0].to_string();
-            let other_peer_id;
-            if nickname1 == own_nickname {
-                other_peer_id = parts[3];
-            } else {
-                other_peer_id = parts[2];
-            }
-            let file_offer: Vec<&str> = val.split_whitespace().collect();
-            if file_offer.len() == 2 {
-                let file_path = file_offer[1].to_string();
-                match File::open(file_path.clone()).await {
-                    Ok(mut file) => {
-                        let mut buffer = Vec::new();
-                        // Read the file into a buffer
-                        if let Err(e) = file.read_to_end(&mut buffer).await {
-                            println!("Failed to read file: {:?}", e);
-                        }
-                        if let Ok(other_peer_id) = libp2p::PeerId::from_str(other_peer_id) {
-                            swarm
-                                .behaviour_mut()
-                                .request_response.request_response.send_request(
-                                    &other_peer_id,
-                                    RequestType::FileOffer(buffer, file_path.clone())
-                                );
-                        }
-                    }
-                    // If the file doesn't exist
-                    Err(_) => {
-                        println!("File not found.");
-                    }
-                };
-            } else {
-                println!("Usage: /offer <file>");
+            if parts.len() < 2 {
+                system!("Usage: /offer <file or pattern>... [nickname]");
+                return;
+            }
+            match manifest::parse_offer(&parts[1..]).await {
+                Ok((paths, nickname)) => offer_files(swarm, topic, state, &paths, nickname.as_deref()).await,
+                Err(e) => error!("{e}"),
             }
         }
-        _ => {
-            if
-                let Err(e) = swarm
-                    .behaviour_mut()
-                    .chat.gossipsub.publish(topic.clone(), line.as_bytes())
-            {
-                println!("Publish error: {:?}", e);
+        name => tracing::warn!("{name} has no handler"),
+    }
+}
+
+// Ask a private room member for a file: the one nickname names, or the only other one. The file name is used as
+// it is, so callers with one in hand, like the RPC server, don't have to quote it into a line
+pub fn request_file(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, topic: &gossipsub::IdentTopic, state: &mut ChatState, file: &str, nickname: Option<&str>) {
+    let Some(room) = state.rooms.get(topic.hash().as_str()) else {
+        system!("You are in a default room. Please connect with a peer before requesting a file.");
+        return;
+    };
+    let own_peer_id = *swarm.local_peer_id();
+    match room.target(&own_peer_id, nickname) {
+        Ok(other_peer_id) => {
+            outbound::send(swarm, state, other_peer_id, RequestType::FileRequest(file.to_string(), own_peer_id));
+        }
+        Err(e) => error!("{e}"),
+    }
+}
+
+// Offer files to a private room member, picked like request_file does. Several files go as a list the other peer
+// picks from
+pub async fn offer_files(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, topic: &gossipsub::IdentTopic, state: &mut ChatState, paths: &[String], nickname: Option<&str>) {
+    let Some(room) = state.rooms.get(topic.hash().as_str()) else {
+        system!("You are in a default room. Please connect with a peer before offering a file.");
+        return;
+    };
+    let other_peer_id = match room.target(swarm.local_peer_id(), nickname) {
+        Ok(other_peer_id) => other_peer_id,
+        Err(e) => {
+            error!("{e}");
+            return;
+        }
+    };
+    if paths.len() > 1 {
+        manifest::offer(swarm, state, other_peer_id, paths).await;
+        return;
+    }
+    let Some(file_path) = paths.first() else { return };
+    if !outbound::offer(swarm, state, other_peer_id, file_path, None).await {
+        error!("File not found.");
+    }
+}
+
+// Publish a chat message to the room we're in
+pub fn send_chat(line: &str, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, topic: &gossipsub::IdentTopic, state: &mut ChatState, own_nickname: &str) {
+    let body = pipeline::outgoing(state, line);
+    let chat_message = ChatMessage::new(own_nickname.to_string(), own_rating_hint(swarm, state), body.clone());
+    let message_id = chat_message.id.clone();
+    match publish_gossip(swarm, state, topic.hash(), &GossipMessage::Chat(chat_message)) {
+        Ok(_) => {
+            if topic.hash().as_str() != state.public_room {
+                state.awaiting_receipts.insert(message_id, body);
             }
         }
+        Err(gossipsub::PublishError::MessageTooLarge) => error!("That message is too long to send. Try splitting it up."),
+        Err(e) => error!("Publish error: {:?}", e),
+    }
+}
+
+// From a private room, invite the peer into that room; otherwise start a new one, with an optional passphrase
+// the other peer must enter to join it
+fn connection_details(topic: &gossipsub::IdentTopic, state: &ChatState, own_nickname: &str, passphrase: Option<&str>) -> ConnectionDetails {
+    let topic_hash: TopicHash = topic.hash().clone();
+    let (room_id, passphrase) = match state.rooms.get(topic_hash.as_str()) {
+        Some(room) => (topic_hash.to_string(), room.passphrase.clone()),
+        None => (Uuid::new_v4().to_string(), passphrase.map(str::to_string)),
+    };
+    ConnectionDetails { initiator_nickname: own_nickname.to_string(), room_id, passphrase }
+}
+
+// /stats: an overview of how we're connected and what's under way
+fn print_stats(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState) {
+    let info = swarm.network_info();
+    let routing_table: usize = swarm.behaviour_mut().kademlia.kbuckets().map(|bucket| bucket.num_entries()).sum();
+    let records = swarm.behaviour_mut().kademlia.store_mut().records().count();
+    system!("Network:");
+    system!("  Connected peers: {} ({} connections)", info.num_peers(), info.connection_counters().num_established());
+    system!("  DHT: {routing_table} peers in the routing table, {records} records stored");
+    state.servers.print();
+    state.registration.print();
+    let mut rooms = vec![state.public_room.clone()];
+    rooms.extend(state.rooms.keys().map(|room| output::room_label(room).to_string()));
+    system!("Rooms: {}", rooms.join(", "));
+    let (sent, queued) = state.outbound.counts();
+    system!(
+        "Waiting on you: {} room invites, {} file requests or offers",
+        state.incoming_invites.len(),
+        state.pending_approvals.len()
+    );
+    system!(
+        "Waiting on others: {} room invites, {sent} file requests or offers, {queued} more queued",
+        state.pending_invites.len()
+    );
+    if state.chunked.is_empty() {
+        system!("Transfers: none under way");
+    } else {
+        system!("Transfers:");
+        chunking::print_progress(state);
+    }
+}
+
+// /id: who we are and where we can be reached, each address ending in our peer ID so it can be dialled as it is
+fn print_own_addresses(swarm: &libp2p::Swarm<SwapBytesBehaviour>, own_nickname: &str) {
+    let peer_id = *swarm.local_peer_id();
+    system!("You are {own_nickname} ({peer_id})");
+    let listening: Vec<_> = swarm.listeners().cloned().collect();
+    // The placeholder added for rendezvous registration has port 0 and can't be dialled
+    let external: Vec<_> = swarm.external_addresses()
+        .filter(|address| !listening.contains(address) && !address.iter().any(|protocol| protocol == Protocol::Tcp(0)))
+        .cloned()
+        .collect();
+    let confirmed = !external.is_empty();
+    for (heading, addresses) in [("Listening on", listening), ("Confirmed reachable from outside at", external)] {
+        if addresses.is_empty() {
+            continue;
+        }
+        system!("{heading}:");
+        for address in addresses {
+            let address = address.clone().with_p2p(peer_id).unwrap_or(address);
+            system!("  {address}");
+        }
+    }
+    if !confirmed {
+        system!("None of your addresses has been confirmed reachable from outside your network yet.");
+    }
+}
+
+fn parse_rating(rating: &str) -> Option<i32> {
+    rating.parse::<i32>().ok().filter(|rating| (-1..=1).contains(rating))
+}
+
+// Close connections to every peer the connection policy now refuses, and drop what was waiting to be sent to them
+fn disconnect_refused(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState) {
+    let refused: Vec<PeerId> = swarm.connected_peers()
+        .chain(state.outbound.queued_peers())
+        .filter(|peer_id| !swarm.behaviour().gate.permits(peer_id))
+        .copied()
+        .collect();
+    for peer_id in refused {
+        state.reconnector.forget(&peer_id);
+        state.outbound.cancel(&peer_id);
+        let _ = swarm.disconnect_peer_id(peer_id);
     }
 }
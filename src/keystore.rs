@@ -0,0 +1,113 @@
+use std::{ error::Error, fs, path::Path };
+use libp2p::{ identity::Keypair, PeerId };
+use serde::{ Deserialize, Serialize };
+
+use crate::{ crypto, line_editor::LineReader, persist, util::PeerData };
+
+const KEYSTORE_VERSION: u8 = 1;
+
+// Everything needed to be the same peer on another machine. The profile keeps our nickname and the
+// signed vouches we've been given, so they can be republished under the same peer ID. Ratings stay with
+// their raters, under our peer ID, so they follow us anyway
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredIdentity {
+    pub keypair: Vec<u8>, // Protobuf-encoded identity keypair
+    pub profile: Option<PeerData>,
+    #[serde(default)]
+    pub trusted: Vec<PeerId>, // Peers we have vouched for
+}
+
+impl StoredIdentity {
+    pub fn new(keypair: &Keypair, profile: Option<PeerData>, trusted: Vec<PeerId>) -> Self {
+        StoredIdentity {
+            keypair: keypair.to_protobuf_encoding().expect("Encoding the keypair failed"),
+            profile,
+            trusted,
+        }
+    }
+
+    pub fn keypair(&self) -> Result<Keypair, Box<dyn Error>> {
+        Ok(Keypair::from_protobuf_encoding(&self.keypair)?)
+    }
+}
+
+// The exported file: a StoredIdentity sealed under a key derived from a passphrase
+#[derive(Serialize, Deserialize)]
+struct EncryptedKeystore {
+    version: u8,
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+// Read the identity we run as from --identity, creating a new one there if the file doesn't exist yet
+pub fn load_or_create(path: &Path) -> Result<StoredIdentity, Box<dyn Error>> {
+    if path.exists() {
+        return Ok(serde_json::from_slice(&fs::read(path)?)?);
+    }
+    let identity = StoredIdentity::new(&Keypair::generate_ed25519(), None, Vec::new());
+    save(path, &identity)?;
+    system!("Created a new identity in {}", path.display());
+    Ok(identity)
+}
+
+// Write an identity where only we can read it, replacing the file whole so a crash can't lose the keypair
+pub fn save(path: &Path, identity: &StoredIdentity) -> Result<(), Box<dyn Error>> {
+    persist::write_private(path, &serde_json::to_vec(identity)?)?;
+    Ok(())
+}
+
+// Encrypt an identity with a passphrase and write it to a file
+pub fn export(path: &Path, identity: &StoredIdentity, passphrase: &str) -> Result<(), Box<dyn Error>> {
+    let salt = crypto::generate_salt();
+    let (nonce, ciphertext) = crypto::seal(&crypto::derive_keystore_key(passphrase, &salt), &serde_json::to_vec(identity)?);
+    let keystore = EncryptedKeystore { version: KEYSTORE_VERSION, salt, nonce, ciphertext };
+    persist::write_private(path, &serde_json::to_vec(&keystore)?)?;
+    Ok(())
+}
+
+// Read and decrypt an exported identity
+pub fn import(path: &Path, passphrase: &str) -> Result<StoredIdentity, Box<dyn Error>> {
+    let keystore: EncryptedKeystore = serde_json::from_slice(&fs::read(path)?)?;
+    if keystore.version != KEYSTORE_VERSION {
+        return Err(format!("Unsupported keystore version {}", keystore.version).into());
+    }
+    let plaintext = crypto::open(&crypto::derive_keystore_key(passphrase, &keystore.salt), keystore.nonce, &keystore.ciphertext)
+        .ok_or("Wrong passphrase, or the keystore is damaged")?;
+    let identity: StoredIdentity = serde_json::from_slice(&plaintext)?;
+    // Make sure the keypair inside is usable before anyone relies on it
+    identity.keypair()?;
+    Ok(identity)
+}
+
+// `swapbytes identity export <file>`: encrypt the identity kept in the --identity file
+pub async fn export_command(identity_path: &Path, file: &Path) -> Result<(), Box<dyn Error>> {
+    let identity: StoredIdentity = serde_json::from_slice(&fs::read(identity_path)
+        .map_err(|e| format!("Could not read {}: {e}", identity_path.display()))?)?;
+    let mut stdin = LineReader::new(false);
+    let passphrase = read_passphrase(&mut stdin, "Choose a passphrase to protect the export:").await?;
+    export(file, &identity, &passphrase)?;
+    system!("Identity {} exported to {}", identity.keypair()?.public().to_peer_id(), file.display());
+    Ok(())
+}
+
+// `swapbytes identity import <file>`: decrypt an export into the --identity file
+pub async fn import_command(identity_path: &Path, file: &Path) -> Result<(), Box<dyn Error>> {
+    if identity_path.exists() {
+        return Err(format!("{} already exists. Move it out of the way before importing over it.", identity_path.display()).into());
+    }
+    let mut stdin = LineReader::new(false);
+    let passphrase = read_passphrase(&mut stdin, "Enter the passphrase for the export:").await?;
+    let identity = import(file, &passphrase)?;
+    save(identity_path, &identity)?;
+    system!("Imported identity {} into {}", identity.keypair()?.public().to_peer_id(), identity_path.display());
+    Ok(())
+}
+
+pub async fn read_passphrase(stdin: &mut LineReader, prompt: &str) -> Result<String, Box<dyn Error>> {
+    system!("{prompt}");
+    match stdin.next_line().await? {
+        Some(line) if !line.trim().is_empty() => Ok(line.trim().to_string()),
+        _ => Err("A passphrase is required.".into()),
+    }
+}
@@ -0,0 +1,82 @@
+use std::path::PathBuf;
+use libp2p::PeerId;
+use serde::{ Deserialize, Serialize };
+
+use crate::{ audit::SignedTradeReceipt, persist::{ unix_now, JsonFile }, trade::FileMeta };
+
+// A trade that went through: what each side gave up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub peer: PeerId,
+    pub timestamp: u64,
+    pub sent: FileMeta,
+    pub received: FileMeta,
+    pub fair: bool,
+    #[serde(default)]
+    pub trade_id: String,
+    #[serde(default)]
+    pub rating: Option<i32>, // What we rated them for this trade, if we did
+    #[serde(default)]
+    pub receipt: Option<SignedTradeReceipt>, // The one they signed for it, once it arrives
+}
+
+// Completed trades, by counterparty, kept apart from the transfer history so a trading relationship can be
+// seen at a glance
+#[derive(Default)]
+pub struct TradeLedger {
+    pub entries: Vec<LedgerEntry>,
+    file: JsonFile<Vec<LedgerEntry>>,
+}
+
+impl TradeLedger {
+    // Load the ledger kept in a file, saving every new trade back to it
+    pub fn load(file: PathBuf) -> Self {
+        let (file, entries) = JsonFile::open(file, "your trade ledger");
+        TradeLedger { entries, file }
+    }
+
+    fn save(&self) {
+        self.file.save(&self.entries);
+    }
+
+    pub fn record(&mut self, peer: PeerId, trade_id: String, sent: FileMeta, received: FileMeta, fair: bool, receipt: Option<SignedTradeReceipt>) {
+        self.entries.push(LedgerEntry { peer, timestamp: unix_now(), sent, received, fair, trade_id, rating: None, receipt });
+        self.save();
+    }
+
+    // Keep the receipt a peer signed for a trade that's already in the ledger
+    pub fn attach(&mut self, peer: &PeerId, receipt: SignedTradeReceipt) {
+        let Some(entry) = self.entries.iter_mut().find(|entry| entry.peer == *peer && entry.trade_id == receipt.trade_id) else { return };
+        entry.receipt = Some(receipt);
+        self.save();
+    }
+
+    // The receipt for our latest trade with a peer, to back up a rating of them
+    pub fn latest_receipt(&self, peer: &PeerId) -> Option<SignedTradeReceipt> {
+        self.entries.iter().rev().filter(|entry| entry.peer == *peer).find_map(|entry| entry.receipt.clone())
+    }
+
+    // Note the rating we gave a peer for one trade, replacing any earlier one for it
+    pub fn rate(&mut self, peer: &PeerId, trade_id: &str, rating: i32) {
+        let Some(entry) = self.entries.iter_mut().find(|entry| entry.peer == *peer && entry.trade_id == trade_id) else { return };
+        entry.rating = Some(rating);
+        self.save();
+    }
+
+    // Our trades with a peer, for /whois
+    pub fn print(&self, peer: &PeerId) {
+        let entries: Vec<_> = self.entries.iter().filter(|entry| entry.peer == *peer).collect();
+        if entries.is_empty() {
+            system!("  Your trades with them: none yet");
+            return;
+        }
+        let (sent, received) = entries.iter().fold((0, 0), |(sent, received), entry| (sent + entry.sent.size, received + entry.received.size));
+        system!("  Your trades with them: {}, you gave {sent} bytes and got {received} bytes", entries.len());
+        for entry in entries {
+            let fair = if entry.fair { " (fair)" } else { "" };
+            let rating = entry.rating.map(|rating| format!(", rated {rating}")).unwrap_or_default();
+            let receipt = if entry.receipt.is_some() { ", receipt signed" } else { "" };
+            system!("    [{}] your {} for their {}{fair}{rating}{receipt}", entry.timestamp, entry.sent, entry.received);
+        }
+    }
+}
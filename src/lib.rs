@@ -0,0 +1,69 @@
+// SwapBytes as a library: start a node with SwapBytesNode::new and drive it with next_event and handle. The
+// swapbytes binary is one user of this API. Modules marked doc(hidden) are the binary's own parts (its command line,
+// config file and the servers it can run instead of a node) and may change at any time
+#[macro_use]
+#[doc(hidden)]
+pub mod output;
+mod address_book;
+mod approval;
+mod audit;
+mod bench;
+mod behaviour;
+#[doc(hidden)]
+pub mod bootstrap;
+mod board;
+mod chunking;
+mod codec;
+mod command;
+mod completion;
+#[doc(hidden)]
+pub mod config;
+mod connections;
+#[doc(hidden)]
+pub mod control;
+mod crypto;
+mod discovery;
+mod emoji;
+mod filenames;
+mod history;
+#[doc(hidden)]
+pub mod keystore;
+mod ledger;
+mod line_editor;
+#[doc(hidden)]
+pub mod logging;
+mod manifest;
+mod negotiation;
+mod node;
+mod outbound;
+#[doc(hidden)]
+pub mod notify;
+mod util;
+mod vault;
+mod wishlist;
+mod input;
+mod pipeline;
+mod persist;
+mod policy;
+mod ratelimit;
+mod rating;
+mod reconnect;
+#[doc(hidden)]
+pub mod relay;
+mod render;
+mod room;
+#[doc(hidden)]
+pub mod rpc;
+mod scan;
+#[doc(hidden)]
+pub mod server;
+mod status;
+mod store;
+mod trade;
+mod tui;
+
+pub use node::{ NodeConfig, NodeEvent, SwapBytesNode };
+pub use output::{ Event, Output, RoomEvent };
+pub use util::{ FilterOptions, GossipOptions, KademliaOptions };
+#[doc(hidden)]
+pub use util::{ Cli, Command, IdentityCommand, DEFAULT_IDENTITY_FILE };
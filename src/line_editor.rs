@@ -0,0 +1,384 @@
+use std::{ fs, io::{ Read, Write }, mem::MaybeUninit, path::PathBuf };
+use tokio::{ io::{ self, AsyncBufReadExt }, sync::mpsc };
+
+use crate::{ output, persist, tui };
+
+// Lines kept for Up, Down and Ctrl-R. The file can grow to twice this before it's cut back
+const MAX_HISTORY: usize = 1000;
+
+// What was typed: a whole line, or Tab part way through one
+pub enum Typed {
+    Line(String),
+    Tab,
+}
+
+// Lines typed at the terminal. When stdin is a terminal we read it a key at a time, so the arrow keys can go
+// through the history, Tab can complete what's being typed and lines printed meanwhile don't break it up. Pipes,
+// --json and daemons read whole lines as before
+pub struct LineReader {
+    source: Source,
+}
+
+enum Source {
+    Lines(io::Lines<io::BufReader<io::Stdin>>),
+    Keys(Editor),
+}
+
+struct Editor {
+    keys: mpsc::UnboundedReceiver<Vec<u8>>,
+    typed: String,
+    pending: Vec<u8>, // The start of a character that's more than one byte long
+    escape: Escape,
+    unread: Vec<u8>, // Keys that came after the last Enter or Tab
+    saved: libc::termios, // How the terminal was set up before, put back on drop
+    history: Vec<String>, // Lines entered, oldest first
+    history_file: Option<PathBuf>,
+    browsing: Option<usize>, // The history line shown after pressing Up
+    draft: String, // What was typed before pressing Up, back after pressing Down past the newest line
+    search: Option<Search>,
+}
+
+// Ctrl-R: the newest history line containing what's typed is shown, and Ctrl-R again goes to older ones
+struct Search {
+    query: String,
+    found: Option<usize>,
+}
+
+enum Escape {
+    None,
+    Started, // After ESC
+    Sequence, // After ESC [, until the byte that ends it
+}
+
+impl LineReader {
+    // Editing is only turned on if asked for and stdin really is a terminal
+    pub fn new(editing: bool) -> Self {
+        let source = match editing.then(Editor::start).flatten() {
+            Some(editor) => Source::Keys(editor),
+            None => Source::Lines(io::BufReader::new(io::stdin()).lines()),
+        };
+        LineReader { source }
+    }
+
+    // Whether keys are being read from a terminal, rather than lines from a pipe or file
+    pub fn editing(&self) -> bool {
+        matches!(self.source, Source::Keys(_))
+    }
+
+    // The next line, with Tab ignored. For prompts, which have nothing to complete
+    pub async fn next_line(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.next_typed().await? {
+                Some(Typed::Line(line)) => return Ok(Some(line)),
+                Some(Typed::Tab) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // The next line or press of Tab. Cancelling this loses nothing: what's been typed is kept for the next call
+    pub async fn next_typed(&mut self) -> io::Result<Option<Typed>> {
+        match &mut self.source {
+            Source::Lines(lines) => Ok(lines.next_line().await?.map(Typed::Line)),
+            Source::Keys(editor) => Ok(editor.next().await),
+        }
+    }
+
+    // Load the lines entered in earlier runs from a file, and add new ones to it. Only a terminal has history
+    pub fn keep_history(&mut self, file: PathBuf) {
+        if let Source::Keys(editor) = &mut self.source {
+            let contents = fs::read_to_string(&file).unwrap_or_default();
+            let lines: Vec<&str> = contents.lines().filter(|line| !line.is_empty()).collect();
+            editor.history = lines[lines.len().saturating_sub(MAX_HISTORY)..].iter().map(|line| line.to_string()).collect();
+            if lines.len() > MAX_HISTORY {
+                let kept: String = editor.history.iter().map(|line| format!("{line}\n")).collect();
+                if let Err(e) = persist::write_private(&file, kept.as_bytes()) {
+                    tracing::debug!("Could not cut back the input history in {}: {e}", file.display());
+                }
+            }
+            editor.history_file = Some(file);
+        }
+    }
+
+    // Add a line to the history, unless it's empty, starts with a space (to keep it out) or repeats the last one.
+    // Only lines given as input are remembered, so answers to prompts such as passphrases never are
+    pub fn remember(&mut self, line: &str) {
+        let Source::Keys(editor) = &mut self.source else { return };
+        if line.trim().is_empty() || line.starts_with(' ') || editor.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        editor.history.push(line.to_string());
+        if editor.history.len() > MAX_HISTORY {
+            editor.history.remove(0);
+        }
+        if let Some(file) = &editor.history_file
+            && let Err(e) = persist::append_private(file, line) {
+            tracing::debug!("Could not save the input history to {}: {e}", file.display());
+        }
+    }
+
+    // What's been typed on the current line so far
+    pub fn typed(&self) -> &str {
+        match &self.source {
+            Source::Lines(_) => "",
+            Source::Keys(editor) => &editor.typed,
+        }
+    }
+
+    // Swap the end of the current line, from byte `start`, for a completion
+    pub fn replace_from(&mut self, start: usize, text: &str) {
+        if let Source::Keys(editor) = &mut self.source {
+            editor.typed.truncate(start);
+            editor.typed.push_str(text);
+            output::show_input(&editor.typed);
+        }
+    }
+}
+
+impl Editor {
+    // Stop the terminal buffering and echoing lines so keys reach us as they're pressed. Signals still work, so
+    // Ctrl-C quits as before
+    fn start() -> Option<Self> {
+        let mut saved = MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: tcgetattr fills in the struct when it returns 0, and isatty only looks at the descriptor
+        let saved = unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 || libc::tcgetattr(libc::STDIN_FILENO, saved.as_mut_ptr()) != 0 {
+                return None;
+            }
+            saved.assume_init()
+        };
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: raw is a valid termios copied from the one the terminal gave us
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return None;
+        }
+        // Reading stdin blocks, so it gets a thread of its own. It ends with the process
+        let (sender, keys) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            let mut stdin = std::io::stdin();
+            let mut buffer = [0; 256];
+            while let Ok(read @ 1..) = stdin.read(&mut buffer) {
+                if sender.send(buffer[..read].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        output::show_input("");
+        Some(Editor {
+            keys,
+            typed: String::new(),
+            pending: Vec::new(),
+            escape: Escape::None,
+            unread: Vec::new(),
+            saved,
+            history: Vec::new(),
+            history_file: None,
+            browsing: None,
+            draft: String::new(),
+            search: None,
+        })
+    }
+
+    async fn next(&mut self) -> Option<Typed> {
+        loop {
+            if self.unread.is_empty() {
+                self.unread = self.keys.recv().await?;
+            }
+            let keys = std::mem::take(&mut self.unread);
+            for (index, key) in keys.iter().enumerate() {
+                if let Some(typed) = self.press(*key) {
+                    self.unread = keys[index + 1..].to_vec();
+                    return Some(typed);
+                }
+            }
+        }
+    }
+
+    // Apply one byte of input. Returns what the user finished typing, if that was Enter or Tab
+    fn press(&mut self, key: u8) -> Option<Typed> {
+        // Arrow and function keys come as escape sequences. Up and Down go through the history, the rest are skipped
+        match (&self.escape, key) {
+            (Escape::Started, b'[' | b'O') => {
+                self.escape = Escape::Sequence;
+                return None;
+            }
+            (Escape::Started, _) => {
+                self.escape = Escape::None;
+                return None;
+            }
+            (Escape::Sequence, 0x40..=0x7e) => {
+                self.escape = Escape::None;
+                match key {
+                    b'A' => self.older(),
+                    b'B' => self.newer(),
+                    _ => return None,
+                }
+                output::show_input(&self.typed);
+                return None;
+            }
+            (Escape::Sequence, _) => return None,
+            (Escape::None, _) => {}
+        }
+        if self.search.is_some() {
+            return self.press_searching(key);
+        }
+        match key {
+            b'\r' | b'\n' => return Some(self.enter()),
+            b'\t' => return Some(Typed::Tab),
+            0x1b => self.escape = Escape::Started,
+            // Ctrl-R searches the history
+            0x12 => {
+                self.search = Some(Search { query: String::new(), found: None });
+                self.show_search();
+                return None;
+            }
+            // Backspace
+            0x7f | 0x08 => {
+                self.typed.pop();
+            }
+            // Ctrl-U clears the line, Ctrl-W the last word
+            0x15 => self.typed.clear(),
+            0x17 => {
+                let end = self.typed.trim_end().len();
+                let start = self.typed[..end].rfind(char::is_whitespace).map_or(0, |space| space + 1);
+                self.typed.truncate(start);
+            }
+            ..0x20 => return None,
+            _ => match self.character(key) {
+                Some(character) => self.typed.push_str(&character),
+                None => return None,
+            },
+        }
+        output::show_input(&self.typed);
+        None
+    }
+
+    // A key while searching the history. Enter runs the line found and Ctrl-G goes back to what was typed; any other
+    // key that isn't part of the search leaves the line found to be edited
+    fn press_searching(&mut self, key: u8) -> Option<Typed> {
+        let search = self.search.as_mut()?;
+        match key {
+            0x12 => {
+                let before = search.found.unwrap_or(self.history.len());
+                search.found = find(&self.history, &search.query, before).or(search.found);
+            }
+            0x7f | 0x08 => {
+                search.query.pop();
+                search.found = find(&self.history, &search.query, self.history.len());
+            }
+            0x07 => {
+                self.search = None;
+                output::show_input(&self.typed);
+                return None;
+            }
+            0x20.. => {
+                let character = self.character(key)?;
+                let search = self.search.as_mut()?;
+                search.query.push_str(&character);
+                search.found = find(&self.history, &search.query, search.found.map_or(self.history.len(), |found| found + 1));
+            }
+            _ => {
+                if let Some(found) = self.search.take().and_then(|search| search.found) {
+                    self.typed = self.history[found].clone();
+                    self.browsing = Some(found);
+                }
+                return match key {
+                    b'\r' | b'\n' => Some(self.enter()),
+                    0x1b => {
+                        self.escape = Escape::Started;
+                        output::show_input(&self.typed);
+                        None
+                    }
+                    _ => {
+                        output::show_input(&self.typed);
+                        None
+                    }
+                };
+            }
+        }
+        self.show_search();
+        None
+    }
+
+    fn show_search(&self) {
+        if let Some(search) = &self.search {
+            let found = search.found.map_or("", |found| self.history[found].as_str());
+            output::show_input(&format!("(search) {}: {found}", search.query));
+        }
+    }
+
+    // One byte of a character. Returns the character once all of its bytes are in
+    fn character(&mut self, key: u8) -> Option<String> {
+        self.pending.push(key);
+        match std::str::from_utf8(&self.pending) {
+            Ok(character) => {
+                let character = character.to_string();
+                self.pending.clear();
+                Some(character)
+            }
+            Err(e) if e.error_len().is_some() => {
+                self.pending.clear();
+                None
+            }
+            Err(_) => None, // More bytes to come
+        }
+    }
+
+    fn enter(&mut self) -> Typed {
+        let line = std::mem::take(&mut self.typed);
+        self.browsing = None;
+        self.draft.clear();
+        if !tui::active() {
+            println!();
+        }
+        output::show_input("");
+        Typed::Line(line)
+    }
+
+    // Up: the line entered before the one shown
+    fn older(&mut self) {
+        let index = match self.browsing {
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.typed.clone();
+                self.history.len() - 1
+            }
+            Some(0) => return,
+            Some(index) => index - 1,
+        };
+        self.browsing = Some(index);
+        self.typed = self.history[index].clone();
+    }
+
+    // Down: the line entered after the one shown, or what was being typed before Up
+    fn newer(&mut self) {
+        match self.browsing {
+            None => {}
+            Some(index) if index + 1 < self.history.len() => {
+                self.browsing = Some(index + 1);
+                self.typed = self.history[index + 1].clone();
+            }
+            Some(_) => {
+                self.browsing = None;
+                self.typed = std::mem::take(&mut self.draft);
+            }
+        }
+    }
+}
+
+// The newest history line before `before` that contains the query
+fn find(history: &[String], query: &str, before: usize) -> Option<usize> {
+    history[..before.min(history.len())].iter().rposition(|line| line.contains(query))
+}
+
+impl Drop for Editor {
+    fn drop(&mut self) {
+        output::hide_input();
+        // SAFETY: saved is the termios the terminal had before we changed it
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved) };
+        let _ = std::io::stdout().flush();
+    }
+}
@@ -0,0 +1,79 @@
+use std::{ error::Error, fmt::{ self, Write as _ }, fs::OpenOptions, io::{ self, Write }, path::Path, sync::Mutex };
+use tracing::{ field::{ Field, Visit }, level_filters::LevelFilter, span, subscriber::Subscriber, Event, Level, Metadata };
+
+use crate::persist;
+
+// Diagnostics (ours and libp2p's) go to stderr, or to a --log-file, so they stay out of the chat on stdout
+struct Logger {
+    max_level: Level,
+    sink: Mutex<Box<dyn Write + Send>>,
+    timestamps: bool, // Terminal output is read live, so only log files get timestamps
+}
+
+// Send tracing events at or above `max_level` to stderr or, when given, the end of `file`
+pub fn init(max_level: Level, file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let sink: Box<dyn Write + Send> = match file {
+        Some(file) => Box::new(OpenOptions::new().create(true).append(true).open(file)
+            .map_err(|e| format!("Could not open log file {}: {e}", file.display()))?),
+        None => Box::new(io::stderr()),
+    };
+    let logger = Logger { max_level, sink: Mutex::new(sink), timestamps: file.is_some() };
+    tracing::subscriber::set_global_default(logger)?;
+    Ok(())
+}
+
+// Collects an event's message followed by its other fields as key=value pairs
+#[derive(Default)]
+struct Fields {
+    message: String,
+    rest: String,
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.rest, " {}={value:?}", field.name());
+        }
+    }
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::from_level(self.max_level))
+    }
+
+    // Spans aren't shown, so they all share one ID
+    fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        let mut line = String::new();
+        if self.timestamps {
+            let now = persist::unix_now();
+            let _ = write!(line, "{now} ");
+        }
+        let text = format!("{}{}", fields.message, fields.rest);
+        let _ = writeln!(line, "{:>5} {}: {}", metadata.level(), metadata.target(), text.trim_start());
+        let mut sink = self.sink.lock().unwrap();
+        let _ = sink.write_all(line.as_bytes());
+        let _ = sink.flush();
+    }
+
+    fn enter(&self, _: &span::Id) {}
+
+    fn exit(&self, _: &span::Id) {}
+}
@@ -1,162 +1,151 @@
-mod behaviour;
-mod util;
-mod input;
-
-use futures::StreamExt;
-use util::{ Cli, get_and_save_nickname, ChatState };
-use input::handle_input;
-use behaviour::{create_swapbytes_behaviour, handle_chat_event, handle_kademlia_event, handle_req_res_event, RendezvousBehaviourEvent, RequestResponseBehaviourEvent, SwapBytesBehaviourEvent};
 use clap::Parser;
-use libp2p::{ gossipsub, kad, multiaddr::Protocol, noise, rendezvous, swarm::SwarmEvent, tcp, yamux, Multiaddr, PeerId };
-use std::{ collections::HashMap, error::Error, time::Duration };
-use tokio::{io::{ self, AsyncBufReadExt }, select, time::MissedTickBehavior};
+use std::{ error::Error, path::PathBuf, time::Duration };
+use swapbytes::{
+    bootstrap,
+    config::{ self, ProfileDir },
+    control, keystore, logging, notify, output::{ self, Output }, relay, rpc, server,
+    Cli, Command, IdentityCommand, NodeConfig, SwapBytesNode, DEFAULT_IDENTITY_FILE,
+};
+use tokio::{ select, signal, sync::mpsc };
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
-
-    // Generates the swarm used to connect and communicate with peers
-    let mut swarm = libp2p::SwarmBuilder
-        ::with_new_identity()
-        .with_tokio()
-        .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)?
-        .with_quic()
-        .with_behaviour(|key| {
-            create_swapbytes_behaviour(key).expect("Failed to create combined behaviour")
-        })?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
-        .build();
+    let mut cli = Cli::parse();
+    config::apply(&mut cli)?;
+    if cli.no_color == Some(true) || cli.json == Some(true) || std::env::var_os("NO_COLOR").is_some() {
+        output::set_colors(false);
+    }
+    // Everything printed from here on goes to the node's frontends too
+    let output = Output::new(cli.json == Some(true));
+    output.scope(run(cli, output.clone())).await
+}
 
-    let mut state = ChatState {
-        pending_messages: HashMap::new(),
-        pending_connections: HashMap::new(),
-        pending_rating_update: HashMap::new(),
-        rendezvous: "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"
-                .parse::<PeerId>()
-                .unwrap(),
+async fn run(mut cli: Cli, output: Output) -> Result<(), Box<dyn Error>> {
+    let daemon_socket = match &cli.command {
+        Some(Command::Daemon { socket }) => Some(socket.clone().unwrap_or(PathBuf::from(control::DEFAULT_SOCKET))),
+        _ => None,
     };
+    output::set_prompts(cli.json != Some(true) && daemon_socket.is_none());
+    if let Some(command) = cli.notify.clone() {
+        notify::set_command(command);
+    }
+    logging::init(cli.log_level.unwrap_or(tracing::Level::WARN), cli.log_file.as_deref())?;
+    // A profile keeps its identity, records, known peers and connection policy in its own directory unless the flags say otherwise
+    let profile_dir = match &cli.profile {
+        Some(name) => Some(ProfileDir::open(name)?),
+        None => None,
+    };
+    if let Some(profile_dir) = &profile_dir {
+        cli.identity = cli.identity.take().or(Some(profile_dir.identity()));
+        cli.records = cli.records.take().or(Some(profile_dir.records()));
+        cli.peers = cli.peers.take().or(Some(profile_dir.peers()));
+        cli.policy = cli.policy.take().or(Some(profile_dir.policy()));
+    }
 
-    // Creates a chatroom to be used by all connected peers by default
-    let mut topic = gossipsub::IdentTopic::new("default");
-
-    swarm.behaviour_mut().chat.gossipsub.subscribe(&topic)?;
-    swarm.behaviour_mut().kademlia.set_mode(Some(kad::Mode::Server));
-
-    // Rendezvous server
-    let rendezvous_addr = cli.server.unwrap_or("127.0.0.1".to_string());
-    let rendezvous_point_address = format!("/ip4/{}/tcp/62649", rendezvous_addr)
-        .parse::<Multiaddr>()
-        .unwrap();
-
-    let external_address = format!("/ip4/{}/tcp/0", rendezvous_addr)
-        .parse::<Multiaddr>()
-        .unwrap();
-    swarm.add_external_address(external_address);
-    swarm.dial(rendezvous_point_address.clone()).unwrap();
-
-    // Discovery ping goes off every 30 seconds
-    let mut discover_tick = tokio::time::interval(Duration::from_secs(30));
-    discover_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
-
-    // Configures the peer to listen for incoming connection on tcp and udp over quic
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-    swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
-
-    // Sets up a buffered reader to handle input from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-
-    let listen_port = cli.port.unwrap_or("0".to_string());
-    let multiaddr = format!("/ip4/0.0.0.0/tcp/{listen_port}");
-    let _ = swarm.listen_on(multiaddr.parse()?)?;
-
-    let peer_id = *swarm.local_peer_id();
-    let nickname = get_and_save_nickname(&mut stdin, peer_id, &mut swarm).await;
+    if let Some(Command::Identity(command)) = &cli.command {
+        let identity_path = cli.identity.clone().unwrap_or(PathBuf::from(DEFAULT_IDENTITY_FILE));
+        return match command {
+            IdentityCommand::Export { file } => keystore::export_command(&identity_path, file).await,
+            IdentityCommand::Import { file } => keystore::import_command(&identity_path, file).await,
+        };
+    }
+    if let Some(Command::Ctl { socket, wait, command }) = &cli.command {
+        let socket = socket.clone().unwrap_or(PathBuf::from(control::DEFAULT_SOCKET));
+        return control::send(&socket, &command.join(" "), Duration::from_secs(*wait)).await;
+    }
+    if let Some(Command::Serve { port, options }) = &cli.command {
+        return server::run(*port, cli.identity.clone(), options.clone()).await;
+    }
+    if let Some(Command::Bootstrap { port, records, options, limits }) = &cli.command {
+        let records = records.clone().unwrap_or(PathBuf::from(bootstrap::DEFAULT_RECORDS_FILE));
+        return bootstrap::run(*port, cli.identity.clone(), records, options.clone(), limits.clone()).await;
+    }
+    if let Some(Command::Relay { port, limits, status }) = &cli.command {
+        return relay::run(*port, cli.identity.clone(), limits.clone(), *status).await;
+    }
+    // Commands from `swapbytes ctl` clients, when running as a daemon
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+    if let Some(socket) = &daemon_socket {
+        tokio::spawn(control::serve(control::listen(socket)?, control_tx, output.clone()));
+    } else {
+        drop(control_tx);
+    }
+    // Calls from JSON-RPC clients, when --rpc is given
+    let (rpc_tx, mut rpc_rx) = mpsc::unbounded_channel();
+    if let Some(address) = cli.rpc {
+        let token_file = profile_dir.as_ref().map(ProfileDir::rpc_token).unwrap_or(PathBuf::from(rpc::DEFAULT_TOKEN_FILE));
+        rpc::serve(address, cli.rpc_token.clone(), &token_file, rpc_tx, output.clone()).await?;
+    } else {
+        drop(rpc_tx);
+    }
 
-    loop {
+    match &profile_dir {
+        Some(profile_dir) => output::print(output::Kind::System, &format!("Using profile {}", profile_dir.root.display())),
+        // Only profiles have somewhere to keep it, so say so rather than lose it quietly at exit
+        None => output::print(output::Kind::Error, "Without --profile, your audit log and private rooms are only kept until SwapBytes exits."),
+    }
+    // Never the directory SwapBytes happens to be started in, which could hold anything
+    let share_dir = match cli.share_dir.clone() {
+        Some(share_dir) => share_dir,
+        None => {
+            let share_dir = profile_dir.as_ref().map_or_else(config::default_share_dir, ProfileDir::share);
+            output::print(output::Kind::System, &format!("Peers can /request the files you put in {}", share_dir.display()));
+            share_dir
+        }
+    };
+    let mut node = SwapBytesNode::new(NodeConfig {
+        nickname: cli.nickname.clone(),
+        port: cli.port.clone(),
+        server: cli.server.clone(),
+        server_peer: cli.server_peer.clone(),
+        identity: cli.identity.clone(),
+        records: cli.records.clone(),
+        peers: cli.peers.clone(),
+        policy: cli.policy.clone(),
+        history: profile_dir.as_ref().map(ProfileDir::history),
+        audit: profile_dir.as_ref().map(ProfileDir::audit),
+        ledger: profile_dir.as_ref().map(ProfileDir::ledger),
+        wishlist: profile_dir.as_ref().map(ProfileDir::wishlist),
+        rooms: profile_dir.as_ref().map(ProfileDir::rooms),
+        input_history: daemon_socket.is_none().then(|| profile_dir.as_ref().map_or_else(config::default_input_history, ProfileDir::input_history)),
+        download_dir: cli.download_dir.clone().or(profile_dir.as_ref().map(ProfileDir::downloads)),
+        share_dir: Some(share_dir),
+        encrypt_downloads: cli.encrypt_downloads.unwrap_or_default(),
+        scan_command: cli.scan_command.clone(),
+        quarantine_dir: cli.quarantine_dir.clone(),
+        namespace: cli.namespace.clone(),
+        registration_ttl: cli.registration_ttl,
+        ping_interval: cli.ping_interval,
+        auto_accept: cli.auto_accept,
+        accept_extensions: cli.accept_extensions.clone(),
+        max_offer_size: cli.max_offer_size,
+        rate_limit: cli.rate_limit,
+        auto_mute: cli.auto_mute.unwrap_or_default(),
+        trade_timeout: cli.trade_timeout,
+        gossip: cli.gossip.clone(),
+        kademlia: cli.kademlia.clone(),
+        filter: cli.filter.clone(),
+        max_message_size: cli.max_message_size,
+        max_upload_rate: cli.max_upload_rate,
+        interactive: daemon_socket.is_none(),
+        tui: cli.tui.unwrap_or_default(),
+        output,
+    }).await?;
+
+    // Ctrl-C at the terminal, or a service manager stopping the daemon
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    while !node.exit_requested() {
         select! {
-            Ok(Some(line)) = stdin.next_line() => {
-                handle_input(line.trim(), &mut swarm, &mut topic, &mut state, nickname.clone(), &mut stdin).await;
-            },
-
-            event = swarm.select_next_some() => match event {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("Your node is listening on {}", address);
-                },
-
-                // Handle all chat events
-                SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Chat(chat_event)) => {
-                    handle_chat_event(chat_event, &mut state, &mut swarm).await;
-                },
-
-                // Handle all Kademlia events
-                SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {id, result, .. })) => {
-                    handle_kademlia_event(id, result, &mut state, &mut swarm).await;
-                },
-
-
-                // Handle all file exchange events
-                SwarmEvent::Behaviour(SwapBytesBehaviourEvent::RequestResponse(RequestResponseBehaviourEvent::RequestResponse(request_response_event))) => {
-                    handle_req_res_event(request_response_event, &mut swarm, &mut stdin, &mut topic).await;
-                },
-
-                // When a new connection is made, discover other peers
-                SwarmEvent::ConnectionEstablished { peer_id, .. } if peer_id == state.rendezvous => {
-                    if let Err(error) = swarm.behaviour_mut().rendezvous.rendezvous.register(
-                        rendezvous::Namespace::from_static("rendezvous"),
-                        state.rendezvous,
-                        None,
-                    ) {
-                        println!("Failed to register: {error}");
-                    } else {
-                        swarm.dial(rendezvous_point_address.clone()).unwrap();
-                        println!("Connection established with rendezvous point {}", peer_id);
-                        swarm.behaviour_mut().rendezvous.rendezvous.discover(
-                            Some(rendezvous::Namespace::new("rendezvous".to_string()).unwrap()),
-                            None,
-                            None,
-                            state.rendezvous,
-                        )
-                    }
-                },
-                // When another peer is discovered, connect with them
-                SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(RendezvousBehaviourEvent::Rendezvous(rendezvous::client::Event::Discovered {
-                    registrations,
-                    ..
-                }))) => {
-                    for registration in registrations {
-                        for address in registration.record.addresses() {
-                            let peer = registration.record.peer_id();
-                            println!("Discovered peer: {} at address: {}", peer, address);
-
-                            let p2p_suffix = Protocol::P2p(peer);
-                            let address_with_p2p =
-                                if !address.ends_with(&Multiaddr::empty().with(p2p_suffix.clone())) {
-                                    address.clone().with(p2p_suffix)
-                                } else {
-                                    address.clone()
-                                };
-
-                            swarm.dial(address_with_p2p).unwrap();
-                        }
-                    }
-                }
-
-                _ => {},
-            },
-
-
-            // If discovery tick, try to discover new peers
-            _ = discover_tick.tick() => {
-                swarm.dial(rendezvous_point_address.clone()).unwrap();
-                swarm.behaviour_mut().rendezvous.rendezvous.discover(
-                    Some(rendezvous::Namespace::new("rendezvous".to_string()).unwrap()),
-                    None,
-                    None,
-                    state.rendezvous
-                )
-        },
-
+            Some(line) = control_rx.recv() => node.command(line.trim()).await,
+            Some(call) = rpc_rx.recv() => node.dispatch(call).await,
+            event = node.next_event() => node.handle(event).await,
+            _ = signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
         }
     }
+    node.shutdown().await;
+    if let Some(socket) = &daemon_socket {
+        let _ = std::fs::remove_file(socket);
+    }
+    Ok(())
 }
-
@@ -0,0 +1,240 @@
+use std::{ collections::{ BTreeMap, HashMap }, path::Path };
+use libp2p::PeerId;
+use regex::Regex;
+use serde::{ Deserialize, Serialize };
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::{
+    audit::AuditEvent,
+    behaviour::{ RequestType, SwapBytesBehaviour },
+    chunking::Received,
+    filenames,
+    notify::{ self, Trigger },
+    outbound,
+    trade::FileMeta,
+    util::ChatState,
+};
+
+// Most files one offer can list
+const MAX_FILES: usize = 64;
+
+// Several files offered at once, described so the other peer can pick which ones they want
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub manifest_id: String,
+    pub files: Vec<FileMeta>,
+}
+
+// A manifest someone sent us, waiting on /take
+struct Incoming {
+    peer: PeerId,
+    manifest: Manifest,
+}
+
+// Multi-file offers, ours waiting on the other peer's choice and theirs waiting on ours. Files we took are
+// accepted without asking when they arrive, as long as they match what the manifest described
+#[derive(Default)]
+pub struct Manifests {
+    next_id: u32,
+    incoming: BTreeMap<u32, Incoming>,
+    outgoing: HashMap<String, (PeerId, Vec<FileMeta>)>,
+    taken: Vec<(PeerId, FileMeta)>,
+}
+
+impl Manifests {
+    // Whether a file offer with this name is one we took from a manifest, so it isn't counted against the peer's
+    // rate limit. The contents are checked when it arrives
+    pub fn expects(&self, peer: &PeerId, filename: &str) -> bool {
+        self.taken.iter().any(|(from, file)| from == peer && file.name == filename)
+    }
+
+    // Take an offered file we picked from a manifest off the list, returning whether it was one
+    pub fn take(&mut self, peer: &PeerId, filename: &str, received: &Received) -> bool {
+        let Some(index) = self.taken.iter().position(|(from, file)| from == peer && file.name == filename && file.describes(received)) else {
+            return false;
+        };
+        self.taken.remove(index);
+        true
+    }
+}
+
+// The files a /offer argument names: the path itself, or every file in its directory matching its * and ? wildcards
+async fn expand(pattern: &str) -> Result<Vec<String>, String> {
+    if !pattern.contains(['*', '?']) {
+        return Ok(vec![pattern.to_string()]);
+    }
+    let (dir, name) = match pattern.rfind('/') {
+        Some(slash) => (&pattern[..=slash], &pattern[slash + 1..]),
+        None => ("", pattern),
+    };
+    if dir.contains(['*', '?']) {
+        return Err(format!("{pattern}: wildcards only work in the file name"));
+    }
+    let regex = format!("^{}$", regex::escape(name).replace(r"\*", ".*").replace(r"\?", "."));
+    let regex = Regex::new(&regex).map_err(|e| e.to_string())?;
+    let mut entries = fs::read_dir(if dir.is_empty() { "." } else { dir }).await.map_err(|e| format!("{dir}: {e}"))?;
+    let mut paths = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        // Hidden files are left out unless the pattern asks for them, as in a shell
+        let hidden = file_name.starts_with('.') && !name.starts_with('.');
+        if !hidden && regex.is_match(&file_name) && entry.file_type().await.is_ok_and(|file_type| file_type.is_file()) {
+            paths.push(format!("{dir}{file_name}"));
+        }
+    }
+    if paths.is_empty() {
+        return Err(format!("No files match {pattern}."));
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+// Split /offer's arguments into files and an optional nickname at the end. The last argument is taken as a nickname
+// when there's more than one and it isn't a file or a pattern
+pub async fn parse_offer(args: &[&str]) -> Result<(Vec<String>, Option<String>), String> {
+    let (patterns, nickname) = match args.split_last() {
+        Some((last, rest)) if !rest.is_empty() && !last.contains(['*', '?']) && !Path::new(last).exists() => (rest, Some(last.to_string())),
+        _ => (args, None),
+    };
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        for path in expand(pattern).await? {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    Ok((paths, nickname))
+}
+
+// Describe several files to a peer so they can choose which to take
+pub async fn offer(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, paths: &[String]) {
+    if !swarm.behaviour().gate.permits(&peer) {
+        error!("Your connection policy doesn't allow {}. Use /allow or /unlist first.", state.peer_name(&peer));
+        return;
+    }
+    if paths.len() > MAX_FILES {
+        error!("That's {} files. Offer at most {MAX_FILES} at once.", paths.len());
+        return;
+    }
+    let mut files = Vec::new();
+    for path in paths {
+        match FileMeta::read(path).await {
+            Ok(file) => files.push(file),
+            Err(e) => {
+                error!("Could not read {path}: {e}");
+                return;
+            }
+        }
+    }
+    let manifest_id = Uuid::new_v4().to_string();
+    for file in &files {
+        state.audit(AuditEvent::OfferSent, peer, &file.name, Some(file.digest.clone()));
+    }
+    state.manifests.outgoing.insert(manifest_id.clone(), (peer, files.clone()));
+    swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::FileManifest(Manifest { manifest_id, files }));
+    system!("Offered {} files to {}. They'll choose which ones to take.", paths.len(), state.peer_name(&peer));
+}
+
+// A peer offered us several files; the user picks with /take
+pub fn received(state: &mut ChatState, peer: PeerId, manifest: Manifest) -> bool {
+    let name = state.peer_name(&peer);
+    if manifest.files.is_empty() || manifest.files.len() > MAX_FILES {
+        tracing::debug!("Ignored an offer of {} files from {peer}", manifest.files.len());
+        return false;
+    }
+    for file in &manifest.files {
+        state.audit(AuditEvent::OfferReceived, peer, &file.name, Some(file.digest.clone()));
+    }
+    state.manifests.next_id += 1;
+    let id = state.manifests.next_id;
+    let description = format!("{name} is offering {} files", manifest.files.len());
+    notify::notify(Trigger::FileOffer, &name, &description);
+    system!("[{id}] {description}:");
+    for (number, file) in manifest.files.iter().enumerate() {
+        // Files we would turn down anyway are marked, and can't be taken
+        let refused = filenames::sanitize(&file.name)
+            .map_err(|reason| format!("its name was refused, {reason}"))
+            .and_then(|saved| state.offer_policy.check(&saved, file.size))
+            .err()
+            .map(|reason| format!(" (refused: {reason})"))
+            .unwrap_or_default();
+        let digest = file.digest.get(..16).unwrap_or(&file.digest);
+        system!("  {}. {} ({} bytes, sha256 {digest}){refused}", number + 1, file.name.escape_debug(), file.size);
+    }
+    system!("Type /take {id} <numbers, like 1,3,4>, /take {id} all or /take {id} none to answer.");
+    state.manifests.incoming.insert(id, Incoming { peer, manifest });
+    true
+}
+
+// /take <id> <numbers|all|none>
+pub fn take(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32, selection: &str) {
+    let Some(incoming) = state.manifests.incoming.get(&id) else {
+        system!("There is no offer {id} waiting for your answer.");
+        return;
+    };
+    let count = incoming.manifest.files.len();
+    let numbers: Vec<usize> = match selection {
+        "all" => (1..=count).collect(),
+        "none" => Vec::new(),
+        _ => {
+            let parsed: Result<Vec<usize>, _> = selection.split(',').map(|number| number.trim().parse::<usize>()).collect();
+            match parsed {
+                Ok(numbers) if numbers.iter().all(|number| (1..=count).contains(number)) => numbers,
+                _ => {
+                    system!("Choose files by their numbers, from 1 to {count}, separated by commas, or use all or none.");
+                    return;
+                }
+            }
+        }
+    };
+    let Some(Incoming { peer, manifest }) = state.manifests.incoming.remove(&id) else { return };
+    let mut indices = Vec::new();
+    for (index, file) in manifest.files.iter().enumerate() {
+        let chosen = numbers.contains(&(index + 1));
+        let allowed = filenames::sanitize(&file.name).is_ok_and(|saved| state.offer_policy.check(&saved, file.size).is_ok());
+        match (chosen, allowed) {
+            (true, true) => {
+                indices.push(index as u32);
+                state.manifests.taken.push((peer, file.clone()));
+                state.audit(AuditEvent::Accepted, peer, &file.name, None);
+            }
+            (true, false) => error!("Skipped {}, which your offer rules turn down.", file.name.escape_debug()),
+            (false, _) => state.audit(AuditEvent::Declined, peer, &file.name, None),
+        }
+    }
+    let taken = indices.len();
+    swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::ManifestSelection { manifest_id: manifest.manifest_id, indices });
+    match taken {
+        0 => system!("[{id}] Turned down all of {}'s files.", state.peer_name(&peer)),
+        _ => system!("[{id}] Taking {taken} of {}'s {count} files. They'll be saved as they arrive.", state.peer_name(&peer)),
+    }
+}
+
+// The other peer chose which of our files to take, so queue those, as long as they haven't changed since
+pub async fn selected(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, manifest_id: &str, indices: Vec<u32>) -> bool {
+    let Some((to, files)) = state.manifests.outgoing.remove(manifest_id) else { return false };
+    if to != peer {
+        state.manifests.outgoing.insert(manifest_id.to_string(), (to, files));
+        return false;
+    }
+    let name = state.peer_name(&peer);
+    let chosen: Vec<_> = files.iter().enumerate().filter(|(index, _)| indices.contains(&(*index as u32))).map(|(_, file)| file).collect();
+    for (index, file) in files.iter().enumerate() {
+        let event = if indices.contains(&(index as u32)) { AuditEvent::PeerAccepted } else { AuditEvent::PeerDeclined };
+        state.audit(event, peer, &file.name, None);
+    }
+    if chosen.is_empty() {
+        system!("{name} didn't take any of the {} files you offered.", files.len());
+        return true;
+    }
+    let names: Vec<_> = chosen.iter().map(|file| file.name.as_str()).collect();
+    system!("{name} is taking {} of the {} files you offered: {}.", chosen.len(), files.len(), names.join(", "));
+    for file in chosen {
+        if !outbound::offer(swarm, state, peer, &file.name, Some(file)).await {
+            error!("{} changed or went missing since you offered it, so it wasn't sent.", file.name);
+        }
+    }
+    true
+}
@@ -0,0 +1,351 @@
+use std::time::Instant;
+use libp2p::{ request_response::ResponseChannel, PeerId };
+use uuid::Uuid;
+
+use crate::{
+    audit::AuditEvent,
+    behaviour::{ RequestType, ResponseType, SwapBytesBehaviour },
+    filenames,
+    notify::{ self, Trigger },
+    trade::{ Escrow, FileMeta, Trade, TradeAnswer, TradeProposal, TradeStatus },
+    util::ChatState,
+};
+
+// Most counter-offers one trade can go through before it's called off, so two peers can't haggle forever
+const MAX_ROUNDS: u32 = 8;
+
+// Working out a trade's terms, before anything is sent. Whoever put forward the latest terms waits (Proposed)
+// while the other side accepts, declines or counters (Offered). A counter-offer swaps the roles, so the last
+// side to name terms is the one that confirms and sends its file first once they're accepted (Agreed). The first
+// answer goes back on the proposal's response channel and every later one as a TradeAnswer request
+
+// Propose swapping one of our files for one of theirs, as a fair trade if asked
+pub async fn propose(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, our_path: &str, wanted: &str, fair: bool) {
+    if !swarm.behaviour().gate.permits(&peer) {
+        error!("Your connection policy doesn't allow {}. Use /allow or /unlist first.", state.peer_name(&peer));
+        return;
+    }
+    let Some(offered) = describe(our_path).await else { return };
+    let trade_id = Uuid::new_v4().to_string();
+    let proposal = TradeProposal { trade_id: trade_id.clone(), offered: offered.clone(), wanted: wanted.to_string(), fair };
+    swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeProposal(proposal));
+    state.audit(AuditEvent::OfferSent, peer, our_path, Some(offered.digest.clone()));
+    state.audit(AuditEvent::RequestSent, peer, wanted, None);
+    let id = state.trades.insert(Trade {
+        trade_id,
+        peer,
+        our_path: our_path.to_string(),
+        ours: Some(offered),
+        wanted: wanted.to_string(),
+        theirs: None,
+        status: TradeStatus::Proposed,
+        escrow: fair.then(Escrow::new),
+        rounds: 0,
+        receipt: None,
+        since: Instant::now(),
+        channel: None,
+    });
+    let kind = if fair { "a fair trade of" } else { "trading" };
+    system!("[{id}] Proposed {kind} your {our_path} for {}'s {wanted}.", state.peer_name(&peer));
+}
+
+// A peer proposed a trade; the user answers it with /trade accept, /trade counter or /trade decline
+pub fn proposal_received(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, proposal: TradeProposal, channel: ResponseChannel<ResponseType>) {
+    let name = state.peer_name(&peer);
+    if let Err(reason) = filenames::sanitize(&proposal.offered.name) {
+        system!("Turned down a trade from {name}: the name of their file was refused, {reason}.");
+        let answer = TradeAnswer::Declined(format!("the name of your file was refused, {reason}"));
+        respond(swarm, &peer, channel, proposal.trade_id, answer);
+        return;
+    }
+    state.audit(AuditEvent::OfferReceived, peer, &proposal.offered.name, Some(proposal.offered.digest.clone()));
+    state.audit(AuditEvent::RequestReceived, peer, &proposal.wanted, None);
+    let kind = if proposal.fair { "a fair trade" } else { "a trade" };
+    let description = format!("{name} proposes {kind}: their {} for your {}", proposal.offered, proposal.wanted);
+    let id = state.trades.insert(Trade {
+        trade_id: proposal.trade_id,
+        peer,
+        our_path: proposal.wanted.clone(),
+        ours: None,
+        wanted: proposal.offered.name.clone(),
+        theirs: Some(proposal.offered),
+        status: TradeStatus::Offered,
+        escrow: proposal.fair.then(Escrow::new),
+        rounds: 0,
+        receipt: None,
+        since: Instant::now(),
+        channel: Some(channel),
+    });
+    notify::notify(Trigger::FileOffer, &name, &description);
+    system!("[{id}] {description}. Type /trade accept {id}, /trade counter {id} <your file> <their file> or /trade decline {id} to answer.");
+}
+
+fn respond(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, peer: &PeerId, channel: ResponseChannel<ResponseType>, trade_id: String, answer: TradeAnswer) {
+    if swarm.behaviour_mut().request_response.request_response.send_response(channel, ResponseType::TradeResponse { trade_id, answer }).is_err() {
+        error!("Could not answer the trade from {peer}, it may have timed out.");
+    }
+}
+
+// Answer the terms a peer put forward: on the proposal's channel if it's their first, otherwise as a request
+pub fn answer(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32, answer: TradeAnswer) {
+    let Some(trade) = state.trades.trades.get_mut(&id) else { return };
+    let (peer, trade_id) = (trade.peer, trade.trade_id.clone());
+    match trade.channel.take() {
+        Some(channel) => respond(swarm, &peer, channel, trade_id, answer),
+        None => {
+            swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeAnswer { trade_id, answer });
+        }
+    }
+}
+
+// Read one of our files to describe it to the other side
+async fn describe(path: &str) -> Option<FileMeta> {
+    match FileMeta::read(path).await {
+        Ok(file) => Some(file),
+        Err(e) => {
+            error!("Could not read {path}: {e}");
+            None
+        }
+    }
+}
+
+// Agree to the terms they put forward, describing our file so they can confirm
+pub async fn accept(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32) {
+    let Some(trade) = state.trades.trades.get(&id).filter(|trade| trade.status == TradeStatus::Offered) else {
+        system!("There is no trade {id} waiting for your answer. Type /trades to see them.");
+        return;
+    };
+    let (peer, our_path, fair) = (trade.peer, trade.our_path.clone(), trade.escrow.is_some());
+    let Some(ours) = describe(&our_path).await else {
+        error!("[{id}] The trade was declined, since {our_path} couldn't be read.");
+        if let Some(trade) = state.trades.trades.get_mut(&id) {
+            trade.status = TradeStatus::Ended(format!("declined, {our_path} couldn't be read"));
+        }
+        answer(swarm, state, id, TradeAnswer::Declined(format!("{our_path} couldn't be read")));
+        return;
+    };
+    let Some(trade) = state.trades.trades.get_mut(&id) else { return };
+    trade.ours = Some(ours.clone());
+    trade.status = TradeStatus::Swapping { sent: false, received: false };
+    trade.since = Instant::now();
+    answer(swarm, state, id, TradeAnswer::Accepted(ours));
+    state.audit(AuditEvent::Accepted, peer, &our_path, None);
+    match fair {
+        true => system!("[{id}] Accepted. Your file will be sent sealed once their sealed file arrives."),
+        false => system!("[{id}] Accepted. Your file will be sent once theirs arrives."),
+    }
+}
+
+// Put different terms to them instead of accepting theirs: another of our files, or another of theirs, or both
+pub async fn counter(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32, our_path: &str, wanted: &str) {
+    let Some(trade) = state.trades.trades.get(&id).filter(|trade| trade.status == TradeStatus::Offered) else {
+        system!("There is no trade {id} waiting for your answer. Type /trades to see them.");
+        return;
+    };
+    if trade.rounds >= MAX_ROUNDS {
+        error!("[{id}] This trade has already been countered {MAX_ROUNDS} times. Accept or decline it.");
+        return;
+    }
+    let peer = trade.peer;
+    let Some(offered) = describe(our_path).await else { return };
+    let Some(trade) = state.trades.trades.get_mut(&id) else { return };
+    trade.rounds += 1;
+    trade.our_path = our_path.to_string();
+    trade.ours = Some(offered.clone());
+    trade.wanted = wanted.to_string();
+    trade.theirs = None;
+    trade.status = TradeStatus::Proposed;
+    trade.since = Instant::now();
+    answer(swarm, state, id, TradeAnswer::Countered { offered: offered.clone(), wanted: wanted.to_string() });
+    state.audit(AuditEvent::OfferSent, peer, our_path, Some(offered.digest));
+    state.audit(AuditEvent::RequestSent, peer, wanted, None);
+    system!("[{id}] Countered with your {our_path} for {}'s {wanted}.", state.peer_name(&peer));
+}
+
+// Their answer to the terms we put forward, from the proposal's response or a later TradeAnswer request.
+// Returns whether it was for terms we were waiting on
+pub fn answered(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, trade_id: &str, answer: TradeAnswer) -> bool {
+    let name = state.peer_name(&peer);
+    let Some(id) = state.trades.find(&peer, trade_id) else { return false };
+    let Some(trade) = state.trades.trades.get_mut(&id).filter(|trade| trade.status == TradeStatus::Proposed) else { return false };
+    let wanted = trade.wanted.clone();
+    match answer {
+        TradeAnswer::Accepted(theirs) => {
+            if let Err(reason) = filenames::sanitize(&theirs.name) {
+                trade.status = TradeStatus::Ended(format!("the name of their file was refused, {reason}"));
+                swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeCancel { trade_id: trade_id.to_string(), reason: format!("the name of their file was refused, {reason}") });
+                error!("[{id}] Called off the trade with {name}: the name of their file was refused, {reason}.");
+                return true;
+            }
+            let our_path = trade.our_path.clone();
+            system!("[{id}] {name} accepted: your {our_path} for their {theirs}. Type /trade confirm {id} to swap, or /trade cancel {id}.");
+            trade.theirs = Some(theirs);
+            trade.status = TradeStatus::Agreed;
+            trade.since = Instant::now();
+            state.audit(AuditEvent::PeerAccepted, peer, &wanted, None);
+        }
+        TradeAnswer::Countered { offered, wanted: ours } => {
+            let refused = match filenames::sanitize(&offered.name) {
+                Err(reason) => Some(format!("the name of their file was refused, {reason}")),
+                Ok(_) if trade.rounds >= MAX_ROUNDS => Some(format!("it was countered more than {MAX_ROUNDS} times")),
+                Ok(_) => None,
+            };
+            if let Some(reason) = refused {
+                trade.status = TradeStatus::Ended(reason.clone());
+                swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeCancel { trade_id: trade_id.to_string(), reason: reason.clone() });
+                error!("[{id}] Called off the trade with {name}: {reason}.");
+                return true;
+            }
+            let description = format!("{name} countered: their {offered} for your {ours}");
+            trade.rounds += 1;
+            trade.our_path = ours.clone();
+            trade.ours = None;
+            trade.wanted = offered.name.clone();
+            trade.theirs = Some(offered.clone());
+            trade.status = TradeStatus::Offered;
+            trade.since = Instant::now();
+            state.audit(AuditEvent::PeerDeclined, peer, &wanted, None);
+            state.audit(AuditEvent::OfferReceived, peer, &offered.name, Some(offered.digest));
+            state.audit(AuditEvent::RequestReceived, peer, &ours, None);
+            notify::notify(Trigger::FileOffer, &name, &description);
+            system!("[{id}] {description}. Type /trade accept {id}, /trade counter {id} <your file> <their file> or /trade decline {id} to answer.");
+        }
+        TradeAnswer::Declined(reason) if reason.is_empty() => {
+            system!("[{id}] {name} turned down the trade.");
+            trade.status = TradeStatus::Ended("turned down".to_string());
+            state.audit(AuditEvent::PeerDeclined, peer, &wanted, None);
+        }
+        TradeAnswer::Declined(reason) => {
+            system!("[{id}] {name} turned down the trade: {reason}.");
+            trade.status = TradeStatus::Ended(format!("turned down: {reason}"));
+            state.audit(AuditEvent::PeerDeclined, peer, &wanted, None);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::{ identity::Keypair, noise, tcp, yamux };
+
+    use crate::{
+        behaviour::{ create_swapbytes_behaviour, DEFAULT_PING_INTERVAL },
+        chunking::DEFAULT_MAX_MESSAGE_SIZE,
+        policy::ConnectionGate,
+        util::{ GossipOptions, KademliaOptions },
+    };
+
+    // A node that's never run, so what it sends is only queued
+    fn node() -> (libp2p::Swarm<SwapBytesBehaviour>, ChatState) {
+        let keypair = Keypair::generate_ed25519();
+        let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair.clone())
+            .with_tokio()
+            .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default).unwrap()
+            .with_behaviour(|key| create_swapbytes_behaviour(
+                key, None, &GossipOptions::default(), &KademliaOptions::default(), DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_PING_INTERVAL, ConnectionGate::default(),
+            )).unwrap()
+            .build();
+        (swarm, ChatState::new(keypair, None))
+    }
+
+    // A file of our own to trade, named after the test so tests running at once don't share one
+    fn file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("swapbytes-negotiation-{}-{name}", std::process::id()));
+        std::fs::write(&path, name).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    // Alice proposes her file for bob's notes.txt, and bob holds it as a trade to answer. Proposals are answered
+    // on their response channel, which only a real connection has, so bob's answers go out as requests instead
+    async fn proposed(alice: &mut (libp2p::Swarm<SwapBytesBehaviour>, ChatState), bob: &mut (libp2p::Swarm<SwapBytesBehaviour>, ChatState), ours: &str) -> (u32, u32) {
+        let (alice_id, bob_id) = (*alice.0.local_peer_id(), *bob.0.local_peer_id());
+        propose(&mut alice.0, &mut alice.1, bob_id, ours, "notes.txt", false).await;
+        let (&alice_trade, trade) = alice.1.trades.trades.iter().next().unwrap();
+        assert_eq!(trade.status, TradeStatus::Proposed);
+        let bob_trade = bob.1.trades.insert(Trade {
+            trade_id: trade.trade_id.clone(),
+            peer: alice_id,
+            our_path: file("notes.txt"),
+            ours: None,
+            wanted: trade.ours.as_ref().unwrap().name.clone(),
+            theirs: trade.ours.clone(),
+            status: TradeStatus::Offered,
+            escrow: None,
+            rounds: 0,
+            receipt: None,
+            since: Instant::now(),
+            channel: None,
+        });
+        (alice_trade, bob_trade)
+    }
+
+    #[tokio::test]
+    async fn accepting_agrees_the_trade_on_both_sides() {
+        let (mut alice, mut bob) = (node(), node());
+        let (alice_trade, bob_trade) = proposed(&mut alice, &mut bob, &file("accept.txt")).await;
+        accept(&mut bob.0, &mut bob.1, bob_trade).await;
+        let theirs = bob.1.trades.trades[&bob_trade].ours.clone().unwrap();
+        assert_eq!(bob.1.trades.trades[&bob_trade].status, TradeStatus::Swapping { sent: false, received: false });
+
+        let (bob_id, trade_id) = (*bob.0.local_peer_id(), alice.1.trades.trades[&alice_trade].trade_id.clone());
+        assert!(answered(&mut alice.0, &mut alice.1, bob_id, &trade_id, TradeAnswer::Accepted(theirs.clone())));
+        let trade = &alice.1.trades.trades[&alice_trade];
+        assert_eq!(trade.status, TradeStatus::Agreed);
+        assert_eq!(trade.theirs, Some(theirs));
+    }
+
+    #[tokio::test]
+    async fn a_counter_offer_swaps_who_answers() {
+        let (mut alice, mut bob) = (node(), node());
+        let (alice_trade, bob_trade) = proposed(&mut alice, &mut bob, &file("counter.txt")).await;
+        let instead = file("instead.txt");
+        counter(&mut bob.0, &mut bob.1, bob_trade, &instead, "other.txt").await;
+        let trade = &bob.1.trades.trades[&bob_trade];
+        assert_eq!((&trade.status, trade.rounds, trade.wanted.as_str()), (&TradeStatus::Proposed, 1, "other.txt"));
+        let offered = trade.ours.clone().unwrap();
+
+        let (bob_id, trade_id) = (*bob.0.local_peer_id(), trade.trade_id.clone());
+        let countered = TradeAnswer::Countered { offered: offered.clone(), wanted: "other.txt".to_string() };
+        assert!(answered(&mut alice.0, &mut alice.1, bob_id, &trade_id, countered));
+        let trade = &alice.1.trades.trades[&alice_trade];
+        assert_eq!((&trade.status, trade.rounds), (&TradeStatus::Offered, 1));
+        assert_eq!((trade.our_path.as_str(), trade.wanted.as_str()), ("other.txt", offered.name.as_str()));
+        assert_eq!((&trade.ours, &trade.theirs), (&None, &Some(offered)));
+    }
+
+    #[tokio::test]
+    async fn answers_out_of_turn_are_refused() {
+        let (mut alice, mut bob) = (node(), node());
+        let (alice_trade, bob_trade) = proposed(&mut alice, &mut bob, &file("order.txt")).await;
+        let (alice_id, bob_id) = (*alice.0.local_peer_id(), *bob.0.local_peer_id());
+        let trade_id = alice.1.trades.trades[&alice_trade].trade_id.clone();
+        let accepted = || TradeAnswer::Accepted(FileMeta::of("notes.txt", b"notes"));
+
+        // Alice put the terms forward, so she can't accept or counter them herself
+        accept(&mut alice.0, &mut alice.1, alice_trade).await;
+        counter(&mut alice.0, &mut alice.1, alice_trade, &file("again.txt"), "notes.txt").await;
+        assert_eq!(alice.1.trades.trades[&alice_trade].status, TradeStatus::Proposed);
+        // and bob is the one answering, so he can't be sent an answer
+        assert!(!answered(&mut bob.0, &mut bob.1, alice_id, &trade_id, accepted()));
+        assert_eq!(bob.1.trades.trades[&bob_trade].status, TradeStatus::Offered);
+        // Only bob can answer alice's trade, and only once
+        assert!(!answered(&mut alice.0, &mut alice.1, alice_id, &trade_id, accepted()));
+        assert!(!answered(&mut alice.0, &mut alice.1, bob_id, "another trade", accepted()));
+        assert!(answered(&mut alice.0, &mut alice.1, bob_id, &trade_id, accepted()));
+        assert!(!answered(&mut alice.0, &mut alice.1, bob_id, &trade_id, TradeAnswer::Declined(String::new())));
+        assert_eq!(alice.1.trades.trades[&alice_trade].status, TradeStatus::Agreed);
+    }
+
+    #[tokio::test]
+    async fn countering_too_often_calls_the_trade_off() {
+        let (mut alice, mut bob) = (node(), node());
+        let (alice_trade, _) = proposed(&mut alice, &mut bob, &file("haggle.txt")).await;
+        let trade = alice.1.trades.trades.get_mut(&alice_trade).unwrap();
+        trade.rounds = MAX_ROUNDS;
+        let trade_id = trade.trade_id.clone();
+        let countered = TradeAnswer::Countered { offered: FileMeta::of("other.txt", b"other"), wanted: "haggle.txt".to_string() };
+        assert!(answered(&mut alice.0, &mut alice.1, *bob.0.local_peer_id(), &trade_id, countered));
+        assert!(matches!(alice.1.trades.trades[&alice_trade].status, TradeStatus::Ended(_)));
+    }
+}
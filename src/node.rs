@@ -0,0 +1,778 @@
+use std::{ collections::{ HashMap, HashSet }, error::Error, net::{ Ipv4Addr, SocketAddrV4 }, path::PathBuf, time::{ Duration, Instant } };
+use futures::StreamExt;
+use libp2p::{ gossipsub::{ self, TopicHash }, identity, kad, multiaddr::Protocol, noise, ping, rendezvous, request_response, swarm::{ dial_opts::DialOpts, DialError, SwarmEvent }, tcp, yamux, Multiaddr, PeerId };
+use tokio::{ select, sync::{ broadcast, mpsc }, time::{ Interval, MissedTickBehavior } };
+
+use crate::{
+    address_book::AddressBook,
+    approval::{ self, OfferPolicy },
+    audit::AuditLog,
+    bench,
+    board,
+    chunking,
+    completion,
+    discovery::{ self, RendezvousServers },
+    behaviour::{ create_swapbytes_behaviour, DEFAULT_PING_INTERVAL, ChatBehaviourEvent, handle_chat_event, handle_kademlia_event, handle_req_res_event, namespace_room, RendezvousBehaviourEvent, RequestResponseBehaviourEvent, RequestType, SwapBytesBehaviour, SwapBytesBehaviourEvent },
+    history::TransferHistory,
+    input::{ self, handle_input, send_chat },
+    keystore,
+    ledger::TradeLedger,
+    line_editor::{ LineReader, Typed },
+    outbound,
+    output::{ self, Output, RoomEvent },
+    pipeline::MessageFilter,
+    policy::ConnectionGate,
+    ratelimit::{ self, RateLimiter },
+    reconnect,
+    room::{ self, publish_room_record, SavedRooms },
+    rpc,
+    scan::{ self, ScanResult, Scanner },
+    server,
+    trade,
+    tui,
+    util::{ check_own_nickname, get_and_save_nickname, own_peer_data, persist_identity, update_own_profile, FilterOptions, GossipOptions, KademliaOptions, NicknameClaim, publish_gossip, republish_own_records, send_read_receipts, ChatState, GossipMessage, REPUBLISH_DEBOUNCE, REPUBLISH_INTERVAL },
+    vault::{ self, DownloadKey },
+    wishlist::Wishlist,
+};
+
+// How long shutdown waits for outstanding DHT puts and goodbyes to get out before closing connections anyway
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+// Gossip has no acknowledgement, so give the swarm at least this long to send our goodbye
+const GOSSIP_FLUSH: Duration = Duration::from_millis(500);
+
+// Everything needed to start a node. Unset files mean that state only lasts for this run
+#[derive(Debug, Default, Clone)]
+pub struct NodeConfig {
+    pub nickname: Option<String>,
+    pub port: Option<String>,
+    pub server: Vec<String>,       // Rendezvous server addresses in order of preference, 127.0.0.1 by default
+    pub server_peer: Option<String>, // The rendezvous server's PeerId, learned from the first to answer if None
+    pub identity: Option<PathBuf>, // Keypair and profile, see keystore
+    pub records: Option<PathBuf>,  // DHT records, see store
+    pub peers: Option<PathBuf>,    // Address book, see address_book
+    pub policy: Option<PathBuf>,   // Allow and deny lists, see policy
+    pub history: Option<PathBuf>,  // Transfer history
+    pub audit: Option<PathBuf>,    // Audit log, see audit
+    pub ledger: Option<PathBuf>,   // Completed trades, see ledger
+    pub wishlist: Option<PathBuf>, // Files we're after, see wishlist
+    pub rooms: Option<PathBuf>,    // Private rooms we're in, see room::SavedRooms
+    pub input_history: Option<PathBuf>, // Lines typed at the terminal, for Up, Down and Ctrl-R
+    pub download_dir: Option<PathBuf>,
+    pub share_dir: Option<PathBuf>,    // The only files peers can /request from, with nothing shared without one
+    pub encrypt_downloads: bool,
+    pub scan_command: Option<String>, // Run on received files before they leave quarantine, see scan
+    pub quarantine_dir: Option<PathBuf>,
+    pub namespace: Option<String>, // Rendezvous namespace, "rendezvous" by default
+    pub registration_ttl: Option<u64>, // Seconds, the server's default if None
+    pub ping_interval: Option<u64>, // Seconds, behaviour::DEFAULT_PING_INTERVAL if None
+    pub auto_accept: Option<f64>,  // Starting /autoaccept rating
+    pub accept_extensions: Vec<String>, // Offered file types we're asked about; empty for any
+    pub max_offer_size: Option<u64>,
+    pub rate_limit: Option<u32>,   // Requests, offers and invites per peer per minute, see ratelimit
+    pub auto_mute: bool,
+    pub trade_timeout: Option<u64>, // Minutes before a waiting trade expires, see trade::expire
+    pub gossip: GossipOptions,
+    pub kademlia: KademliaOptions,
+    pub filter: FilterOptions,
+    pub max_message_size: Option<u64>, // Largest file request or response in one piece, see chunking
+    pub max_upload_rate: Option<u64>,  // Bytes per second each file is sent at, at most, see chunking
+    pub interactive: bool, // Whether to read commands and answer prompts on stdin
+    pub tui: bool, // Draw the full-screen interface, see tui. Only when keys are read from a terminal
+    pub output: Output, // Where what the node prints goes, see output; a new one by default
+}
+
+// Something for a node to act on, from `next_event`
+pub struct NodeEvent(Next);
+
+enum Next {
+    Typed(Typed),
+    Swarm(Box<SwarmEvent<SwapBytesBehaviourEvent>>),
+    Republish,
+    Redial,
+    Expire,
+    Resume,
+    Scanned(ScanResult),
+    Discover,
+}
+
+// A running SwapBytes peer. Drive it with `next_event` and `handle` (or `step`) and act on it with `send_message`,
+// `request_file` and `command`; everything it does is reported on the `events` stream
+pub struct SwapBytesNode {
+    swarm: libp2p::Swarm<SwapBytesBehaviour>,
+    state: ChatState,
+    topic: gossipsub::IdentTopic,
+    nickname: String,
+    stdin: LineReader,
+    interactive: bool,
+    output: Output,
+    rendezvous_down: bool, // Whether the user has been told the rendezvous server can't be reached
+    namespace: rendezvous::Namespace,
+    // From the last discovery, so the next one only fetches registrations made since
+    discovery_cookie: Option<rendezvous::Cookie>,
+    republish_tick: Interval,
+    discover_tick: Interval,
+    reconnect_tick: Interval,
+    expiry_tick: Interval, // For trades and approvals waiting too long, see trade::expire, old room keys, and writing changed DHT records to disk
+    resume_tick: Interval, // For chunks held back by --max-upload-rate, see chunking::resume
+    scan_results: mpsc::UnboundedReceiver<ScanResult>,
+}
+
+impl SwapBytesNode {
+    pub async fn new(config: NodeConfig) -> Result<Self, Box<dyn Error>> {
+        let output = config.output.clone();
+        output.scope(Self::start(config)).await
+    }
+
+    async fn start(config: NodeConfig) -> Result<Self, Box<dyn Error>> {
+        // Run as the saved identity if one was given, otherwise as a new peer for this run only
+        let stored_identity = match &config.identity {
+            Some(path) => Some(keystore::load_or_create(path)?),
+            None => None,
+        };
+        let keypair = match &stored_identity {
+            Some(stored_identity) => stored_identity.keypair()?,
+            None => identity::Keypair::generate_ed25519(),
+        };
+
+        let max_message_size = config.max_message_size.unwrap_or(chunking::DEFAULT_MAX_MESSAGE_SIZE);
+        let gate = config.policy.clone().map(ConnectionGate::load).unwrap_or_default();
+        let ping_interval = config.ping_interval.map(Duration::from_secs).unwrap_or(DEFAULT_PING_INTERVAL);
+
+        // Generates the swarm used to connect and communicate with peers
+        let mut swarm = libp2p::SwarmBuilder
+            ::with_existing_identity(keypair.clone())
+            .with_tokio()
+            .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)?
+            .with_quic()
+            .with_behaviour(|key| create_swapbytes_behaviour(key, config.records.clone(), &config.gossip, &config.kademlia, max_message_size, ping_interval, gate))?
+            .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+
+        let server_peer = config.server_peer.as_deref()
+            .map(|peer| peer.parse::<PeerId>().map_err(|_| format!("--server-peer must be a PeerId, not {peer:?}")))
+            .transpose()?;
+        let mut state = ChatState::new(keypair, server_peer);
+        state.identity_file = config.identity.clone();
+        if let Some(server_peer) = server_peer {
+            swarm.behaviour_mut().gate.exempt(server_peer);
+        }
+        if let Some(peers_file) = &config.peers {
+            state.address_book = AddressBook::load(peers_file.clone());
+            // Seed Kademlia with the peers we knew last time and try to reconnect, in case the rendezvous server is down
+            for (peer_id, known_peer) in &state.address_book.peers {
+                for address in &known_peer.addresses {
+                    swarm.behaviour_mut().kademlia.add_address(peer_id, address.clone());
+                }
+                let dial = DialOpts::peer_id(*peer_id).addresses(known_peer.addresses.clone()).build();
+                if let Err(e) = swarm.dial(dial) {
+                    tracing::info!("Could not reconnect to {}: {e}", known_peer.nickname.as_deref().unwrap_or(&peer_id.to_string()));
+                }
+            }
+        }
+        if let Some(history) = &config.history {
+            state.transfer_history = TransferHistory::load(history.clone());
+        }
+        if let Some(audit) = &config.audit {
+            state.audit_log = AuditLog::load(audit.clone());
+        }
+        if let Some(ledger) = &config.ledger {
+            state.trade_ledger = TradeLedger::load(ledger.clone());
+        }
+        if let Some(wishlist) = &config.wishlist {
+            state.wishlist = Wishlist::load(wishlist.clone());
+        }
+        if let Some(download_dir) = &config.download_dir {
+            std::fs::create_dir_all(download_dir)
+                .map_err(|e| format!("Could not create download directory {}: {e}", download_dir.display()))?;
+            state.download_dir = download_dir.clone();
+        }
+        state.share_dir = config.share_dir.clone();
+        let (scan_results_tx, scan_results) = mpsc::unbounded_channel();
+        if let Some(command) = &config.scan_command {
+            let quarantine = config.quarantine_dir.clone().unwrap_or_else(|| state.download_dir.join(".quarantine"));
+            state.scanner = Some(Scanner::new(command, quarantine, scan_results_tx)?);
+        }
+        state.auto_accept_threshold = config.auto_accept;
+        state.offer_policy = OfferPolicy::new(&config.accept_extensions, config.max_offer_size);
+        state.message_filter = MessageFilter::new(&config.filter)?;
+        state.chunked = chunking::ChunkedTransfers::new(config.max_upload_rate);
+        state.rate_limiter = RateLimiter::new(config.rate_limit.unwrap_or(ratelimit::DEFAULT_RATE_LIMIT), config.auto_mute);
+        state.registration.ttl = config.registration_ttl;
+        state.kademlia = config.kademlia.clone();
+        state.trades.timeout = match config.trade_timeout.unwrap_or(trade::DEFAULT_TIMEOUT_MINUTES) {
+            0 => None,
+            minutes => Some(Duration::from_secs(minutes * 60)),
+        };
+        let namespace = rendezvous::Namespace::new(config.namespace.clone().unwrap_or("rendezvous".to_string()))
+            .map_err(|_| "The rendezvous namespace is too long")?;
+        state.public_room = namespace_room(&namespace.to_string()).to_string();
+
+        // Creates a chatroom to be used by all connected peers by default, or by everyone in our namespace
+        let topic = gossipsub::IdentTopic::new(&state.public_room);
+
+        swarm.behaviour_mut().chat.gossipsub.subscribe(&topic)?;
+        swarm.behaviour_mut().chat.gossipsub.subscribe(&gossipsub::IdentTopic::new(board::TOPIC))?;
+        output::set_room(&state.public_room);
+        // Rooms we were still in when we last stopped without leaving them. We listen in them again straight away,
+        // and tell their members we're back as they turn up
+        if let Some(rooms_file) = &config.rooms {
+            let (saved_rooms, rooms) = SavedRooms::load(rooms_file.clone());
+            state.saved_rooms = saved_rooms;
+            for (room_id, room) in rooms {
+                swarm.behaviour_mut().chat.gossipsub.subscribe(&gossipsub::IdentTopic::new(&room_id))?;
+                let others: Vec<_> = room.others(swarm.local_peer_id()).iter().map(|member| member.nickname.clone()).collect();
+                system!("Rejoined the private room {} with {}. Type /switch {} to type in it.",
+                    output::room_label(&room_id), if others.is_empty() { "nobody else".to_string() } else { others.join(", ") }, output::room_label(&room_id));
+                state.saved_rooms.returning.insert(room_id.clone());
+                state.rooms.insert(room_id, room);
+            }
+        }
+        swarm.behaviour_mut().kademlia.set_mode(Some(kad::Mode::Server));
+
+        // Rendezvous servers, the first of which is used until it stops answering
+        let servers = match config.server.is_empty() {
+            true => vec!["127.0.0.1".to_string()],
+            false => config.server.clone(),
+        };
+        let servers = servers.iter().map(|server| server_address(server)).collect::<Result<Vec<_>, _>>()?;
+        let rendezvous_point_addresses = servers.iter()
+            .map(|server| Multiaddr::from(*server.ip()).with(Protocol::Tcp(server.port())))
+            .collect();
+
+        let external_address = Multiaddr::from(*servers[0].ip()).with(Protocol::Tcp(0));
+        swarm.add_external_address(external_address);
+        state.servers = RendezvousServers::new(rendezvous_point_addresses);
+        let mut rendezvous_down = false;
+        dial_rendezvous(&mut swarm, &mut state, &mut rendezvous_down);
+
+        // Our own records are put again every hour so they never expire from the DHT
+        let mut republish_tick = tokio::time::interval(REPUBLISH_INTERVAL);
+        republish_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        republish_tick.reset();
+
+        // Discovery ping goes off every 30 seconds
+        let mut discover_tick = tokio::time::interval(Duration::from_secs(30));
+        discover_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut resume_tick = tokio::time::interval(Duration::from_millis(50));
+        resume_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        // Configures the peer to listen for incoming connection on tcp and udp over quic
+        swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+        swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
+
+        // Reads input from stdin, a key at a time when a person is typing at a terminal
+        let mut stdin = LineReader::new(config.interactive && output::prompts_enabled());
+        if let Some(input_history) = &config.input_history {
+            stdin.keep_history(input_history.clone());
+        }
+
+        let listen_port = config.port.clone().unwrap_or("0".to_string());
+        let multiaddr = format!("/ip4/0.0.0.0/tcp/{listen_port}");
+        let _ = swarm.listen_on(multiaddr.parse()?)?;
+
+        let peer_id = *swarm.local_peer_id();
+        let saved_profile = stored_identity.and_then(|stored_identity| {
+            state.trusted.extend(stored_identity.trusted);
+            stored_identity.profile
+        });
+        if !config.interactive && saved_profile.is_none() && config.nickname.is_none() {
+            return Err("Without a terminal there is no one to ask for a nickname. Give one with --nickname, or use an --identity that has one saved".into());
+        }
+        if config.encrypt_downloads {
+            let passphrase = match std::env::var(vault::PASSPHRASE_VAR) {
+                Ok(passphrase) if !passphrase.is_empty() => passphrase,
+                _ if config.interactive => keystore::read_passphrase(&mut stdin, "Choose a passphrase to encrypt downloaded files with:").await?,
+                _ => return Err(format!("Without a terminal there is no one to ask for a passphrase. Set {} to use --encrypt-downloads", vault::PASSPHRASE_VAR).into()),
+            };
+            state.download_key = Some(DownloadKey::new(passphrase));
+        }
+        let nickname = get_and_save_nickname(&mut stdin, peer_id, &mut swarm, saved_profile, config.nickname.clone(), state.kademlia.profile_quorum()).await;
+        // Let peers know how big a file they can send us in one piece
+        if own_peer_data(&mut swarm).is_some_and(|own| own.profile.max_message_size != Some(max_message_size)) {
+            update_own_profile(&mut swarm, &state, |profile| profile.max_message_size = Some(max_message_size));
+        }
+        persist_identity(&mut swarm, &state);
+        // After the prompts, which are answered on the normal screen
+        if config.tui && stdin.editing() {
+            tui::start();
+        }
+
+        Ok(SwapBytesNode {
+            output: config.output.clone(),
+            swarm,
+            state,
+            topic,
+            nickname,
+            stdin,
+            interactive: config.interactive,
+            rendezvous_down,
+            namespace,
+            discovery_cookie: None,
+            republish_tick,
+            discover_tick,
+            reconnect_tick: tokio::time::interval(Duration::from_secs(1)),
+            expiry_tick: tokio::time::interval(Duration::from_secs(10)),
+            resume_tick,
+            scan_results,
+        })
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
+
+    // Every event from now on
+    pub fn events(&self) -> broadcast::Receiver<RoomEvent> {
+        self.output.subscribe()
+    }
+
+    // Send a chat message to the room we're in
+    pub fn send_message(&mut self, text: &str) {
+        self.output.clone().sync_scope(|| {
+            send_read_receipts(&mut self.swarm, &mut self.state);
+            send_chat(text, &mut self.swarm, &self.topic, &mut self.state, &self.nickname);
+        });
+    }
+
+    // Ask a private room member for a file. `nickname` picks the member in rooms with more than one other
+    pub async fn request_file(&mut self, file: &str, nickname: Option<&str>) {
+        self.output.clone().scope(async {
+            input::request_file(&mut self.swarm, &self.topic, &mut self.state, file, nickname);
+        }).await;
+    }
+
+    // Run a line as if the user had typed it
+    pub async fn command(&mut self, line: &str) {
+        self.output.clone().scope(async {
+            handle_input(line.trim_end(), &mut self.swarm, &mut self.topic, &mut self.state, &mut self.nickname, &mut self.stdin).await;
+            self.state.saved_rooms.sync(&self.state.rooms);
+        }).await
+    }
+
+    // Whether /exit has been typed or sent; the node should be shut down once it has
+    pub fn exit_requested(&self) -> bool {
+        self.state.exit_requested
+    }
+
+    // Leave the network cleanly: say goodbye to the public room and every private room we're in, telling the other
+    // members of private rooms directly too, drop our rendezvous registration, let outstanding DHT puts and goodbyes
+    // finish (within SHUTDOWN_GRACE) and close every connection
+    pub async fn shutdown(self) {
+        let output = self.output.clone();
+        output.scope(self.leave()).await
+    }
+
+    async fn leave(mut self) {
+        system!("Leaving SwapBytes...");
+        let swarm = &mut self.swarm;
+        let state = &mut self.state;
+        let local_peer_id = *swarm.local_peer_id();
+        let mut pending_puts: HashSet<kad::QueryId> = state.pending_republish.drain().collect();
+        let mut goodbyes = HashSet::new();
+        // Leaving on purpose leaves our rooms for good, so the next run doesn't rejoin them
+        state.saved_rooms.sync(&HashMap::new());
+        let room_ids: Vec<String> = state.rooms.keys().cloned().collect();
+        for room_id in room_ids {
+            let Some(room) = state.rooms.get_mut(&room_id) else { continue };
+            room.remove_member(&local_peer_id);
+            pending_puts.extend(publish_room_record(swarm, state.kademlia.room_quorum(), &room_id, room));
+            for member in room.members.clone() {
+                let request = RequestType::Leaving { room_id: room_id.clone() };
+                goodbyes.insert(swarm.behaviour_mut().request_response.request_response.send_request(&member.peer_id, request));
+            }
+            if let Err(e) = publish_gossip(swarm, state, TopicHash::from_raw(&room_id), &GossipMessage::MemberLeft(local_peer_id)) {
+                tracing::info!("Could not say goodbye to room {room_id}: {e:?}");
+            }
+        }
+        // The public room hears even if we're in a private one, so our board posts come down everywhere
+        let public_room = TopicHash::from_raw(&state.public_room);
+        if let Err(e) = publish_gossip(swarm, state, public_room, &GossipMessage::Leaving(self.nickname.clone())) {
+            tracing::info!("Could not say goodbye: {e:?}");
+        }
+        if let Some(server) = state.rendezvous && swarm.is_connected(&server) {
+            swarm.behaviour_mut().rendezvous.rendezvous.unregister(self.namespace.clone(), server);
+        }
+        persist_identity(swarm, state);
+
+        // Keep the swarm running so the messages above actually go out
+        let started = Instant::now();
+        let deadline = tokio::time::sleep(SHUTDOWN_GRACE);
+        tokio::pin!(deadline);
+        while !pending_puts.is_empty() || !goodbyes.is_empty() || started.elapsed() < GOSSIP_FLUSH {
+            select! {
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { id, .. })) => {
+                        pending_puts.remove(&id);
+                    }
+                    SwarmEvent::Behaviour(SwapBytesBehaviourEvent::RequestResponse(RequestResponseBehaviourEvent::RequestResponse(
+                        request_response::Event::Message { message: request_response::Message::Response { request_id, .. }, .. }
+                        | request_response::Event::OutboundFailure { request_id, .. }
+                    ))) => {
+                        goodbyes.remove(&request_id);
+                    }
+                    _ => {}
+                },
+                _ = tokio::time::sleep(GOSSIP_FLUSH.saturating_sub(started.elapsed())), if pending_puts.is_empty() && goodbyes.is_empty() => {},
+                _ = &mut deadline => {
+                    tracing::warn!("Gave up waiting for {} DHT puts to finish", pending_puts.len());
+                    break;
+                },
+            }
+        }
+
+        let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+        for peer_id in peers {
+            let _ = swarm.disconnect_peer_id(peer_id);
+        }
+        while swarm.network_info().num_peers() > 0 {
+            select! {
+                _ = swarm.select_next_some() => {},
+                _ = &mut deadline => break,
+            }
+        }
+        if let Some(flush) = swarm.behaviour_mut().kademlia.store_mut().flush() {
+            let _ = flush.await;
+        }
+        system!("Thank you for using SwapBytes! Goodbye!");
+    }
+
+    // Answer a JSON-RPC call
+    pub async fn dispatch(&mut self, call: rpc::Call) {
+        self.output.clone().scope(async {
+            rpc::dispatch(call, &mut self.swarm, &mut self.topic, &mut self.state, &mut self.nickname, &mut self.stdin).await;
+            self.state.saved_rooms.sync(&self.state.rooms);
+        }).await
+    }
+
+    // Wait for the next thing to happen on the network (or, for interactive nodes, at the terminal) and handle it.
+    // Don't race this against other work, as a cancelled step can stop part way through handling; race next_event
+    // and run handle outside the select instead
+    pub async fn step(&mut self) {
+        let event = self.next_event().await;
+        self.handle(event).await;
+    }
+
+    // Wait for the next thing to happen, without acting on it. Cancelling this loses nothing
+    pub async fn next_event(&mut self) -> NodeEvent {
+        NodeEvent(select! {
+            Ok(Some(typed)) = self.stdin.next_typed(), if self.interactive => Next::Typed(typed),
+            event = self.swarm.select_next_some() => Next::Swarm(Box::new(event)),
+            _ = self.republish_tick.tick() => Next::Republish,
+            _ = self.reconnect_tick.tick() => Next::Redial,
+            _ = self.expiry_tick.tick() => Next::Expire,
+            _ = self.resume_tick.tick(), if self.state.chunked.held() => Next::Resume,
+            Some(result) = self.scan_results.recv(), if self.state.scanner.is_some() => Next::Scanned(result),
+            _ = self.discover_tick.tick() => Next::Discover,
+        })
+    }
+
+    // Act on what next_event returned
+    pub async fn handle(&mut self, event: NodeEvent) {
+        self.output.clone().scope(self.act(event)).await
+    }
+
+    async fn act(&mut self, NodeEvent(event): NodeEvent) {
+        match event {
+            Next::Typed(typed) => {
+                let line = match typed {
+                    Typed::Tab => return completion::complete(&mut self.stdin, &self.state, &self.nickname).await,
+                    Typed::Line(line) => {
+                        self.stdin.remember(&line);
+                        line
+                    }
+                };
+                let line = if output::json_mode() {
+                    match output::parse_command(&line) {
+                        Ok(line) => line,
+                        Err(e) => {
+                            error!("{e}");
+                            return;
+                        }
+                    }
+                } else {
+                    line
+                };
+                handle_input(line.trim(), &mut self.swarm, &mut self.topic, &mut self.state, &mut self.nickname, &mut self.stdin).await;
+            },
+
+            Next::Swarm(event) => self.handle_swarm_event(*event).await,
+
+            Next::Republish => {
+                republish_own_records(&mut self.swarm, &mut self.state);
+                board::republish(&mut self.swarm, &self.state, &self.nickname);
+            },
+
+            Next::Redial => reconnect::redial_due(&mut self.swarm, &mut self.state),
+
+            Next::Expire => {
+                trade::expire(&mut self.swarm, &mut self.state);
+                approval::expire(&mut self.swarm, &mut self.state);
+                outbound::expire(&mut self.state);
+                room::expire_keys(&mut self.state);
+                self.swarm.behaviour_mut().kademlia.store_mut().flush();
+            }
+
+            Next::Resume => chunking::resume(&mut self.swarm, &mut self.state).await,
+
+            Next::Scanned(result) => scan::finish(&mut self.state, result).await,
+
+            // If discovery tick, try to discover new peers, reconnecting to the rendezvous server if we lost it
+            Next::Discover => {
+                let swarm = &mut self.swarm;
+                dial_rendezvous(swarm, &mut self.state, &mut self.rendezvous_down);
+                // Nothing to ask until we know who the server is
+                if let Some(server) = self.state.rendezvous {
+                    if self.state.registration.renewal_due() {
+                        self.state.registration.lapsed();
+                        if let Err(error) = swarm.behaviour_mut().rendezvous.rendezvous.register(self.namespace.clone(), server, self.state.registration.ttl) {
+                            tracing::warn!("Failed to renew our registration: {error}");
+                        }
+                    }
+                    swarm.behaviour_mut().rendezvous.rendezvous.discover(
+                        Some(self.namespace.clone()),
+                        self.discovery_cookie.clone(),
+                        None,
+                        server
+                    )
+                }
+            },
+        }
+        self.state.saved_rooms.sync(&self.state.rooms);
+        tui::refresh(&self.swarm, &self.state);
+    }
+
+    async fn handle_swarm_event(&mut self, event: SwarmEvent<SwapBytesBehaviourEvent>) {
+        let swarm = &mut self.swarm;
+        let state = &mut self.state;
+        state.connections.observe(&event);
+        if let SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Chat(ChatBehaviourEvent::Gossipsub(gossip_event))) = &event {
+            state.subscribers.observe(gossip_event);
+        }
+        // Until we know the rendezvous server's PeerId, its dial is only recognised by its connection
+        let mut server_dial = false;
+        if let SwarmEvent::ConnectionEstablished { connection_id, .. } | SwarmEvent::OutgoingConnectionError { connection_id, .. } = &event {
+            bench::dial_finished(swarm, state, *connection_id);
+            server_dial = state.servers.dial_finished(*connection_id);
+        }
+        if server_dial && let SwarmEvent::ConnectionEstablished { peer_id, .. } = &event {
+            system!("Trusting {peer_id} as the rendezvous server at {}. Give --server-peer {peer_id} to insist on it.", state.servers.active());
+            state.rendezvous = Some(*peer_id);
+            swarm.behaviour_mut().gate.exempt(*peer_id);
+        }
+        match event {
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } if server_dial || peer_id.is_some() && peer_id == state.rendezvous => {
+                if state.servers.failed(&error) {
+                    self.rendezvous_down = false;
+                    dial_rendezvous(swarm, state, &mut self.rendezvous_down);
+                } else {
+                    rendezvous_unreachable(&mut self.rendezvous_down, error);
+                }
+            },
+
+            // Pings keep checking the active rendezvous server is still answering
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(RendezvousBehaviourEvent::Ping(ping::Event { peer, result, .. })))
+                if Some(peer) == state.rendezvous => match result {
+                Ok(_) => state.servers.answered(),
+                Err(error) => if state.servers.failed(&error) {
+                    // Register with and discover through the next server once this connection is gone
+                    state.servers.switching = true;
+                    let _ = swarm.disconnect_peer_id(peer);
+                },
+            },
+
+            SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } => state.dials.failed(peer_id, error),
+
+            SwarmEvent::NewListenAddr { address, .. } => {
+                tracing::info!("Your node is listening on {}", address);
+            },
+
+            // A member of a room we've come back to is around, so they can hear we're back
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Chat(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { topic, .. })))
+                if state.saved_rooms.returning.contains(topic.as_str()) => {
+                room::announce_return(swarm, state, topic.as_str(), &self.nickname);
+            },
+
+            // Peers joining the board haven't seen our posts yet
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Chat(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { topic, .. })))
+                if topic == board::topic() => {
+                board::republish(swarm, state, &self.nickname);
+            },
+
+            // Handle all chat events
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Chat(chat_event)) => {
+                handle_chat_event(chat_event, state, swarm, &mut self.topic).await;
+            },
+
+            // Handle all Kademlia events
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {id, result, .. })) => {
+                handle_kademlia_event(id, result, state, swarm, &self.topic, &mut self.nickname).await;
+            },
+
+            // New peers in the routing table may be closer to our keys than whoever holds our records now.
+            // The first one is also the first peer who can tell whether the nickname we started with is taken
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(kad::Event::RoutingUpdated { is_new_peer: true, .. })) => {
+                match &mut state.nickname_claim {
+                    NicknameClaim::Unchecked => check_own_nickname(swarm, state, &self.nickname),
+                    NicknameClaim::Checking { retry, .. } => *retry = true,
+                    NicknameClaim::Taken | NicknameClaim::Claimed => {}
+                }
+                if state.last_republish.elapsed() > REPUBLISH_DEBOUNCE {
+                    republish_own_records(swarm, state);
+                }
+            },
+
+
+            // Handle all file exchange events
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::RequestResponse(RequestResponseBehaviourEvent::RequestResponse(request_response_event))) => {
+                handle_req_res_event(request_response_event, swarm, &mut self.topic, state).await;
+            },
+
+            // When a new connection is made, discover other peers
+            SwarmEvent::ConnectionEstablished { peer_id, .. } if Some(peer_id) == state.rendezvous => {
+                state.servers.answered();
+                // A `swapbytes bootstrap` server holds DHT records too. Plain rendezvous servers just turn Kademlia away
+                swarm.behaviour_mut().kademlia.add_address(&peer_id, state.servers.active().clone());
+                if std::mem::take(&mut self.rendezvous_down) {
+                    system!("Reconnected to the rendezvous server.");
+                }
+                if let Err(error) = swarm.behaviour_mut().rendezvous.rendezvous.register(
+                    self.namespace.clone(),
+                    peer_id,
+                    state.registration.ttl,
+                ) {
+                    tracing::warn!("Failed to register: {error}");
+                } else {
+                    tracing::info!("Connection established with rendezvous point {}", peer_id);
+                    // The server may have restarted while we were away, so fetch everything again
+                    self.discovery_cookie = None;
+                    swarm.behaviour_mut().rendezvous.rendezvous.discover(
+                        Some(self.namespace.clone()),
+                        None,
+                        None,
+                        peer_id,
+                    )
+                }
+            },
+            // Records put before anyone was around only reached our own store, so publish them now there is someone to take them
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established, .. } => {
+                // Only addresses we dialled are worth keeping; inbound ones are the other side's ephemeral ports
+                if endpoint.is_dialer() {
+                    state.address_book.record_connection(peer_id, endpoint.get_remote_address().clone());
+                }
+                if num_established.get() == 1 && !state.records_published && state.pending_republish.is_empty() {
+                    republish_own_records(swarm, state);
+                }
+                if num_established.get() == 1 {
+                    state.dials.connected(peer_id);
+                    reconnect::peer_connected(swarm, state, peer_id);
+                }
+            },
+
+            // Connections go once they're idle, and pings don't keep them open, so the connection to the rendezvous
+            // server is dialled again as soon as it closes instead. Only if we were registered over it, so a server
+            // turning us away isn't dialled over and over; the discovery tick retries those
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } if Some(peer_id) == state.rendezvous => {
+                let was_registered = state.registration.lapsed();
+                if std::mem::take(&mut state.servers.switching) || was_registered {
+                    dial_rendezvous(swarm, state, &mut self.rendezvous_down);
+                }
+            },
+
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                state.dials.disconnected(peer_id);
+                state.subscribers.disconnected(&peer_id);
+                reconnect::peer_disconnected(swarm, state, peer_id);
+            },
+
+            // When another peer is discovered, connect with them
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(RendezvousBehaviourEvent::Rendezvous(rendezvous::client::Event::Discovered {
+                registrations,
+                cookie,
+                ..
+            }))) => {
+                self.discovery_cookie = Some(cookie);
+                for registration in registrations {
+                    let peer = registration.record.peer_id();
+                    // Our own registration comes back too
+                    if peer == *swarm.local_peer_id() {
+                        continue;
+                    }
+                    if !swarm.is_connected(&peer) {
+                        for address in registration.record.addresses() {
+                            tracing::info!("Discovered peer: {} at address: {}", peer, address);
+                            output::publish(output::Event::PeerDiscovered { peer, address: address.clone() });
+                        }
+                    }
+                    discovery::dial(swarm, state, peer, registration.record.addresses().to_vec());
+                }
+            }
+
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(RendezvousBehaviourEvent::Rendezvous(rendezvous::client::Event::Registered {
+                ttl,
+                ..
+            }))) => {
+                tracing::info!("Registered with the rendezvous server for {ttl}s");
+                state.registration.registered(ttl);
+            }
+
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(RendezvousBehaviourEvent::Rendezvous(rendezvous::client::Event::RegisterFailed {
+                error,
+                ..
+            }))) => match error {
+                rendezvous::ErrorCode::InvalidTtl => error!("The rendezvous server doesn't accept a registration TTL of {}s. Other peers can't discover you through it.", state.registration.ttl.unwrap_or(rendezvous::DEFAULT_TTL)),
+                error => error!("Could not register with the rendezvous server: {error:?}"),
+            },
+
+            // A cookie the server no longer knows, after it restarted say, is dropped so the next discovery fetches everything
+            SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(RendezvousBehaviourEvent::Rendezvous(rendezvous::client::Event::DiscoverFailed {
+                error,
+                ..
+            }))) => {
+                tracing::debug!("Discovery failed: {error:?}");
+                self.discovery_cookie = None;
+            }
+
+            _ => {},
+        }
+    }
+}
+
+// A --server: an IPv4 address, with the default port unless it has its own
+fn server_address(server: &str) -> Result<SocketAddrV4, String> {
+    server.parse::<SocketAddrV4>()
+        .or_else(|_| server.parse::<Ipv4Addr>().map(|ip| SocketAddrV4::new(ip, server::DEFAULT_PORT)))
+        .map_err(|_| format!("The rendezvous server must be an IPv4 address, optionally with a port, not {server:?}"))
+}
+
+// Connect to the active rendezvous server unless we already are. Failures are retried on the next discovery tick
+fn dial_rendezvous(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, rendezvous_down: &mut bool) {
+    let address = state.servers.active().clone();
+    let dial = match state.rendezvous {
+        Some(server) if swarm.is_connected(&server) => return,
+        Some(server) => DialOpts::peer_id(server).addresses(vec![address]).build(),
+        // Whoever answers at the address is taken to be the server, see handle_swarm_event
+        None if state.servers.dialling() => return,
+        None => DialOpts::unknown_peer_id().address(address).build(),
+    };
+    let connection_id = dial.connection_id();
+    match swarm.dial(dial) {
+        Ok(()) if state.rendezvous.is_none() => state.servers.dialling_unknown(connection_id),
+        // Still dialling from last time
+        Ok(()) | Err(DialError::DialPeerConditionFalse(_)) => {}
+        Err(e) => rendezvous_unreachable(rendezvous_down, e),
+    }
+}
+
+// Tell the user once that peer discovery is down, rather than every time a retry fails
+fn rendezvous_unreachable(rendezvous_down: &mut bool, error: impl std::fmt::Display + std::fmt::Debug) {
+    if *rendezvous_down {
+        tracing::debug!("Still can't reach the rendezvous server: {error}");
+        return;
+    }
+    *rendezvous_down = true;
+    output::report_failure("Could not reach the rendezvous server, retrying every 30 seconds", error);
+}
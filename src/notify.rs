@@ -0,0 +1,62 @@
+use std::sync::OnceLock;
+use tokio::process::Command;
+
+// The user's --notify command, run whenever something needs their attention
+static COMMAND: OnceLock<String> = OnceLock::new();
+
+// What a notification is about, passed to the command as SWAPBYTES_EVENT
+pub enum Trigger {
+    Mention,
+    Invite,
+    FileOffer,
+    FileRequest,
+    WishMatch,
+    Benchmark,
+}
+
+impl Trigger {
+    fn name(&self) -> &'static str {
+        match self {
+            Trigger::Mention => "mention",
+            Trigger::Invite => "invite",
+            Trigger::FileOffer => "file_offer",
+            Trigger::FileRequest => "file_request",
+            Trigger::WishMatch => "wish_match",
+            Trigger::Benchmark => "benchmark",
+        }
+    }
+}
+
+pub fn set_command(command: String) {
+    let _ = COMMAND.set(command);
+}
+
+// Run the --notify command in the background through the shell, describing the event in environment variables
+pub fn notify(trigger: Trigger, from: &str, text: &str) {
+    let Some(command) = COMMAND.get() else { return };
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("SWAPBYTES_EVENT", trigger.name())
+        .env("SWAPBYTES_FROM", from)
+        .env("SWAPBYTES_TEXT", text)
+        .spawn();
+    match child {
+        Ok(mut child) => {
+            tokio::spawn(async move {
+                match child.wait().await {
+                    Ok(status) if !status.success() => tracing::warn!("The notify command exited with {status}"),
+                    Err(e) => tracing::warn!("Failed to wait for the notify command: {e}"),
+                    _ => {}
+                }
+            });
+        }
+        Err(e) => tracing::warn!("Failed to run the notify command: {e}"),
+    }
+}
+
+// Whether a message mentions us by nickname, as a whole word and with or without an @
+pub fn mentions(body: &str, nickname: &str) -> bool {
+    body.split(|c: char| !c.is_alphanumeric() && c != '_' && c != '-')
+        .any(|word| word.eq_ignore_ascii_case(nickname))
+}
@@ -0,0 +1,248 @@
+use std::{ collections::{ HashMap, VecDeque }, path::Path, time::{ Duration, Instant } };
+use bytes::Bytes;
+use libp2p::{ request_response::{ OutboundFailure, OutboundRequestId }, PeerId };
+use tokio::fs;
+
+use crate::{ audit::{ self, AuditEvent }, behaviour::{ RequestType, SwapBytesBehaviour, REQUEST_TIMEOUT }, chunking, reconnect, trade::FileMeta, util::ChatState };
+
+// File requests and offers sent to one peer at a time; the rest wait their turn
+const MAX_IN_FLIGHT: usize = 2;
+// Transfers that can wait for one peer before new ones are refused
+const MAX_QUEUED: usize = 32;
+// How long a peer has to answer a chunked offer: the time they have to answer with /yes or /no, and a little more
+// for the answer to arrive
+const UNANSWERED_TIMEOUT: Duration = Duration::from_secs(REQUEST_TIMEOUT.as_secs() + 60);
+
+// File requests and offers on their way to each peer. Only MAX_IN_FLIGHT go out at once, so a burst of /request
+// and /offer commands doesn't flood a peer; transfers cut off by a dropped connection go back to the front of the queue
+#[derive(Default)]
+pub struct OutboundQueue {
+    in_flight: HashMap<OutboundRequestId, (PeerId, Transfer)>,
+    queued: HashMap<PeerId, VecDeque<Transfer>>,
+    // The chunk stream behind each chunked offer in flight, stopped unless the peer takes the offer
+    streams: HashMap<OutboundRequestId, String>,
+    // Chunked offers waiting for the peer's OfferAnswer, by transfer ID: who has it, the file, and since when its
+    // chunks were last being sent
+    unanswered: HashMap<String, (PeerId, String, Instant)>,
+}
+
+// A file request or offer for one peer. Offers too big for one message stay on disk until their turn comes, and
+// are then streamed from it a chunk at a time
+pub enum Transfer {
+    Send(Box<RequestType>),
+    StreamOffer { path: String, size: u64 },
+}
+
+impl Transfer {
+    fn is_offer(&self) -> bool {
+        match self {
+            Transfer::Send(request) => matches!(**request, RequestType::FileOffer(..)),
+            Transfer::StreamOffer { .. } => true,
+        }
+    }
+}
+
+impl OutboundQueue {
+    // Whether anything is being sent or waiting to be sent to this peer
+    pub fn has_transfers(&self, peer_id: &PeerId) -> bool {
+        self.in_flight.values().any(|(peer, _)| peer == peer_id) || self.queued.contains_key(peer_id)
+    }
+
+    // Whether one of our file requests to this peer is waiting on its response, which can come after its first chunks
+    pub fn awaits_file_from(&self, peer_id: &PeerId) -> bool {
+        self.in_flight.values().any(|(peer, transfer)| peer == peer_id && matches!(transfer, Transfer::Send(request) if matches!(**request, RequestType::FileRequest(..))))
+    }
+
+    // Transfers sent and waiting on an answer, and transfers waiting to be sent, for /stats
+    pub fn counts(&self) -> (usize, usize) {
+        (self.in_flight.len(), self.queued.values().map(VecDeque::len).sum())
+    }
+
+    fn in_flight_to(&self, peer_id: &PeerId) -> usize {
+        self.in_flight.values().filter(|(peer, _)| peer == peer_id).count()
+    }
+
+    // Peers with transfers waiting for a free slot
+    pub fn queued_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.queued.keys()
+    }
+
+    // Drop everything waiting for a peer we've given up on, returning how many transfers that was
+    pub fn cancel(&mut self, peer_id: &PeerId) -> usize {
+        self.queued.remove(peer_id).map_or(0, |queued| queued.len())
+    }
+}
+
+pub fn filename(transfer: &Transfer) -> &str {
+    match transfer {
+        Transfer::Send(request) => match &**request {
+            RequestType::FileRequest(filename, _) | RequestType::FileOffer(_, filename) => filename,
+            _ => "",
+        },
+        Transfer::StreamOffer { path, .. } => path,
+    }
+}
+
+// Queue a file request or offer for a peer, sending it straight away if there's a free slot
+pub fn send(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer_id: PeerId, request: RequestType) {
+    let (event, digest) = match &request {
+        RequestType::FileOffer(data, _) => (AuditEvent::OfferSent, Some(audit::digest(data))),
+        _ => (AuditEvent::RequestSent, None),
+    };
+    queue(swarm, state, peer_id, Transfer::Send(Box::new(request)), event, digest);
+}
+
+// Queue an offer of a file on disk, as long as it's still what `expected` describes, if anything. Files that fit in
+// one message are read now, and bigger ones only digested a block at a time. Returns false if the file couldn't be
+// read or has changed
+pub async fn offer(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer_id: PeerId, path: &str, expected: Option<&FileMeta>) -> bool {
+    let Ok(metadata) = fs::metadata(path).await else { return false };
+    if metadata.len() <= chunking::chunk_size(state, &peer_id) as u64 {
+        let Ok(data) = fs::read(path).await else { return false };
+        if expected.is_some_and(|expected| !expected.matches(&data)) {
+            return false;
+        }
+        send(swarm, state, peer_id, RequestType::FileOffer(data, path.to_string()));
+        return true;
+    }
+    let Ok(digest) = audit::digest_file(Path::new(path)).await else { return false };
+    if expected.is_some_and(|expected| expected.size != metadata.len() || expected.digest != digest) {
+        return false;
+    }
+    let transfer = Transfer::StreamOffer { path: path.to_string(), size: metadata.len() };
+    queue(swarm, state, peer_id, transfer, AuditEvent::OfferSent, Some(digest));
+    true
+}
+
+fn queue(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer_id: PeerId, transfer: Transfer, event: AuditEvent, digest: Option<String>) {
+    if !swarm.behaviour().gate.permits(&peer_id) {
+        error!("Your connection policy doesn't allow {}. Use /allow or /unlist first.", state.peer_name(&peer_id));
+        return;
+    }
+    let queued = state.outbound.queued.entry(peer_id).or_default();
+    if queued.len() >= MAX_QUEUED {
+        error!("{MAX_QUEUED} transfers are already waiting for {}. Try again once some have finished.", state.peer_name(&peer_id));
+        return;
+    }
+    let name = filename(&transfer).to_string();
+    queued.push_back(transfer);
+    state.audit(event, peer_id, &name, digest);
+    pump(swarm, state, peer_id);
+    if let Some(waiting) = state.outbound.queued.get(&peer_id) {
+        system!("{name} is queued behind {} other transfer(s) to {}.", waiting.len() - 1 + state.outbound.in_flight_to(&peer_id), state.peer_name(&peer_id));
+    }
+}
+
+// Send queued transfers to a peer while there are free slots, unless we're waiting for them to reconnect
+pub fn pump(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer_id: PeerId) {
+    if state.reconnector.is_redialling(&peer_id) {
+        return;
+    }
+    while state.outbound.in_flight_to(&peer_id) < MAX_IN_FLIGHT {
+        let Some(queued) = state.outbound.queued.get_mut(&peer_id) else { return };
+        let Some(transfer) = queued.pop_front() else { return };
+        if queued.is_empty() {
+            state.outbound.queued.remove(&peer_id);
+        }
+        // Offers too big for the peer to take in one message are streamed in pieces
+        let (request_id, stream) = match &transfer {
+            Transfer::Send(request) if let RequestType::FileOffer(data, filename) = &**request && data.len() > chunking::chunk_size(state, &peer_id) => {
+                let transfer_id = chunking::new_transfer_id();
+                let offer = RequestType::ChunkedFileOffer { transfer_id: transfer_id.clone(), filename: filename.clone(), size: data.len() as u64 };
+                let request_id = swarm.behaviour_mut().request_response.request_response.send_request(&peer_id, offer);
+                chunking::stream_bytes(swarm, state, peer_id, transfer_id.clone(), Bytes::copy_from_slice(data));
+                (request_id, Some(transfer_id))
+            }
+            Transfer::Send(request) => (swarm.behaviour_mut().request_response.request_response.send_request(&peer_id, (**request).clone()), None),
+            // Opened only now, so offers waiting their turn don't hold files open
+            Transfer::StreamOffer { path, size } => {
+                let file = match std::fs::File::open(path) {
+                    Ok(file) => fs::File::from_std(file),
+                    Err(e) => {
+                        error!("Could not send {path} to {}: {e}", state.peer_name(&peer_id));
+                        continue;
+                    }
+                };
+                let transfer_id = chunking::new_transfer_id();
+                let offer = RequestType::ChunkedFileOffer { transfer_id: transfer_id.clone(), filename: path.clone(), size: *size };
+                let request_id = swarm.behaviour_mut().request_response.request_response.send_request(&peer_id, offer);
+                chunking::stream_later(state, peer_id, transfer_id.clone(), file, *size);
+                (request_id, Some(transfer_id))
+            }
+        };
+        if transfer.is_offer() {
+            state.pending_file_offers.insert(request_id, filename(&transfer).to_string());
+        }
+        // The answer can come before the offer is acknowledged, if every chunk got there first
+        if let Some(transfer_id) = stream {
+            state.outbound.unanswered.insert(transfer_id.clone(), (peer_id, filename(&transfer).to_string(), Instant::now()));
+            state.outbound.streams.insert(request_id, transfer_id);
+        }
+        state.outbound.in_flight.insert(request_id, (peer_id, transfer));
+    }
+}
+
+// The response to a transfer arrived, freeing a slot for the next one. Returns the request it answered
+pub fn completed(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, request_id: &OutboundRequestId) -> Option<Transfer> {
+    stop_stream(state, request_id);
+    let (peer_id, transfer) = state.outbound.in_flight.remove(request_id)?;
+    pump(swarm, state, peer_id);
+    Some(transfer)
+}
+
+// The peer took a chunked offer, so its chunks keep going while the answer is waited for
+pub fn offer_taken(state: &mut ChatState, request_id: &OutboundRequestId) {
+    state.outbound.streams.remove(request_id);
+}
+
+// A peer's OfferAnswer arrived. Returns the file it answers for, if it was one of our offers to them
+pub fn answered(state: &mut ChatState, peer_id: PeerId, transfer_id: &str) -> Option<String> {
+    if state.outbound.unanswered.get(transfer_id).is_none_or(|(peer, _, _)| *peer != peer_id) {
+        return None;
+    }
+    chunking::stop_stream(state, transfer_id);
+    state.outbound.unanswered.remove(transfer_id).map(|(_, filename, _)| filename)
+}
+
+// Forget chunked offers whose answer never came, as the peer has long since dropped them. The wait only starts once
+// every chunk is sent, since the peer can't answer before that
+pub fn expire(state: &mut ChatState) {
+    for (transfer_id, (_, _, since)) in state.outbound.unanswered.iter_mut() {
+        if state.chunked.is_sending(transfer_id) {
+            *since = Instant::now();
+        }
+    }
+    let expired: Vec<String> = state.outbound.unanswered.iter()
+        .filter(|(_, (_, _, since))| since.elapsed() >= UNANSWERED_TIMEOUT)
+        .map(|(transfer_id, _)| transfer_id.clone())
+        .collect();
+    for transfer_id in expired {
+        let Some((peer_id, filename, _)) = state.outbound.unanswered.remove(&transfer_id) else { continue };
+        chunking::stop_stream(state, &transfer_id);
+        system!("{} never answered your offer of {filename}.", state.peer_name(&peer_id));
+    }
+}
+
+// A chunked offer that didn't get through takes its stream with it
+fn stop_stream(state: &mut ChatState, request_id: &OutboundRequestId) {
+    if let Some(transfer_id) = state.outbound.streams.remove(request_id) {
+        chunking::stop_stream(state, &transfer_id);
+        state.outbound.unanswered.remove(&transfer_id);
+    }
+}
+
+// A transfer failed. If the connection dropped it goes back in the queue until the peer reconnects; anything else
+// is reported. Returns false if the request wasn't a transfer
+pub fn failed(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, request_id: &OutboundRequestId, error: &OutboundFailure) -> bool {
+    let Some((peer_id, transfer)) = state.outbound.in_flight.remove(request_id) else { return false };
+    state.pending_file_offers.remove(request_id);
+    stop_stream(state, request_id);
+    if matches!(error, OutboundFailure::ConnectionClosed | OutboundFailure::DialFailure) {
+        state.outbound.queued.entry(peer_id).or_default().push_front(transfer);
+        reconnect::peer_disconnected(swarm, state, peer_id);
+    } else {
+        error!("Sending {} to {} failed: {error}", filename(&transfer), state.peer_name(&peer_id));
+        pump(swarm, state, peer_id);
+    }
+    true
+}
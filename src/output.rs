@@ -0,0 +1,333 @@
+use std::{ fmt, future::Future, io::Write, sync::{ atomic::{ AtomicBool, Ordering }, Arc, Mutex } };
+use libp2p::{ Multiaddr, PeerId };
+use serde::{ Deserialize, Serialize };
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::{ history::Direction, tui };
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+
+// There's one terminal however many nodes there are, so how it's drawn on is global
+static COLORS: AtomicBool = AtomicBool::new(true);
+static PROMPTS: AtomicBool = AtomicBool::new(true);
+// What's been typed so far while the line editor is reading the terminal, redrawn under every line printed
+static INPUT: Mutex<Option<String>> = Mutex::new(None);
+
+// The output of the node whose work is running, see Output::scope
+tokio::task_local! {
+    static CURRENT: Output;
+}
+
+// Where a node's events go, and what they're labelled with. Each node has its own, so several can run in one
+// process: while one is handling something, what any module prints goes to that node's frontends (ctl clients,
+// RPC, embedders) and is labelled with its room. Anything printed outside a node only reaches the terminal
+#[derive(Debug, Clone)]
+pub struct Output(Arc<Channel>);
+
+#[derive(Debug)]
+struct Channel {
+    events: broadcast::Sender<RoomEvent>,
+    json: bool, // --json: print every event as JSON instead of text
+    room: Mutex<Option<String>>,
+    // The room a message being handled arrived in, shown instead of room while it's set, see in_room
+    arrived_in: Mutex<Option<String>>,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Self {
+        Output(Arc::new(Channel { events: broadcast::channel(256).0, json, room: Mutex::new(None), arrived_in: Mutex::new(None) }))
+    }
+
+    // Receive every event from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<RoomEvent> {
+        self.0.events.subscribe()
+    }
+
+    // Run some work with what it prints going to this output
+    pub async fn scope<F: Future>(&self, work: F) -> F::Output {
+        CURRENT.scope(self.clone(), work).await
+    }
+
+    pub fn sync_scope<R>(&self, work: impl FnOnce() -> R) -> R {
+        CURRENT.sync_scope(self.clone(), work)
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::new(false)
+    }
+}
+
+fn current() -> Option<Output> {
+    CURRENT.try_with(Output::clone).ok()
+}
+
+// What a line is, which decides how it's colored
+pub enum Kind {
+    Chat,   // Messages from peers, colored per peer by render
+    System, // Status updates, prompts and command output
+    Error,  // Something went wrong
+}
+
+pub fn set_colors(enabled: bool) {
+    COLORS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn colors_enabled() -> bool {
+    COLORS.load(Ordering::Relaxed)
+}
+
+pub fn json_mode() -> bool {
+    current().is_some_and(|output| output.0.json)
+}
+
+// Whether anyone is at the terminal to answer prompts. Scripts and daemons answer with commands instead
+pub fn set_prompts(enabled: bool) {
+    PROMPTS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn prompts_enabled() -> bool {
+    PROMPTS.load(Ordering::Relaxed)
+}
+
+// The room events happen in, shown in front of every line
+pub fn set_room(room: &str) {
+    if let Some(output) = current() {
+        *output.0.room.lock().unwrap() = Some(room.to_string());
+    }
+}
+
+// Label what's printed with the room a message arrived in, rather than the one the user is typing in, until the
+// guard is dropped
+pub struct ArrivedIn(Option<Output>);
+
+pub fn in_room(room: &str) -> ArrivedIn {
+    let output = current();
+    if let Some(output) = &output {
+        *output.0.arrived_in.lock().unwrap() = Some(room.to_string());
+    }
+    ArrivedIn(output)
+}
+
+impl Drop for ArrivedIn {
+    fn drop(&mut self) {
+        if let Some(output) = &self.0 {
+            *output.0.arrived_in.lock().unwrap() = None;
+        }
+    }
+}
+
+fn current_room() -> Option<String> {
+    let output = current()?;
+    let arrived_in = output.0.arrived_in.lock().unwrap().clone();
+    arrived_in.or_else(|| output.0.room.lock().unwrap().clone())
+}
+
+// Show what's being typed at the bottom of the terminal, and keep it there
+pub fn show_input(typed: &str) {
+    *INPUT.lock().unwrap() = Some(typed.to_string());
+    if tui::set_input(typed) {
+        return;
+    }
+    print!("\r\x1b[K{typed}");
+    let _ = std::io::stdout().flush();
+}
+
+pub fn hide_input() {
+    if tui::stop() {
+        INPUT.lock().unwrap().take();
+        return;
+    }
+    if INPUT.lock().unwrap().take().is_some_and(|typed| !typed.is_empty()) {
+        println!();
+    }
+}
+
+// Something the user should know about. Lines printed with chat!, system! and error! become Chat, System and
+// Error events
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Message {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        peer: PeerId,
+        nickname: String,
+        rating: Option<f64>,
+        ratings: u32,
+        text: String,
+    },
+    // Messages already shown under a nickname the peer isn't registered with
+    NicknameMismatch { peer: PeerId, claimed: String, registered: String, messages: Vec<String> },
+    FileRequest { id: u32, peer: PeerId, nickname: String, filename: String },
+    FileOffer { id: u32, peer: PeerId, nickname: String, filename: String, size: usize },
+    BenchRequest { id: u32, peer: PeerId, nickname: String, size: u64 },
+    TransferComplete { peer: PeerId, filename: String, direction: Direction },
+    PeerDiscovered { peer: PeerId, address: Multiaddr },
+    Chat { text: String },
+    System { text: String },
+    Error { text: String },
+}
+
+// Plain text for frontends without their own wording
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Message { nickname, text, .. } => write!(f, "{nickname}: {text}"),
+            Event::FileRequest { id, nickname, filename, .. } => {
+                write!(f, "[{id}] {nickname} wants you to send {filename}. Type /yes {id} or /no {id} to answer.")
+            }
+            Event::FileOffer { id, nickname, filename, size, .. } => {
+                write!(f, "[{id}] {nickname} is offering {filename} ({size} bytes). Type /yes {id} or /no {id} to answer.")
+            }
+            Event::BenchRequest { id, nickname, size, .. } => {
+                write!(f, "[{id}] {nickname} wants to measure the connection by sending you {size} bytes. Type /yes {id} or /no {id} to answer.")
+            }
+            Event::TransferComplete { peer, filename, direction: Direction::Sent } => write!(f, "Sent {filename} to {peer}"),
+            Event::TransferComplete { peer, filename, direction: Direction::Received } => write!(f, "Received {filename} from {peer}"),
+            Event::PeerDiscovered { peer, address } => write!(f, "Discovered peer {peer} at {address}"),
+            Event::NicknameMismatch { peer, claimed, registered, .. } => {
+                write!(f, "Peer {peer} is using the nickname {claimed} but is registered as {registered}")
+            }
+            Event::Chat { text } | Event::System { text } | Event::Error { text } => write!(f, "{text}"),
+        }
+    }
+}
+
+// An event along with the room that was active when it happened
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomEvent {
+    pub room: Option<String>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl fmt::Display for RoomEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(room) = &self.room {
+            write!(f, "[{}] ", room_label(room))?;
+        }
+        write!(f, "{}", self.event)
+    }
+}
+
+// Private rooms are shortened to the start of their ID
+pub fn room_label(room: &str) -> &str {
+    if Uuid::try_parse(room).is_ok() { &room[..8] } else { room }
+}
+
+// Send an event to every frontend. In --json mode that includes the terminal; otherwise the terminal gets the
+// text printed by print or print_text
+pub fn publish(event: Event) {
+    let event = RoomEvent { room: current_room(), event };
+    if json_mode() {
+        match serde_json::to_string(&event) {
+            Ok(line) => println!("{line}"),
+            Err(e) => tracing::error!("Failed to encode an event: {e}"),
+        }
+    }
+    if let Some(output) = current() {
+        let _ = output.0.events.send(event);
+    }
+}
+
+pub fn print(kind: Kind, text: &str) {
+    let text = text.to_string();
+    publish(match kind {
+        Kind::Chat => Event::Chat { text: text.clone() },
+        Kind::System => Event::System { text: text.clone() },
+        Kind::Error => Event::Error { text: text.clone() },
+    });
+    print_text(kind, &text);
+}
+
+// Something on the network didn't work. The user is told what failed and why; the log gets the full error
+pub fn report_failure(what: &str, error: impl fmt::Display + fmt::Debug) {
+    tracing::debug!("{what}: {error:?}");
+    print(Kind::Error, &format!("{what}: {error}"));
+}
+
+// Print a line in text mode only, for when a structured event has already been published for it
+pub fn print_text(kind: Kind, text: &str) {
+    if json_mode() {
+        return;
+    }
+    let line = line(kind, text);
+    if tui::print(&line) {
+        return;
+    }
+    match INPUT.lock().unwrap().as_deref() {
+        // Clear the line being typed, print over it and put it back underneath
+        Some(typed) => {
+            print!("\r\x1b[K{line}\n{typed}");
+            let _ = std::io::stdout().flush();
+        }
+        None => println!("{line}"),
+    }
+}
+
+fn line(kind: Kind, text: &str) -> String {
+    let colors = colors_enabled();
+    let prefix = match current_room().as_deref() {
+        Some(room) if colors => format!("{DIM}[{}]{RESET} ", room_label(room)),
+        Some(room) => format!("[{}] ", room_label(room)),
+        None => String::new(),
+    };
+    let color = match kind {
+        _ if !colors => None,
+        Kind::Chat => None,
+        Kind::System => Some(YELLOW),
+        Kind::Error => Some(RED),
+    };
+    match color {
+        Some(color) => format!("{prefix}{color}{text}{RESET}"),
+        None => format!("{prefix}{text}"),
+    }
+}
+
+// println! for messages from peers
+macro_rules! chat {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Kind::Chat, &format!($($arg)*))
+    };
+}
+
+// println! for status messages
+macro_rules! system {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Kind::System, &format!($($arg)*))
+    };
+}
+
+// println! for errors
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::output::print($crate::output::Kind::Error, &format!($($arg)*))
+    };
+}
+
+// A command read from stdin in --json mode: {"command": "say", "text": "hello"} sends a chat message, anything
+// else is run as the slash command of that name, e.g. {"command": "offer", "args": ["notes.txt", "kush"]}
+#[derive(Deserialize)]
+struct JsonCommand {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    text: String,
+}
+
+// Turn a JSON command into the line a user would have typed
+pub fn parse_command(line: &str) -> Result<String, String> {
+    let command: JsonCommand = serde_json::from_str(line).map_err(|e| format!("Invalid JSON command: {e}"))?;
+    match command.command.as_str() {
+        "say" if command.text.is_empty() => Err("The say command needs some text".to_string()),
+        "say" => Ok(command.text),
+        name => Ok(format!("/{name} {}", command.args.join(" ")).trim_end().to_string()),
+    }
+}
@@ -0,0 +1,102 @@
+use std::{ fs, io::{ self, Write }, marker::PhantomData, path::{ Path, PathBuf }, time::{ SystemTime, UNIX_EPOCH } };
+use serde::{ de::DeserializeOwned, Serialize };
+
+// Seconds since the UNIX epoch, which is how times are kept in files, records and signed messages
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// A file holding one value as JSON, such as the address book or the wishlist, rewritten whole after every change.
+// Without a path, as when there's no profile, nothing is read or written and the value only lasts for this run
+pub struct JsonFile<T> {
+    path: Option<PathBuf>,
+    what: &'static str, // What the file holds, for errors
+    pretty: bool,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for JsonFile<T> {
+    fn default() -> Self {
+        JsonFile { path: None, what: "", pretty: false, value: PhantomData }
+    }
+}
+
+impl<T> Clone for JsonFile<T> {
+    fn clone(&self) -> Self {
+        JsonFile { path: self.path.clone(), what: self.what, pretty: self.pretty, value: PhantomData }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Default> JsonFile<T> {
+    // Read what a file holds. A missing file holds the default, and so does one that can't be read, after a warning
+    pub fn open(path: PathBuf, what: &'static str) -> (Self, T) {
+        let value = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable {what} in {}: {e}", path.display());
+                T::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => T::default(),
+            Err(e) => {
+                tracing::warn!("Could not read {what} from {}: {e}", path.display());
+                T::default()
+            }
+        };
+        (JsonFile { path: Some(path), what, pretty: false, value: PhantomData }, value)
+    }
+
+    // Write the file indented, for files people edit by hand
+    pub fn pretty(mut self) -> Self {
+        self.pretty = true;
+        self
+    }
+
+    // Replace what the file holds, returning whether it was written. Failures are logged, as there's nothing
+    // better to do about them than carry on with the value in memory
+    pub fn save(&self, value: &T) -> bool {
+        let Some(path) = &self.path else { return false };
+        let contents = if self.pretty { serde_json::to_vec_pretty(value) } else { serde_json::to_vec(value) };
+        let saved = contents.map_err(io::Error::from).and_then(|contents| write_private(path, &contents));
+        if let Err(e) = &saved {
+            tracing::error!("Failed to save {} to {}: {e}", self.what, path.display());
+        }
+        saved.is_ok()
+    }
+}
+
+// Open a file only its owner can read, whether or not it existed before
+fn open_private(path: &Path, options: &mut fs::OpenOptions) -> io::Result<fs::File> {
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(options, 0o600);
+    let file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    Ok(file)
+}
+
+// Where a file is written before it replaces the one at path, next to it so the rename stays on one filesystem
+fn temporary(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+// Replace a file with contents only its owner can read. They go to a temporary file first, so a crash or a full
+// disk leaves the old file whole rather than half written
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temporary = temporary(path);
+    let written = open_private(&temporary, fs::OpenOptions::new().write(true).create(true).truncate(true))
+        .and_then(|mut file| file.write_all(contents).and_then(|_| file.sync_all()));
+    if let Err(e) = written.and_then(|_| fs::rename(&temporary, path)) {
+        let _ = fs::remove_file(&temporary);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Add a line to the end of a file only its owner can read
+pub fn append_private(path: &Path, line: &str) -> io::Result<()> {
+    writeln!(open_private(path, fs::OpenOptions::new().create(true).append(true))?, "{line}")
+}
@@ -0,0 +1,135 @@
+use regex::{ Regex, RegexBuilder };
+
+use crate::{ emoji, util::{ ChatState, FilterOptions } };
+
+// Masked by --mask-profanity
+const PROFANITY: &[&str] = &["fuck", "fucking", "shit", "bitch", "bastard", "asshole", "dick", "cunt", "piss", "crap", "bollocks", "wanker"];
+
+// The filters applied to incoming chat, built from the --drop-pattern, --mask-profanity, --mask-word and
+// --max-message-length options
+#[derive(Default)]
+pub struct MessageFilter {
+    drop: Vec<Regex>,
+    mask: Option<Regex>,
+    max_length: Option<usize>,
+}
+
+impl MessageFilter {
+    pub fn new(options: &FilterOptions) -> Result<Self, String> {
+        let drop = options.drop_patterns.iter()
+            .map(|pattern| Regex::new(pattern).map_err(|e| format!("Invalid drop pattern {pattern:?}: {e}")))
+            .collect::<Result<_, _>>()?;
+        let words: Vec<String> = options.mask_profanity.unwrap_or_default().then_some(PROFANITY).into_iter().flatten()
+            .map(|word| word.to_string())
+            .chain(options.mask_words.iter().cloned())
+            .map(|word| {
+                // Only whole words are masked, but \b can't match next to a character like the + in c++
+                let edge = |c: Option<char>| if c.is_some_and(|c| c.is_alphanumeric() || c == '_') { r"\b" } else { "" };
+                format!("{}{}{}", edge(word.chars().next()), regex::escape(&word), edge(word.chars().last()))
+            })
+            .collect();
+        let mask = match words.is_empty() {
+            true => None,
+            false => Some(RegexBuilder::new(&format!("({})", words.join("|")))
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| e.to_string())?),
+        };
+        Ok(MessageFilter { drop, mask, max_length: options.max_message_length })
+    }
+}
+
+// Transformations applied to a message body before it is published
+pub fn outgoing(state: &ChatState, body: &str) -> String {
+    let mut body = body.to_string();
+    if state.emoji_enabled {
+        body = emoji::expand_shortcodes(&body);
+    }
+    body
+}
+
+// Transformations applied to a received message body before it is displayed, or None if it should be dropped
+pub fn incoming(state: &ChatState, body: &str) -> Option<String> {
+    let filter = &state.message_filter;
+    if filter.drop.iter().any(|pattern| pattern.is_match(body)) {
+        tracing::debug!("Dropped a message matching a drop pattern");
+        return None;
+    }
+    let mut body = body.to_string();
+    if let Some(max_length) = filter.max_length
+        && let Some((cut, _)) = body.char_indices().nth(max_length) {
+        body.truncate(cut);
+        body.push_str("… (truncated)");
+    }
+    if let Some(mask) = &filter.mask {
+        body = mask.replace_all(&body, |word: &regex::Captures| "#".repeat(word[0].chars().count())).into_owned();
+    }
+    if state.emoji_enabled {
+        body = emoji::expand_shortcodes(&body);
+    }
+    Some(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(options: FilterOptions) -> ChatState {
+        let mut state = ChatState::new(libp2p::identity::Keypair::generate_ed25519(), None);
+        state.message_filter = MessageFilter::new(&options).unwrap();
+        state
+    }
+
+    #[test]
+    fn nothing_is_filtered_by_default() {
+        let state = state(FilterOptions::default());
+        assert_eq!(incoming(&state, "crap, a shit day").as_deref(), Some("crap, a shit day"));
+    }
+
+    #[test]
+    fn messages_matching_a_drop_pattern_are_dropped() {
+        let state = state(FilterOptions { drop_patterns: vec![r"(?i)free\s+crypto".to_string(), "^spam$".to_string()], ..Default::default() });
+        assert_eq!(incoming(&state, "Get FREE  crypto now"), None);
+        assert_eq!(incoming(&state, "spam"), None);
+        assert_eq!(incoming(&state, "no spam here").as_deref(), Some("no spam here"));
+    }
+
+    #[test]
+    fn an_invalid_drop_pattern_is_refused() {
+        let options = FilterOptions { drop_patterns: vec!["(unclosed".to_string()], ..Default::default() };
+        assert!(MessageFilter::new(&options).is_err());
+    }
+
+    #[test]
+    fn masked_words_are_hashed_out_as_whole_words_in_any_case() {
+        let state = state(FilterOptions { mask_profanity: Some(true), mask_words: vec!["c++".to_string()], ..Default::default() });
+        assert_eq!(incoming(&state, "Shit, I wrote it in C++").as_deref(), Some("####, I wrote it in ###"));
+        // Only whole words, so words that merely contain one are left alone
+        assert_eq!(incoming(&state, "Scunthorpe dickens").as_deref(), Some("Scunthorpe dickens"));
+    }
+
+    #[test]
+    fn long_messages_are_cut_at_a_character_boundary() {
+        let state = state(FilterOptions { max_message_length: Some(3), ..Default::default() });
+        assert_eq!(incoming(&state, "héllo").as_deref(), Some("hél… (truncated)"));
+        assert_eq!(incoming(&state, "hé!").as_deref(), Some("hé!"));
+    }
+
+    // Messages are cut before they're masked, and masked before shortcodes are expanded, so neither a mask nor
+    // an emoji counts towards the length or is cut in half
+    #[test]
+    fn the_stages_run_in_order() {
+        let mut state = state(FilterOptions { mask_words: vec!["darn".to_string()], max_message_length: Some(13), ..Default::default() });
+        assert_eq!(incoming(&state, "darn :smile: and more").as_deref(), Some("#### 😄 … (truncated)"));
+        state.emoji_enabled = false;
+        assert_eq!(incoming(&state, "darn :smile:").as_deref(), Some("#### :smile:"));
+    }
+
+    #[test]
+    fn shortcodes_are_expanded_in_outgoing_messages_unless_turned_off() {
+        let mut state = state(FilterOptions::default());
+        assert_eq!(outgoing(&state, "nice :+1:"), "nice 👍");
+        state.emoji_enabled = false;
+        assert_eq!(outgoing(&state, "nice :+1:"), "nice :+1:");
+    }
+}
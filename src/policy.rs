@@ -0,0 +1,202 @@
+use std::{ collections::{ BTreeSet, HashSet }, convert::Infallible, fmt, path::PathBuf, task::{ Context, Poll } };
+use libp2p::{
+    core::{ transport::PortUse, Endpoint },
+    swarm::{ dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm },
+    Multiaddr, PeerId,
+};
+use serde::{ Deserialize, Serialize };
+
+use crate::persist::JsonFile;
+
+// Who we accept connections from, as saved in the --policy file
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolicyLists {
+    // Only peers on the allow list can connect
+    pub allow_only: bool,
+    pub allowed: BTreeSet<PeerId>,
+    // Never connect to these peers, whatever the mode
+    pub denied: BTreeSet<PeerId>,
+}
+
+// Why a connection was refused
+#[derive(Debug)]
+pub struct Refused(PeerId);
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not allowed to connect by the connection policy", self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+// Refuses connections from denied peers, and from everyone off the allow list in allow-only mode, before any other
+// behaviour sees them. That way denied peers can't send us requests, gossip or invites either
+#[derive(Default)]
+pub struct ConnectionGate {
+    lists: PolicyLists,
+    // Peers the node needs whatever the lists say, like the rendezvous server
+    exempt: HashSet<PeerId>,
+    file: JsonFile<PolicyLists>,
+}
+
+impl ConnectionGate {
+    pub fn load(file: PathBuf) -> Self {
+        let (file, lists) = JsonFile::open(file, "the connection policy");
+        ConnectionGate { lists, exempt: HashSet::new(), file: file.pretty() }
+    }
+
+    // Only these peers can connect, for servers given --allow-peers. Nothing is saved back
+    pub fn allow_only(allowed: impl IntoIterator<Item = PeerId>) -> Self {
+        let lists = PolicyLists { allow_only: true, allowed: allowed.into_iter().collect(), denied: BTreeSet::new() };
+        ConnectionGate { lists, exempt: HashSet::new(), file: JsonFile::default() }
+    }
+
+    pub fn exempt(&mut self, peer_id: PeerId) {
+        self.exempt.insert(peer_id);
+    }
+
+    pub fn lists(&self) -> &PolicyLists {
+        &self.lists
+    }
+
+    pub fn permits(&self, peer_id: &PeerId) -> bool {
+        self.exempt.contains(peer_id)
+            || (!self.lists.denied.contains(peer_id) && (!self.lists.allow_only || self.lists.allowed.contains(peer_id)))
+    }
+
+    pub fn deny(&mut self, peer_id: PeerId) {
+        self.lists.allowed.remove(&peer_id);
+        self.lists.denied.insert(peer_id);
+        self.save();
+    }
+
+    pub fn allow(&mut self, peer_id: PeerId) {
+        self.lists.denied.remove(&peer_id);
+        self.lists.allowed.insert(peer_id);
+        self.save();
+    }
+
+    // Take a peer off both lists, returning whether it was on either
+    pub fn unlist(&mut self, peer_id: &PeerId) -> bool {
+        let removed = self.lists.denied.remove(peer_id) | self.lists.allowed.remove(peer_id);
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    pub fn set_allow_only(&mut self, allow_only: bool) {
+        self.lists.allow_only = allow_only;
+        self.save();
+    }
+
+    fn check(&self, peer_id: PeerId) -> Result<(), ConnectionDenied> {
+        if self.permits(&peer_id) {
+            Ok(())
+        } else {
+            tracing::debug!("Refused a connection with {peer_id}");
+            Err(ConnectionDenied::new(Refused(peer_id)))
+        }
+    }
+
+    fn save(&self) {
+        self.file.save(&self.lists);
+    }
+}
+
+impl NetworkBehaviour for ConnectionGate {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_pending_outbound_connection(&mut self, _: ConnectionId, peer_id: Option<PeerId>, _: &[Multiaddr], _: Endpoint) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        if let Some(peer_id) = peer_id {
+            self.check(peer_id)?;
+        }
+        Ok(vec![])
+    }
+
+    fn handle_established_inbound_connection(&mut self, _: ConnectionId, peer_id: PeerId, _: &Multiaddr, _: &Multiaddr) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check(peer_id)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(&mut self, _: ConnectionId, peer_id: PeerId, _: &Multiaddr, _: Endpoint, _: PortUse) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check(peer_id)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, _: FromSwarm) {}
+
+    fn on_connection_handler_event(&mut self, _: PeerId, _: ConnectionId, event: THandlerOutEvent<Self>) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> PeerId {
+        libp2p::identity::Keypair::generate_ed25519().public().to_peer_id()
+    }
+
+    #[test]
+    fn everyone_is_permitted_until_denied() {
+        let mut gate = ConnectionGate::default();
+        let (denied, other) = (peer(), peer());
+        assert!(gate.permits(&denied));
+        gate.deny(denied);
+        assert!(!gate.permits(&denied));
+        assert!(gate.permits(&other));
+        assert!(gate.unlist(&denied));
+        assert!(gate.permits(&denied));
+    }
+
+    #[test]
+    fn allow_only_permits_just_the_allow_list() {
+        let mut gate = ConnectionGate::default();
+        let (allowed, other) = (peer(), peer());
+        gate.allow(allowed);
+        gate.set_allow_only(true);
+        assert!(gate.permits(&allowed));
+        assert!(!gate.permits(&other));
+        assert!(ConnectionGate::allow_only([allowed]).permits(&allowed));
+        assert!(!ConnectionGate::allow_only([allowed]).permits(&other));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let peer_id = peer();
+        // Denying takes a peer off the allow list, and allowing takes them off the deny list
+        let mut gate = ConnectionGate::default();
+        gate.allow(peer_id);
+        gate.deny(peer_id);
+        assert!(!gate.lists().allowed.contains(&peer_id));
+        assert!(!gate.permits(&peer_id));
+        gate.allow(peer_id);
+        assert!(gate.permits(&peer_id));
+
+        // A hand-edited policy file can list them on both, and the deny list still wins, in either mode
+        let lists = |allow_only| PolicyLists { allow_only, allowed: BTreeSet::from([peer_id]), denied: BTreeSet::from([peer_id]) };
+        for allow_only in [false, true] {
+            let gate = ConnectionGate { lists: lists(allow_only), exempt: HashSet::new(), file: JsonFile::default() };
+            assert!(!gate.permits(&peer_id));
+        }
+    }
+
+    #[test]
+    fn exempt_peers_are_permitted_whatever_the_lists_say() {
+        let mut gate = ConnectionGate::allow_only([]);
+        let server = peer();
+        gate.deny(server);
+        gate.exempt(server);
+        assert!(gate.permits(&server));
+        assert!(!gate.permits(&peer()));
+    }
+}
@@ -0,0 +1,196 @@
+use std::{ collections::HashMap, time::{ Duration, Instant } };
+use libp2p::PeerId;
+
+use crate::{ behaviour::{ RequestType, ResponseType }, trade::TradeAnswer, util::{ ChatState, PrivateRoomProtocol } };
+
+// File requests, offers and invites a peer can send each minute unless --rate-limit says otherwise
+pub const DEFAULT_RATE_LIMIT: u32 = 10;
+const WINDOW: Duration = Duration::from_secs(60);
+// With --auto-mute, going over the limit in this many minutes mutes the peer
+const STRIKES_BEFORE_MUTE: u32 = 3;
+// How long a mute lasts, and how long strikes are remembered
+const MUTE_FOR: Duration = Duration::from_secs(10 * 60);
+
+struct PeerWindow {
+    started: Instant,
+    count: u32,
+    strikes: u32,
+    last_strike: Instant,
+}
+
+// What counting one more request from a peer came to
+#[derive(Debug, PartialEq, Eq)]
+enum Count {
+    Allowed,
+    Refused,
+    Strike, // The first request over the limit this minute
+    Muted,  // A strike that got them muted
+}
+
+// Counts what each peer sends us, so one peer can't flood us with prompts
+pub struct RateLimiter {
+    limit: u32, // Per minute; 0 turns limiting off
+    auto_mute: bool,
+    windows: HashMap<PeerId, PeerWindow>,
+    muted: HashMap<PeerId, Instant>, // Until when
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(DEFAULT_RATE_LIMIT, false)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, auto_mute: bool) -> Self {
+        RateLimiter { limit, auto_mute, windows: HashMap::new(), muted: HashMap::new() }
+    }
+
+    pub fn is_muted(&mut self, peer_id: &PeerId) -> bool {
+        match self.muted.get(peer_id) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                self.muted.remove(peer_id);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Returns whether the peer was muted
+    pub fn unmute(&mut self, peer_id: &PeerId) -> bool {
+        self.windows.remove(peer_id);
+        self.muted.remove(peer_id).is_some()
+    }
+
+    // Count a request from a peer that arrived at `now`
+    fn count(&mut self, peer_id: PeerId, now: Instant) -> Count {
+        match self.muted.get(&peer_id) {
+            Some(until) if *until > now => return Count::Refused,
+            Some(_) => {
+                self.muted.remove(&peer_id);
+            }
+            None => {}
+        }
+        if self.limit == 0 {
+            return Count::Allowed;
+        }
+        let window = self.windows.entry(peer_id).or_insert(PeerWindow { started: now, count: 0, strikes: 0, last_strike: now });
+        if now.duration_since(window.started) >= WINDOW {
+            window.started = now;
+            window.count = 0;
+        }
+        window.count += 1;
+        if window.count <= self.limit {
+            return Count::Allowed;
+        }
+        // Only the first request over the limit is a strike; the rest that minute are just dropped
+        if window.count > self.limit + 1 {
+            return Count::Refused;
+        }
+        if now.duration_since(window.last_strike) >= MUTE_FOR {
+            window.strikes = 0;
+        }
+        window.strikes += 1;
+        window.last_strike = now;
+        if self.auto_mute && window.strikes >= STRIKES_BEFORE_MUTE {
+            self.windows.remove(&peer_id);
+            self.muted.insert(peer_id, now + MUTE_FOR);
+            return Count::Muted;
+        }
+        Count::Strike
+    }
+}
+
+// The answer to send instead when a request is dropped, for the kinds of request that are limited
+pub fn refusal(request: &RequestType) -> Option<ResponseType> {
+    match request {
+        RequestType::FileRequest(..) => Some(ResponseType::FileResponse(vec![], String::new())),
+        RequestType::FileOffer(..) | RequestType::ChunkedFileOffer { .. } => Some(ResponseType::FileOfferResponse(false)),
+        RequestType::FileManifest(..) => Some(ResponseType::ManifestAck(false)),
+        RequestType::BenchRequest { bench_id, .. } => Some(ResponseType::BenchResponse { bench_id: bench_id.clone(), accepted: false, addresses: Vec::new() }),
+        RequestType::TradeProposal(proposal) => Some(ResponseType::TradeResponse {
+            trade_id: proposal.trade_id.clone(),
+            answer: TradeAnswer::Declined("you sent too many requests".to_string()),
+        }),
+        RequestType::PrivateRoomRequest(invite) => Some(ResponseType::PrivateRoomResponse(PrivateRoomProtocol::Reject(invite.room_id.clone()))),
+        _ => None,
+    }
+}
+
+// Files we chose from a peer's multi-file offer don't count against their limit, since we asked for them
+pub fn taken_from_manifest(state: &ChatState, peer_id: &PeerId, request: &RequestType) -> bool {
+    match request {
+        RequestType::FileOffer(_, filename) | RequestType::ChunkedFileOffer { filename, .. } => state.manifests.expects(peer_id, filename),
+        _ => false,
+    }
+}
+
+// Count a request from a peer, returning whether it's within their limit. The user is told the first time a peer
+// goes over in each minute, and when they get muted
+pub fn allow(state: &mut ChatState, peer_id: PeerId) -> bool {
+    let limit = state.rate_limiter.limit;
+    match state.rate_limiter.count(peer_id, Instant::now()) {
+        Count::Allowed => true,
+        Count::Refused => false,
+        Count::Strike => {
+            error!("{} sent more than {limit} file requests, offers and invites in a minute. The rest are being refused.", state.peer_name(&peer_id));
+            false
+        }
+        Count::Muted => {
+            error!("Muted {} for {} minutes for sending too many requests. /unmute lets them back.", state.peer_name(&peer_id), MUTE_FOR.as_secs() / 60);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> PeerId {
+        libp2p::identity::Keypair::generate_ed25519().public().to_peer_id()
+    }
+
+    // Count `requests` from a peer all at once
+    fn burst(limiter: &mut RateLimiter, peer_id: PeerId, now: Instant, requests: usize) -> Vec<Count> {
+        (0..requests).map(|_| limiter.count(peer_id, now)).collect()
+    }
+
+    #[test]
+    fn a_burst_is_allowed_up_to_the_limit_with_one_strike_after() {
+        let mut limiter = RateLimiter::new(3, false);
+        let (peer_id, now) = (peer(), Instant::now());
+        assert_eq!(burst(&mut limiter, peer_id, now, 5), vec![Count::Allowed, Count::Allowed, Count::Allowed, Count::Strike, Count::Refused]);
+        // Each peer has a limit of their own
+        assert_eq!(limiter.count(peer(), now), Count::Allowed);
+    }
+
+    #[test]
+    fn the_limit_refills_a_minute_after_the_window_started() {
+        let mut limiter = RateLimiter::new(2, false);
+        let (peer_id, now) = (peer(), Instant::now());
+        burst(&mut limiter, peer_id, now, 3);
+        assert_eq!(limiter.count(peer_id, now + WINDOW - Duration::from_secs(1)), Count::Refused);
+        assert_eq!(burst(&mut limiter, peer_id, now + WINDOW, 3), vec![Count::Allowed, Count::Allowed, Count::Strike]);
+    }
+
+    #[test]
+    fn repeated_strikes_mute_with_auto_mute_until_the_mute_ends() {
+        let mut limiter = RateLimiter::new(1, true);
+        let (peer_id, now) = (peer(), Instant::now());
+        let minute = |n: u32| now + WINDOW * n;
+        assert_eq!(burst(&mut limiter, peer_id, minute(0), 2), vec![Count::Allowed, Count::Strike]);
+        assert_eq!(burst(&mut limiter, peer_id, minute(1), 2), vec![Count::Allowed, Count::Strike]);
+        assert_eq!(burst(&mut limiter, peer_id, minute(2), 2), vec![Count::Allowed, Count::Muted]);
+        assert_eq!(limiter.count(peer_id, minute(3)), Count::Refused);
+        assert_eq!(limiter.count(peer_id, minute(2) + MUTE_FOR), Count::Allowed);
+    }
+
+    #[test]
+    fn a_limit_of_zero_allows_everything() {
+        let mut limiter = RateLimiter::new(0, true);
+        let peer_id = peer();
+        assert!(burst(&mut limiter, peer_id, Instant::now(), 100).iter().all(|count| *count == Count::Allowed));
+    }
+}
@@ -0,0 +1,390 @@
+use std::{ cmp::Ordering, collections::{ HashMap, HashSet }, fmt, time::Instant };
+use libp2p::{ identity::{ self, Keypair }, kad, PeerId };
+use serde::{ Deserialize, Serialize };
+
+use crate::{ audit::SignedTradeReceipt, behaviour::SwapBytesBehaviour, persist::unix_now, util::PEER_CACHE_TTL };
+
+// A single rating, signed by the rater over the ratee and the score so it can't be forged or altered
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedRating {
+    pub rater: PeerId,
+    pub rater_key: Vec<u8>, // Protobuf-encoded identity key of the rater
+    pub ratee: PeerId,
+    pub score: i32,
+    pub timestamp: u64, // Seconds since the UNIX epoch; the latest rating from each rater wins
+    pub signature: Vec<u8>,
+    // The ratee's receipt for a trade with the rater, showing the rating comes from someone who traded with them
+    #[serde(default)]
+    pub proof: Option<SignedTradeReceipt>,
+}
+
+impl SignedRating {
+    pub fn new(keypair: &Keypair, ratee: PeerId, score: i32, proof: Option<SignedTradeReceipt>) -> Self {
+        let timestamp = unix_now();
+        let signature = keypair.sign(&payload(&ratee, score, timestamp)).expect("Signing failed");
+        SignedRating {
+            rater: keypair.public().to_peer_id(),
+            rater_key: keypair.public().encode_protobuf(),
+            ratee,
+            score,
+            timestamp,
+            signature,
+            proof,
+        }
+    }
+
+    // Whether the rating comes with a receipt the ratee signed for a trade with the rater
+    pub fn proven(&self) -> bool {
+        self.proof.as_ref().is_some_and(|receipt| receipt.verify(&self.ratee, &self.rater))
+    }
+
+    // Check the rating is about this peer and was signed by the rater it names
+    pub fn verify(&self, ratee: &PeerId) -> bool {
+        if self.ratee != *ratee || self.rater == *ratee || !(-1..=1).contains(&self.score) {
+            return false;
+        }
+        match identity::PublicKey::try_decode_protobuf(&self.rater_key) {
+            Ok(public_key) => public_key.to_peer_id() == self.rater && public_key.verify(&payload(&self.ratee, self.score, self.timestamp), &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+// The ratings that count: ones that verify, and only the latest from each rater,
+// so replaying old ratings or rating the same peer repeatedly has no effect
+pub fn effective(ratings: &[SignedRating], ratee: &PeerId) -> Vec<SignedRating> {
+    let mut latest: HashMap<PeerId, &SignedRating> = HashMap::new();
+    for rating in ratings.iter().filter(|rating| rating.verify(ratee)) {
+        let newer = latest.get(&rating.rater).is_none_or(|current| rating.timestamp > current.timestamp);
+        if newer {
+            latest.insert(rating.rater, rating);
+        }
+    }
+    latest.into_values().cloned().collect()
+}
+
+// How much a rating without a trade receipt counts next to one with. Anyone can rate anyone, so on their own
+// they'd let a handful of throwaway identities set a peer's reputation
+pub const UNPROVEN_WEIGHT: f64 = 0.25;
+
+// A peer's reputation: the total of their effective ratings, how many there are, and the same for those
+// backed by a trade receipt
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RatingSummary {
+    pub sum: i32,
+    pub count: u32,
+    #[serde(default)]
+    pub proven: u32,
+    #[serde(default)]
+    pub proven_sum: i32,
+}
+
+impl RatingSummary {
+    // The average score, with ratings that have no trade receipt weighted by UNPROVEN_WEIGHT
+    pub fn average(&self) -> f64 {
+        let unproven = self.count.saturating_sub(self.proven) as f64 * UNPROVEN_WEIGHT;
+        let weight = self.proven as f64 + unproven;
+        if weight == 0.0 {
+            0.0
+        } else {
+            (self.proven_sum as f64 + (self.sum - self.proven_sum) as f64 * UNPROVEN_WEIGHT) / weight
+        }
+    }
+
+    // Order by average, then by number of ratings, so 300 positive trades beat 3
+    pub fn cmp_reputation(&self, other: &RatingSummary) -> Ordering {
+        self.average()
+            .total_cmp(&other.average())
+            .then_with(|| self.count.cmp(&other.count))
+    }
+}
+
+impl fmt::Display for RatingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.count {
+            0 => write!(f, "unrated"),
+            1 => write!(f, "{:+.2}★ from 1 rating", self.average()),
+            count => write!(f, "{:+.2}★ from {count} ratings", self.average()),
+        }?;
+        match self.proven {
+            0 => Ok(()),
+            proven => write!(f, ", {proven} backed by a trade receipt"),
+        }
+    }
+}
+
+// The displayed rating: the average of the effective ratings and how many there are
+pub fn aggregate(ratings: &[SignedRating], ratee: &PeerId) -> RatingSummary {
+    let effective = effective(ratings, ratee);
+    let proven: Vec<&SignedRating> = effective.iter().filter(|rating| rating.proven()).collect();
+    RatingSummary {
+        sum: effective.iter().map(|rating| rating.score).sum(),
+        count: effective.len() as u32,
+        proven: proven.len() as u32,
+        proven_sum: proven.iter().map(|rating| rating.score).sum(),
+    }
+}
+
+// Where a rating is stored in the DHT. Each rater has their own key for each peer they rate, so the ratee, who
+// publishes their own profile, has no way to drop or change the ratings they've been given
+pub fn record_key(ratee: &PeerId, rater: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("rating:{ratee}:{rater}"))
+}
+
+// What raters provide in the DHT, so the ratings for a peer can be found without knowing who gave them
+pub fn raters_key(ratee: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("rating:{ratee}"))
+}
+
+// Store our rating of a peer under our own key and announce it. Returns whether it replaces an earlier one
+pub fn publish(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, rating: &SignedRating, quorum: kad::Quorum) -> Result<bool, kad::store::Error> {
+    let key = record_key(&rating.ratee, &rating.rater);
+    let kademlia = &mut swarm.behaviour_mut().kademlia;
+    let previous = kad::store::RecordStore::get(kademlia.store_mut(), &key).is_some();
+    let record = kad::Record { key, value: serde_json::to_vec(rating).expect("Serialization failed"), publisher: None, expires: None };
+    kademlia.put_record(record, quorum)?;
+    kademlia.start_providing(raters_key(&rating.ratee))?;
+    Ok(previous)
+}
+
+// The ratings we've given, from our local record store
+pub fn given(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>) -> Vec<SignedRating> {
+    let local_peer_id = *swarm.local_peer_id();
+    kad::store::RecordStore::records(swarm.behaviour_mut().kademlia.store_mut())
+        .filter(|record| record.key.as_ref().starts_with(b"rating:"))
+        .filter_map(|record| serde_json::from_slice::<SignedRating>(&record.value).ok())
+        .filter(|rating| rating.rater == local_peer_id)
+        .collect()
+}
+
+// A lookup of one peer's ratings: finding who has rated them, then fetching each of those ratings
+#[derive(Default)]
+struct Lookup {
+    found: Vec<SignedRating>,
+    raters: HashSet<PeerId>,
+    fetching: usize,
+    finding: bool,
+}
+
+// The ratings we've looked up in the DHT for each peer, kept as long as a cached profile
+#[derive(Default)]
+pub struct Ratings {
+    known: HashMap<PeerId, (Vec<SignedRating>, Instant)>,
+    lookups: HashMap<PeerId, Lookup>,
+    finding: HashMap<kad::QueryId, PeerId>, // Provider queries for the raters of a peer
+    fetching: HashMap<kad::QueryId, PeerId>, // Record queries for a single rating
+}
+
+impl Ratings {
+    // A peer's rating, if their ratings have been looked up
+    pub fn summary(&self, ratee: &PeerId) -> Option<RatingSummary> {
+        self.known.get(ratee).map(|(ratings, _)| aggregate(ratings, ratee))
+    }
+
+    // Look up a peer's ratings unless we have them already. Returns whether a lookup is under way
+    pub fn lookup(&mut self, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, ratee: PeerId) -> bool {
+        if self.lookups.contains_key(&ratee) {
+            return true;
+        }
+        if self.known.get(&ratee).is_some_and(|(_, fetched)| fetched.elapsed() <= PEER_CACHE_TTL) {
+            return false;
+        }
+        let query_id = swarm.behaviour_mut().kademlia.get_providers(raters_key(&ratee));
+        self.finding.insert(query_id, ratee);
+        self.lookups.insert(ratee, Lookup { finding: true, ..Default::default() });
+        true
+    }
+
+    // Add or replace our own rating without waiting for a lookup
+    pub fn given(&mut self, rating: SignedRating) {
+        if let Some((ratings, _)) = self.known.get_mut(&rating.ratee) {
+            ratings.retain(|existing| existing.rater != rating.rater);
+            ratings.push(rating);
+        }
+    }
+
+    // Some of the raters of a peer were found: fetch their ratings
+    pub fn raters_found(&mut self, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, id: &kad::QueryId, raters: HashSet<PeerId>) {
+        let Some(ratee) = self.finding.get(id).copied() else { return };
+        let Some(lookup) = self.lookups.get_mut(&ratee) else { return };
+        for rater in raters {
+            if lookup.raters.insert(rater) {
+                let query_id = swarm.behaviour_mut().kademlia.get_record(record_key(&ratee, &rater));
+                self.fetching.insert(query_id, ratee);
+                lookup.fetching += 1;
+            }
+        }
+    }
+
+    // Every rater of a peer has been found, or the search failed. Returns the peer if that finished their lookup
+    pub fn raters_finished(&mut self, id: &kad::QueryId) -> Option<PeerId> {
+        let ratee = self.finding.remove(id)?;
+        self.lookups.get_mut(&ratee)?.finding = false;
+        self.finish(ratee)
+    }
+
+    // Whether a query is fetching a rating
+    pub fn fetches(&self, id: &kad::QueryId) -> bool {
+        self.fetching.contains_key(id)
+    }
+
+    // A rating was fetched, or couldn't be. Only a rating of the peer signed by the rater whose key it's under is kept.
+    // Returns the peer if that finished their lookup
+    pub fn fetched(&mut self, id: &kad::QueryId, record: Option<&kad::Record>) -> Option<PeerId> {
+        let ratee = self.fetching.remove(id)?;
+        let lookup = self.lookups.get_mut(&ratee)?;
+        lookup.fetching -= 1;
+        let rating = record.and_then(|record| serde_json::from_slice::<SignedRating>(&record.value).ok()
+            .filter(|rating| record.key == record_key(&ratee, &rating.rater) && rating.verify(&ratee)));
+        lookup.found.extend(rating);
+        self.finish(ratee)
+    }
+
+    fn finish(&mut self, ratee: PeerId) -> Option<PeerId> {
+        let lookup = self.lookups.get(&ratee)?;
+        if lookup.finding || lookup.fetching > 0 {
+            return None;
+        }
+        let lookup = self.lookups.remove(&ratee)?;
+        self.known.insert(ratee, (lookup.found, Instant::now()));
+        Some(ratee)
+    }
+}
+
+// A statement that the voucher trusts the vouchee, signed like a rating so it can't be forged
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedVouch {
+    pub voucher: PeerId,
+    pub voucher_key: Vec<u8>, // Protobuf-encoded identity key of the voucher
+    pub vouchee: PeerId,
+    pub timestamp: u64,
+    pub signature: Vec<u8>,
+}
+
+impl SignedVouch {
+    pub fn new(keypair: &Keypair, vouchee: PeerId) -> Self {
+        let timestamp = unix_now();
+        let signature = keypair.sign(&vouch_payload(&vouchee, timestamp)).expect("Signing failed");
+        SignedVouch {
+            voucher: keypair.public().to_peer_id(),
+            voucher_key: keypair.public().encode_protobuf(),
+            vouchee,
+            timestamp,
+            signature,
+        }
+    }
+
+    // Check the vouch is for this peer and was signed by the voucher it names
+    pub fn verify(&self, vouchee: &PeerId) -> bool {
+        if self.vouchee != *vouchee || self.voucher == *vouchee {
+            return false;
+        }
+        match identity::PublicKey::try_decode_protobuf(&self.voucher_key) {
+            Ok(public_key) => public_key.to_peer_id() == self.voucher && public_key.verify(&vouch_payload(&self.vouchee, self.timestamp), &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+// The peers with a valid vouch for the vouchee
+pub fn vouchers(vouches: &[SignedVouch], vouchee: &PeerId) -> HashSet<PeerId> {
+    vouches.iter().filter(|vouch| vouch.verify(vouchee)).map(|vouch| vouch.voucher).collect()
+}
+
+// Keep one vouch per voucher
+pub fn upsert_vouch(vouches: &mut Vec<SignedVouch>, vouch: SignedVouch) {
+    vouches.retain(|existing| existing.voucher != vouch.voucher);
+    vouches.push(vouch);
+}
+
+fn payload(ratee: &PeerId, score: i32, timestamp: u64) -> Vec<u8> {
+    let mut payload = b"swapbytes rating".to_vec();
+    payload.extend(ratee.to_bytes());
+    payload.extend(score.to_be_bytes());
+    payload.extend(timestamp.to_be_bytes());
+    payload
+}
+
+fn vouch_payload(vouchee: &PeerId, timestamp: u64) -> Vec<u8> {
+    let mut payload = b"swapbytes vouch".to_vec();
+    payload.extend(vouchee.to_bytes());
+    payload.extend(timestamp.to_be_bytes());
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A rating of `ratee` by a new peer, backed by a receipt from the ratee if `proven`
+    fn rating(ratee: &Keypair, score: i32, proven: bool) -> SignedRating {
+        let rater = Keypair::generate_ed25519();
+        let proof = proven.then(|| SignedTradeReceipt::new(ratee, rater.public().to_peer_id(), "trade", "gave", "got"));
+        SignedRating::new(&rater, ratee.public().to_peer_id(), score, proof)
+    }
+
+    #[test]
+    fn verify_checks_the_ratee_score_and_signature() {
+        let (rater, ratee) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let ratee_id = ratee.public().to_peer_id();
+        let rating = SignedRating::new(&rater, ratee_id, 1, None);
+        assert!(rating.verify(&ratee_id));
+        assert!(!rating.verify(&rater.public().to_peer_id()), "about another peer");
+
+        let mut raised = rating.clone();
+        raised.score = -1;
+        assert!(!raised.verify(&ratee_id), "an altered score");
+
+        let mut claimed = rating.clone();
+        claimed.rater = Keypair::generate_ed25519().public().to_peer_id();
+        assert!(!claimed.verify(&ratee_id), "claiming another rater");
+
+        let mut resigned = rating.clone();
+        resigned.signature = Keypair::generate_ed25519().sign(&payload(&ratee_id, 1, rating.timestamp)).unwrap();
+        assert!(!resigned.verify(&ratee_id), "signed with another key");
+
+        assert!(!SignedRating::new(&rater, ratee_id, 5, None).verify(&ratee_id), "out of range");
+        assert!(!SignedRating::new(&ratee, ratee_id, 1, None).verify(&ratee_id), "rating themselves");
+    }
+
+    #[test]
+    fn effective_keeps_the_latest_valid_rating_from_each_rater() {
+        let (rater, other, ratee) = (Keypair::generate_ed25519(), Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let ratee_id = ratee.public().to_peer_id();
+        let mut old = SignedRating::new(&rater, ratee_id, -1, None);
+        old.timestamp -= 60;
+        old.signature = rater.sign(&payload(&ratee_id, old.score, old.timestamp)).unwrap();
+        let latest = SignedRating::new(&rater, ratee_id, 1, None);
+        let mut forged = SignedRating::new(&rater, ratee_id, -1, None);
+        forged.timestamp += 60;
+        let from_other = SignedRating::new(&other, ratee_id, 0, None);
+
+        // Replaying the old rating, in any order, and a forged newer one change nothing
+        let ratings = vec![latest.clone(), old.clone(), forged, old, from_other.clone(), latest.clone()];
+        let mut kept = effective(&ratings, &ratee_id);
+        kept.sort_by_key(|rating| rating.score);
+        assert_eq!(kept, vec![from_other, latest]);
+        assert!(effective(&ratings, &rater.public().to_peer_id()).is_empty());
+    }
+
+    // Four throwaway identities rating a peer down shouldn't outweigh two peers who traded with them
+    #[test]
+    fn aggregate_weights_unproven_ratings_down() {
+        let ratee = Keypair::generate_ed25519();
+        let mut ratings: Vec<SignedRating> = (0..2).map(|_| rating(&ratee, 1, true)).collect();
+        ratings.extend((0..4).map(|_| rating(&ratee, -1, false)));
+        let summary = aggregate(&ratings, &ratee.public().to_peer_id());
+        assert_eq!(summary, RatingSummary { sum: -2, count: 6, proven: 2, proven_sum: 2 });
+        assert!((summary.average() - 1.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn aggregate_of_only_unproven_ratings_is_their_average() {
+        let ratee = Keypair::generate_ed25519();
+        let ratings = vec![rating(&ratee, 1, false), rating(&ratee, 1, false), rating(&ratee, -1, false), rating(&ratee, 0, false)];
+        let summary = aggregate(&ratings, &ratee.public().to_peer_id());
+        assert_eq!(summary.proven, 0);
+        assert!((summary.average() - 0.25).abs() < 1e-9);
+        assert_eq!(RatingSummary::default().average(), 0.0);
+    }
+}
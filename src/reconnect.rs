@@ -0,0 +1,94 @@
+use std::{ collections::HashMap, time::{ Duration, Instant } };
+use libp2p::{ swarm::dial_opts::DialOpts, PeerId };
+
+use crate::{ behaviour::SwapBytesBehaviour, outbound, util::ChatState };
+
+// First wait before re-dialling a dropped peer; it doubles with every failed attempt up to MAX_BACKOFF
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// Attempts before we give up on a peer and drop anything waiting to be sent to them
+const MAX_ATTEMPTS: u32 = 8;
+
+// A peer we're trying to get back
+struct Redial {
+    attempts: u32,
+    next: Instant,
+}
+
+// Keeps the peers we care about connected: the members of our private room and anyone we're swapping files with.
+// Their transfers wait in the outbound queue until they're back
+#[derive(Default)]
+pub struct Reconnector {
+    redials: HashMap<PeerId, Redial>,
+}
+
+impl Reconnector {
+    pub fn is_redialling(&self, peer_id: &PeerId) -> bool {
+        self.redials.contains_key(peer_id)
+    }
+
+    // Stop trying to get a peer back
+    pub fn forget(&mut self, peer_id: &PeerId) {
+        self.redials.remove(peer_id);
+    }
+}
+
+// Whether losing this peer is worth re-dialling for. Peers the connection policy refuses never are
+fn cares_about(swarm: &libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState, peer_id: &PeerId) -> bool {
+    if !swarm.behaviour().gate.permits(peer_id) {
+        return false;
+    }
+    state.outbound.has_transfers(peer_id)
+        || state.rooms.values().any(|room| room.members.iter().any(|member| member.peer_id == *peer_id))
+}
+
+// The last connection to a peer closed. Start re-dialling if it's one we care about
+pub fn peer_disconnected(swarm: &libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer_id: PeerId) {
+    if state.reconnector.redials.contains_key(&peer_id) || !cares_about(swarm, state, &peer_id) {
+        return;
+    }
+    system!("Lost the connection to {}, reconnecting...", state.peer_name(&peer_id));
+    state.reconnector.redials.insert(peer_id, Redial { attempts: 0, next: Instant::now() + INITIAL_BACKOFF });
+}
+
+// A connection to a peer is up. If we were trying to get them back, stop and send whatever was waiting for them
+pub fn peer_connected(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer_id: PeerId) {
+    if state.reconnector.redials.remove(&peer_id).is_some() {
+        system!("Reconnected to {}.", state.peer_name(&peer_id));
+        outbound::pump(swarm, state, peer_id);
+    }
+}
+
+// Dial every dropped peer whose backoff has run out, giving up on those that have had MAX_ATTEMPTS
+pub fn redial_due(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState) {
+    let now = Instant::now();
+    let due: Vec<PeerId> = state.reconnector.redials.iter()
+        .filter(|(_, redial)| redial.next <= now)
+        .map(|(peer_id, _)| *peer_id)
+        .collect();
+    for peer_id in due {
+        if swarm.is_connected(&peer_id) {
+            peer_connected(swarm, state, peer_id);
+            continue;
+        }
+        // They left the room or were denied in the meantime
+        if !cares_about(swarm, state, &peer_id) {
+            state.reconnector.redials.remove(&peer_id);
+            continue;
+        }
+        let Some(redial) = state.reconnector.redials.get_mut(&peer_id) else { continue };
+        if redial.attempts >= MAX_ATTEMPTS {
+            state.reconnector.redials.remove(&peer_id);
+            let dropped = state.outbound.cancel(&peer_id);
+            error!("Could not reconnect to {}.{}", state.peer_name(&peer_id),
+                if dropped > 0 { format!(" {dropped} file transfer(s) to them were cancelled.") } else { String::new() });
+            continue;
+        }
+        redial.attempts += 1;
+        redial.next = now + (INITIAL_BACKOFF * 2u32.pow(redial.attempts)).min(MAX_BACKOFF);
+        let addresses = state.address_book.peers.get(&peer_id).map(|known| known.addresses.clone()).unwrap_or_default();
+        if let Err(e) = swarm.dial(DialOpts::peer_id(peer_id).addresses(addresses).build()) {
+            tracing::debug!("Redial of {peer_id} failed: {e}");
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::{ error::Error, net::SocketAddr, path::PathBuf, time::Duration };
+use futures::StreamExt;
+use libp2p::{ identify, identity, noise, ping, relay, swarm::{ NetworkBehaviour, SwarmEvent }, tcp, yamux, Swarm };
+use tokio::{ select, signal };
+
+use crate::{ keystore, status::{ RelayStats, StatusBoard }, util::RelayOptions };
+
+// Where `swapbytes relay` listens unless given --port, next to the rendezvous server's port
+pub const DEFAULT_PORT: u16 = 62650;
+
+#[derive(NetworkBehaviour)]
+pub struct RelayBehaviour {
+    pub relay: relay::Behaviour,
+    // Tells peers the address they reached us on, which is the address the relay hands out in reservations
+    pub identify: identify::Behaviour,
+    pub ping: ping::Behaviour,
+}
+
+impl RelayBehaviour {
+    pub fn new(key: &identity::Keypair, options: &RelayOptions) -> Self {
+        RelayBehaviour {
+            relay: relay::Behaviour::new(key.public().to_peer_id(), config(options)),
+            identify: identify::Behaviour::new(identify::Config::new("/swapbytes/1.0.0".to_string(), key.public())),
+            ping: ping::Behaviour::new(ping::Config::new()),
+        }
+    }
+}
+
+// libp2p's defaults with the caps given on the command line. They are deliberately small, as a relay only has to
+// carry peers until they can connect directly
+fn config(options: &RelayOptions) -> relay::Config {
+    let default = relay::Config::default();
+    relay::Config {
+        max_reservations: options.max_reservations.unwrap_or(default.max_reservations),
+        max_circuits: options.max_circuits.unwrap_or(default.max_circuits),
+        max_circuits_per_peer: options.max_circuits_per_peer.unwrap_or(default.max_circuits_per_peer),
+        max_circuit_duration: options.max_circuit_duration.map(Duration::from_secs).unwrap_or(default.max_circuit_duration),
+        max_circuit_bytes: options.max_circuit_bytes.unwrap_or(default.max_circuit_bytes),
+        ..default
+    }
+}
+
+// The saved identity if one was given, so the relay keeps the address peers know it by; otherwise a new one
+pub fn keypair(identity: Option<&PathBuf>) -> Result<identity::Keypair, Box<dyn Error>> {
+    match identity {
+        Some(path) => keystore::load_or_create(path)?.keypair(),
+        None => Ok(identity::Keypair::generate_ed25519()),
+    }
+}
+
+// What the relay did, logged for the operator and counted for --status
+pub fn handle_event(event: relay::Event, stats: &mut RelayStats) {
+    match event {
+        relay::Event::ReservationReqAccepted { src_peer_id, renewed } => {
+            if !renewed {
+                tracing::info!("{src_peer_id} reserved a slot");
+            }
+            stats.reserved(src_peer_id);
+        }
+        relay::Event::ReservationReqDenied { src_peer_id } => tracing::info!("Turned down {src_peer_id}'s reservation, the relay is full"),
+        relay::Event::ReservationTimedOut { src_peer_id } => {
+            tracing::debug!("{src_peer_id}'s reservation expired");
+            stats.unreserved(&src_peer_id);
+        }
+        relay::Event::CircuitReqAccepted { src_peer_id, dst_peer_id } => {
+            tracing::info!("Relaying {src_peer_id} to {dst_peer_id}");
+            stats.circuit_opened();
+        }
+        relay::Event::CircuitReqDenied { src_peer_id, dst_peer_id } => {
+            tracing::info!("Turned down relaying {src_peer_id} to {dst_peer_id}, over the circuit limits or no reservation");
+            stats.circuits_denied += 1;
+        }
+        relay::Event::CircuitClosed { src_peer_id, dst_peer_id, error } => {
+            match error {
+                Some(error) => tracing::debug!("Circuit from {src_peer_id} to {dst_peer_id} closed: {error}"),
+                None => tracing::debug!("Circuit from {src_peer_id} to {dst_peer_id} closed"),
+            }
+            stats.circuit_closed();
+        }
+        event => tracing::debug!("{event:?}"),
+    }
+}
+
+// Peers can only be given the relay's address once it knows it, which is whatever they saw us connect from
+pub fn handle_identify<B: NetworkBehaviour>(swarm: &mut Swarm<B>, event: identify::Event) {
+    if let identify::Event::Received { info, .. } = event {
+        swarm.add_external_address(info.observed_addr);
+    }
+}
+
+// `swapbytes relay`: run a circuit relay v2 server for peers behind NATs until Ctrl-C
+pub async fn run(port: u16, identity: Option<PathBuf>, options: RelayOptions, status: Option<SocketAddr>) -> Result<(), Box<dyn Error>> {
+    let keypair = keypair(identity.as_ref())?;
+    let mut swarm = libp2p::SwarmBuilder
+        ::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)?
+        .with_quic()
+        .with_behaviour(|key| RelayBehaviour::new(key, &options))?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+    swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{port}").parse()?)?;
+    swarm.listen_on(format!("/ip4/0.0.0.0/udp/{port}/quic-v1").parse()?)?;
+    let peer_id = *swarm.local_peer_id();
+    let status = StatusBoard::new(status).await?;
+    let mut stats = RelayStats::default();
+
+    loop {
+        select! {
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => system!("Relay listening on {address}/p2p/{peer_id}"),
+                SwarmEvent::Behaviour(RelayBehaviourEvent::Relay(event)) => handle_event(event, &mut stats),
+                SwarmEvent::Behaviour(RelayBehaviourEvent::Identify(event)) => handle_identify(&mut swarm, event),
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => stats.unreserved(&peer_id),
+                _ => {}
+            },
+            _ = signal::ctrl_c() => break,
+        }
+        status.update(|status| {
+            status.connected_peers = swarm.network_info().num_peers();
+            status.relay = Some(stats.clone());
+        });
+    }
+    Ok(())
+}
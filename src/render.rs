@@ -0,0 +1,122 @@
+use std::hash::{ DefaultHasher, Hash, Hasher };
+
+use libp2p::PeerId;
+
+use crate::{ output::{ self, Event, Kind }, rating::RatingSummary };
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const ITALIC: &str = "\x1b[3m";
+const CODE: &str = "\x1b[36m";
+
+// Colors handed out to nicknames; the same nickname always gets the same one
+const NICKNAME_COLORS: &[&str] = &[
+    "\x1b[31m", "\x1b[32m", "\x1b[33m", "\x1b[34m", "\x1b[35m", "\x1b[36m",
+    "\x1b[91m", "\x1b[92m", "\x1b[93m", "\x1b[94m", "\x1b[95m", "\x1b[96m",
+];
+
+// How chat messages are displayed, changed with /set
+pub struct RenderSettings {
+    pub markdown: bool,
+    pub colors: bool,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings { markdown: true, colors: output::colors_enabled() }
+    }
+}
+
+// Print rows as columns padded to line up, the first row being the headings
+pub fn print_table(rows: &[Vec<String>]) {
+    let mut widths = Vec::new();
+    for row in rows {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in rows {
+        let cells: Vec<_> = row.iter().zip(&widths).map(|(cell, width)| format!("{cell:<width$}")).collect();
+        system!("{}", cells.join("  ").trim_end());
+    }
+}
+
+// Print a chat message from a peer
+// `id` is the chat message's ID, if it came with one, so frontends can flag it later
+pub fn print_chat(settings: &RenderSettings, id: Option<&str>, peer: &PeerId, nickname: &str, rating: RatingSummary, body: &str) {
+    output::publish(Event::Message {
+        id: id.map(str::to_string),
+        peer: *peer,
+        nickname: nickname.to_string(),
+        rating: (rating.count > 0).then(|| rating.average()),
+        ratings: rating.count,
+        text: body.to_string(),
+    });
+    output::print_text(Kind::Chat, &chat_line(settings, nickname, rating, body));
+}
+
+pub fn chat_line(settings: &RenderSettings, nickname: &str, rating: RatingSummary, body: &str) -> String {
+    let nickname = if settings.colors {
+        format!("{}{nickname}{RESET}", nickname_color(nickname))
+    } else {
+        nickname.to_string()
+    };
+    let body = if settings.markdown {
+        render_markdown(body, settings.colors)
+    } else {
+        body.to_string()
+    };
+    format!("{nickname} ( {rating} ): {body}")
+}
+
+fn nickname_color(nickname: &str) -> &'static str {
+    let mut hasher = DefaultHasher::new();
+    nickname.hash(&mut hasher);
+    NICKNAME_COLORS[(hasher.finish() % NICKNAME_COLORS.len() as u64) as usize]
+}
+
+// Render **bold**, *italics* / _italics_ and `inline code`. Unclosed markers are left as typed.
+// Without colors the markers are simply stripped
+pub fn render_markdown(text: &str, colors: bool) -> String {
+    let style = |code: &'static str| if colors { code } else { "" };
+    let reset = style(RESET);
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (marker, open) = if rest.starts_with("**") {
+            ("**", style(BOLD))
+        } else if rest.starts_with('`') {
+            ("`", style(CODE))
+        } else if rest.starts_with('*') {
+            ("*", style(ITALIC))
+        } else if rest.starts_with('_') {
+            ("_", style(ITALIC))
+        } else {
+            let next = rest.find(['*', '_', '`']).unwrap_or(rest.len());
+            rendered.push_str(&rest[..next]);
+            rest = &rest[next..];
+            continue;
+        };
+        let inner = &rest[marker.len()..];
+        match inner.find(marker).filter(|end| *end > 0) {
+            Some(end) => {
+                // Code spans are shown verbatim; other spans may contain further formatting
+                let content = if marker == "`" {
+                    inner[..end].to_string()
+                } else {
+                    render_markdown(&inner[..end], colors)
+                };
+                rendered.push_str(open);
+                rendered.push_str(&content);
+                rendered.push_str(reset);
+                rest = &inner[end + marker.len()..];
+            }
+            None => {
+                rendered.push_str(marker);
+                rest = inner;
+            }
+        }
+    }
+    rendered
+}
@@ -0,0 +1,735 @@
+use std::{ collections::{ HashMap, HashSet }, path::PathBuf, time::{ Duration, Instant } };
+use chacha20poly1305::Key;
+use libp2p::{ gossipsub::{ self, IdentTopic, TopicHash }, identity::{ self, Keypair }, kad, request_response::{ OutboundRequestId, ResponseChannel }, PeerId };
+use serde::{ Deserialize, Serialize };
+use x25519_dalek::StaticSecret;
+
+use crate::{
+    behaviour::{ RequestType, ResponseType, SwapBytesBehaviour },
+    crypto,
+    output,
+    persist::{ unix_now, JsonFile },
+    util::{ publish_gossip, ChatState, GossipMessage, PrivateRoomProtocol },
+};
+
+// A peer taking part in a private room
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomMember {
+    pub peer_id: PeerId,
+    pub nickname: String,
+}
+
+// Room metadata, stored in the DHT under "room:<room_id>" and sent to each peer as they join. The topic is only the
+// opaque room ID, so who started a room, when and who's in it all come from here
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomRecord {
+    pub room_id: String,
+    pub creator: PeerId,
+    pub created: u64, // Unix seconds
+    pub members: Vec<RoomMember>,
+    #[serde(default)]
+    pub topic: Option<String>,
+}
+
+// Longest topic /topic set takes, in characters
+pub const MAX_TOPIC_LENGTH: usize = 200;
+
+// How long messages sealed with a room's old key are still read after the creator replaces it, for members whose
+// new key was still on its way
+const PREVIOUS_KEY_GRACE: Duration = Duration::from_secs(60);
+
+// Who is in a room, as its creator last decided. Kicked peers can be invited back; banned ones can't
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Roster {
+    pub room_id: String,
+    pub creator: PeerId,
+    pub version: u64, // Only a higher version replaces the roster a member already has
+    pub members: Vec<RoomMember>,
+    pub kicked: Vec<PeerId>,
+    pub banned: Vec<PeerId>,
+    // Left out when there is none, so rosters without a topic are signed as they were before topics
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+}
+
+// A roster signed by the room's creator, so only they can kick or ban
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedRoster {
+    pub roster: Roster,
+    pub creator_key: Vec<u8>, // Protobuf-encoded identity key of the creator
+    pub signature: Vec<u8>,
+}
+
+impl SignedRoster {
+    pub fn new(keypair: &Keypair, roster: Roster) -> Self {
+        let signature = keypair.sign(&roster_payload(&roster)).expect("Signing failed");
+        SignedRoster { roster, creator_key: keypair.public().encode_protobuf(), signature }
+    }
+
+    // Check the roster is for this room and was signed by the creator it names
+    pub fn verify(&self, room_id: &str) -> bool {
+        if self.roster.room_id != room_id {
+            return false;
+        }
+        match identity::PublicKey::try_decode_protobuf(&self.creator_key) {
+            Ok(public_key) => public_key.to_peer_id() == self.roster.creator && public_key.verify(&roster_payload(&self.roster), &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+fn roster_payload(roster: &Roster) -> Vec<u8> {
+    let mut payload = b"swapbytes roster".to_vec();
+    payload.extend(serde_json::to_vec(roster).expect("Serialization failed"));
+    payload
+}
+
+// An invite we've sent and are waiting on an answer for
+pub struct PendingInvite {
+    pub secret: StaticSecret,
+    pub passphrase: Option<String>,
+    pub nickname: String,
+    pub request: OutboundRequestId, // So we hear if it couldn't be delivered
+}
+
+// How one invite of a round ended
+pub enum InviteOutcome {
+    Joined,
+    Declined,
+    Failed, // The peer couldn't be found, was banned or couldn't be reached
+}
+
+// The answers to a /connect sent to several peers at once. Each answer is shown as it comes in, and the round is
+// summed up once every invite has one
+#[derive(Default)]
+pub struct InviteRound {
+    pub waiting: usize, // Invites without an answer, counting those whose peer is still being looked up
+    joined: Vec<String>,
+    declined: Vec<String>,
+    failed: Vec<String>,
+}
+
+// An invite we've received and the user hasn't answered yet
+pub struct IncomingInvite {
+    pub peer: PeerId,
+    pub room_id: String,
+    pub initiator_nickname: String,
+    pub public_key: [u8; 32],
+    pub passphrase_required: bool,
+    pub channel: ResponseChannel<ResponseType>,
+}
+
+// Count an invite's answer towards its round, if it was sent as part of one, and sum the round up once it's the last
+pub fn invite_answered(state: &mut ChatState, room_id: &str, nickname: &str, outcome: InviteOutcome) {
+    let Some(round) = state.invite_rounds.get_mut(room_id) else { return };
+    match outcome {
+        InviteOutcome::Joined => round.joined.push(nickname.to_string()),
+        InviteOutcome::Declined => round.declined.push(nickname.to_string()),
+        InviteOutcome::Failed => round.failed.push(nickname.to_string()),
+    }
+    round.waiting = round.waiting.saturating_sub(1);
+    if round.waiting > 0 {
+        return;
+    }
+    let Some(round) = state.invite_rounds.remove(room_id) else { return };
+    let mut answers = Vec::new();
+    if !round.joined.is_empty() {
+        answers.push(format!("{} joined", round.joined.join(", ")));
+    }
+    if !round.declined.is_empty() {
+        answers.push(format!("{} declined", round.declined.join(", ")));
+    }
+    if !round.failed.is_empty() {
+        answers.push(format!("{} couldn't be invited", round.failed.join(", ")));
+    }
+    let no_room = if state.rooms.contains_key(room_id) { "" } else { ", so no room was started" };
+    system!("Everyone you invited has answered: {}{no_room}.", answers.join("; "));
+}
+
+// An invite that couldn't be delivered. Returns false if the request wasn't an invite
+pub fn invite_failed(state: &mut ChatState, request_id: &OutboundRequestId) -> bool {
+    let Some(key) = state.pending_invites.iter().find(|(_, invite)| invite.request == *request_id).map(|(key, _)| key.clone()) else {
+        return false;
+    };
+    let Some(invite) = state.pending_invites.remove(&key) else { return false };
+    error!("Could not deliver the invite to {}.", invite.nickname);
+    invite_answered(state, &key.0, &invite.nickname, InviteOutcome::Failed);
+    true
+}
+
+// Who is subscribed to each topic, kept up to date from gossipsub's subscription events, so who's in a room can be
+// answered for any room we're in
+#[derive(Default)]
+pub struct Subscribers {
+    topics: HashMap<TopicHash, HashSet<PeerId>>,
+}
+
+impl Subscribers {
+    pub fn observe(&mut self, event: &gossipsub::Event) {
+        match event {
+            gossipsub::Event::Subscribed { peer_id, topic } => {
+                self.topics.entry(topic.clone()).or_default().insert(*peer_id);
+            }
+            gossipsub::Event::Unsubscribed { peer_id, topic } => {
+                if let Some(peers) = self.topics.get_mut(topic) {
+                    peers.remove(peer_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Gossipsub forgets a peer's subscriptions when its last connection closes without telling us, so we do the same
+    pub fn disconnected(&mut self, peer_id: &PeerId) {
+        for peers in self.topics.values_mut() {
+            peers.remove(peer_id);
+        }
+    }
+
+    pub fn is_subscribed(&self, peer_id: &PeerId, topic: &TopicHash) -> bool {
+        self.topics.get(topic).is_some_and(|peers| peers.contains(peer_id))
+    }
+
+    // Everyone subscribed to a topic, not counting us
+    pub fn of(&self, topic: &TopicHash) -> impl Iterator<Item = &PeerId> {
+        self.topics.get(topic).into_iter().flatten()
+    }
+}
+
+// Local state for a private room we're in
+pub struct Room {
+    pub members: Vec<RoomMember>,
+    pub creator: PeerId,
+    pub created: u64, // Unix seconds
+    pub key: Key,
+    // The key before the creator last replaced it and when that was, for messages sent by members who hadn't got
+    // the new one yet. It's dropped after PREVIOUS_KEY_GRACE
+    pub previous_key: Option<(Key, Instant)>,
+    pub passphrase: Option<String>,
+    // The latest roster from the room's creator. Rooms made by peers without moderation have none
+    pub roster: Option<SignedRoster>,
+    // What the room is for, set by its creator with /topic set
+    pub topic: Option<String>,
+}
+
+// A private room as it's kept on disk, see SavedRooms
+#[derive(Serialize, Deserialize)]
+struct SavedRoom {
+    room_id: String,
+    creator: PeerId,
+    created: u64,
+    members: Vec<RoomMember>,
+    key: [u8; 32],
+    passphrase: Option<String>,
+    roster: Option<SignedRoster>,
+    #[serde(default)]
+    topic: Option<String>,
+}
+
+// The private rooms we're in, kept in a file so that if we stop without leaving them, by crashing or being killed,
+// the next run joins them again and tells their members we're back. Leaving a room, or SwapBytes with /exit, drops
+// it
+#[derive(Default)]
+pub struct SavedRooms {
+    file: JsonFile<Vec<SavedRoom>>,
+    written: Vec<u8>, // What the file holds, so it's only written again when a room changes
+    // Rooms joined again at startup whose members haven't heard we're back yet
+    pub returning: HashSet<String>,
+}
+
+impl SavedRooms {
+    // Load the rooms kept in a file, saving every change back to it
+    pub fn load(file: PathBuf) -> (Self, Vec<(String, Room)>) {
+        let (file, saved): (_, Vec<SavedRoom>) = JsonFile::open(file, "your rooms");
+        let written = serde_json::to_vec(&saved).expect("Serialization failed");
+        let rooms = saved.into_iter()
+            .map(|saved| (saved.room_id, Room {
+                members: saved.members,
+                creator: saved.creator,
+                created: saved.created,
+                key: *Key::from_slice(&saved.key),
+                previous_key: None,
+                passphrase: saved.passphrase,
+                roster: saved.roster,
+                topic: saved.topic,
+            }))
+            .collect();
+        (SavedRooms { file, written, returning: HashSet::new() }, rooms)
+    }
+
+    // Write the rooms we're in to the file if they've changed since it was last written
+    pub fn sync(&mut self, rooms: &HashMap<String, Room>) {
+        let mut saved: Vec<SavedRoom> = rooms.iter()
+            .map(|(room_id, room)| SavedRoom {
+                room_id: room_id.clone(),
+                creator: room.creator,
+                created: room.created,
+                members: room.members.clone(),
+                key: room.key.into(),
+                passphrase: room.passphrase.clone(),
+                roster: room.roster.clone(),
+                topic: room.topic.clone(),
+            })
+            .collect();
+        saved.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+        let contents = serde_json::to_vec(&saved).expect("Serialization failed");
+        if contents != self.written && self.file.save(&saved) {
+            self.written = contents;
+        }
+    }
+}
+
+impl Room {
+    // Everyone in the room except us
+    pub fn others(&self, local_peer_id: &PeerId) -> Vec<&RoomMember> {
+        self.members.iter().filter(|member| member.peer_id != *local_peer_id).collect()
+    }
+
+    pub fn add_member(&mut self, member: RoomMember) {
+        if !self.members.iter().any(|existing| existing.peer_id == member.peer_id) {
+            self.members.push(member);
+        }
+    }
+
+    pub fn remove_member(&mut self, peer_id: &PeerId) -> Option<RoomMember> {
+        let index = self.members.iter().position(|member| member.peer_id == *peer_id)?;
+        Some(self.members.remove(index))
+    }
+
+    // Decrypt a message sent to the room, with the key before the last change if it was sealed with that and the
+    // change was recent
+    pub fn open(&self, nonce: [u8; 12], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        crypto::open(&self.key, nonce, ciphertext).or_else(|| {
+            let (previous, replaced) = self.previous_key.as_ref()?;
+            (replaced.elapsed() < PREVIOUS_KEY_GRACE).then(|| crypto::open(previous, nonce, ciphertext)).flatten()
+        })
+    }
+
+    // Replace the room key, keeping the old one for PREVIOUS_KEY_GRACE
+    fn replace_key(&mut self, key: Key) {
+        self.previous_key = Some((std::mem::replace(&mut self.key, key), Instant::now()));
+    }
+
+    pub fn is_member(&self, peer_id: &PeerId) -> bool {
+        self.members.iter().any(|member| member.peer_id == *peer_id)
+    }
+
+    pub fn record(&self, room_id: &str) -> RoomRecord {
+        RoomRecord {
+            room_id: room_id.to_string(),
+            creator: self.creator,
+            created: self.created,
+            members: self.members.clone(),
+            topic: self.topic.clone(),
+        }
+    }
+
+    // How long ago the room was started
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(unix_now().saturating_sub(self.created))
+    }
+
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.roster.as_ref().is_some_and(|signed| signed.roster.banned.contains(peer_id))
+    }
+
+    // As the creator, sign the membership as it is now. Kicking or banning someone adds them to those lists, and
+    // anyone back in the room is no longer kicked
+    pub fn sign_roster(&mut self, keypair: &Keypair, room_id: &str, kick: Option<PeerId>, ban: bool) -> SignedRoster {
+        let previous = self.roster.as_ref().map(|signed| &signed.roster);
+        let version = previous.map_or(1, |roster| roster.version + 1);
+        let mut kicked: Vec<PeerId> = previous.map(|roster| roster.kicked.clone()).unwrap_or_default();
+        let mut banned: Vec<PeerId> = previous.map(|roster| roster.banned.clone()).unwrap_or_default();
+        if let Some(peer_id) = kick {
+            self.remove_member(&peer_id);
+            if ban { banned.push(peer_id) } else { kicked.push(peer_id) }
+        }
+        kicked.retain(|peer_id| !self.is_member(peer_id));
+        let roster = Roster {
+            room_id: room_id.to_string(),
+            creator: keypair.public().to_peer_id(),
+            version,
+            members: self.members.clone(),
+            kicked,
+            banned,
+            topic: self.topic.clone(),
+        };
+        let signed = SignedRoster::new(keypair, roster);
+        self.roster = Some(signed.clone());
+        signed
+    }
+
+    // Whether a roster can replace the one we hold: it must be signed by the room's creator and newer, unless it's
+    // the first we've been sent
+    pub fn accepts_roster(&self, room_id: &str, signed: &SignedRoster) -> bool {
+        signed.roster.creator == self.creator
+            && self.roster.as_ref().is_none_or(|current| signed.roster.version > current.roster.version)
+            && signed.verify(room_id)
+    }
+
+    // Take a newer roster from the creator, returning the members it added and the ones it removed
+    pub fn apply_roster(&mut self, signed: SignedRoster) -> (Vec<RoomMember>, Vec<RoomMember>) {
+        let mut removed = Vec::new();
+        for peer_id in signed.roster.kicked.iter().chain(&signed.roster.banned) {
+            removed.extend(self.remove_member(peer_id));
+        }
+        // Members let in by someone other than the creator, or whose MemberJoined we missed
+        let added: Vec<RoomMember> = signed.roster.members.iter().filter(|member| !self.is_member(&member.peer_id)).cloned().collect();
+        for member in &added {
+            self.add_member(member.clone());
+        }
+        self.topic = signed.roster.topic.clone();
+        self.roster = Some(signed);
+        (added, removed)
+    }
+
+    // Pick the member a file command is aimed at: the named one, or the only other member
+    pub fn target(&self, local_peer_id: &PeerId, nickname: Option<&str>) -> Result<PeerId, String> {
+        let others = self.others(local_peer_id);
+        match nickname {
+            Some(nickname) => others
+                .iter()
+                .find(|member| member.nickname == nickname)
+                .map(|member| member.peer_id)
+                .ok_or(format!("{nickname} is not in this room.")),
+            None if others.len() == 1 => Ok(others[0].peer_id),
+            None if others.is_empty() => Err("There is nobody else in this room.".to_string()),
+            None => Err("There are several peers in this room. Please add the nickname of the peer.".to_string()),
+        }
+    }
+}
+
+pub fn room_record_key(room_id: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("room:{}", room_id))
+}
+
+// Store the current membership of a room in the DHT
+pub fn publish_room_record(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, quorum: kad::Quorum, room_id: &str, room: &Room) -> Option<kad::QueryId> {
+    let record = kad::Record {
+        key: room_record_key(room_id),
+        value: serde_json::to_vec(&room.record(room_id)).expect("Serialization failed"),
+        publisher: None,
+        expires: None,
+    };
+    swarm.behaviour_mut().kademlia.put_record(record, quorum)
+        .map_err(|e| tracing::warn!("Failed to store room record: {:?}", e))
+        .ok()
+}
+
+// As the room's creator, sign its current membership and send it to the other members
+pub fn publish_roster(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, room_id: &str, kick: Option<PeerId>, ban: bool) {
+    let keypair = state.keypair.clone();
+    let Some(room) = state.rooms.get_mut(room_id) else { return };
+    if room.creator != keypair.public().to_peer_id() {
+        return;
+    }
+    let signed = room.sign_roster(&keypair, room_id, kick, ban);
+    if let Err(e) = publish_gossip(swarm, state, TopicHash::from_raw(room_id), &GossipMessage::Roster(signed)) {
+        tracing::warn!("Failed to send the room roster: {:?}", e);
+    }
+}
+
+// As the room's creator, replace the room key after someone is kicked or banned, so they can't read what's said
+// next. Each remaining member is sent the new key in a room key request, sealed with the key it replaces: it only
+// goes over the encrypted connection to that member, and members only take a new key from the creator
+pub fn rekey(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, room_id: &str) {
+    let local_peer_id = *swarm.local_peer_id();
+    let Some(room) = state.rooms.get_mut(room_id) else { return };
+    if room.creator != local_peer_id {
+        return;
+    }
+    let key = crypto::generate_room_key();
+    let (nonce, ciphertext) = crypto::seal(&room.key, key.as_slice());
+    room.replace_key(key);
+    let (members, roster, record) = (room.members.clone(), room.roster.clone(), Some(Box::new(room.record(room_id))));
+    for member in room.others(&local_peer_id) {
+        swarm.behaviour_mut().request_response.request_response.send_request(
+            &member.peer_id,
+            RequestType::RoomKeyRequest {
+                room_id: room_id.to_string(),
+                nonce,
+                ciphertext: ciphertext.clone(),
+                members: members.clone(),
+                roster: roster.clone(),
+                record: record.clone(),
+            },
+        );
+    }
+}
+
+// A new room key from the creator of a room we're in, sealed with the one it replaces. Returns whether it was taken
+pub fn rekeyed(state: &mut ChatState, peer: &PeerId, room_id: &str, nonce: [u8; 12], ciphertext: &[u8]) -> bool {
+    let Some(room) = state.rooms.get_mut(room_id) else { return false };
+    if room.creator != *peer {
+        tracing::debug!("Ignored a new key for {room_id} from {peer}, who didn't start the room");
+        return false;
+    }
+    match crypto::open(&room.key, nonce, ciphertext) {
+        Some(key) if key.len() == 32 => {
+            room.replace_key(*Key::from_slice(&key));
+            true
+        }
+        _ => {
+            error!("Could not take the new key for the private room {room_id}: it was not sealed with the current one.");
+            false
+        }
+    }
+}
+
+// Forget old room keys once their grace period is over
+pub fn expire_keys(state: &mut ChatState) {
+    for room in state.rooms.values_mut() {
+        if room.previous_key.as_ref().is_some_and(|(_, replaced)| replaced.elapsed() >= PREVIOUS_KEY_GRACE) {
+            room.previous_key = None;
+        }
+    }
+}
+
+// Go back to the public room, forgetting the private room we were typing in. We stay in any others
+pub fn return_to_default(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, topic: &mut gossipsub::IdentTopic) {
+    state.rooms.remove(topic.hash().as_str());
+    state.awaiting_receipts.clear();
+    // Read receipts can't be sealed once the room is gone
+    state.unread_messages.retain(|(room, _)| *room != topic.hash());
+    let default_topic = gossipsub::IdentTopic::new(&state.public_room);
+    swarm.behaviour_mut().chat.gossipsub.unsubscribe(topic);
+    // We stay subscribed to the public room, unless that has somehow been lost
+    if let Err(e) = swarm.behaviour_mut().chat.gossipsub.subscribe(&default_topic) {
+        output::report_failure("Could not rejoin the public room", e);
+    }
+    *topic = default_topic;
+    output::set_room(&state.public_room);
+}
+
+// Join a private room and make it the one we type in. We keep listening to the public room and any other rooms
+// we're in, with their lines labelled by room; /switch moves between them
+pub fn join_room(
+    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
+    state: &mut ChatState,
+    topic: &mut gossipsub::IdentTopic,
+    room_id: &str,
+    room: Room,
+) {
+    state.rooms.insert(room_id.to_string(), room);
+    let private_topic = IdentTopic::new(room_id.to_string());
+    if let Err(e) = swarm.behaviour_mut().chat.gossipsub.subscribe(&private_topic) {
+        error!("Failed to subscribe to the private room: {:?}", e);
+    }
+    *topic = private_topic;
+    output::set_room(room_id);
+    system!("You have joined the private room: {room_id}");
+    if let Some(topic) = state.rooms.get(room_id).and_then(|room| room.topic.as_ref()) {
+        system!("Topic: {topic}");
+    }
+    system!("You're still in {}. Type /switch to see your rooms and move between them.", state.public_room);
+}
+
+// /topic: show what the room is for
+pub fn show_topic(state: &ChatState, room_id: &str) {
+    match state.rooms.get(room_id).map(|room| &room.topic) {
+        Some(Some(topic)) => system!("Topic: {topic}"),
+        Some(None) => system!("This room has no topic. Its creator can set one with /topic set <text>."),
+        None => system!("You are not in a private room."),
+    }
+}
+
+// /topic set <text> and /topic clear: as the room's creator, change its topic. It goes out to the members in a new
+// roster, so they know it came from the creator, and into the room's record for anyone joining later
+pub fn set_topic(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, room_id: &str, topic: Option<String>) {
+    let local_peer_id = *swarm.local_peer_id();
+    let Some(room) = state.rooms.get_mut(room_id) else {
+        system!("You are not in a private room.");
+        return;
+    };
+    if room.creator != local_peer_id {
+        error!("Only the room's creator can change its topic.");
+        return;
+    }
+    if topic.as_ref().is_some_and(|topic| topic.chars().count() > MAX_TOPIC_LENGTH) {
+        error!("Topics can be at most {MAX_TOPIC_LENGTH} characters long.");
+        return;
+    }
+    match &topic {
+        Some(topic) => system!("You set the room's topic to: {topic}"),
+        None => system!("You cleared the room's topic."),
+    }
+    room.topic = topic;
+    publish_room_record(swarm, state.kademlia.room_quorum(), room_id, room);
+    publish_roster(swarm, state, room_id, None, false);
+}
+
+// Tell a room we've come back to after a restart that we're in it again. Called as its members' subscriptions
+// arrive, until one of them is there to hear it
+pub fn announce_return(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, room_id: &str, nickname: &str) {
+    let member = RoomMember { peer_id: *swarm.local_peer_id(), nickname: nickname.to_string() };
+    match publish_gossip(swarm, state, TopicHash::from_raw(room_id), &GossipMessage::MemberJoined(member)) {
+        Ok(_) => {
+            state.saved_rooms.returning.remove(room_id);
+        }
+        Err(e) => tracing::debug!("Could not tell room {room_id} we're back yet: {e:?}"),
+    }
+}
+
+// /switch [room]: choose which room what we type goes to, by its label, the public room's name or a member's
+// nickname. Without a room, list the rooms we're in
+pub fn switch(state: &ChatState, topic: &mut gossipsub::IdentTopic, target: Option<&str>) {
+    let Some(target) = target else {
+        system!("Your rooms, * marking the one you're typing in:");
+        let marker = |room_id: &str| if topic.hash().as_str() == room_id { "*" } else { " " };
+        system!("{} {} (public)", marker(&state.public_room), state.public_room);
+        for (room_id, room) in &state.rooms {
+            let members: Vec<_> = room.members.iter().map(|member| member.nickname.as_str()).collect();
+            system!("{} {} with {}", marker(room_id), output::room_label(room_id), members.join(", "));
+        }
+        return;
+    };
+    let room_id = if target == state.public_room || target == "public" {
+        Some(state.public_room.clone())
+    } else {
+        state.rooms.keys().find(|room_id| room_id.starts_with(target)).cloned().or_else(|| {
+            state.rooms.iter().find(|(_, room)| room.members.iter().any(|member| member.nickname == target)).map(|(room_id, _)| room_id.clone())
+        })
+    };
+    let Some(room_id) = room_id else {
+        error!("You aren't in a room called {target}. Type /switch to see your rooms.");
+        return;
+    };
+    *topic = gossipsub::IdentTopic::new(&room_id);
+    output::set_room(&room_id);
+    system!("Now typing in {}.", output::room_label(&room_id));
+}
+
+// Answer a queued invite. Accepting completes the key exchange; the inviter then sends us the room key
+pub fn respond_to_invite(
+    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
+    state: &mut ChatState,
+    invite: IncomingInvite,
+    accept: bool,
+    passphrase: Option<String>,
+) {
+    let IncomingInvite { room_id, initiator_nickname, public_key, channel, .. } = invite;
+    let private_room_response = if accept {
+        let (secret, own_public_key) = crypto::generate_keypair();
+        state.pending_room_joins.insert(room_id.clone(), crypto::derive_room_key(&secret, public_key, &room_id, passphrase.as_deref()));
+        system!("Joining {initiator_nickname}'s private room...");
+        PrivateRoomProtocol::Accept {
+            room_id: room_id.clone(),
+            public_key: own_public_key,
+            passphrase_proof: passphrase.map(|passphrase| crypto::passphrase_proof(&room_id, &passphrase)),
+        }
+    } else {
+        system!("Declined {initiator_nickname}'s private room request.");
+        PrivateRoomProtocol::Reject(room_id)
+    };
+    // Send the response back to the requester
+    if let Err(e) = swarm.behaviour_mut().request_response.request_response.send_response(
+        channel,
+        ResponseType::PrivateRoomResponse(private_room_response),
+    ) {
+        error!("Error sending response: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOM_ID: &str = "room";
+
+    fn member(keypair: &Keypair, nickname: &str) -> RoomMember {
+        RoomMember { peer_id: keypair.public().to_peer_id(), nickname: nickname.to_string() }
+    }
+
+    fn room(creator: &Keypair, members: Vec<RoomMember>) -> Room {
+        Room {
+            members,
+            creator: creator.public().to_peer_id(),
+            created: unix_now(),
+            key: crypto::generate_room_key(),
+            previous_key: None,
+            passphrase: None,
+            roster: None,
+            topic: None,
+        }
+    }
+
+    #[test]
+    fn sign_roster_counts_up_versions_and_verifies() {
+        let (creator, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let mut room = room(&creator, vec![member(&creator, "alice"), member(&bob, "bob")]);
+        let first = room.sign_roster(&creator, ROOM_ID, None, false);
+        let second = room.sign_roster(&creator, ROOM_ID, None, false);
+        assert_eq!((first.roster.version, second.roster.version), (1, 2));
+        assert_eq!(second.roster.members, room.members);
+        assert!(second.verify(ROOM_ID));
+        assert!(!second.verify("another room"));
+    }
+
+    #[test]
+    fn a_kicked_member_is_removed_and_can_come_back() {
+        let (creator, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let members = vec![member(&creator, "alice"), member(&bob, "bob")];
+        let mut creators_room = room(&creator, members.clone());
+        let mut bobs_room = room(&creator, members);
+        bobs_room.apply_roster(creators_room.sign_roster(&creator, ROOM_ID, None, false));
+
+        let kick = creators_room.sign_roster(&creator, ROOM_ID, Some(bob.public().to_peer_id()), false);
+        assert_eq!(kick.roster.kicked, vec![bob.public().to_peer_id()]);
+        assert!(bobs_room.accepts_roster(ROOM_ID, &kick));
+        let (added, removed) = bobs_room.apply_roster(kick);
+        assert!(added.is_empty());
+        assert_eq!(removed, vec![member(&bob, "bob")]);
+        assert!(!bobs_room.is_member(&bob.public().to_peer_id()));
+        assert!(!bobs_room.is_banned(&bob.public().to_peer_id()));
+
+        // Invited back, they're no longer listed as kicked
+        creators_room.add_member(member(&bob, "bob"));
+        let back = creators_room.sign_roster(&creator, ROOM_ID, None, false);
+        assert!(back.roster.kicked.is_empty());
+        assert_eq!(bobs_room.apply_roster(back).0, vec![member(&bob, "bob")]);
+    }
+
+    #[test]
+    fn a_banned_member_stays_banned() {
+        let (creator, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let mut room = room(&creator, vec![member(&creator, "alice"), member(&bob, "bob")]);
+        room.sign_roster(&creator, ROOM_ID, Some(bob.public().to_peer_id()), true);
+        room.add_member(member(&bob, "bob"));
+        let roster = room.sign_roster(&creator, ROOM_ID, None, false);
+        assert_eq!(roster.roster.banned, vec![bob.public().to_peer_id()]);
+        assert!(room.is_banned(&bob.public().to_peer_id()));
+    }
+
+    #[test]
+    fn rosters_must_be_newer_and_from_the_creator() {
+        let (creator, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let members = vec![member(&creator, "alice"), member(&bob, "bob")];
+        let mut creators_room = room(&creator, members.clone());
+        let mut bobs_room = room(&creator, members);
+        let first = creators_room.sign_roster(&creator, ROOM_ID, None, false);
+        let second = creators_room.sign_roster(&creator, ROOM_ID, None, false);
+
+        // The first roster is taken even though none is held yet
+        assert!(bobs_room.accepts_roster(ROOM_ID, &first));
+        bobs_room.apply_roster(second.clone());
+        assert!(!bobs_room.accepts_roster(ROOM_ID, &first), "a stale version");
+        assert!(!bobs_room.accepts_roster(ROOM_ID, &second), "the same version again");
+
+        // Signed by a member claiming to be the creator
+        let mut forged = second.roster.clone();
+        forged.version += 1;
+        forged.members.retain(|member| member.peer_id == bob.public().to_peer_id());
+        let mut forged = SignedRoster::new(&bob, forged);
+        assert!(!bobs_room.accepts_roster(ROOM_ID, &forged));
+        forged.creator_key = creator.public().encode_protobuf();
+        assert!(!bobs_room.accepts_roster(ROOM_ID, &forged));
+
+        // Validly signed by a member, but for a room they didn't start
+        let mut own = second.roster.clone();
+        own.creator = bob.public().to_peer_id();
+        own.version += 1;
+        let own = SignedRoster::new(&bob, own);
+        assert!(own.verify(ROOM_ID));
+        assert!(!bobs_room.accepts_roster(ROOM_ID, &own));
+    }
+}
@@ -0,0 +1,361 @@
+use std::{ error::Error, net::SocketAddr, path::Path, sync::Arc };
+use base64::prelude::*;
+use libp2p::gossipsub;
+use serde::Deserialize;
+use serde_json::{ json, Value };
+use tokio::{
+    io::{ self, AsyncReadExt, AsyncWriteExt },
+    net::{ TcpListener, TcpStream },
+    sync::{ broadcast, mpsc, oneshot },
+};
+
+use crate::{ behaviour::{ RequestType, SwapBytesBehaviour }, crypto, input::{ self, handle_input }, line_editor::LineReader, outbound, output::{ self, Output, RoomEvent }, persist, util::ChatState };
+
+// Standard JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// Large enough for files offered with offer_data, anything bigger is refused
+const MAX_REQUEST_SIZE: usize = 32 * 1024 * 1024;
+
+// Where the bearer token clients need is written, next to the control socket unless a profile is used
+pub const DEFAULT_TOKEN_FILE: &str = "swapbytes.rpc-token";
+
+// The web UI, served at / when built with the web-ui feature
+#[cfg(feature = "web-ui")]
+const WEB_UI: &str = include_str!("../web/index.html");
+
+// A JSON-RPC call passed to the event loop, which owns the swarm and chat state
+pub struct Call {
+    pub method: String,
+    pub params: Value,
+    pub reply: oneshot::Sender<Result<Value, RpcError>>,
+}
+
+pub struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError { code, message: message.into() }
+    }
+}
+
+// An HTTP request as read off a connection
+pub(crate) struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+// Who may use the server: requests must be addressed to it by one of its own names, so pages on other sites
+// can't reach it by renaming themselves in DNS, and calls must carry its bearer token
+struct Access {
+    hosts: Vec<String>,
+    token: String,
+}
+
+impl Access {
+    fn new(address: SocketAddr, token: String) -> Self {
+        let mut hosts = vec![address.to_string()];
+        if address.ip().is_loopback() {
+            hosts.push(format!("localhost:{}", address.port()));
+        }
+        Access { hosts, token }
+    }
+
+    // Whether the Host and any Origin name this server
+    fn addressed(&self, request: &HttpRequest) -> bool {
+        let host_allowed = request.header("host").is_some_and(|host| self.hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)));
+        let origin_allowed = request.header("origin")
+            .is_none_or(|origin| self.hosts.iter().any(|allowed| origin.eq_ignore_ascii_case(&format!("http://{allowed}"))));
+        host_allowed && origin_allowed
+    }
+
+    // Whether the request carries our token, compared without stopping at the first difference
+    fn authorized(&self, request: &HttpRequest) -> bool {
+        let Some(token) = request.header("authorization").and_then(|value| value.strip_prefix("Bearer ")) else { return false };
+        token.len() == self.token.len() && token.bytes().zip(self.token.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+    }
+}
+
+// The token from --rpc-token, or a new one, written where clients can read it and other users can't
+fn write_token(file: &Path, token: Option<String>) -> Result<String, Box<dyn Error>> {
+    let token = token.unwrap_or_else(crypto::generate_token);
+    persist::write_private(file, token.as_bytes()).map_err(|e| format!("Could not write the RPC token to {}: {e}", file.display()))?;
+    Ok(token)
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+// Accept JSON-RPC 2.0 requests POSTed over HTTP. Each connection carries one request. Only loopback addresses
+// are served with a generated token; anywhere else the token has to be one the user chose
+pub async fn serve(address: SocketAddr, token: Option<String>, token_file: &Path, calls: mpsc::UnboundedSender<Call>, output: Output) -> Result<(), Box<dyn Error>> {
+    if token.is_none() && !address.ip().is_loopback() {
+        return Err(format!("Serving --rpc on {address} needs an --rpc-token, as anyone who can reach it could use your node").into());
+    }
+    let access = Arc::new(Access::new(address, write_token(token_file, token)?));
+    let listener = TcpListener::bind(address).await
+        .map_err(|e| format!("Could not start the RPC server on {address}: {e}"))?;
+    output::print(output::Kind::System, &format!("Serving JSON-RPC on {address}. Clients need the token in {}", token_file.display()));
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let (calls, access, output) = (calls.clone(), access.clone(), output.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(stream, &access, calls, &output).await {
+                            tracing::debug!("RPC connection failed: {e}");
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("Failed to accept an RPC connection: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle_connection(mut stream: TcpStream, access: &Access, calls: mpsc::UnboundedSender<Call>, output: &Output) -> io::Result<()> {
+    let Some(http_request) = read_request(&mut stream).await? else {
+        return write_response(&mut stream, "400 Bad Request", "").await;
+    };
+    if !access.addressed(&http_request) {
+        return write_response(&mut stream, "403 Forbidden", "").await;
+    }
+    let (method, path) = (http_request.method.as_str(), http_request.path.as_str());
+    // The page itself holds nothing secret; it asks for the token, which its calls then carry
+    #[cfg(feature = "web-ui")]
+    if method == "GET" && path == "/" {
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{WEB_UI}",
+            WEB_UI.len()
+        );
+        return stream.write_all(response.as_bytes()).await;
+    }
+    if method != "POST" || path != "/" {
+        return write_response(&mut stream, "405 Method Not Allowed", "").await;
+    }
+    // Browsers send forms to other sites without asking, but not JSON
+    let content_type = http_request.header("content-type").and_then(|value| value.split(';').next()).unwrap_or_default();
+    if !content_type.trim().eq_ignore_ascii_case("application/json") {
+        return write_response(&mut stream, "415 Unsupported Media Type", "").await;
+    }
+    if !access.authorized(&http_request) {
+        return write_response(&mut stream, "401 Unauthorized", "").await;
+    }
+    let request: Request = match serde_json::from_slice(&http_request.body) {
+        Ok(request) => request,
+        Err(e) => return write_result(&mut stream, Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string()))).await,
+    };
+    if request.jsonrpc != "2.0" {
+        return write_result(&mut stream, request.id, Err(RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported"))).await;
+    }
+    if request.method == "subscribe" {
+        return stream_events(&mut stream, request.id, output.subscribe()).await;
+    }
+    let (reply, result) = oneshot::channel();
+    let call = Call { method: request.method, params: request.params, reply };
+    if calls.send(call).is_err() {
+        return write_response(&mut stream, "503 Service Unavailable", "").await;
+    }
+    let result = result.await.unwrap_or_else(|_| Err(RpcError::new(INVALID_REQUEST, "The node shut down")));
+    write_result(&mut stream, request.id, result).await
+}
+
+// Read an HTTP request with its headers and body
+pub(crate) async fn read_request(stream: &mut TcpStream) -> io::Result<Option<HttpRequest>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        let header_length = match request.parse(&buffer) {
+            Ok(httparse::Status::Complete(length)) => length,
+            Ok(httparse::Status::Partial) if buffer.len() < MAX_REQUEST_SIZE => continue,
+            _ => return Ok(None),
+        };
+        let content_length = request.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+            .and_then(|header| std::str::from_utf8(header.value).ok()?.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        if content_length > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        let method = request.method.unwrap_or_default().to_string();
+        let path = request.path.unwrap_or_default().to_string();
+        let headers = request.headers.iter()
+            .map(|header| (header.name.to_string(), String::from_utf8_lossy(header.value).into_owned()))
+            .collect();
+        let mut body = buffer.split_off(header_length);
+        while body.len() < content_length {
+            let read = stream.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            body.extend_from_slice(&chunk[..read]);
+        }
+        body.truncate(content_length);
+        return Ok(Some(HttpRequest { method, path, headers, body }));
+    }
+}
+
+pub(crate) async fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+async fn write_result(stream: &mut TcpStream, id: Value, result: Result<Value, RpcError>) -> io::Result<()> {
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": error.code, "message": error.message } }),
+    };
+    write_response(stream, "200 OK", &response.to_string()).await
+}
+
+// `subscribe`: answer, then keep the connection open and send every event as a JSON-RPC notification, one per line
+async fn stream_events(stream: &mut TcpStream, id: Value, mut events: broadcast::Receiver<RoomEvent>) -> io::Result<()> {
+    let header = "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n";
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(format!("{}\n", json!({ "jsonrpc": "2.0", "id": id, "result": true })).as_bytes()).await?;
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                let notification = json!({ "jsonrpc": "2.0", "method": "event", "params": event });
+                stream.write_all(format!("{notification}\n").as_bytes()).await?;
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => tracing::warn!("An RPC subscriber fell behind and missed {skipped} events"),
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+// Run a call in the event loop and send back its result
+pub async fn dispatch(
+    call: Call,
+    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
+    topic: &mut gossipsub::IdentTopic,
+    state: &mut ChatState,
+    nickname: &mut String,
+    stdin: &mut LineReader,
+) {
+    let result = run(&call.method, &call.params, swarm, topic, state, nickname, stdin).await;
+    let _ = call.reply.send(result);
+}
+
+// Most methods are the matching chat command; its output arrives as events
+async fn run(
+    method: &str,
+    params: &Value,
+    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
+    topic: &mut gossipsub::IdentTopic,
+    state: &mut ChatState,
+    nickname: &mut String,
+    stdin: &mut LineReader,
+) -> Result<Value, RpcError> {
+    let line = match method {
+        "send_message" => {
+            let text = string_param(params, "text")?;
+            if text.trim().is_empty() || text.starts_with('/') {
+                return Err(RpcError::new(INVALID_PARAMS, "Messages can't be empty or start with /"));
+            }
+            text.to_string()
+        }
+        "list_peers" => return Ok(list_peers(swarm, state)),
+        "request_file" | "offer_file" => {
+            if !state.rooms.contains_key(topic.hash().as_str()) {
+                return Err(RpcError::new(INVALID_REQUEST, "Files can only be swapped in a private room"));
+            }
+            let file = string_param(params, "file")?;
+            let nickname = params.get("nickname").and_then(Value::as_str);
+            if method == "request_file" {
+                input::request_file(swarm, topic, state, file, nickname);
+            } else {
+                input::offer_files(swarm, topic, state, &[file.to_string()], nickname).await;
+            }
+            return Ok(Value::Null);
+        }
+        "offer_data" => return offer_data(params, swarm, topic, state),
+        "accept_offer" | "reject_offer" => {
+            let id = params.get("id").and_then(Value::as_u64)
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Missing numeric parameter id"))?;
+            if !u32::try_from(id).is_ok_and(|id| state.pending_approvals.contains_key(&id)) {
+                return Err(RpcError::new(INVALID_PARAMS, format!("No file request or offer with ID {id}")));
+            }
+            let command = if method == "accept_offer" { "/yes" } else { "/no" };
+            format!("{command} {id}")
+        }
+        _ => return Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method {method}"))),
+    };
+    handle_input(&line, swarm, topic, state, nickname, stdin).await;
+    Ok(Value::Null)
+}
+
+// Offer a file sent with the call rather than one on disk, e.g. one dropped onto the web UI
+fn offer_data(params: &Value, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, topic: &gossipsub::IdentTopic, state: &mut ChatState) -> Result<Value, RpcError> {
+    let Some(room) = state.rooms.get(topic.hash().as_str()) else {
+        return Err(RpcError::new(INVALID_REQUEST, "Files can only be swapped in a private room"));
+    };
+    // Only the file's own name is passed on, never a path
+    let name = Path::new(string_param(params, "name")?).file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Invalid file name"))?
+        .to_string();
+    let data = BASE64_STANDARD.decode(string_param(params, "data")?)
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("data must be base64: {e}")))?;
+    let other_peer_id = room.target(swarm.local_peer_id(), params.get("nickname").and_then(Value::as_str))
+        .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+    outbound::send(swarm, state, other_peer_id, RequestType::FileOffer(data, name));
+    Ok(Value::Null)
+}
+
+fn string_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params.get(name).and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Missing string parameter {name}")))
+}
+
+// Connected peers with whatever we have cached about them. Unlike /list this doesn't wait on the DHT
+fn list_peers(swarm: &libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState) -> Value {
+    let connected: Vec<_> = swarm.connected_peers().filter(|peer_id| Some(**peer_id) != state.rendezvous).cloned().collect();
+    let peers: Vec<Value> = connected.iter().map(|peer_id| match state.cached_peer(peer_id).cloned() {
+        Some(peer) => {
+            let rating = state.ratings.summary(peer_id).unwrap_or_default();
+            json!({
+                "peer": peer_id,
+                "nickname": peer.nickname,
+                "rating": (rating.count > 0).then(|| rating.average()),
+                "ratings": rating.count,
+            })
+        }
+        None => json!({ "peer": peer_id, "nickname": null, "rating": null, "ratings": 0 }),
+    }).collect();
+    Value::Array(peers)
+}
@@ -0,0 +1,104 @@
+use std::{ fmt, path::{ Path, PathBuf }, process::Stdio, time::Duration };
+use libp2p::PeerId;
+use serde::{ Deserialize, Serialize };
+use tokio::{ fs, io::AsyncWriteExt, process::Command, sync::mpsc, time::timeout };
+
+use crate::{ filenames, util::ChatState, vault };
+
+// A scan still running after this long counts as failed, and the file stays in quarantine
+const SCAN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// What --scan-command made of a received file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScanVerdict {
+    Clean,
+    Flagged(String), // The scanner's last line of output
+    Failed(String),  // Why the scanner couldn't give an answer
+}
+
+impl fmt::Display for ScanVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScanVerdict::Clean => write!(f, "clean"),
+            ScanVerdict::Flagged(output) => write!(f, "flagged: {output}"),
+            ScanVerdict::Failed(reason) => write!(f, "not scanned: {reason}"),
+        }
+    }
+}
+
+// A file the scanner has finished with
+pub struct ScanResult {
+    pub peer: PeerId,
+    pub filename: String, // As the sender named it
+    pub name: String,     // What it'll be saved as
+    pub path: PathBuf,    // Where it is in quarantine
+    pub verdict: ScanVerdict,
+}
+
+// Runs --scan-command on received files while they wait in quarantine. Scans run in the background and
+// their results come back to the node through a channel
+pub struct Scanner {
+    program: String,
+    args: Vec<String>,
+    pub quarantine: PathBuf,
+    results: mpsc::UnboundedSender<ScanResult>,
+}
+
+impl Scanner {
+    // The command is split on whitespace, and the file's path is added as its last argument
+    pub fn new(command: &str, quarantine: PathBuf, results: mpsc::UnboundedSender<ScanResult>) -> Result<Self, String> {
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words.next().ok_or("--scan-command is empty")?;
+        std::fs::create_dir_all(&quarantine).map_err(|e| format!("Could not create the quarantine directory {}: {e}", quarantine.display()))?;
+        Ok(Scanner { program, args: words.collect(), quarantine, results })
+    }
+}
+
+// Write a received file into quarantine and start scanning it, returning where it was put
+pub async fn quarantine(state: &ChatState, peer: PeerId, filename: &str, name: &str, data: &[u8]) -> std::io::Result<PathBuf> {
+    let scanner = state.scanner.as_ref().expect("Only called with a scanner");
+    let (path, mut file) = filenames::create_unique(&scanner.quarantine, name).await?;
+    file.write_all(data).await?;
+    file.flush().await?;
+    let mut command = Command::new(&scanner.program);
+    command.args(&scanner.args).arg(&path).stdin(Stdio::null()).kill_on_drop(true);
+    let (results, filename, name, scanned) = (scanner.results.clone(), filename.to_string(), name.to_string(), path.clone());
+    tokio::spawn(async move {
+        let verdict = match timeout(SCAN_TIMEOUT, command.output()).await {
+            Err(_) => ScanVerdict::Failed(format!("the scanner took longer than {} minutes", SCAN_TIMEOUT.as_secs() / 60)),
+            Ok(Err(e)) => ScanVerdict::Failed(format!("the scanner couldn't be run: {e}")),
+            Ok(Ok(output)) if output.status.success() => ScanVerdict::Clean,
+            Ok(Ok(output)) => match output.status.code() {
+                Some(_) => {
+                    let stdout = String::from_utf8_lossy(&output.stdout);
+                    ScanVerdict::Flagged(stdout.lines().rfind(|line| !line.trim().is_empty()).unwrap_or("no details").trim().to_string())
+                }
+                None => ScanVerdict::Failed("the scanner was killed".to_string()),
+            },
+        };
+        let _ = results.send(ScanResult { peer, filename, name, path: scanned, verdict });
+    });
+    Ok(path)
+}
+
+// The scanner is done with a file: move it into the download directory if it's clean, otherwise leave it in
+// quarantine. Either way the verdict goes into the transfer history
+pub async fn finish(state: &mut ChatState, result: ScanResult) {
+    state.transfer_history.record_verdict(&result.peer, &result.filename, &result.verdict);
+    let (name, from) = (result.name, state.peer_name(&result.peer));
+    match result.verdict {
+        ScanVerdict::Clean => match release(state, &result.path, &name).await {
+            Ok(path) => system!("{name} from {from} was scanned clean and moved to {}.", path.display()),
+            Err(e) => error!("{name} from {from} was scanned clean, but couldn't be moved out of quarantine: {e}"),
+        },
+        verdict => error!("{name} from {from} was kept in quarantine at {} ({verdict}).", result.path.display()),
+    }
+}
+
+// Move a file out of quarantine, encrypting it on the way if downloads are encrypted
+async fn release(state: &ChatState, path: &Path, name: &str) -> std::io::Result<PathBuf> {
+    let data = fs::read(path).await?;
+    let released = vault::write(&state.download_dir, name, &data, state.download_key.as_ref()).await?;
+    fs::remove_file(path).await?;
+    Ok(released)
+}
@@ -0,0 +1,187 @@
+use std::{ collections::BTreeMap, error::Error, fs, path::{ Path, PathBuf }, time::Duration };
+use futures::StreamExt;
+use libp2p::{ identity, noise, ping, rendezvous, swarm::{ dial_opts::DialOpts, NetworkBehaviour, SwarmEvent }, tcp, yamux, Multiaddr, PeerId, Swarm };
+use serde::{ Deserialize, Serialize };
+use tokio::{ select, signal };
+
+use crate::{ keystore, persist::{ unix_now, JsonFile }, policy::ConnectionGate, status::StatusBoard, util::ServerOptions };
+
+// Clients look for the rendezvous server on this port
+pub const DEFAULT_PORT: u16 = 62649;
+// Where the server keeps its identity without --identity, so clients can go on expecting the same PeerId
+pub const DEFAULT_IDENTITY_FILE: &str = "rendezvous.identity";
+
+#[derive(NetworkBehaviour)]
+pub struct ServerBehaviour {
+    pub gate: ConnectionGate,
+    pub rendezvous: rendezvous::server::Behaviour,
+    pub ping: ping::Behaviour,
+}
+
+// A registration as the server last saw it, enough to find the peer again after a restart
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedRegistration {
+    peer: PeerId,
+    namespace: String,
+    addresses: Vec<Multiaddr>,
+    expires: u64, // Seconds since the UNIX epoch
+}
+
+// Who is registered under which namespace. The rendezvous behaviour keeps registrations to itself and can't be
+// handed them back, so after a restart the server dials everyone still registered and they register again as
+// soon as they're connected, instead of waiting for their next refresh
+#[derive(Default)]
+pub(crate) struct Directory {
+    registrations: Vec<SavedRegistration>,
+    file: JsonFile<Vec<SavedRegistration>>,
+}
+
+impl Directory {
+    // Load the registrations kept in a file, dropping any that expired while the server was down
+    pub(crate) fn load(file: PathBuf) -> Self {
+        let (file, mut registrations): (_, Vec<SavedRegistration>) = JsonFile::open(file, "registrations");
+        let now = unix_now();
+        registrations.retain(|registration| registration.expires > now);
+        Directory { registrations, file }
+    }
+
+    fn save(&self) {
+        self.file.save(&self.registrations);
+    }
+
+    fn registered(&mut self, registration: &rendezvous::Registration) {
+        let (peer, namespace) = (registration.record.peer_id(), registration.namespace.to_string());
+        self.registrations.retain(|saved| saved.peer != peer || saved.namespace != namespace);
+        self.registrations.push(SavedRegistration {
+            peer,
+            namespace,
+            addresses: registration.record.addresses().to_vec(),
+            expires: unix_now() + registration.ttl,
+        });
+        self.save();
+    }
+
+    fn unregistered(&mut self, peer: &PeerId, namespace: &str) {
+        self.registrations.retain(|saved| saved.peer != *peer || saved.namespace != namespace);
+        self.save();
+    }
+
+    // How many peers are registered under each namespace, for --status
+    pub(crate) fn namespaces(&self) -> BTreeMap<String, usize> {
+        let mut namespaces = BTreeMap::new();
+        for saved in &self.registrations {
+            *namespaces.entry(saved.namespace.clone()).or_default() += 1;
+        }
+        namespaces
+    }
+
+    // Dial everyone still registered from before a restart, so they register again
+    pub(crate) fn redial<B: NetworkBehaviour>(&self, swarm: &mut Swarm<B>) {
+        if !self.registrations.is_empty() {
+            system!("Reconnecting to {} peers registered before the restart", self.registrations.len());
+        }
+        for saved in &self.registrations {
+            let dial = DialOpts::peer_id(saved.peer).addresses(saved.addresses.clone()).build();
+            if let Err(e) = swarm.dial(dial) {
+                tracing::info!("Could not dial registered peer {}: {e}", saved.peer);
+            }
+        }
+    }
+
+    // Keep track of what the rendezvous behaviour did
+    pub(crate) fn handle_event(&mut self, event: rendezvous::server::Event) {
+        match event {
+            rendezvous::server::Event::PeerRegistered { peer, registration } => {
+                tracing::info!("{peer} registered under {}", registration.namespace);
+                self.registered(&registration);
+            }
+            rendezvous::server::Event::PeerUnregistered { peer, namespace } => {
+                tracing::info!("{peer} unregistered from {namespace}");
+                self.unregistered(&peer, &namespace.to_string());
+            }
+            rendezvous::server::Event::RegistrationExpired(registration) => {
+                tracing::info!("{}'s registration under {} expired", registration.record.peer_id(), registration.namespace);
+                self.unregistered(&registration.record.peer_id(), &registration.namespace.to_string());
+            }
+            rendezvous::server::Event::DiscoverServed { enquirer, registrations } => {
+                tracing::debug!("Served {} registrations to {enquirer}", registrations.len());
+            }
+            _ => {}
+        }
+    }
+}
+
+// The server's identity from --identity, or from rendezvous.identity, created on the first run either way. Its
+// PeerId is what clients pin with --server-peer
+pub(crate) fn keypair(identity: Option<&Path>) -> Result<identity::Keypair, Box<dyn Error>> {
+    keystore::load_or_create(identity.unwrap_or(Path::new(DEFAULT_IDENTITY_FILE)))?.keypair()
+}
+
+// Tell whoever runs the server what clients should be given to find it
+pub(crate) fn print_address(peer_id: &PeerId, port: u16) {
+    system!("Clients find this server with --server <its IPv4 address>:{port} --server-peer {peer_id}");
+}
+
+// Who an --allow-peers file lets use the server: one PeerId per line, with # starting a comment. Without a file
+// everyone can
+pub(crate) fn load_allowed(file: Option<&Path>) -> Result<ConnectionGate, Box<dyn Error>> {
+    let Some(file) = file else { return Ok(ConnectionGate::default()) };
+    let contents = fs::read_to_string(file).map_err(|e| format!("Could not read {}: {e}", file.display()))?;
+    let mut allowed = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let peer_id = line.parse::<PeerId>().map_err(|e| format!("{} line {}: {e}", file.display(), number + 1))?;
+        allowed.push(peer_id);
+    }
+    system!("Only peers listed in {} can connect ({} of them)", file.display(), allowed.len());
+    Ok(ConnectionGate::allow_only(allowed))
+}
+
+// Registrations shorter than `min_ttl` seconds are refused, 2 hours if not given
+pub(crate) fn rendezvous_behaviour(min_ttl: Option<u64>) -> rendezvous::server::Behaviour {
+    rendezvous::server::Behaviour::new(rendezvous::server::Config::default().with_min_ttl(min_ttl.unwrap_or(rendezvous::MIN_TTL)))
+}
+
+// `swapbytes serve`: run a rendezvous server for SwapBytes clients until Ctrl-C
+pub async fn run(port: u16, identity: Option<PathBuf>, options: ServerOptions) -> Result<(), Box<dyn Error>> {
+    let gate = load_allowed(options.allow_peers.as_deref())?;
+    let mut swarm = libp2p::SwarmBuilder
+        ::with_existing_identity(keypair(identity.as_deref())?)
+        .with_tokio()
+        .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|_| ServerBehaviour {
+            gate,
+            rendezvous: rendezvous_behaviour(options.min_ttl),
+            ping: ping::Behaviour::new(ping::Config::new()),
+        })?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+    swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{port}").parse()?)?;
+    system!("Rendezvous server {} listening on port {port}", swarm.local_peer_id());
+    print_address(swarm.local_peer_id(), port);
+
+    let mut directory = options.registrations.map(Directory::load).unwrap_or_default();
+    directory.redial(&mut swarm);
+    let status = StatusBoard::new(options.status).await?;
+
+    loop {
+        select! {
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::Behaviour(ServerBehaviourEvent::Rendezvous(event)) => directory.handle_event(event),
+                SwarmEvent::ConnectionEstablished { peer_id, .. } => tracing::debug!("{peer_id} connected"),
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => tracing::debug!("{peer_id} disconnected"),
+                SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => tracing::info!("Refused {send_back_addr}: {error}"),
+                _ => {}
+            },
+            _ = signal::ctrl_c() => break,
+        }
+        status.update(|status| {
+            status.connected_peers = swarm.network_info().num_peers();
+            status.registrations = Some(directory.namespaces());
+        });
+    }
+    Ok(())
+}
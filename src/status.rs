@@ -0,0 +1,105 @@
+use std::{ collections::{ BTreeMap, HashSet }, error::Error, net::SocketAddr, time::Instant };
+use libp2p::PeerId;
+use serde::Serialize;
+use tokio::{ net::{ TcpListener, TcpStream }, sync::watch };
+
+use crate::rpc;
+
+// What a server, relay or bootstrap node is doing for its community, for operators to monitor. Sections for
+// roles the node doesn't have are left out
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ServerStatus {
+    pub uptime_secs: u64,
+    pub connected_peers: usize,
+    // Registered peers under each rendezvous namespace
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registrations: Option<BTreeMap<String, usize>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay: Option<RelayStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dht_records: Option<usize>,
+}
+
+// Counted by relay::handle_event
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RelayStats {
+    pub reservations: usize, // Held now
+    pub circuits: usize,     // Open now
+    pub circuits_relayed: u64,
+    pub circuits_denied: u64,
+    #[serde(skip)]
+    reserved: HashSet<PeerId>,
+}
+
+impl RelayStats {
+    pub fn reserved(&mut self, peer: PeerId) {
+        self.reserved.insert(peer);
+        self.reservations = self.reserved.len();
+    }
+
+    // The reservation timed out, or went with the peer's last connection
+    pub fn unreserved(&mut self, peer: &PeerId) {
+        self.reserved.remove(peer);
+        self.reservations = self.reserved.len();
+    }
+
+    pub fn circuit_opened(&mut self) {
+        self.circuits += 1;
+        self.circuits_relayed += 1;
+    }
+
+    pub fn circuit_closed(&mut self) {
+        self.circuits = self.circuits.saturating_sub(1);
+    }
+}
+
+// The latest status, refreshed by the node's event loop and read by the --status endpoint
+pub struct StatusBoard {
+    sender: watch::Sender<ServerStatus>,
+}
+
+impl StatusBoard {
+    // Serve the status as JSON to anyone who GETs it from `address`, if one was given
+    pub async fn new(address: Option<SocketAddr>) -> Result<Self, Box<dyn Error>> {
+        let (sender, receiver) = watch::channel(ServerStatus::default());
+        if let Some(address) = address {
+            let listener = TcpListener::bind(address).await
+                .map_err(|e| format!("Could not serve the status on {address}: {e}"))?;
+            system!("Serving status on http://{address}/");
+            tokio::spawn(serve(listener, receiver, Instant::now()));
+        }
+        Ok(StatusBoard { sender })
+    }
+
+    pub fn update(&self, update: impl FnOnce(&mut ServerStatus)) {
+        self.sender.send_modify(update);
+    }
+}
+
+async fn serve(listener: TcpListener, status: watch::Receiver<ServerStatus>, started: Instant) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let mut status = status.borrow().clone();
+                status.uptime_secs = started.elapsed().as_secs();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, status).await {
+                        tracing::debug!("Status connection failed: {e}");
+                    }
+                });
+            }
+            Err(e) => tracing::warn!("Failed to accept a status connection: {e}"),
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, status: ServerStatus) -> tokio::io::Result<()> {
+    match rpc::read_request(&mut stream).await? {
+        Some(request) if request.method == "GET" => {
+            let body = serde_json::to_string(&status).unwrap_or_default();
+            rpc::write_response(&mut stream, "200 OK", &body).await
+        }
+        Some(_) => rpc::write_response(&mut stream, "405 Method Not Allowed", "").await,
+        None => rpc::write_response(&mut stream, "400 Bad Request", "").await,
+    }
+}
@@ -0,0 +1,123 @@
+use std::{ borrow::Cow, path::PathBuf, sync::{ Arc, Mutex }, time::{ Duration, Instant } };
+use libp2p::{ kad::{ self, store::{ MemoryStore, RecordStore }, ProviderRecord, Record, RecordKey }, PeerId };
+use serde::{ Deserialize, Serialize };
+use tokio::task::JoinHandle;
+
+use crate::persist::{ unix_now, JsonFile };
+
+// A Kademlia record store that keeps its records in a file as well as in memory, so nicknames and
+// ratings survive a restart. Changes are written back by flush, which the owner calls on a timer and at
+// shutdown. Without a file it behaves exactly like the MemoryStore it wraps
+pub struct DiskStore {
+    inner: MemoryStore,
+    file: Option<JsonFile<Vec<SavedRecord>>>,
+    dirty: bool, // Records changed since the last flush
+    snapshots: u64, // Flushes started
+    written: Arc<Mutex<u64>>, // The latest flush written, so an older one finishing late doesn't overwrite it
+}
+
+// A record as saved to disk. Expiry is kept as wall-clock time since Instants don't outlive the process
+#[derive(Serialize, Deserialize)]
+struct SavedRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    publisher: Option<PeerId>,
+    expires_at: Option<u64>, // Seconds since the UNIX epoch
+}
+
+impl DiskStore {
+    pub fn new(local_peer_id: PeerId, file: Option<PathBuf>) -> Self {
+        let mut inner = MemoryStore::new(local_peer_id);
+        let file = file.map(|file| {
+            let (file, saved) = JsonFile::open(file, "DHT records");
+            for record in restore(saved) {
+                if let Err(e) = inner.put(record) {
+                    tracing::warn!("Failed to restore a saved record: {e}");
+                }
+            }
+            file
+        });
+        DiskStore { inner, file, dirty: false, snapshots: 0, written: Arc::new(Mutex::new(0)) }
+    }
+
+    // Write every record back to the file if any changed since the last flush. The records are copied here and
+    // written on the blocking pool, as a bootstrap node can hold a lot of them; await the handle to know they're
+    // on disk, as shutdown does
+    pub fn flush(&mut self) -> Option<JoinHandle<()>> {
+        let file = self.file.clone().filter(|_| self.dirty)?;
+        self.dirty = false;
+        self.snapshots += 1;
+        let snapshot = self.snapshots;
+        let written = self.written.clone();
+        let records: Vec<SavedRecord> = self.inner.records().map(|record| SavedRecord {
+            key: record.key.to_vec(),
+            value: record.value.clone(),
+            publisher: record.publisher,
+            expires_at: record.expires.map(|expires| unix_now() + expires.saturating_duration_since(Instant::now()).as_secs()),
+        }).collect();
+        Some(tokio::task::spawn_blocking(move || {
+            let mut written = written.lock().unwrap();
+            if *written < snapshot {
+                file.save(&records);
+                *written = snapshot;
+            }
+        }))
+    }
+}
+
+impl RecordStore for DiskStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, key: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.inner.get(key)
+    }
+
+    fn put(&mut self, record: Record) -> kad::store::Result<()> {
+        self.inner.put(record)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &RecordKey) {
+        self.inner.remove(key);
+        self.dirty = true;
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    // Provider records, which say who has rated a peer, are only kept in memory: raters announce them again
+    // whenever they republish, see rating::publish
+    fn add_provider(&mut self, record: ProviderRecord) -> kad::store::Result<()> {
+        self.inner.add_provider(record)
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, key: &RecordKey, provider: &PeerId) {
+        self.inner.remove_provider(key, provider)
+    }
+}
+
+// Turn saved records back into records, dropping any that expired while we were offline
+fn restore(saved: Vec<SavedRecord>) -> Vec<Record> {
+    let now = unix_now();
+    saved
+        .into_iter()
+        .filter(|record| record.expires_at.is_none_or(|expires_at| expires_at > now))
+        .map(|record| Record {
+            key: RecordKey::from(record.key),
+            value: record.value,
+            publisher: record.publisher,
+            expires: record.expires_at.map(|expires_at| Instant::now() + Duration::from_secs(expires_at - now)),
+        })
+        .collect()
+}
@@ -0,0 +1,549 @@
+use std::{ collections::BTreeMap, fmt, path::Path, time::{ Duration, Instant } };
+use bytes::Bytes;
+use chacha20poly1305::Key;
+use libp2p::{ request_response::ResponseChannel, PeerId };
+use serde::{ Deserialize, Serialize };
+use tokio::fs;
+
+use crate::{
+    audit::{ self, AuditEvent, SignedTradeReceipt },
+    behaviour::{ record_sent, save_received, save_received_file, RequestType, ResponseType, SwapBytesBehaviour },
+    chunking::{ self, Received },
+    crypto,
+    negotiation,
+    output,
+    util::{ update_peer_rating, ChatState },
+};
+
+// Minutes a trade can wait on an answer or a confirmation unless --trade-timeout says otherwise
+pub const DEFAULT_TIMEOUT_MINUTES: u64 = 30;
+
+// One side of a trade, described before anything is sent so both peers know what they're agreeing to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMeta {
+    pub name: String,
+    pub size: u64,
+    pub digest: String, // SHA-256, hex
+}
+
+impl FileMeta {
+    pub fn of(name: &str, data: &[u8]) -> Self {
+        FileMeta { name: name.to_string(), size: data.len() as u64, digest: audit::digest(data) }
+    }
+
+    // Describe a file on disk, read a block at a time so big files aren't loaded whole
+    pub async fn read(path: &str) -> std::io::Result<Self> {
+        let size = fs::metadata(path).await?.len();
+        Ok(FileMeta { name: path.to_string(), size, digest: audit::digest_file(Path::new(path)).await? })
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() as u64 == self.size && audit::digest(data) == self.digest
+    }
+
+    // The same for a file a peer sent, which may still be in its part file
+    pub fn describes(&self, file: &Received) -> bool {
+        file.size() == self.size && file.digest() == self.digest
+    }
+}
+
+impl fmt::Display for FileMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} bytes, sha256 {})", self.name, self.size, self.digest.get(..16).unwrap_or(&self.digest))
+    }
+}
+
+// Our file, described, for one of theirs, by name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TradeProposal {
+    pub trade_id: String,
+    pub offered: FileMeta,
+    pub wanted: String,
+    // Both files are sent encrypted, and the keys only swapped once each side holds the other's, see Escrow
+    #[serde(default)]
+    pub fair: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TradeAnswer {
+    Accepted(FileMeta), // The wanted file, described
+    Declined(String), // Why, or empty if they just said no
+    // Different terms: one of their files, described, for one of ours, by name. See negotiation
+    Countered { offered: FileMeta, wanted: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TradeStatus {
+    Proposed, // Waiting for them to answer our proposal or counter-offer
+    Offered,  // Waiting for us to answer theirs
+    Agreed,   // They accepted our proposal, and we have to confirm
+    Swapping { sent: bool, received: bool },
+    Done,
+    Ended(String), // Declined, cancelled or failed, and why
+}
+
+impl fmt::Display for TradeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TradeStatus::Proposed => write!(f, "waiting for them to answer"),
+            TradeStatus::Offered => write!(f, "waiting for you to accept, counter or decline"),
+            TradeStatus::Agreed => write!(f, "accepted, waiting for you to confirm"),
+            TradeStatus::Swapping { sent, received } => {
+                write!(f, "swapping, {} sent, {} received", if *sent { "yours" } else { "nothing" }, if *received { "theirs" } else { "nothing" })
+            }
+            TradeStatus::Done => write!(f, "done"),
+            TradeStatus::Ended(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+// Where a fair trade is up to. Each side sends its file sealed with a key of its own, says when it holds all of
+// the other's, and only sends its key once the other side holds its file too. Neither side can read the other's
+// file until it has handed over all of its own, so the most a peer can walk off with is a key
+pub struct Escrow {
+    key: Key,
+    sent: bool,         // Our sealed file has gone
+    held: bool,         // They told us they hold all of it
+    theirs: Option<Received>, // Their sealed file, nonce first, until their key arrives
+    key_sent: bool,
+}
+
+impl Escrow {
+    pub(crate) fn new() -> Self {
+        Escrow { key: crypto::generate_file_key(), sent: false, held: false, theirs: None, key_sent: false }
+    }
+}
+
+// A file-for-file trade with another peer, tracked from proposal to both files arriving
+pub struct Trade {
+    pub trade_id: String,
+    pub peer: PeerId,
+    pub our_path: String,
+    pub ours: Option<FileMeta>,   // Known once we've read our file
+    pub wanted: String,           // Their file, by the name it was asked for or offered under
+    pub theirs: Option<FileMeta>, // Known once they've described it
+    pub status: TradeStatus,
+    pub escrow: Option<Escrow>, // For fair trades
+    pub rounds: u32, // Counter-offers made so far, by either side
+    pub receipt: Option<SignedTradeReceipt>, // Theirs, once the trade is complete
+    pub(crate) channel: Option<ResponseChannel<ResponseType>>, // To answer their proposal with
+    pub(crate) since: Instant, // When it last changed hands, see expire
+}
+
+impl Trade {
+    pub(crate) fn is_open(&self) -> bool {
+        !matches!(self.status, TradeStatus::Done | TradeStatus::Ended(_))
+    }
+
+    // How far a fair trade has got before any keys are swapped
+    fn progress(&self) -> String {
+        match (&self.status, &self.escrow) {
+            (TradeStatus::Swapping { sent: false, received: false }, Some(escrow)) => format!(
+                "fair swap, yours {}, theirs {}",
+                match (escrow.sent, escrow.held) {
+                    (false, _) => "not sent yet",
+                    (true, false) => "sent sealed",
+                    (true, true) => "held by them",
+                },
+                if escrow.theirs.is_some() { "held sealed" } else { "not here yet" },
+            ),
+            (status, _) => status.to_string(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Trades {
+    next_id: u32,
+    pub trades: BTreeMap<u32, Trade>,
+    // How long a trade can wait on an answer or a confirmation before it's called off, never if None
+    pub timeout: Option<Duration>,
+}
+
+impl Trades {
+    pub(crate) fn insert(&mut self, trade: Trade) -> u32 {
+        self.next_id += 1;
+        self.trades.insert(self.next_id, trade);
+        self.next_id
+    }
+
+    pub(crate) fn find(&self, peer: &PeerId, trade_id: &str) -> Option<u32> {
+        self.trades.iter().find(|(_, trade)| trade.peer == *peer && trade.trade_id == trade_id).map(|(id, _)| *id)
+    }
+
+    // Whether any trade with the peer is waiting on their file, so their chunks can come before its announcement
+    pub fn awaits_file_from(&self, peer: &PeerId) -> bool {
+        self.trades.values().any(|trade| trade.peer == *peer && self.expects_file(peer, &trade.trade_id))
+    }
+
+    // A trade's file is on its way in pieces, so it has started
+    pub fn started(&mut self, peer: &PeerId, trade_id: &str) {
+        let Some(id) = self.find(peer, trade_id) else { return };
+        if let Some(trade) = self.trades.get_mut(&id) {
+            trade.since = Instant::now();
+        }
+    }
+
+    // Whether we're waiting for this trade's file from a peer
+    pub fn expects_file(&self, peer: &PeerId, trade_id: &str) -> bool {
+        self.find(peer, trade_id)
+            .and_then(|id| self.trades.get(&id))
+            .is_some_and(|trade| {
+                matches!(trade.status, TradeStatus::Swapping { received: false, .. })
+                    && trade.escrow.as_ref().is_none_or(|escrow| escrow.theirs.is_none())
+            })
+    }
+}
+
+// Turn down a proposed trade, or call off one in progress
+pub fn cancel(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32) {
+    let Some(trade) = state.trades.trades.get_mut(&id).filter(|trade| trade.is_open()) else {
+        system!("There is no open trade {id}. Type /trades to see them.");
+        return;
+    };
+    let (peer, trade_id) = (trade.peer, trade.trade_id.clone());
+    match trade.status {
+        // Their proposal or counter-offer is still waiting on our answer
+        TradeStatus::Offered => {
+            trade.status = TradeStatus::Ended("you declined".to_string());
+            let our_path = trade.our_path.clone();
+            negotiation::answer(swarm, state, id, TradeAnswer::Declined(String::new()));
+            state.audit(AuditEvent::Declined, peer, &our_path, None);
+        }
+        _ => {
+            trade.status = TradeStatus::Ended("you cancelled".to_string());
+            swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeCancel { trade_id, reason: "they cancelled".to_string() });
+        }
+    }
+    system!("[{id}] Trade called off.");
+}
+
+// Call off trades that have waited longer than the timeout on an answer or a confirmation, telling the other
+// side. Once either file is on its way a trade is left to finish
+pub fn expire(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState) {
+    let Some(timeout) = state.trades.timeout else { return };
+    let expired: Vec<u32> = state.trades.trades.iter()
+        .filter(|(_, trade)| trade.since.elapsed() >= timeout)
+        .filter(|(_, trade)| match &trade.status {
+            TradeStatus::Proposed | TradeStatus::Offered | TradeStatus::Agreed => true,
+            TradeStatus::Swapping { sent: false, received: false } => trade.escrow.as_ref().is_none_or(|escrow| !escrow.sent && escrow.theirs.is_none()),
+            _ => false,
+        })
+        .map(|(id, _)| *id)
+        .collect();
+    let minutes = timeout.as_secs() / 60;
+    for id in expired {
+        let Some(trade) = state.trades.trades.get_mut(&id) else { continue };
+        let (peer, trade_id) = (trade.peer, trade.trade_id.clone());
+        let (ours, theirs) = match trade.status {
+            TradeStatus::Proposed => ("they didn't answer", "it expired waiting for your answer"),
+            TradeStatus::Offered => ("you didn't answer", "it expired waiting for their answer"),
+            TradeStatus::Agreed => ("you didn't confirm", "it expired waiting for them to confirm"),
+            _ => ("they didn't confirm", "it expired waiting for you to confirm"),
+        };
+        let offered = trade.status == TradeStatus::Offered;
+        trade.status = TradeStatus::Ended(format!("expired, {ours} within {minutes} minutes"));
+        trade.escrow = None;
+        match offered {
+            // Their proposal or counter-offer is turned down, on its response channel if it's still open
+            true => negotiation::answer(swarm, state, id, TradeAnswer::Declined(theirs.to_string())),
+            false => {
+                swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeCancel { trade_id, reason: theirs.to_string() });
+            }
+        }
+        error!("[{id}] The trade with {} expired: {ours} within {minutes} minutes.", state.peer_name(&peer));
+    }
+}
+
+// Confirm a trade they accepted, sending our file first
+pub async fn confirm(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32) {
+    if !state.trades.trades.get(&id).is_some_and(|trade| trade.status == TradeStatus::Agreed) {
+        system!("There is no accepted trade {id} to confirm. Type /trades to see them.");
+        return;
+    }
+    if send_ours(swarm, state, id).await {
+        match state.trades.trades.get(&id).is_some_and(|trade| trade.escrow.is_some()) {
+            true => system!("[{id}] Confirmed. Your file is on its way, sealed until you both hold each other's."),
+            false => system!("[{id}] Confirmed. Your file is on its way."),
+        }
+    }
+}
+
+// The other side called a trade off
+pub fn cancelled(state: &mut ChatState, peer: PeerId, trade_id: &str, reason: &str) -> bool {
+    let name = state.peer_name(&peer);
+    let Some(id) = state.trades.find(&peer, trade_id) else { return false };
+    let Some(trade) = state.trades.trades.get_mut(&id).filter(|trade| trade.is_open()) else { return false };
+    trade.status = TradeStatus::Ended(reason.to_string());
+    trade.channel = None;
+    error!("[{id}] {name} called off the trade: {reason}.");
+    true
+}
+
+// Their file arrived. It's only kept if it's what they described, and then ours goes back if it hasn't yet.
+// Returns whether it was accepted
+pub async fn file_arrived(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, trade_id: &str, file: Received) -> bool {
+    if !state.trades.expects_file(&peer, trade_id) {
+        tracing::debug!("Ignored a trade file from {peer} we weren't expecting");
+        return false;
+    }
+    let Some(id) = state.trades.find(&peer, trade_id) else { return false };
+    let Some(trade) = state.trades.trades.get_mut(&id) else { return false };
+    let Some(theirs) = trade.theirs.clone() else { return false };
+    if trade.escrow.is_some() {
+        return sealed_file_arrived(swarm, state, id, theirs, file).await;
+    }
+    if !theirs.describes(&file) {
+        let reason = "the file they sent doesn't match what they described";
+        trade.status = TradeStatus::Ended(reason.to_string());
+        swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeCancel { trade_id: trade_id.to_string(), reason: "the file you sent doesn't match what you described".to_string() });
+        error!("[{id}] Called off the trade with {}: {reason}.", state.peer_name(&peer));
+        return false;
+    }
+    save_received(swarm, state, peer, &theirs.name, file).await;
+    let Some(trade) = state.trades.trades.get_mut(&id) else { return true };
+    let TradeStatus::Swapping { sent, .. } = trade.status else { return true };
+    trade.status = TradeStatus::Swapping { sent, received: true };
+    if !sent && !send_ours(swarm, state, id).await {
+        return true;
+    }
+    complete(swarm, state, id);
+    true
+}
+
+// Their sealed file arrived in a fair trade. We can't check it yet beyond its size, so hold on to it, tell them
+// we have it, and send ours if we haven't
+async fn sealed_file_arrived(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32, theirs: FileMeta, file: Received) -> bool {
+    let Some(trade) = state.trades.trades.get_mut(&id) else { return false };
+    let (peer, trade_id) = (trade.peer, trade.trade_id.clone());
+    // A nonce, then their file, then the tag
+    if file.size() != theirs.size + 12 + 16 {
+        let reason = "the sealed file they sent is the wrong size";
+        trade.status = TradeStatus::Ended(reason.to_string());
+        swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeCancel { trade_id, reason: "the sealed file you sent is the wrong size".to_string() });
+        error!("[{id}] Called off the trade with {}: {reason}.", state.peer_name(&peer));
+        return false;
+    }
+    let Some(escrow) = trade.escrow.as_mut() else { return false };
+    escrow.theirs = Some(file);
+    let sent = escrow.sent;
+    swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeHeld { trade_id });
+    system!("[{id}] {}'s sealed file arrived. Keys are swapped once they hold yours.", state.peer_name(&peer));
+    if !sent && !send_ours(swarm, state, id).await {
+        return true;
+    }
+    release_key(swarm, state, id);
+    true
+}
+
+// They hold all of our sealed file
+pub fn held(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, trade_id: &str) -> bool {
+    let Some(id) = state.trades.find(&peer, trade_id) else { return false };
+    let Some(escrow) = state.trades.trades.get_mut(&id).filter(|trade| trade.is_open()).and_then(|trade| trade.escrow.as_mut()) else { return false };
+    if !escrow.sent {
+        return false;
+    }
+    escrow.held = true;
+    release_key(swarm, state, id);
+    true
+}
+
+// Send our key once we hold their sealed file and they hold ours
+fn release_key(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32) {
+    let Some(trade) = state.trades.trades.get_mut(&id).filter(|trade| trade.is_open()) else { return };
+    let (peer, trade_id, our_path) = (trade.peer, trade.trade_id.clone(), trade.our_path.clone());
+    let digest = trade.ours.as_ref().map(|ours| ours.digest.clone());
+    let TradeStatus::Swapping { received, .. } = trade.status else { return };
+    let Some(escrow) = trade.escrow.as_mut() else { return };
+    // Once their file is open we no longer hold it sealed, but we've had it
+    if (escrow.theirs.is_none() && !received) || !escrow.held || escrow.key_sent {
+        return;
+    }
+    escrow.key_sent = true;
+    let key = escrow.key.into();
+    trade.status = TradeStatus::Swapping { sent: true, received };
+    swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeKey { trade_id, key });
+    record_sent(swarm, state, peer, &our_path, digest);
+    complete(swarm, state, id);
+}
+
+// Their key arrived, so open the sealed file we've been holding. It's only kept if it's what they described,
+// and then our key goes back if it hasn't yet. Returns whether it was accepted
+pub async fn key_arrived(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, peer: PeerId, trade_id: &str, key: [u8; 32]) -> bool {
+    let Some(id) = state.trades.find(&peer, trade_id) else { return false };
+    let Some(trade) = state.trades.trades.get_mut(&id).filter(|trade| matches!(trade.status, TradeStatus::Swapping { received: false, .. })) else { return false };
+    let Some(theirs) = trade.theirs.clone() else { return false };
+    let Some(sealed) = trade.escrow.as_mut().and_then(|escrow| escrow.theirs.take()) else { return false };
+    let sealed = match sealed.read().await {
+        Ok(sealed) => sealed,
+        Err(e) => {
+            error!("[{id}] Could not read back {}'s sealed file: {e}", state.peer_name(&peer));
+            return false;
+        }
+    };
+    let Some(trade) = state.trades.trades.get_mut(&id) else { return false };
+    let (nonce, ciphertext) = sealed.split_at(12);
+    let data = crypto::open(Key::from_slice(&key), nonce.try_into().expect("Split at 12"), ciphertext).filter(|data| theirs.matches(data));
+    let Some(data) = data else {
+        let reason = "their key doesn't open the file they described";
+        trade.status = TradeStatus::Ended(reason.to_string());
+        swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeCancel { trade_id: trade_id.to_string(), reason: "your key doesn't open the file you described".to_string() });
+        error!("[{id}] Called off the trade with {}: {reason}.", state.peer_name(&peer));
+        return false;
+    };
+    if let Some(escrow) = trade.escrow.as_mut() {
+        // They only send their key once they hold our sealed file
+        escrow.held = escrow.sent;
+    }
+    let TradeStatus::Swapping { sent, .. } = trade.status else { return false };
+    trade.status = TradeStatus::Swapping { sent, received: true };
+    save_received_file(swarm, state, peer, &theirs.name, &data).await;
+    release_key(swarm, state, id);
+    complete(swarm, state, id);
+    true
+}
+
+// Our file as it goes out: read whole when it's sealed or fits in one message, otherwise streamed from disk
+enum OurFile {
+    Whole(Vec<u8>),
+    Streamed(fs::File),
+}
+
+// Open our file again, as long as it's still what we described. Streamed files are checked a block at a time
+async fn open_ours(path: &str, ours: &FileMeta, whole: bool) -> Option<OurFile> {
+    if whole {
+        return fs::read(path).await.ok().filter(|data| ours.matches(data)).map(OurFile::Whole);
+    }
+    let file = fs::File::open(path).await.ok()?;
+    let size = file.metadata().await.ok()?.len();
+    let digest = audit::digest_file(Path::new(path)).await.ok()?;
+    (size == ours.size && digest == ours.digest).then_some(OurFile::Streamed(file))
+}
+
+// Send our file, as long as it's still what we described
+async fn send_ours(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32) -> bool {
+    let Some(trade) = state.trades.trades.get(&id) else { return false };
+    let (peer, trade_id, our_path, ours) = (trade.peer, trade.trade_id.clone(), trade.our_path.clone(), trade.ours.clone());
+    // A sealed file is encrypted whole, so it's read whole
+    let whole = trade.escrow.is_some() || ours.as_ref().is_some_and(|ours| ours.size <= chunking::chunk_size(state, &peer) as u64);
+    let outgoing = match &ours {
+        Some(ours) => open_ours(&our_path, ours, whole).await,
+        None => None,
+    };
+    let (Some(ours), Some(outgoing)) = (ours, outgoing) else {
+        let reason = format!("{our_path} changed or went missing since the trade was agreed");
+        swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeCancel { trade_id, reason: "their file changed or went missing".to_string() });
+        error!("[{id}] Called off the trade: {reason}.");
+        if let Some(trade) = state.trades.trades.get_mut(&id) {
+            trade.status = TradeStatus::Ended(reason);
+        }
+        return false;
+    };
+    let fair = match outgoing {
+        OurFile::Whole(mut data) => {
+            // In a fair trade only the sealed file goes now, and our key once they hold it
+            let fair = match state.trades.trades.get_mut(&id).and_then(|trade| trade.escrow.as_mut()) {
+                Some(escrow) => {
+                    let (nonce, ciphertext) = crypto::seal(&escrow.key, &data);
+                    escrow.sent = true;
+                    data = [nonce.as_slice(), &ciphertext].concat();
+                    true
+                }
+                None => false,
+            };
+            // Sealed files too big for the peer to take in one message are streamed in pieces from memory
+            if data.len() > chunking::chunk_size(state, &peer) {
+                let transfer_id = chunking::new_transfer_id();
+                let size = data.len() as u64;
+                swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::ChunkedTradeFile { trade_id, transfer_id: transfer_id.clone(), size });
+                chunking::stream_bytes(swarm, state, peer, transfer_id, Bytes::from(data));
+            } else {
+                swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeFile { trade_id, data });
+            }
+            fair
+        }
+        // Like requested files, read from disk a chunk at a time as the peer takes them
+        OurFile::Streamed(file) => {
+            let transfer_id = chunking::new_transfer_id();
+            swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::ChunkedTradeFile { trade_id, transfer_id: transfer_id.clone(), size: ours.size });
+            chunking::stream(swarm, state, peer, transfer_id, file, ours.size).await;
+            false
+        }
+    };
+    if fair {
+        if let Some(trade) = state.trades.trades.get_mut(&id).filter(|trade| trade.status == TradeStatus::Agreed) {
+            trade.status = TradeStatus::Swapping { sent: false, received: false };
+        }
+        return true;
+    }
+    record_sent(swarm, state, peer, &our_path, Some(ours.digest));
+    if let Some(trade) = state.trades.trades.get_mut(&id) {
+        let received = matches!(trade.status, TradeStatus::Swapping { received: true, .. });
+        trade.status = TradeStatus::Swapping { sent: true, received };
+    }
+    true
+}
+
+fn complete(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32) {
+    let Some(trade) = state.trades.trades.get_mut(&id) else { return };
+    if trade.status != (TradeStatus::Swapping { sent: true, received: true }) {
+        return;
+    }
+    trade.status = TradeStatus::Done;
+    let (our_path, wanted, peer) = (trade.our_path.clone(), trade.wanted.clone(), trade.peer);
+    if let (Some(ours), Some(theirs)) = (trade.ours.clone(), trade.theirs.clone()) {
+        // Sign for the trade so they can show it happened, and keep theirs if it beat ours here
+        let receipt = SignedTradeReceipt::new(&state.keypair, peer, &trade.trade_id, &ours.digest, &theirs.digest);
+        swarm.behaviour_mut().request_response.request_response.send_request(&peer, RequestType::TradeReceipt(receipt));
+        state.trade_ledger.record(peer, trade.trade_id.clone(), ours, theirs, trade.escrow.is_some(), trade.receipt.clone());
+    }
+    let name = state.peer_name(&peer);
+    system!("[{id}] Trade with {name} complete: you sent {our_path} and received {wanted}.");
+    // Asked once, right after the trade; the next line typed answers it. Scripts and daemons rate with /trade rate instead
+    if output::prompts_enabled() {
+        state.rating_prompt = Some(id);
+        system!("[{id}] Rate {name} for this trade: -1, 0, 1 (or press enter to skip)");
+    } else {
+        system!("[{id}] Rate {name} for this trade with /trade rate {id} <-1|0|1>.");
+    }
+}
+
+// Their signed receipt for a trade, kept if it names both files as we swapped them. Returns whether it was kept
+pub fn receipt_arrived(state: &mut ChatState, peer: PeerId, receipt: SignedTradeReceipt) -> bool {
+    let local_peer_id = state.keypair.public().to_peer_id();
+    let Some(id) = state.trades.find(&peer, &receipt.trade_id) else { return false };
+    let Some(trade) = state.trades.trades.get_mut(&id) else { return false };
+    let matches = trade.ours.as_ref().is_some_and(|ours| ours.digest == receipt.got)
+        && trade.theirs.as_ref().is_some_and(|theirs| theirs.digest == receipt.gave);
+    if !matches || !receipt.verify(&peer, &local_peer_id) {
+        tracing::debug!("Ignored a trade receipt from {peer} that doesn't match the trade");
+        return false;
+    }
+    trade.receipt = Some(receipt.clone());
+    if trade.status == TradeStatus::Done {
+        state.trade_ledger.attach(&peer, receipt);
+    }
+    true
+}
+
+// Rate the peer we completed a trade with, noting the rating against the trade in the ledger
+pub async fn rate(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32, rating: i32) {
+    let Some(trade) = state.trades.trades.get(&id).filter(|trade| trade.status == TradeStatus::Done) else {
+        system!("There is no completed trade {id}. Type /trades to see them.");
+        return;
+    };
+    let (peer, trade_id, proof) = (trade.peer, trade.trade_id.clone(), trade.receipt.clone());
+    update_peer_rating(swarm, peer, rating, proof, state).await;
+    state.trade_ledger.rate(&peer, &trade_id, rating);
+    system!("[{id}] You have rated {} with {rating} for this trade.", state.peer_name(&peer));
+}
+
+// /trades
+pub fn list(state: &ChatState) {
+    if state.trades.trades.is_empty() {
+        system!("You haven't proposed or been offered any trades.");
+    }
+    for (id, trade) in &state.trades.trades {
+        system!("[{id}] your {} for {}'s {}: {}", trade.our_path, state.peer_name(&trade.peer), trade.wanted, trade.progress());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{ collections::VecDeque, io::Write, mem::MaybeUninit, sync::Mutex };
+
+use crate::{ behaviour::SwapBytesBehaviour, chunking, output, util::ChatState };
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const BOLD: &str = "\x1b[1m";
+
+// Lines kept for the chat pane
+const MAX_LINES: usize = 1000;
+const SIDEBAR_WIDTH: usize = 28;
+// Transfers shown at once, with a count of the rest
+const MAX_TRANSFERS: usize = 4;
+
+// There's one terminal however many nodes there are, so like output's INPUT this is global
+static SCREEN: Mutex<Option<Screen>> = Mutex::new(None);
+
+// --tui: the terminal split into a chat pane, a sidebar of connected peers with their ratings, a pane of running
+// transfers and the input line. Lines that would have scrolled past go to the chat pane, and the line editor
+// draws into the input line instead of the bottom of the terminal
+#[derive(Default)]
+struct Screen {
+    lines: VecDeque<String>,
+    peers: Vec<String>,
+    transfers: Vec<String>,
+    input: String,
+}
+
+// Switch to the terminal's alternate screen, so what was there before comes back on exit
+pub fn start() {
+    print!("\x1b[?1049h");
+    let mut screen = SCREEN.lock().unwrap();
+    let screen = screen.insert(Screen::default());
+    screen.draw();
+}
+
+// Back to the normal screen. Returns whether the TUI was running
+pub fn stop() -> bool {
+    if SCREEN.lock().unwrap().take().is_none() {
+        return false;
+    }
+    print!("\x1b[?1049l");
+    let _ = std::io::stdout().flush();
+    true
+}
+
+pub fn active() -> bool {
+    SCREEN.lock().unwrap().is_some()
+}
+
+// Add a printed line to the chat pane. Returns false if the TUI isn't running, for it to be printed as usual
+pub fn print(line: &str) -> bool {
+    let mut screen = SCREEN.lock().unwrap();
+    let Some(screen) = screen.as_mut() else { return false };
+    screen.lines.extend(line.lines().map(str::to_string));
+    while screen.lines.len() > MAX_LINES {
+        screen.lines.pop_front();
+    }
+    screen.draw();
+    true
+}
+
+// Show what's being typed in the input line. Returns false if the TUI isn't running
+pub fn set_input(typed: &str) -> bool {
+    let mut screen = SCREEN.lock().unwrap();
+    let Some(screen) = screen.as_mut() else { return false };
+    screen.input = typed.to_string();
+    screen.draw();
+    true
+}
+
+// Bring the peer sidebar and transfers pane up to date, after the node has handled something
+pub fn refresh(swarm: &libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState) {
+    let mut screen = SCREEN.lock().unwrap();
+    let Some(screen) = screen.as_mut() else { return };
+    let mut peers: Vec<_> = swarm.connected_peers()
+        .filter(|peer_id| Some(**peer_id) != state.rendezvous)
+        .map(|peer_id| {
+            let nickname = state.peer_cache.get(peer_id).map_or_else(|| state.peer_name(peer_id), |(peer, _)| peer.nickname.clone());
+            (nickname, state.ratings.summary(peer_id).unwrap_or_default())
+        })
+        .collect();
+    peers.sort_by(|a, b| b.1.cmp_reputation(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let peers: Vec<String> = peers.into_iter().map(|(nickname, rating)| match rating.count {
+        0 => format!("{nickname}  unrated"),
+        count => format!("{nickname}  {:+.2}★ ({count})", rating.average()),
+    }).collect();
+    let transfers = chunking::progress(state);
+    if screen.peers != peers || screen.transfers != transfers {
+        screen.peers = peers;
+        screen.transfers = transfers;
+        screen.draw();
+    }
+}
+
+impl Screen {
+    // Draw the whole screen in one write, so it doesn't flicker
+    fn draw(&self) {
+        let (rows, columns) = size();
+        let (dim, bold, reset) = match output::colors_enabled() {
+            true => (DIM, BOLD, RESET),
+            false => ("", "", ""),
+        };
+        let sidebar = if columns >= SIDEBAR_WIDTH * 3 { SIDEBAR_WIDTH } else { 0 };
+        let chat_width = columns - sidebar - usize::from(sidebar > 0);
+        let transfer_rows = self.transfers.len().clamp(1, MAX_TRANSFERS);
+        // Under the chat: a heading, the transfers, a rule and the input line
+        let chat_rows = rows.saturating_sub(transfer_rows + 3).max(1);
+
+        let mut frame = String::from("\x1b[?25l\x1b[H");
+        let wrapped: Vec<String> = self.lines.iter().flat_map(|line| wrap(line, chat_width)).collect();
+        let chat = &wrapped[wrapped.len().saturating_sub(chat_rows)..];
+        let mut sidebar_lines = vec![format!("{bold}Peers ({}){reset}", self.peers.len())];
+        sidebar_lines.extend(self.peers.iter().cloned());
+        if sidebar_lines.len() > chat_rows {
+            let more = sidebar_lines.len() - chat_rows + 1;
+            sidebar_lines.truncate(chat_rows - 1);
+            sidebar_lines.push(format!("{dim}and {more} more{reset}"));
+        }
+        for row in 0..chat_rows {
+            frame.push_str(&format!("\x1b[{};1H\x1b[K", row + 1));
+            if let Some(line) = chat.get(row) {
+                frame.push_str(line);
+            }
+            if sidebar > 0 {
+                frame.push_str(&format!("\x1b[{};{}H{dim}│{reset} ", row + 1, chat_width + 1));
+                if let Some(line) = sidebar_lines.get(row) {
+                    frame.push_str(&fit(line, sidebar - 1));
+                }
+            }
+        }
+
+        let mut row = chat_rows + 1;
+        frame.push_str(&format!("\x1b[{row};1H\x1b[K{dim}{}{reset}", rule(" Transfers ", columns)));
+        let mut transfers: Vec<String> = self.transfers.iter().take(MAX_TRANSFERS).cloned().collect();
+        if self.transfers.len() > MAX_TRANSFERS {
+            transfers.pop();
+            transfers.push(format!("and {} more, see /stats", self.transfers.len() - MAX_TRANSFERS + 1));
+        }
+        if transfers.is_empty() {
+            transfers.push(format!("{dim}Nothing is being sent or received{reset}"));
+        }
+        for line in &transfers {
+            row += 1;
+            frame.push_str(&format!("\x1b[{row};1H\x1b[K{}", fit(line, columns)));
+        }
+        frame.push_str(&format!("\x1b[{};1H\x1b[K{dim}{}{reset}", row + 1, rule("", columns)));
+
+        // Lines starting with / are commands; anything else is sent to the room
+        let mode = if self.input.starts_with('/') { "command" } else { "chat" };
+        let label = format!("{mode}> ");
+        let room = columns.saturating_sub(label.chars().count() + 1);
+        let shown: String = self.input.chars().skip(self.input.chars().count().saturating_sub(room)).collect();
+        frame.push_str(&format!("\x1b[{};1H\x1b[K{bold}{label}{reset}{shown}\x1b[?25h", row + 2));
+        print!("{frame}");
+        let _ = std::io::stdout().flush();
+    }
+}
+
+// Rows and columns of the terminal, or a common size if it won't say
+fn size() -> (usize, usize) {
+    let mut size = MaybeUninit::<libc::winsize>::uninit();
+    // SAFETY: TIOCGWINSZ fills in the winsize when it returns 0
+    let size = unsafe {
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, size.as_mut_ptr()) != 0 {
+            return (24, 80);
+        }
+        size.assume_init()
+    };
+    match (size.ws_row as usize, size.ws_col as usize) {
+        (0, _) | (_, 0) => (24, 80),
+        (rows, columns) => (rows, columns.max(20)),
+    }
+}
+
+fn rule(title: &str, width: usize) -> String {
+    let mut rule = format!("──{title}");
+    let length = rule.chars().count();
+    rule.extend(std::iter::repeat_n('─', width.saturating_sub(length)));
+    rule
+}
+
+// Split a line into rows of at most `width` characters. Color codes don't take up room, and a color that's on at
+// the end of a row is carried on to the next
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let mut rows = Vec::new();
+    let (mut row, mut shown, mut color) = (String::new(), 0, String::new());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            let mut code = String::from(c);
+            while let Some(c) = chars.next_if(|c| !c.is_ascii_alphabetic()) {
+                code.push(c);
+            }
+            code.extend(chars.next());
+            color = if code == RESET { String::new() } else { code.clone() };
+            row.push_str(&code);
+            continue;
+        }
+        if shown == width {
+            if !color.is_empty() {
+                row.push_str(RESET);
+            }
+            rows.push(std::mem::replace(&mut row, color.clone()));
+            shown = 0;
+        }
+        row.push(c);
+        shown += 1;
+    }
+    rows.push(row);
+    rows
+}
+
+// The first row of a line, cut off to fit
+fn fit(line: &str, width: usize) -> String {
+    let mut first = wrap(line, width).swap_remove(0);
+    if first.contains('\x1b') {
+        first.push_str(RESET);
+    }
+    first
+}
@@ -1,10 +1,12 @@
-use std::collections::HashMap;
-use clap::Parser;
-use libp2p::{ kad, PeerId };
+use std::{ collections::{ HashMap, HashSet, VecDeque }, net::SocketAddr, num::NonZeroUsize, path::PathBuf, str::FromStr, time::{ Duration, Instant } };
+use clap::{ Args, Parser, Subcommand };
+use libp2p::{ gossipsub::{ self, TopicHash }, identity, kad::{ self, store::RecordStore }, request_response::OutboundRequestId, PeerId };
 use serde::{Deserialize, Serialize};
-use tokio::io;
+use uuid::Uuid;
 
-use crate::behaviour::SwapBytesBehaviour;
+use chacha20poly1305::Key;
+
+use crate::{ address_book::AddressBook, approval::{ OfferPolicy, PendingApproval }, audit::{ AuditEvent, AuditLog, SignedTradeReceipt }, behaviour::SwapBytesBehaviour, bench::Benchmarks, board::{ Board, BoardPost }, chunking::ChunkedTransfers, connections::{ self, Connections }, crypto, discovery::{ DialStates, Registration, RendezvousServers }, history::TransferHistory, keystore::{ self, StoredIdentity }, ledger::TradeLedger, line_editor::LineReader, manifest::Manifests, outbound::OutboundQueue, output, persist::unix_now, pipeline::MessageFilter, render::{ self, RenderSettings }, ratelimit::RateLimiter, rating::{ self, Ratings, RatingSummary, SignedRating, SignedVouch }, reconnect::Reconnector, relay, scan::Scanner, server, room::{ IncomingInvite, InviteRound, PendingInvite, Room, RoomMember, SavedRooms, SignedRoster, Subscribers }, trade::Trades, vault::DownloadKey, wishlist::Wishlist };
 
 // CLI options
 #[derive(Parser, Debug)]
@@ -13,29 +15,1154 @@ pub struct Cli {
     #[arg(long)]
     pub port: Option<String>,
 
+    /// Rendezvous server address (IPv4), with :port if it isn't on 62649. Give it more than once for fallbacks,
+    /// which discovery switches to in order when the active server stops answering
+    #[arg(long)]
+    pub server: Vec<String>,
+
+    /// The PeerId the rendezvous server must have, as it prints at startup. Without it, whichever peer answers at
+    /// the server's address is trusted
+    #[arg(long)]
+    pub server_peer: Option<String>,
+
+    /// Register and discover peers under this rendezvous namespace, so separate groups can share a server
+    #[arg(long)]
+    pub namespace: Option<String>,
+
+    /// Seconds the rendezvous server should keep our registration, renewed halfway through. Shorter drops peers
+    /// that went away sooner but registers more often. Defaults to the server's, usually 2 hours
+    #[arg(long)]
+    pub registration_ttl: Option<u64>,
+
+    /// Seconds between pings checking the rendezvous server and other peers are still there, 30 by default
+    #[arg(long)]
+    pub ping_interval: Option<u64>,
+
+    /// Save downloaded files in this directory
+    #[arg(long)]
+    pub download_dir: Option<PathBuf>,
+
+    /// Only serve /request from files directly in this directory, by default the profile's share directory, or
+    /// ~/.swapbytes/share without a profile
+    #[arg(long)]
+    pub share_dir: Option<PathBuf>,
+
+    /// Encrypt received files with a passphrase as they're saved, asked for at startup or read from
+    /// SWAPBYTES_DOWNLOAD_PASSPHRASE. /decrypt recovers them
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub encrypt_downloads: Option<bool>,
+
+    /// Run this command on every received file before it goes into the download directory, with the file's path
+    /// added as the last argument, e.g. "clamscan --no-summary". Files it exits non-zero for stay in quarantine
+    #[arg(long)]
+    pub scan_command: Option<String>,
+
+    /// Where received files wait for --scan-command, .quarantine in the download directory by default
+    #[arg(long)]
+    pub quarantine_dir: Option<PathBuf>,
+
+    /// Largest file request or response to accept in one message, in bytes. Bigger files are split into
+    /// pieces this size, and peers are told about it so they do the same. Defaults to 8 MiB
+    #[arg(long)]
+    pub max_message_size: Option<u64>,
+
+    /// Send each file to a peer at no more than this many bytes per second. Unlimited by default
+    #[arg(long)]
+    pub max_upload_rate: Option<u64>,
+
+    /// How many file requests, offers and invites each peer can send per minute before the rest are refused.
+    /// Defaults to 10, and 0 turns the limit off
+    #[arg(long)]
+    pub rate_limit: Option<u32>,
+
+    /// Mute peers that keep going over --rate-limit for 10 minutes, hiding their messages too
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub auto_mute: Option<bool>,
+
+    /// Minutes a trade can wait on an answer or a confirmation before it's called off. Defaults to 30,
+    /// and 0 lets trades wait forever
+    #[arg(long)]
+    pub trade_timeout: Option<u64>,
+
+    /// Only be asked about offered files with these extensions, e.g. pdf,txt. Others are turned down automatically
+    #[arg(long, value_delimiter = ',')]
+    pub accept_extensions: Vec<String>,
+
+    /// Turn down offered files bigger than this many bytes without asking
+    #[arg(long)]
+    pub max_offer_size: Option<u64>,
+
+    /// Accept file offers from peers rated at least this much (-1 to 1) without asking, like /autoaccept
+    #[arg(long, allow_hyphen_values = true)]
+    pub auto_accept: Option<f64>,
+
+    /// Use this nickname instead of asking for one at startup
+    #[arg(long)]
+    pub nickname: Option<String>,
+
+    /// Read settings from this TOML (or .json) file instead of ~/.config/swapbytes/config.toml
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Keep this persona's identity, history and downloads in its own directory under ~/.swapbytes/profiles
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Keep your identity in this file between runs instead of generating a new one each time
+    #[arg(long)]
+    pub identity: Option<PathBuf>,
+
+    /// Remember the peers you connect to in this file and reconnect to them at startup
+    #[arg(long)]
+    pub peers: Option<PathBuf>,
+
+    /// Keep the /allow and /deny lists in this file between runs
+    #[arg(long)]
+    pub policy: Option<PathBuf>,
+
+    /// Keep DHT records in this file so they survive restarts
+    #[arg(long)]
+    pub records: Option<PathBuf>,
+
+    /// Print plain text without colors. Also set by the NO_COLOR environment variable
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub no_color: Option<bool>,
+
+    /// Print events as JSON objects, one per line, and read JSON commands from stdin, for scripts and bots
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub json: Option<bool>,
+
+    /// Draw a full-screen interface: the chat, connected peers with their ratings, running transfers and the input line
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub tui: Option<bool>,
+
+    /// Serve a JSON-RPC 2.0 API over HTTP on this address, e.g. 127.0.0.1:7878
+    #[arg(long)]
+    pub rpc: Option<SocketAddr>,
+
+    /// The bearer token JSON-RPC clients must send, instead of a random one. Needed to serve --rpc anywhere but
+    /// a loopback address. Either way it's written to rpc-token in the profile, or swapbytes.rpc-token
+    #[arg(long)]
+    pub rpc_token: Option<String>,
+
+    /// Run this shell command on mentions, private room invites and file offers and requests.
+    /// It gets SWAPBYTES_EVENT, SWAPBYTES_FROM and SWAPBYTES_TEXT in its environment
+    #[arg(long)]
+    pub notify: Option<String>,
+
+    /// Show diagnostics at this level and above: error, warn, info, debug or trace. Defaults to warn
+    #[arg(long)]
+    pub log_level: Option<tracing::Level>,
+
+    /// Write diagnostics to this file instead of the terminal
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub gossip: GossipOptions,
+
+    #[command(flatten)]
+    pub kademlia: KademliaOptions,
+
+    #[command(flatten)]
+    pub filter: FilterOptions,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// Gossipsub tuning, from the command line or the config file. Anything unset keeps its default
+#[derive(Args, Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct GossipOptions {
+    /// Largest chat message, in bytes after encryption. Defaults to 256 KiB
+    #[arg(long)]
+    pub gossip_max_transmit_size: Option<usize>,
+
+    /// Milliseconds between gossipsub heartbeats. Defaults to 1000
+    #[arg(long)]
+    pub gossip_heartbeat_ms: Option<u64>,
+
+    /// How many heartbeats of recent messages to keep for peers that missed them. Defaults to 5, and must be at least 3
+    #[arg(long)]
+    pub gossip_history_length: Option<usize>,
+
+    /// Send our own messages to every peer in the room rather than just the gossipsub mesh. Defaults to true
+    #[arg(long)]
+    pub gossip_flood_publish: Option<bool>,
+
+    /// How many of the --gossip-history-length heartbeats of messages to tell peers about. Defaults to 3
+    #[arg(long)]
+    pub gossip_history_gossip: Option<usize>,
+
+    /// Seconds to remember the IDs of messages already seen, so copies arriving later aren't shown again. Defaults to 60
+    #[arg(long)]
+    pub gossip_duplicate_cache_secs: Option<u64>,
+
+    /// Start from settings for small or busy rooms; any other --gossip option given still wins. Defaults to small
+    #[arg(long)]
+    pub gossip_preset: Option<GossipPreset>,
+}
+
+impl GossipOptions {
+    // Fill in whatever isn't set here from `other`
+    pub fn or(self, other: GossipOptions) -> GossipOptions {
+        GossipOptions {
+            gossip_max_transmit_size: self.gossip_max_transmit_size.or(other.gossip_max_transmit_size),
+            gossip_heartbeat_ms: self.gossip_heartbeat_ms.or(other.gossip_heartbeat_ms),
+            gossip_history_length: self.gossip_history_length.or(other.gossip_history_length),
+            gossip_flood_publish: self.gossip_flood_publish.or(other.gossip_flood_publish),
+            gossip_history_gossip: self.gossip_history_gossip.or(other.gossip_history_gossip),
+            gossip_duplicate_cache_secs: self.gossip_duplicate_cache_secs.or(other.gossip_duplicate_cache_secs),
+            gossip_preset: self.gossip_preset.or(other.gossip_preset),
+        }
+    }
+
+    // The settings given, with the rest filled in from the preset
+    pub fn with_preset(self) -> GossipOptions {
+        let preset = self.gossip_preset.unwrap_or_default().options();
+        self.or(preset)
+    }
+}
+
+// Starting points for gossipsub tuning
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum GossipPreset {
+    // gossipsub's own defaults, bar the larger messages: fine for rooms of a few dozen people chatting
+    #[default]
+    Small,
+    // Rooms with many peers and many messages a second. History is kept and advertised for longer, so peers that
+    // fall behind can still fetch what they missed, and seen message IDs are kept for longer, so copies that arrive
+    // late over a big mesh aren't shown twice. Our own messages go to the mesh rather than every peer
+    Busy,
+}
+
+impl GossipPreset {
+    fn options(self) -> GossipOptions {
+        match self {
+            GossipPreset::Small => GossipOptions {
+                gossip_history_length: Some(5),
+                gossip_history_gossip: Some(3),
+                gossip_duplicate_cache_secs: Some(60),
+                gossip_flood_publish: Some(true),
+                ..Default::default()
+            },
+            GossipPreset::Busy => GossipOptions {
+                gossip_history_length: Some(12),
+                gossip_history_gossip: Some(6),
+                gossip_duplicate_cache_secs: Some(300),
+                gossip_flood_publish: Some(false),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl FromStr for GossipPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "small" => Ok(GossipPreset::Small),
+            "busy" => Ok(GossipPreset::Busy),
+            other => Err(format!("Expected small or busy, not {other}")),
+        }
+    }
+}
+
+impl TryFrom<String> for GossipPreset {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// How many peers a DHT put has to reach to count as done: one, majority, all (of the 20 closest peers) or a number.
+// Puts reach as many peers as they can either way; the quorum only decides when a put is reported as failed
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Quorum(pub kad::Quorum);
+
+impl FromStr for Quorum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "one" => Ok(Quorum(kad::Quorum::One)),
+            "majority" => Ok(Quorum(kad::Quorum::Majority)),
+            "all" => Ok(Quorum(kad::Quorum::All)),
+            n => n.parse::<NonZeroUsize>()
+                .map(|n| Quorum(kad::Quorum::N(n)))
+                .map_err(|_| format!("Expected one, majority, all or a number of peers, not {n}")),
+        }
+    }
+}
+
+impl TryFrom<String> for Quorum {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Kademlia tuning, from the command line or the config file. Anything unset keeps its default
+#[derive(Args, Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct KademliaOptions {
+    /// How many peers each DHT query asks at once. Defaults to 3
+    #[arg(long)]
+    pub kad_parallelism: Option<NonZeroUsize>,
+
+    /// How many peers a DHT put has to reach: one, majority, all or a number. Defaults to one, as majority and all
+    /// (of 20) fail on small networks
     #[arg(long)]
-    pub server: Option<String>,
+    pub kad_quorum: Option<Quorum>,
+
+    /// --kad-quorum for our own profile, nickname and the ratings and vouches we add to other peers' profiles
+    #[arg(long)]
+    pub kad_profile_quorum: Option<Quorum>,
+
+    /// --kad-quorum for private room member lists, which are best effort as rooms share them over gossip as well
+    #[arg(long)]
+    pub kad_room_quorum: Option<Quorum>,
+}
+
+impl KademliaOptions {
+    // Fill in whatever isn't set here from `other`
+    pub fn or(self, other: KademliaOptions) -> KademliaOptions {
+        KademliaOptions {
+            kad_parallelism: self.kad_parallelism.or(other.kad_parallelism),
+            kad_quorum: self.kad_quorum.or(other.kad_quorum),
+            kad_profile_quorum: self.kad_profile_quorum.or(other.kad_profile_quorum),
+            kad_room_quorum: self.kad_room_quorum.or(other.kad_room_quorum),
+        }
+    }
+
+    fn quorum(&self) -> kad::Quorum {
+        self.kad_quorum.map_or(kad::Quorum::One, |quorum| quorum.0)
+    }
+
+    // For puts of profile records, which matter most
+    pub fn profile_quorum(&self) -> kad::Quorum {
+        self.kad_profile_quorum.map_or_else(|| self.quorum(), |quorum| quorum.0)
+    }
+
+    // For puts of room records
+    pub fn room_quorum(&self) -> kad::Quorum {
+        self.kad_room_quorum.map_or_else(|| self.quorum(), |quorum| quorum.0)
+    }
+}
+
+// Filters for incoming chat, from the command line or the config file, see pipeline
+#[derive(Args, Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct FilterOptions {
+    /// Hide incoming messages that match this regular expression. Can be given more than once
+    #[arg(long = "drop-pattern")]
+    pub drop_patterns: Vec<String>,
+
+    /// Mask common swear words in incoming messages with #s
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub mask_profanity: Option<bool>,
+
+    /// Mask this word in incoming messages too. Can be given more than once
+    #[arg(long = "mask-word")]
+    pub mask_words: Vec<String>,
+
+    /// Cut incoming messages longer than this many characters
+    #[arg(long)]
+    pub max_message_length: Option<usize>,
+}
+
+impl FilterOptions {
+    // Fill in whatever isn't set here from `other`
+    pub fn or(self, other: FilterOptions) -> FilterOptions {
+        FilterOptions {
+            drop_patterns: if self.drop_patterns.is_empty() { other.drop_patterns } else { self.drop_patterns },
+            mask_profanity: self.mask_profanity.or(other.mask_profanity),
+            mask_words: if self.mask_words.is_empty() { other.mask_words } else { self.mask_words },
+            max_message_length: self.max_message_length.or(other.max_message_length),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Export or import an identity to move it between machines
+    #[command(subcommand)]
+    Identity(IdentityCommand),
+    /// Run without a terminal, taking commands from `swapbytes ctl` over a control socket
+    Daemon {
+        /// The control socket to listen on, swapbytes.sock by default
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Send a command to a running daemon, e.g. `swapbytes ctl /list`. Without a command, follow its output
+    Ctl {
+        /// The daemon's control socket, swapbytes.sock by default
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// Seconds to keep printing the daemon's output after the last line
+        #[arg(long, default_value_t = 2)]
+        wait: u64,
+        command: Vec<String>,
+    },
+    /// Run a rendezvous server for SwapBytes clients to find each other through
+    Serve {
+        /// The TCP port to listen on, 62649 by default
+        #[arg(long, default_value_t = server::DEFAULT_PORT)]
+        port: u16,
+        #[command(flatten)]
+        options: ServerOptions,
+    },
+    /// Run the rendezvous server, a relay and a DHT node with persistent storage in one, for an always-on node
+    /// that anchors a community
+    Bootstrap {
+        /// The TCP and UDP port to listen on, 62649 by default so clients find the rendezvous server
+        #[arg(long, default_value_t = server::DEFAULT_PORT)]
+        port: u16,
+        /// Keep DHT records in this file, bootstrap-records.json by default
+        #[arg(long)]
+        records: Option<PathBuf>,
+        #[command(flatten)]
+        options: ServerOptions,
+        #[command(flatten)]
+        limits: RelayOptions,
+    },
+    /// Run a circuit relay for SwapBytes peers behind NATs. Use --identity to keep its address between runs
+    Relay {
+        /// The TCP and UDP port to listen on, 62650 by default
+        #[arg(long, default_value_t = relay::DEFAULT_PORT)]
+        port: u16,
+        #[command(flatten)]
+        limits: RelayOptions,
+        /// Serve counters for monitoring as JSON over HTTP on this address, e.g. 127.0.0.1:8080
+        #[arg(long)]
+        status: Option<SocketAddr>,
+    },
+}
+
+// How `swapbytes serve` and `swapbytes bootstrap` run their rendezvous server, see server
+#[derive(Args, Debug, Default, Clone)]
+pub struct ServerOptions {
+    /// Keep registrations in this file, so clients are found again after the server restarts
+    #[arg(long)]
+    pub registrations: Option<PathBuf>,
+
+    /// Shortest registration TTL to accept, in seconds. Defaults to 2 hours
+    #[arg(long)]
+    pub min_ttl: Option<u64>,
+
+    /// Serve counters for monitoring as JSON over HTTP on this address, e.g. 127.0.0.1:8080
+    #[arg(long)]
+    pub status: Option<SocketAddr>,
+
+    /// Only let the peers listed in this file connect, one PeerId per line. Everyone can by default
+    #[arg(long)]
+    pub allow_peers: Option<PathBuf>,
+}
+
+// Caps on what a relay carries for others, see relay. Anything unset keeps libp2p's default
+#[derive(Args, Debug, Default, Clone)]
+pub struct RelayOptions {
+    /// Peers that can hold a reservation at once. Defaults to 128
+    #[arg(long)]
+    pub max_reservations: Option<usize>,
+
+    /// Circuits relayed at once. Defaults to 16
+    #[arg(long)]
+    pub max_circuits: Option<usize>,
+
+    /// Circuits relayed at once for any one peer. Defaults to 4
+    #[arg(long)]
+    pub max_circuits_per_peer: Option<usize>,
+
+    /// Seconds before a circuit is closed. Defaults to 120
+    #[arg(long)]
+    pub max_circuit_duration: Option<u64>,
+
+    /// Bytes relayed in each direction before a circuit is closed. Defaults to 128 KiB
+    #[arg(long)]
+    pub max_circuit_bytes: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IdentityCommand {
+    /// Encrypt the identity in the --identity file (swapbytes.identity by default) into <FILE>
+    Export { file: PathBuf },
+    /// Decrypt <FILE> into the --identity file (swapbytes.identity by default)
+    Import { file: PathBuf },
+}
+
+// Where the identity subcommands look when --identity isn't given
+pub const DEFAULT_IDENTITY_FILE: &str = "swapbytes.identity";
+
+// Details of a /connect invite carried through the DHT lookups
+#[derive(Clone)]
+pub struct ConnectionDetails {
+    pub initiator_nickname: String,
+    pub room_id: String, // A new room, or the private room we're already in
+    pub passphrase: Option<String>,
 }
 
 // Private Connection Request
 pub enum ConnectionRequest {
-    NicknameLookup(String, PeerId),
-    PeerData(PeerId, String, PeerId),
+    NicknameLookup(String, ConnectionDetails),
+    PeerData(PeerId, ConnectionDetails),
+}
+
+// Where the nickname we started with stands. Its reverse record is only put once no one else turns out to hold it,
+// see republish_own_records
+#[derive(Clone, Copy, PartialEq)]
+pub enum NicknameClaim {
+    // No DHT peer has been asked yet, or none answered
+    Unchecked,
+    // Looking it up. Retry is set when peers join meanwhile, so the lookup is tried again if it reaches no one
+    Checking { query: kad::QueryId, retry: bool },
+    // Someone else holds it; it's checked again at the next republish
+    Taken,
+    Claimed,
+}
+
+impl NicknameClaim {
+    pub fn checks(&self, id: &kad::QueryId) -> bool {
+        matches!(self, NicknameClaim::Checking { query, .. } if query == id)
+    }
 }
 
+// How long a cached PeerData entry is trusted before going back to the DHT
+pub const PEER_CACHE_TTL: Duration = Duration::from_secs(300);
+
+// How long a record lasts in the DHT after it was last put (libp2p's default, set explicitly because the
+// nickname check below goes by it)
+pub const RECORD_TTL: Duration = Duration::from_secs(48 * 60 * 60);
+// How often our own records are put again. Well inside RECORD_TTL
+pub const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// A nickname record put again within this long has a holder who is still around. Two republishes, so one that
+// failed isn't enough to lose the nickname
+pub const NICKNAME_HELD_FOR: Duration = Duration::from_secs(2 * 60 * 60);
+// Routing table changes come in bursts as peers join, so republish for them at most this often
+pub const REPUBLISH_DEBOUNCE: Duration = Duration::from_secs(60);
+
 // Swapbytes state
 pub struct ChatState {
     pub pending_messages: HashMap<kad::QueryId, (PeerId, Vec<u8>)>,
     pub pending_connections: HashMap<kad::QueryId, ConnectionRequest>,
-    pub pending_rating_update: HashMap<kad::QueryId, i32>,
-    pub rendezvous: PeerId,
+    // /rate lookups of a nickname's PeerId, with the rating to give
+    pub pending_rating_lookups: HashMap<kad::QueryId, i32>,
+    // /vouch: resolving the nickname, then updating the peer's record
+    pub pending_vouch_lookups: HashSet<kad::QueryId>,
+    pub pending_vouches: HashSet<kad::QueryId>,
+    // /whois: resolving the nickname, fetching the profile, then waiting on the peer's ratings
+    pub pending_whois_lookups: HashSet<kad::QueryId>,
+    pub pending_whois: HashSet<kad::QueryId>,
+    pub pending_whois_ratings: HashSet<PeerId>,
+    // /bench lookups of a nickname's PeerId, with the size to send
+    pub pending_bench_lookups: HashMap<kad::QueryId, u64>,
+    // /ping lookups of a nickname's PeerId, with the nickname
+    pub pending_ping_lookups: HashMap<kad::QueryId, String>,
+    // Puts of our own records from the republish loop, so failures can be reported
+    pub pending_republish: HashSet<kad::QueryId>,
+    pub last_republish: Instant,
+    // Whether our records have reached other peers yet; until then every new connection is a chance to publish them
+    pub records_published: bool,
+    // Set by /exit; the node then shuts down instead of waiting for more input
+    pub exit_requested: bool,
+    // /exit was typed with transfers still going, so the next line says whether to leave anyway
+    pub exit_prompt: bool,
+    // The room everyone starts in and returns to from private rooms, picked by the namespace, see behaviour::namespace_room
+    pub public_room: String,
+    // /nick: checking the new nickname isn't held by another peer
+    pub pending_nickname_claims: HashMap<kad::QueryId, String>,
+    // The nickname we started with gets the same check once there are peers to ask
+    pub nickname_claim: NicknameClaim,
+    pub pending_verifications: HashMap<kad::QueryId, NicknameCheck>,
+    pub peer_cache: HashMap<PeerId, (PeerData, Instant)>,
+    // Peers' ratings, looked up whenever their profile is
+    pub ratings: Ratings,
+    // Messages we sent in a private room that are still waiting on receipts: id -> body
+    pub awaiting_receipts: HashMap<String, String>,
+    // Messages received in a private room that we haven't acknowledged as read yet
+    pub unread_messages: Vec<(TopicHash, String)>,
+    // Invites we've sent, by room ID and invitee
+    pub pending_invites: HashMap<(String, PeerId), PendingInvite>,
+    // Invites sent to several peers at once, by room ID
+    pub invite_rounds: HashMap<String, InviteRound>,
+    // Keys agreed with an inviter, used to unwrap the room key once they send it, by room ID
+    pub pending_room_joins: HashMap<String, Key>,
+    // Private rooms we're in, by room ID (which is also the topic)
+    pub rooms: HashMap<String, Room>,
+    // The rooms above as kept on disk, so they survive a crash, see SavedRooms
+    pub saved_rooms: SavedRooms,
+    // Invites waiting on /accept or /decline, by the ID shown to the user
+    pub incoming_invites: HashMap<u32, IncomingInvite>,
+    pub next_invite_id: u32,
+    // File requests and offers waiting on /yes or /no, and when their request arrived, see approval::expire
+    pub pending_approvals: HashMap<u32, (PendingApproval, Instant)>,
+    pub next_approval_id: u32,
+    // Whether :shortcodes: are turned into emoji, toggled with /emoji
+    // Peers we have vouched for this session
+    pub trusted: HashSet<PeerId>,
+    pub emoji_enabled: bool,
+    // Minimum average rating at which file offers are accepted without asking
+    pub auto_accept_threshold: Option<f64>,
+    pub render: RenderSettings,
+    // Files we've offered that are waiting on an answer, by request
+    pub pending_file_offers: HashMap<OutboundRequestId, String>,
+    // Completed file exchanges; only peers we've swapped with can be rated
+    pub transfer_history: TransferHistory,
+    // File-for-file trades, see trade
+    pub trades: Trades,
+    // Multi-file offers, see manifest
+    pub manifests: Manifests,
+    // Throughput benchmarks, see bench
+    pub benchmarks: Benchmarks,
+    // Every open connection and how it's doing, see connections
+    pub connections: Connections,
+    // Who is subscribed to each room's topic, see Subscribers
+    pub subscribers: Subscribers,
+    // Wants and haves from /want and /have, ours and other peers', see board
+    pub board: Board,
+    // Hash-chained record of every request, offer, answer and transfer, see audit
+    pub audit_log: AuditLog,
+    // Completed trades by counterparty, see ledger
+    pub trade_ledger: TradeLedger,
+    // The completed trade whose rating prompt the next typed line answers, see trade::complete
+    pub rating_prompt: Option<u32>,
+    // Peers discovered through the rendezvous server and whether they're connected, see discovery
+    pub dials: DialStates,
+    // Our rendezvous registration and its TTL, see discovery
+    pub registration: Registration,
+    // The configured rendezvous servers and which one discovery uses, see discovery
+    pub servers: RendezvousServers,
+    // Files the user is after, checked against /have posts, see wishlist
+    pub wishlist: Wishlist,
+    pub address_book: AddressBook,
+    // File requests and offers being sent, see outbound
+    pub outbound: OutboundQueue,
+    // Files arriving in pieces, see chunking
+    pub chunked: ChunkedTransfers,
+    // Re-dials dropped room members and transfer peers, see reconnect
+    pub reconnector: Reconnector,
+    // Limits how many file requests, offers and invites each peer can send, see ratelimit
+    pub rate_limiter: RateLimiter,
+    // Drop-list, masking and length limit for incoming chat, see pipeline
+    pub message_filter: MessageFilter,
+    // Offers outside it are turned down without a prompt
+    pub offer_policy: OfferPolicy,
+    // Where received files are saved
+    pub download_dir: PathBuf,
+    // The only files peers can /request, see approval::shared_path. Nothing is shared without one
+    pub share_dir: Option<PathBuf>,
+    // Received files are encrypted with this when --encrypt-downloads is on, see vault
+    pub download_key: Option<DownloadKey>,
+    // Received files wait in quarantine for --scan-command when there is one, see scan
+    pub scanner: Option<Scanner>,
+    pub peer_listing: Option<PeerListing>,
+    // Profile lookups for /list and /top, see PeerLookups
+    pub peer_lookups: PeerLookups,
+    pub keypair: identity::Keypair,
+    // The --identity file to keep up to date, if we're running with a saved identity
+    pub identity_file: Option<PathBuf>,
+    // The rendezvous servers' PeerId: from --server-peer, or learned from the first to answer at a server's address
+    pub rendezvous: Option<PeerId>,
+    // Quorums for DHT puts, see KademliaOptions
+    pub kademlia: KademliaOptions,
 }
 
-// Struct to store in DHT
-#[derive(Serialize, Deserialize)]
+impl ChatState {
+    pub fn new(keypair: identity::Keypair, rendezvous: Option<PeerId>) -> Self {
+        ChatState {
+            pending_messages: HashMap::new(),
+            pending_connections: HashMap::new(),
+            pending_rating_lookups: HashMap::new(),
+            pending_vouch_lookups: HashSet::new(),
+            pending_vouches: HashSet::new(),
+            pending_whois_lookups: HashSet::new(),
+            pending_whois: HashSet::new(),
+            pending_whois_ratings: HashSet::new(),
+            pending_bench_lookups: HashMap::new(),
+            pending_ping_lookups: HashMap::new(),
+            pending_republish: HashSet::new(),
+            last_republish: Instant::now(),
+            records_published: false,
+            exit_requested: false,
+            exit_prompt: false,
+            public_room: "default".to_string(),
+            pending_nickname_claims: HashMap::new(),
+            nickname_claim: NicknameClaim::Unchecked,
+            pending_verifications: HashMap::new(),
+            peer_cache: HashMap::new(),
+            ratings: Ratings::default(),
+            awaiting_receipts: HashMap::new(),
+            unread_messages: Vec::new(),
+            pending_invites: HashMap::new(),
+            invite_rounds: HashMap::new(),
+            pending_room_joins: HashMap::new(),
+            rooms: HashMap::new(),
+            saved_rooms: SavedRooms::default(),
+            incoming_invites: HashMap::new(),
+            next_invite_id: 1,
+            pending_approvals: HashMap::new(),
+            next_approval_id: 1,
+            trusted: HashSet::new(),
+            emoji_enabled: true,
+            auto_accept_threshold: None,
+            render: RenderSettings::default(),
+            pending_file_offers: HashMap::new(),
+            transfer_history: TransferHistory::default(),
+            audit_log: AuditLog::default(),
+            trade_ledger: TradeLedger::default(),
+            rating_prompt: None,
+            dials: DialStates::default(),
+            registration: Registration::default(),
+            servers: RendezvousServers::default(),
+            wishlist: Wishlist::default(),
+            trades: Trades::default(),
+            manifests: Manifests::default(),
+            benchmarks: Benchmarks::default(),
+            connections: Connections::default(),
+            subscribers: Subscribers::default(),
+            board: Board::default(),
+            address_book: AddressBook::default(),
+            outbound: OutboundQueue::default(),
+            chunked: ChunkedTransfers::default(),
+            reconnector: Reconnector::default(),
+            rate_limiter: RateLimiter::default(),
+            message_filter: MessageFilter::default(),
+            offer_policy: OfferPolicy::default(),
+            download_dir: PathBuf::from("."),
+            share_dir: None,
+            download_key: None,
+            scanner: None,
+            peer_listing: None,
+            peer_lookups: PeerLookups::default(),
+            keypair,
+            identity_file: None,
+            rendezvous,
+            kademlia: KademliaOptions::default(),
+        }
+    }
+
+    // Add an entry to the audit log, signed with our identity
+    pub fn audit(&mut self, event: AuditEvent, peer: PeerId, filename: &str, digest: Option<String>) {
+        self.audit_log.append(&self.keypair, event, peer, filename, digest, None);
+    }
+
+    // A room member's nickname, or the one in the address book, falling back to the peer ID
+    pub fn peer_name(&self, peer_id: &PeerId) -> String {
+        self.rooms.values()
+            .flat_map(|room| &room.members)
+            .find(|member| member.peer_id == *peer_id)
+            .map(|member| member.nickname.clone())
+            .or_else(|| self.address_book.peers.get(peer_id).and_then(|known| known.nickname.clone()))
+            .unwrap_or_else(|| peer_id.to_string())
+    }
+
+    // The peer a peer ID or nickname refers to, for nicknames we've seen in a room, the address book or the cache
+    pub fn find_peer(&self, name: &str) -> Option<PeerId> {
+        if let Ok(peer_id) = name.parse() {
+            return Some(peer_id);
+        }
+        self.rooms.values()
+            .flat_map(|room| &room.members)
+            .find(|member| member.nickname == name)
+            .map(|member| member.peer_id)
+            .or_else(|| self.address_book.peers.iter().find(|(_, known)| known.nickname.as_deref() == Some(name)).map(|(peer_id, _)| *peer_id))
+            .or_else(|| self.peer_cache.iter().find(|(_, (peer, _))| peer.nickname == name).map(|(peer_id, _)| *peer_id))
+    }
+
+    // Every nickname find_peer knows, in order, for completing them
+    pub fn known_nicknames(&self) -> Vec<String> {
+        let mut nicknames: Vec<String> = self.rooms.values()
+            .flat_map(|room| &room.members)
+            .map(|member| member.nickname.clone())
+            .chain(self.address_book.peers.values().filter_map(|known| known.nickname.clone()))
+            .chain(self.peer_cache.values().map(|(peer, _)| peer.nickname.clone()))
+            .collect();
+        nicknames.sort();
+        nicknames.dedup();
+        nicknames
+    }
+
+    // Look up a peer in the cache, dropping the entry if it has outlived the TTL
+    pub fn cached_peer(&mut self, peer_id: &PeerId) -> Option<&PeerData> {
+        let expired = self.peer_cache
+            .get(peer_id)
+            .is_some_and(|(_, fetched)| fetched.elapsed() > PEER_CACHE_TTL);
+        if expired {
+            self.peer_cache.remove(peer_id);
+        }
+        self.peer_cache.get(peer_id).map(|(peer, _)| peer)
+    }
+
+    // Whether a file offer or request from this peer can skip the prompt. Only peers we have a
+    // cached, non-negative rating for qualify; anyone rated below 0 is always asked about
+    pub fn auto_accepts(&mut self, peer_id: &PeerId) -> bool {
+        let Some(threshold) = self.auto_accept_threshold else { return false };
+        let Some(rating) = self.ratings.summary(peer_id) else { return false };
+        rating.count > 0 && rating.average() >= 0.0 && rating.average() >= threshold
+    }
+
+    pub fn cache_peer(&mut self, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, peer_id: PeerId, peer: PeerData) {
+        self.address_book.set_nickname(&peer_id, &peer.nickname);
+        self.peer_cache.insert(peer_id, (peer, Instant::now()));
+        self.ratings.lookup(swarm, peer_id);
+    }
+
+    pub fn invalidate_peer(&mut self, peer_id: &PeerId) {
+        self.peer_cache.remove(peer_id);
+    }
+
+    // Record the result of a /list or /top lookup, start the next queued one, and print the listing once every
+    // peer in it is resolved. Returns false if the query wasn't one of these lookups
+    pub fn resolve_listed_peer(&mut self, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, id: &kad::QueryId, peer: Option<PeerData>) -> bool {
+        let Some(peer_id) = self.peer_lookups.finished(id) else { return false };
+        self.peer_lookups.dispatch(swarm);
+        let mut complete = false;
+        if let Some(listing) = self.peer_listing.as_mut()
+            && listing.waiting.remove(&peer_id) {
+            listing.resolved.push((peer_id, peer.clone()));
+            complete = listing.waiting.is_empty();
+        }
+        if let Some(peer) = peer {
+            self.cache_peer(swarm, peer_id, peer);
+        }
+        if complete {
+            self.finish_listing(swarm);
+        }
+        true
+    }
+
+    // A peer's ratings have been looked up: print the listing if it was only waiting on them
+    pub fn ratings_found(&mut self, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, peer_id: &PeerId) {
+        if self.peer_listing.as_mut().is_some_and(|listing| listing.rating_lookups.remove(peer_id)) {
+            self.finish_listing(swarm);
+        }
+    }
+
+    fn finish_listing(&mut self, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>) {
+        if self.peer_listing.as_ref().is_some_and(|listing| listing.waiting.is_empty() && listing.rating_lookups.is_empty())
+            && let Some(listing) = self.peer_listing.take() {
+            listing.print(swarm, self);
+        }
+    }
+
+    // Show a listing now if every peer in it and their ratings are already known, or once the rest have been
+    // looked up
+    pub fn start_listing(&mut self, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, mut listing: PeerListing) {
+        let peers: Vec<PeerId> = listing.resolved.iter().map(|(peer_id, _)| *peer_id).chain(listing.waiting.iter().copied()).collect();
+        listing.rating_lookups = peers.into_iter().filter(|peer_id| self.ratings.lookup(swarm, *peer_id)).collect();
+        if listing.waiting.is_empty() && listing.rating_lookups.is_empty() {
+            listing.print(swarm, self);
+            return;
+        }
+        for peer_id in &listing.waiting {
+            self.peer_lookups.request(*peer_id);
+        }
+        self.peer_lookups.dispatch(swarm);
+        self.peer_listing = Some(listing);
+    }
+}
+
+// How many peers /top shows
+const LEADERBOARD_SIZE: usize = 10;
+
+// What a peer listing is for
+#[derive(Default, Clone, PartialEq, Eq)]
+pub enum ListingKind {
+    // /list: everyone we're connected to
+    #[default]
+    Connected,
+    // /top: the best rated peers we know of
+    Leaderboard,
+    // /who: everyone in a room, us included
+    Members(TopicHash),
+}
+
+// A /list, /top or /who in progress: peers still being looked up, the ones resolved so far and the peers whose
+// ratings are still being looked up
+#[derive(Default)]
+pub struct PeerListing {
+    pub kind: ListingKind,
+    pub waiting: HashSet<PeerId>,
+    pub resolved: Vec<(PeerId, Option<PeerData>)>,
+    pub rating_lookups: HashSet<PeerId>,
+}
+
+// How many profile lookups for /list and /top run in the DHT at once
+const MAX_CONCURRENT_LOOKUPS: usize = 8;
+
+// Profile lookups for peer listings. Each peer is looked up once however many listings want it, and only a few
+// queries run at a time, so listing a lot of peers doesn't flood the DHT
+#[derive(Default)]
+pub struct PeerLookups {
+    queued: VecDeque<PeerId>,
+    in_flight: HashMap<kad::QueryId, PeerId>,
+}
+
+impl PeerLookups {
+    // Queue a lookup, unless the peer is already queued or being looked up
+    pub fn request(&mut self, peer_id: PeerId) {
+        if !self.queued.contains(&peer_id) && !self.in_flight.values().any(|in_flight| *in_flight == peer_id) {
+            self.queued.push_back(peer_id);
+        }
+    }
+
+    // Start queued lookups while there is room for them
+    pub fn dispatch(&mut self, swarm: &mut libp2p::Swarm<SwapBytesBehaviour>) {
+        while self.in_flight.len() < MAX_CONCURRENT_LOOKUPS {
+            let Some(peer_id) = self.queued.pop_front() else { break };
+            let query_id = swarm.behaviour_mut().kademlia.get_record(kad::RecordKey::new(&peer_id.to_bytes()));
+            self.in_flight.insert(query_id, peer_id);
+        }
+    }
+
+    // Which peer a finished query was looking up, if it was one of ours
+    fn finished(&mut self, id: &kad::QueryId) -> Option<PeerId> {
+        self.in_flight.remove(id)
+    }
+}
+
+impl PeerListing {
+    pub fn print(&self, swarm: &libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState) {
+        match &self.kind {
+            ListingKind::Connected => self.print_connected(swarm, state),
+            ListingKind::Leaderboard => self.print_leaderboard(state),
+            ListingKind::Members(room) => self.print_members(swarm, state, room),
+        }
+    }
+
+    // Print the highest rated peers; only rated peers make the board, and each rating is a completed trade
+    fn print_leaderboard(&self, state: &ChatState) {
+        let mut rated: Vec<(&PeerData, RatingSummary)> = self.resolved
+            .iter()
+            .filter_map(|(peer_id, peer)| peer.as_ref().map(|peer| (peer, state.ratings.summary(peer_id).unwrap_or_default())))
+            .filter(|(_, rating)| rating.count > 0)
+            .collect();
+        rated.sort_by(|a, b| b.1.cmp_reputation(&a.1).then_with(|| a.0.nickname.cmp(&b.0.nickname)));
+        if rated.is_empty() {
+            system!("No rated peers found yet.");
+        }
+        for (rank, (peer, rating)) in rated.iter().take(LEADERBOARD_SIZE).enumerate() {
+            let trades = if rating.count == 1 { "trade" } else { "trades" };
+            system!("{}. {}: {:+.2}★, {} completed {trades}", rank + 1, peer.nickname, rating.average(), rating.count);
+        }
+    }
+
+    // Print a table of the peers, best rated first, with how we're connected to each and the rooms we share with
+    // them; peers without a record go last
+    fn print_connected(&self, swarm: &libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState) {
+        let mut known: Vec<(&PeerId, &PeerData, RatingSummary)> = self.resolved
+            .iter()
+            .filter_map(|(peer_id, peer)| peer.as_ref().map(|peer| (peer_id, peer, state.ratings.summary(peer_id).unwrap_or_default())))
+            .collect();
+        known.sort_by(|a, b| b.2.cmp_reputation(&a.2).then_with(|| a.1.nickname.cmp(&b.1.nickname)));
+        if known.is_empty() {
+            system!("No other SwapBytes peers are connected.");
+        } else {
+            let topics: HashMap<&PeerId, Vec<&TopicHash>> = swarm.behaviour().chat.gossipsub.all_peers().collect();
+            let mut rows = vec![["Nickname", "Rating", "Vouched", "Transport", "Ping", "Rooms", "Connected"].map(String::from).to_vec()];
+            for (peer_id, peer, rating) in known {
+                let connections = state.connections.to(peer_id);
+                let mut transports: Vec<_> = connections.iter().map(|connection| connection.transport).collect();
+                transports.sort();
+                transports.dedup();
+                let rooms: Vec<_> = topics.get(peer_id).into_iter().flatten()
+                    .map(|topic| topic.as_str())
+                    .filter(|room| *room == state.public_room || state.rooms.contains_key(*room))
+                    .map(output::room_label)
+                    .collect();
+                rows.push(vec![
+                    peer.nickname.clone(),
+                    match rating.count {
+                        0 => "unrated".to_string(),
+                        count => format!("{:+.2}★ ({count})", rating.average()),
+                    },
+                    match peer.trusted_vouches(peer_id, &state.trusted) {
+                        0 => "-".to_string(),
+                        vouches => vouches.to_string(),
+                    },
+                    transports.iter().map(ToString::to_string).collect::<Vec<_>>().join("+"),
+                    connections.iter().filter_map(|connection| connection.rtt).min()
+                        .map_or("-".to_string(), |rtt| format!("{} ms", rtt.as_millis())),
+                    if rooms.is_empty() { "-".to_string() } else { rooms.join(", ") },
+                    connections.first().map_or("-".to_string(), |connection| connections::format_age(connection.since.elapsed())),
+                ]);
+            }
+            render::print_table(&rows);
+        }
+        let unknown = self.resolved.iter().filter(|(_, peer)| peer.is_none()).count();
+        if unknown > 0 {
+            system!("({unknown} other connected peers have no SwapBytes profile)");
+        }
+    }
+
+    // Print everyone in a room with their rating, us first. Members of a private room whose subscription we haven't
+    // seen, because they've gone or haven't rejoined yet, are listed as away
+    fn print_members(&self, swarm: &libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState, room: &TopicHash) {
+        let local_peer_id = swarm.local_peer_id();
+        let members = state.rooms.get(room.as_str()).map_or(&[][..], |room| room.members.as_slice());
+        let mut present: Vec<(&str, String, RatingSummary)> = self.resolved
+            .iter()
+            .map(|(peer_id, peer)| {
+                let nickname = peer.as_ref().map(|peer| peer.nickname.clone())
+                    .or_else(|| members.iter().find(|member| member.peer_id == *peer_id).map(|member| member.nickname.clone()))
+                    .unwrap_or_else(|| peer_id.to_string());
+                let status = if peer_id == local_peer_id {
+                    "you"
+                } else if state.subscribers.is_subscribed(peer_id, room) {
+                    "here"
+                } else {
+                    "away"
+                };
+                (status, nickname, state.ratings.summary(peer_id).unwrap_or_default())
+            })
+            .collect();
+        let order = |status: &str| ["you", "here", "away"].iter().position(|s| *s == status);
+        present.sort_by(|a, b| order(a.0).cmp(&order(b.0)).then_with(|| a.1.cmp(&b.1)));
+        let here = present.iter().filter(|(status, ..)| *status != "away").count();
+        let label = output::room_label(room.as_str());
+        match state.rooms.get(room.as_str()) {
+            Some(private) => {
+                let creator = match members.iter().find(|member| member.peer_id == private.creator) {
+                    _ if private.creator == *local_peer_id => "you".to_string(),
+                    Some(member) => member.nickname.clone(),
+                    None => "a peer who has since left".to_string(),
+                };
+                system!("{here} here in {label}, started by {creator} {} ago:", connections::format_age(private.age()));
+            }
+            None => system!("{here} here in {label}:"),
+        }
+        let mut rows = vec![["Nickname", "Rating", "Status"].map(String::from).to_vec()];
+        for (status, nickname, rating) in present {
+            rows.push(vec![
+                nickname,
+                match rating.count {
+                    0 => "unrated".to_string(),
+                    count => format!("{:+.2}★ ({count})", rating.average()),
+                },
+                status.to_string(),
+            ]);
+        }
+        render::print_table(&rows);
+    }
+}
+
+// Struct to store in DHT. Ratings aren't part of it: each rater stores theirs under its own key, see rating
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PeerData {
     pub nickname: String,
-    pub rating: i32,
+    #[serde(default)]
+    pub vouches: Vec<SignedVouch>,
+    // Missing from records written before profiles existed, so every field has a default
+    #[serde(default, flatten)]
+    pub profile: Profile,
+}
+
+// The optional parts of a peer's profile, shown by /whois
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub bio: Option<String>,
+    pub tags: Vec<String>, // Interests
+    pub shared_files: u32, // Files this peer has sent to others
+    pub namespaces: Vec<String>, // Rooms or topics the peer prefers to swap in
+    pub max_message_size: Option<u64>, // Largest file request or offer the peer accepts in one piece
+}
+
+impl PeerData {
+    pub fn new(nickname: String) -> Self {
+        PeerData { nickname, vouches: Vec::new(), profile: Profile::default() }
+    }
+
+    // Print everything we know about the peer for /whois
+    pub fn print_profile(&self, peer_id: &PeerId, rating: RatingSummary) {
+        system!("{} ({peer_id})", self.nickname);
+        system!("  Rating: {rating}");
+        if let Some(bio) = &self.profile.bio {
+            system!("  Bio: {bio}");
+        }
+        if !self.profile.tags.is_empty() {
+            system!("  Interests: {}", self.profile.tags.join(", "));
+        }
+        system!("  Files shared: {}", self.profile.shared_files);
+        if !self.profile.namespaces.is_empty() {
+            system!("  Preferred namespaces: {}", self.profile.namespaces.join(", "));
+        }
+    }
+
+    // How many of the peers we trust have vouched for this one
+    pub fn trusted_vouches(&self, peer_id: &PeerId, trusted: &HashSet<PeerId>) -> usize {
+        rating::vouchers(&self.vouches, peer_id).intersection(trusted).count()
+    }
+}
+
+// Everything that can be published on a gossipsub topic
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GossipMessage {
+    Chat(ChatMessage),
+    // Sent after /nick so peers drop their cached PeerData for the sender
+    NicknameChanged { old: String, new: String },
+    // Private room acknowledgements, keyed by ChatMessage id
+    Delivered(String),
+    Read(String),
+    // Private room membership changes
+    MemberJoined(RoomMember),
+    MemberLeft(PeerId),
+    // The creator's signed membership, sent after every change, see room
+    Roster(SignedRoster),
+    // Sent to the default room with our nickname when we quit
+    Leaving(String),
+    // Everything the author has on the board, replacing their earlier posts, see board
+    Board { nickname: String, posts: Vec<BoardPost> },
+    // Any of the above, sealed with the private room key
+    Encrypted { nonce: [u8; 12], ciphertext: Vec<u8> },
+}
+
+// A nickname claimed in chat being checked against the DHT, along with the messages shown under it meanwhile
+pub struct NicknameCheck {
+    pub peer: PeerId,
+    pub claimed: String,
+    pub messages: Vec<(String, String)>, // IDs and text of the messages shown
+}
+
+impl NicknameCheck {
+    // The peer is registered under another nickname: point out the messages already shown under the false one
+    pub fn flag(&self, registered: &str) {
+        let (claimed, count) = (&self.claimed, self.messages.len());
+        error!("Warning: peer {} is using the nickname {claimed} but is registered as {registered}.", self.peer);
+        error!("{count} message{} shown above as {claimed} came from {registered}:", if count == 1 { "" } else { "s" });
+        for (_, text) in &self.messages {
+            error!("  ⚠ {registered} (claiming to be {claimed}): {text}");
+        }
+        output::publish(output::Event::NicknameMismatch {
+            peer: self.peer,
+            claimed: claimed.clone(),
+            registered: registered.to_string(),
+            messages: self.messages.iter().map(|(id, _)| id.clone()).collect(),
+        });
+    }
+}
+
+// Envelope published on gossipsub topics so receivers don't need a DHT lookup per message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: String,
+    pub nickname: String,
+    pub rating_hint: RatingSummary,
+    pub timestamp: u64,
+    pub body: String,
+}
+
+impl ChatMessage {
+    pub fn new(nickname: String, rating_hint: RatingSummary, body: String) -> Self {
+        let timestamp = unix_now();
+        ChatMessage { id: Uuid::new_v4().to_string(), nickname, rating_hint, timestamp, body }
+    }
 }
 
 // Struct to store private room invitation data
@@ -43,51 +1170,83 @@ pub struct PeerData {
 pub struct Invite {  // New struct for the invite data
     pub room_id: String,
     pub initiator_nickname: String,
+    pub public_key: [u8; 32], // Initiator's X25519 key for the room's encryption key
+    pub initiator_key: Vec<u8>, // Protobuf-encoded identity key used to check the token
+    pub token: Vec<u8>, // Initiator's signature over the room ID, the invitee and when it expires
+    pub passphrase_required: bool,
+    // Unix seconds after which the token is refused, see crypto::INVITE_TTL
+    #[serde(default)]
+    pub expires: Option<u64>,
 }
 
 // Enum to handle private room invitations
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PrivateRoomProtocol {
     Invite(Invite),
-    Accept(String),
+    Accept {
+        room_id: String,
+        public_key: [u8; 32], // Responder's X25519 key
+        passphrase_proof: Option<[u8; 32]>,
+    },
     Reject(String),
 }
 
-// Ask for a nickname and save it to the DHT
+// Pick a nickname and save it to the DHT. A nickname given with --nickname or in the config wins,
+// then the one in a saved identity; we only ask when there is neither, so headless nodes never block
 pub async fn get_and_save_nickname(
-    stdin: &mut io::Lines<io::BufReader<io::Stdin>>,
+    stdin: &mut LineReader,
     peer_id: PeerId,
-    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>
-) -> String{
-    let nickname;
-    println!("Enter a nickname: ");
+    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
+    saved_profile: Option<PeerData>,
+    configured_nickname: Option<String>,
+    quorum: kad::Quorum,
+) -> String {
+    let configured_nickname = configured_nickname
+        .map(|nickname| nickname.trim().to_string())
+        .filter(|nickname| !nickname.is_empty());
+    // A saved identity keeps its ratings, vouches and profile even under a new nickname
+    let peer_data = match (saved_profile, configured_nickname) {
+        (Some(peer_data), Some(nickname)) => PeerData { nickname, ..peer_data },
+        (Some(peer_data), None) => peer_data,
+        (None, Some(nickname)) => PeerData::new(nickname),
+        (None, None) => PeerData::new(prompt_nickname(stdin).await),
+    };
+    system!("Your nickname is: {}", peer_data.nickname);
+    save_profile(swarm, peer_id, &peer_data, quorum);
+    peer_data.nickname
+}
+
+async fn prompt_nickname(stdin: &mut LineReader) -> String {
+    system!("Enter a nickname: ");
     loop {
         match stdin.next_line().await {
             Ok(Some(line)) => {
                 let trimmed = line.trim();
                 if !trimmed.is_empty() {
-                    nickname = trimmed.to_string();
-                    break;
+                    return trimmed.to_string();
                 } else {
-                    println!("Nickname cannot be empty. Please enter a valid nickname.");
+                    error!("Nickname cannot be empty. Please enter a valid nickname.");
                 }
             }
             Ok(None) => {
-                println!("No input received. Please try again.");
+                system!("No input received. Please try again.");
             }
             Err(e) => {
-                println!("Error reading input: {}. Please try again.", e);
+                error!("Error reading input: {}. Please try again.", e);
             }
         }
     }
+}
 
-    println!("Your nickname is: {}", nickname);
-    let peer_data = PeerData {
-        nickname: nickname.trim().to_string(),
-        rating: 0, // Initial rating
-    };
-
-    let serialized = serde_json::to_vec(&peer_data).expect("Serialization failed");
+// Store the PeerData record in the DHT, returning the put if it started. A put that can't start is reported and
+// left for the next republish
+pub fn save_profile(
+    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
+    peer_id: PeerId,
+    peer_data: &PeerData,
+    quorum: kad::Quorum,
+) -> Option<kad::QueryId> {
+    let serialized = serde_json::to_vec(peer_data).expect("Serialization failed");
 
     let nickname_record = kad::Record {
         key: kad::RecordKey::new(&peer_id.to_bytes()),
@@ -98,12 +1257,23 @@ pub async fn get_and_save_nickname(
 
     swarm
         .behaviour_mut()
-        .kademlia.put_record(nickname_record, kad::Quorum::All)
-        .expect("Failed to store record locally.");
+        .kademlia.put_record(nickname_record, quorum)
+        .map_err(|e| output::report_failure("Could not store your profile", e))
+        .ok()
+}
+
+// Store the PeerData record and the reverse nickname record in the DHT, returning the puts that were started
+pub fn save_nickname(
+    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
+    peer_id: PeerId,
+    peer_data: &PeerData,
+    quorum: kad::Quorum,
+) -> Vec<kad::QueryId> {
+    let record_query = save_profile(swarm, peer_id, peer_data, quorum);
 
     // Storing nickname: peer record - uses double the storage but allows for easy lookup
     let reverse_key = kad::RecordKey::new(
-        &format!("nickname:{}", nickname).as_bytes()
+        &format!("nickname:{}", peer_data.nickname).as_bytes()
     );
 
     // Storing reverse: nickname: peerID
@@ -114,22 +1284,199 @@ pub async fn get_and_save_nickname(
         publisher: None,
         expires: None,
     };
-    swarm
+    let reverse_query = swarm
         .behaviour_mut()
-        .kademlia.put_record(reverse_record, kad::Quorum::All)
-        .expect("Failed to store reverse record locally.");
-    nickname
+        .kademlia.put_record(reverse_record, quorum)
+        .map_err(|e| output::report_failure("Could not store your nickname", e));
+    [record_query, reverse_query.ok()].into_iter().flatten().collect()
 }
 
+// Whether a reverse nickname record found in the DHT still has a holder. Records don't say when they were put,
+// but they expire RECORD_TTL after it, so the time left tells their age. A record without an expiry comes
+// straight from its publisher
+pub fn nickname_held(record: &kad::Record) -> bool {
+    record.expires.is_none_or(|expires| {
+        RECORD_TTL.saturating_sub(expires.saturating_duration_since(Instant::now())) < NICKNAME_HELD_FOR
+    })
+}
+
+// Nobody else holds the nickname we started with, so put the reverse record that lets peers find us by it
+pub fn claim_own_nickname(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState) {
+    state.nickname_claim = NicknameClaim::Claimed;
+    let Some(peer_data) = own_peer_data(swarm) else { return };
+    let peer_id = *swarm.local_peer_id();
+    let quorum = state.kademlia.profile_quorum();
+    state.pending_republish.extend(save_nickname(swarm, peer_id, &peer_data, quorum));
+}
+
+// Look the nickname we started with up in the DHT, to warn if someone else holds it, see NicknameClaim
+pub fn check_own_nickname(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, nickname: &str) {
+    let reverse_key = kad::RecordKey::new(&format!("nickname:{nickname}"));
+    let query = swarm.behaviour_mut().kademlia.get_record(reverse_key);
+    state.nickname_claim = NicknameClaim::Checking { query, retry: false };
+}
+
+// Change our nickname, re-publishing our records and telling the current room
+pub fn change_nickname(
+    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
+    state: &mut ChatState,
+    topic: &gossipsub::IdentTopic,
+    own_nickname: &mut String,
+    new_nickname: &str,
+) {
+    let peer_id = *swarm.local_peer_id();
+    // keep the ratings, vouches and profile we have under the new nickname
+    let nickname = new_nickname.trim().to_string();
+    let peer_data = match own_peer_data(swarm) {
+        Some(peer_data) => PeerData { nickname, ..peer_data },
+        None => PeerData::new(nickname),
+    };
+    let old_key = kad::RecordKey::new(&format!("nickname:{}", own_nickname).as_bytes());
+    swarm.behaviour_mut().kademlia.remove_record(&old_key);
+    save_nickname(swarm, peer_id, &peer_data, state.kademlia.profile_quorum());
+    state.nickname_claim = NicknameClaim::Claimed;
+    persist_identity(swarm, state);
 
-// Update a peer rating
+    let announcement = GossipMessage::NicknameChanged {
+        old: own_nickname.clone(),
+        new: new_nickname.to_string(),
+    };
+    if let Err(e) = publish_gossip(swarm, state, topic.hash(), &announcement) {
+        tracing::warn!("Failed to announce nickname change: {:?}", e);
+    }
+    *own_nickname = new_nickname.to_string();
+    system!("Your nickname is now: {}", own_nickname);
+}
+
+
+// Alternatives to offer when a nickname is taken
+pub fn suggest_nicknames(nickname: &str, own_peer_id: &PeerId) -> Vec<String> {
+    let peer_id = own_peer_id.to_base58();
+    vec![
+        format!("{nickname}2"),
+        format!("{nickname}_{}", &peer_id[peer_id.len() - 4..]),
+        format!("the_{nickname}"),
+    ]
+}
+
+// Rate a peer. We keep one rating per peer under our own key, so rating again replaces the earlier one.
+// Used by both /rate and completed trades, and only allowed once a file has been exchanged with the peer
 pub async fn update_peer_rating(
     swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
     peer_id: PeerId,
     rating: i32,
+    proof: Option<SignedTradeReceipt>,
     state: &mut ChatState,
 ) {
-    let reverse_key = kad::RecordKey::new(&peer_id.to_bytes());
-    let query_id = swarm.behaviour_mut().kademlia.get_record(reverse_key);
-    state.pending_rating_update.insert(query_id, rating);
-}
\ No newline at end of file
+    if !state.transfer_history.has_exchanged_with(&peer_id) {
+        error!("You haven't exchanged any files with {peer_id}, so you can't rate them.");
+        return;
+    }
+    let rating = SignedRating::new(&state.keypair, peer_id, rating, proof);
+    let name = state.peer_name(&peer_id);
+    match rating::publish(swarm, &rating, state.kademlia.profile_quorum()) {
+        Ok(previous) => {
+            if previous {
+                system!("You had already rated {name}; your rating has been replaced.");
+            }
+            state.ratings.given(rating);
+            match state.ratings.summary(&peer_id) {
+                Some(summary) => system!("Updated rating for {name}: {summary}"),
+                None => system!("Your rating for {name} has been stored."),
+            }
+        }
+        Err(e) => output::report_failure(&format!("Could not store your rating for {name}"), e),
+    }
+}
+
+
+// Read our own PeerData from the local record store
+pub fn own_peer_data(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>) -> Option<PeerData> {
+    let key = kad::RecordKey::new(&swarm.local_peer_id().to_bytes());
+    swarm
+        .behaviour_mut()
+        .kademlia.store_mut().get(&key)
+        .and_then(|record| serde_json::from_slice::<PeerData>(&record.value).ok())
+}
+
+// Write our profile and trusted peers back to the --identity file so the next run picks them up
+pub fn persist_identity(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState) {
+    let Some(path) = &state.identity_file else { return };
+    let identity = StoredIdentity::new(&state.keypair, own_peer_data(swarm), state.trusted.iter().copied().collect());
+    if let Err(e) = keystore::save(path, &identity) {
+        tracing::error!("Failed to save your identity to {}: {e}", path.display());
+    }
+}
+
+// Put our own records again so they don't expire from the DHT and reach peers that joined since.
+// Failures are reported when the put queries finish. Until the nickname we started with has been checked,
+// only the profile is put, and the check starts as soon as there's a DHT peer to ask
+pub fn republish_own_records(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState) {
+    state.last_republish = Instant::now();
+    let Some(peer_data) = own_peer_data(swarm) else { return };
+    let peer_id = *swarm.local_peer_id();
+    let quorum = state.kademlia.profile_quorum();
+    if state.nickname_claim == NicknameClaim::Claimed {
+        state.pending_republish.extend(save_nickname(swarm, peer_id, &peer_data, quorum));
+    } else {
+        state.pending_republish.extend(save_profile(swarm, peer_id, &peer_data, quorum));
+        if matches!(state.nickname_claim, NicknameClaim::Unchecked | NicknameClaim::Taken)
+            && swarm.behaviour_mut().kademlia.kbuckets().any(|bucket| bucket.num_entries() > 0) {
+            check_own_nickname(swarm, state, &peer_data.nickname);
+        }
+    }
+    // The ratings we've given are ours to keep published, and announcing them again is what lets others find them
+    for rating in rating::given(swarm) {
+        if let Err(e) = rating::publish(swarm, &rating, quorum) {
+            tracing::warn!("Failed to republish your rating of {}: {e}", rating.ratee);
+        }
+    }
+    state.ratings.lookup(swarm, peer_id);
+}
+
+// Change our own profile and republish it
+pub fn update_own_profile(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState, update: impl FnOnce(&mut Profile)) {
+    let Some(mut peer_data) = own_peer_data(swarm) else {
+        system!("Your profile hasn't been published yet.");
+        return;
+    };
+    update(&mut peer_data.profile);
+    let peer_id = *swarm.local_peer_id();
+    if state.nickname_claim == NicknameClaim::Claimed {
+        save_nickname(swarm, peer_id, &peer_data, state.kademlia.profile_quorum());
+    } else {
+        save_profile(swarm, peer_id, &peer_data, state.kademlia.profile_quorum());
+    }
+    persist_identity(swarm, state);
+}
+
+// Our own rating, used as the rating hint on outgoing messages
+pub fn own_rating_hint(swarm: &libp2p::Swarm<SwapBytesBehaviour>, state: &ChatState) -> RatingSummary {
+    state.ratings.summary(swarm.local_peer_id()).unwrap_or_default()
+}
+
+
+// Serialize and publish a message on a gossipsub topic, encrypting it if the topic is a keyed private room
+pub fn publish_gossip(
+    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
+    state: &ChatState,
+    topic: TopicHash,
+    message: &GossipMessage,
+) -> Result<gossipsub::MessageId, gossipsub::PublishError> {
+    let mut serialized = serde_json::to_vec(message).expect("Serialization failed");
+    if let Some(Room { key, .. }) = state.rooms.get(topic.as_str()) {
+        let (nonce, ciphertext) = crypto::seal(key, &serialized);
+        serialized = serde_json::to_vec(&GossipMessage::Encrypted { nonce, ciphertext }).expect("Serialization failed");
+    }
+    swarm.behaviour_mut().chat.gossipsub.publish(topic, serialized)
+}
+
+// Acknowledge everything received in private rooms as read, called once the user is active again
+pub fn send_read_receipts(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState) {
+    let unread: Vec<_> = state.unread_messages.drain(..).collect();
+    for (topic, message_id) in unread {
+        if let Err(e) = publish_gossip(swarm, state, topic, &GossipMessage::Read(message_id)) {
+            tracing::warn!("Failed to send read receipt: {:?}", e);
+        }
+    }
+}
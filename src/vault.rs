@@ -0,0 +1,86 @@
+use std::{ borrow::Cow, path::{ Path, PathBuf } };
+use chacha20poly1305::Key;
+use tokio::io::AsyncWriteExt;
+
+use crate::{ crypto, filenames };
+
+// Environment variable --encrypt-downloads reads the passphrase from, for nodes without a terminal
+pub const PASSPHRASE_VAR: &str = "SWAPBYTES_DOWNLOAD_PASSPHRASE";
+// Encrypted downloads get this extension on top of their own
+pub const EXTENSION: &str = "enc";
+const MAGIC: &[u8] = b"SWAPBYTES-ENC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+// The passphrase received files are encrypted with. Stretching it is slow, so the key is derived once per run
+// and every file written in that run shares its salt
+pub struct DownloadKey {
+    passphrase: String,
+    salt: [u8; SALT_LEN],
+    key: Key,
+}
+
+impl DownloadKey {
+    pub fn new(passphrase: String) -> Self {
+        let salt = crypto::generate_salt();
+        let key = crypto::derive_keystore_key(&passphrase, &salt);
+        DownloadKey { passphrase, salt, key }
+    }
+
+    pub fn passphrase(&self) -> &str {
+        &self.passphrase
+    }
+
+    // The file's header, salt and nonce, then the ciphertext
+    fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let (nonce, ciphertext) = crypto::seal(&self.key, plaintext);
+        [MAGIC, &self.salt, &nonce, &ciphertext].concat()
+    }
+}
+
+// Decrypt a file written with --encrypt-downloads. `known` saves stretching the passphrase again for this run's files
+pub fn open(data: &[u8], passphrase: &str, known: Option<&DownloadKey>) -> Result<Vec<u8>, String> {
+    let body = data.strip_prefix(MAGIC).ok_or("This is not a file encrypted by SwapBytes")?;
+    if body.len() < SALT_LEN + NONCE_LEN {
+        return Err("The file is damaged".to_string());
+    }
+    let (salt, rest) = body.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let key = match known {
+        Some(known) if known.salt == salt && known.passphrase == passphrase => known.key,
+        _ => crypto::derive_keystore_key(passphrase, salt),
+    };
+    crypto::open(&key, nonce.try_into().expect("split at NONCE_LEN"), ciphertext)
+        .ok_or("Wrong passphrase, or the file is damaged".to_string())
+}
+
+// Write a received file under a sanitized name, encrypted if there's a download key, returning where it went
+pub async fn write(dir: &Path, name: &str, data: &[u8], key: Option<&DownloadKey>) -> std::io::Result<PathBuf> {
+    let (name, contents) = match key {
+        Some(key) => (format!("{name}.{EXTENSION}"), Cow::Owned(key.seal(data))),
+        None => (name.to_string(), Cow::Borrowed(data)),
+    };
+    let (path, mut file) = filenames::create_unique(dir, &name).await?;
+    file.write_all(&contents).await?;
+    Ok(path)
+}
+
+pub fn report_saved(path: &Path, encrypted: bool) {
+    if encrypted {
+        system!("File received and saved encrypted as {}. Use /decrypt to read it.", path.display());
+    } else {
+        system!("File received and saved as {}.", path.display());
+    }
+}
+
+// Where /decrypt puts a file by default: the same name without .enc
+pub fn decrypted_path(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(extension) if extension == EXTENSION => path.with_extension(""),
+        _ => {
+            let mut name = path.as_os_str().to_owned();
+            name.push(".decrypted");
+            PathBuf::from(name)
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{ collections::{ BTreeMap, HashSet }, path::PathBuf };
+use libp2p::PeerId;
+
+use crate::{
+    behaviour::{ RequestType, SwapBytesBehaviour },
+    board::{ BoardPost, PostKind },
+    notify::{ self, Trigger },
+    outbound,
+    persist::JsonFile,
+    util::ChatState,
+};
+
+// Most wishes kept, so checking every post against them stays cheap
+const MAX_WISHES: usize = 50;
+// Wishes this long or longer made only of hex digits are taken as the start of a file's SHA-256
+const MIN_DIGEST_PREFIX: usize = 16;
+
+// A file someone has on the board that one of our wishes matches, waiting on /get
+pub struct WishMatch {
+    pub peer: PeerId,
+    pub nickname: String,
+    pub filename: String, // As they posted it, which is also the name to request it by
+    pub size: Option<u64>,
+    pub wish: String,
+}
+
+// Files we're after, by name, SHA-256 or keyword, checked against every /have post that comes in. Each file
+// a wish matches is announced once, numbered for /get
+#[derive(Default)]
+pub struct Wishlist {
+    pub wishes: Vec<String>,
+    file: JsonFile<Vec<String>>,
+    next_id: u32,
+    pub matches: BTreeMap<u32, WishMatch>,
+    announced: HashSet<(PeerId, String)>,
+}
+
+impl Wishlist {
+    // Load the wishes kept in a file, saving every change back to it
+    pub fn load(file: PathBuf) -> Self {
+        let (file, wishes) = JsonFile::open(file, "your wishlist");
+        Wishlist { wishes, file, ..Default::default() }
+    }
+
+    fn save(&self) {
+        self.file.save(&self.wishes);
+    }
+
+    // The first wish a posted file matches: its SHA-256 starting with a hash wish, or its name containing
+    // a name or keyword wish, ignoring case
+    fn matching(&self, post: &BoardPost) -> Option<&String> {
+        let name = post.text.to_lowercase();
+        self.wishes.iter().find(|wish| {
+            let wish = wish.to_lowercase();
+            match is_digest(&wish) {
+                true => post.digest.as_ref().is_some_and(|digest| digest.starts_with(&wish)),
+                false => name.contains(&wish),
+            }
+        })
+    }
+}
+
+fn is_digest(wish: &str) -> bool {
+    wish.len() >= MIN_DIGEST_PREFIX && wish.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Check a peer's board posts against our wishes, announcing files we haven't told the user about yet
+pub fn check(state: &mut ChatState, peer: PeerId, nickname: &str, posts: &[BoardPost]) {
+    for post in posts.iter().filter(|post| post.kind == PostKind::Have) {
+        let Some(wish) = state.wishlist.matching(post).cloned() else { continue };
+        if !state.wishlist.announced.insert((peer, post.text.clone())) {
+            continue;
+        }
+        let name = state.cached_peer(&peer).map_or(nickname.to_string(), |peer| peer.nickname.clone());
+        state.wishlist.next_id += 1;
+        let id = state.wishlist.next_id;
+        let description = format!("{name} has {post}, which matches your wish for {wish}");
+        notify::notify(Trigger::WishMatch, &name, &description);
+        system!("[{id}] {description}. Type /get {id} to request it.");
+        state.wishlist.matches.insert(id, WishMatch { peer, nickname: name, filename: post.text.clone(), size: post.size, wish });
+    }
+}
+
+// /wish <file name, hash or keyword>
+pub fn add(state: &mut ChatState, wish: &str) {
+    if state.wishlist.wishes.len() >= MAX_WISHES {
+        error!("Your wishlist already has {MAX_WISHES} entries. Take one off with /wish remove <number> first.");
+        return;
+    }
+    if state.wishlist.wishes.iter().any(|existing| existing.eq_ignore_ascii_case(wish)) {
+        system!("{wish} is already on your wishlist.");
+        return;
+    }
+    state.wishlist.wishes.push(wish.to_string());
+    state.wishlist.save();
+    match is_digest(wish) {
+        true => system!("Added {wish} to your wishlist. You'll be told when someone has a file with that hash."),
+        false => system!("Added {wish} to your wishlist. You'll be told when someone has a file with that in its name."),
+    }
+    // Posts already on the board count too
+    let haves = state.board.haves();
+    for (peer, nickname, posts) in haves {
+        check(state, peer, &nickname, &posts);
+    }
+}
+
+// /wish remove <number>
+pub fn remove(state: &mut ChatState, number: usize) {
+    match number.checked_sub(1).filter(|index| *index < state.wishlist.wishes.len()) {
+        Some(index) => {
+            let wish = state.wishlist.wishes.remove(index);
+            state.wishlist.save();
+            system!("Took {wish} off your wishlist.");
+        }
+        None => system!("There is no wish {number}. Type /wishlist to see them."),
+    }
+}
+
+// /wishlist: our wishes, then the files that matched them
+pub fn list(state: &ChatState) {
+    if state.wishlist.wishes.is_empty() {
+        system!("Your wishlist is empty. Add to it with /wish <file name, hash or keyword>.");
+    }
+    for (number, wish) in state.wishlist.wishes.iter().enumerate() {
+        system!("{}. {wish}", number + 1);
+    }
+    for (id, found) in &state.wishlist.matches {
+        let size = found.size.map(|size| format!(" ({size} bytes)")).unwrap_or_default();
+        system!("[{id}] {} has {}{size}, for {}. /get {id} requests it.", found.nickname, found.filename, found.wish);
+    }
+}
+
+// /get <id>: request a matched file from whoever has it, without needing a private room first
+pub fn get(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, state: &mut ChatState, id: u32) {
+    let Some(found) = state.wishlist.matches.remove(&id) else {
+        system!("There is no wishlist match {id}. Type /wishlist to see them.");
+        return;
+    };
+    if !swarm.is_connected(&found.peer) {
+        error!("{} isn't connected any more, so {} couldn't be requested.", found.nickname, found.filename);
+        state.wishlist.matches.insert(id, found);
+        return;
+    }
+    let own_peer_id = *swarm.local_peer_id();
+    system!("Requesting {} from {}...", found.filename, found.nickname);
+    outbound::send(swarm, state, found.peer, RequestType::FileRequest(found.filename, own_peer_id));
+}
@@ -0,0 +1,61 @@
+// Two nodes in one process: the second is pointed at the first through its address book, so the test doesn't
+// depend on mDNS or a rendezvous server, and a chat message has to make it across
+use std::{ collections::HashMap, fs, net::TcpListener, time::{ Duration, SystemTime, UNIX_EPOCH } };
+use serde_json::json;
+use swapbytes::{ Event, NodeConfig, SwapBytesNode };
+use tokio::time::{ interval, timeout };
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_message_reaches_the_other_node() {
+    let dir = std::env::temp_dir().join(format!("swapbytes-two-nodes-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let port = free_port();
+    let mut alice = SwapBytesNode::new(NodeConfig {
+        nickname: Some("alice".into()),
+        port: Some(port.to_string()),
+        ..Default::default()
+    }).await.unwrap();
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let known = HashMap::from([(alice.peer_id().to_string(), json!({
+        "addresses": [format!("/ip4/127.0.0.1/tcp/{port}")],
+        "nickname": "alice",
+        "last_seen": now,
+    }))]);
+    let peers = dir.join("peers.json");
+    fs::write(&peers, serde_json::to_vec(&known).unwrap()).unwrap();
+    let mut bob = SwapBytesNode::new(NodeConfig {
+        nickname: Some("bob".into()),
+        peers: Some(peers),
+        ..Default::default()
+    }).await.unwrap();
+
+    // Gossip only reaches peers once they've heard each other subscribe, so keep sending until it arrives
+    let bob_id = bob.peer_id();
+    let mut events = alice.events();
+    let mut resend = interval(Duration::from_secs(1));
+    let received = timeout(Duration::from_secs(30), async {
+        loop {
+            tokio::select! {
+                event = alice.next_event() => alice.handle(event).await,
+                event = bob.next_event() => bob.handle(event).await,
+                _ = resend.tick() => bob.send_message("hello from bob"),
+                Ok(event) = events.recv() => {
+                    if let Event::Message { peer, text, .. } = event.event
+                        && peer == bob_id {
+                        return text;
+                    }
+                }
+            }
+        }
+    }).await;
+    assert_eq!(received.as_deref(), Ok("hello from bob"));
+
+    bob.shutdown().await;
+    alice.shutdown().await;
+    let _ = fs::remove_dir_all(&dir);
+}